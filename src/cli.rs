@@ -0,0 +1,495 @@
+use std::env;
+use std::error::Error;
+use std::io::IsTerminal;
+
+use crate::db::SortMode;
+use crate::db::Transfer;
+use crate::rpc::{self, InspectedClip, ListedClip};
+use crate::store::Health;
+use crate::trust::{self, Trust};
+use crate::{import, native, options, persist, snippets};
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const CYAN: &str = "\x1b[36m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+
+/// Exit code when the daemon couldn't do what was asked, like showing a clip
+/// that isn't there.
+pub const FAILED: i32 = 1;
+/// Exit code when the command line is wrong, or its input can't be read.
+pub const INVALID: i32 = 2;
+/// Exit code when the daemon isn't running, or stopped answering.
+pub const NOT_RUNNING: i32 = 3;
+
+/// Why a command failed, as far as the exit code goes. Other errors are
+/// problems with the command line.
+#[derive(Debug)]
+enum Failure {
+    /// The daemon tried and told why it couldn't
+    Refused(String),
+    NotRunning(String),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Refused(reason) => write!(f, "{}", reason),
+            Failure::NotRunning(reason) => write!(f, "can't reach the daemon, is it running? ({})", reason),
+        }
+    }
+}
+
+impl Error for Failure {}
+
+/// The exit code for an error `run` returned.
+pub fn exit_code(error: &(dyn Error + 'static)) -> i32 {
+    match error.downcast_ref::<Failure>() {
+        Some(Failure::Refused(_)) => FAILED,
+        Some(Failure::NotRunning(_)) => NOT_RUNNING,
+        None if error.is::<tarpc::client::RpcError>() => NOT_RUNNING,
+        None => INVALID,
+    }
+}
+
+/// Runs a command against the running daemon.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    // commands that work without the daemon
+    match args.get(1).map(|c| c.as_ref()) {
+        Some("db-check") => return db_check(),
+        Some("doctor") => return doctor().await,
+        Some("native-host") => return native::run().await,
+        Some("oneshot") => {
+            let print = match args.get(2).map(|a| a.as_ref()) {
+                None => false,
+                Some("--print") => true,
+                Some(arg) => return Err(format!("oneshot takes --print or nothing, not {}", arg).into()),
+            };
+            return crate::oneshot::run(print).await.map_err(|e| Failure::Refused(e.to_string()).into());
+        }
+        _ if native::launched_by_browser(args) => return native::run().await,
+        _ => {}
+    }
+
+    let client = rpc::create_client(options::socket_path()).await.map_err(|e| Failure::NotRunning(e.to_string()))?;
+    match args.get(1).map(|c| c.as_ref()) {
+        Some("show") => client.show(tarpc::context::current()).await?.map_err(Failure::Refused)?,
+        Some(command @ ("pause" | "start")) => {
+            let selection = flag(args, "--selection").map(|s| s.parse()).transpose()?;
+            if command == "pause" {
+                client.pause(tarpc::context::current(), selection).await?.map_err(Failure::Refused)?
+            } else {
+                client.start(tarpc::context::current(), selection).await?.map_err(Failure::Refused)?
+            }
+        }
+        Some("panic") => {
+            let pause = args.iter().any(|a| a == "--pause");
+            client.panic(tarpc::context::current(), pause).await?.map_err(Failure::Refused)?
+        }
+        Some("watch") => client.watch(tarpc::context::current()).await?.map_err(Failure::Refused)?,
+        Some("paste-last") => client.paste_last(tarpc::context::current()).await?.map_err(Failure::Refused)?,
+        Some("list") => {
+            let sort = match flag(args, "--sort") {
+                Some(sort) => sort.parse()?,
+                None => SortMode::Recency,
+            };
+            let preview_lines = match flag(args, "--preview-lines") {
+                Some(lines) => lines.parse()?,
+                None => 1,
+            };
+            let color = use_color(flag(args, "--color"))?;
+            let query = positional(args, &["--sort", "--preview-lines", "--color"]);
+
+            let clips = client.list(tarpc::context::current(), sort, query).await?;
+            for clip in clips {
+                print_clip(&clip, preview_lines, color);
+            }
+        }
+        Some("inspect") => {
+            let id = args.get(2).ok_or("which clip? Give the id rpt list shows")?.parse()?;
+            match client.inspect(tarpc::context::current(), id).await? {
+                Some(clip) => print_inspected(&clip),
+                None => return Err(Failure::Refused(format!("there is no clip {}", id)).into()),
+            }
+        }
+        Some("trust") => {
+            let trust = match args.get(2).map(|c| c.as_ref()) {
+                None => {
+                    for (class, trust) in client.trusted(tarpc::context::current()).await? {
+                        let decision = match trust {
+                            Trust::Allow => "allow",
+                            Trust::Deny => "deny",
+                        };
+                        println!("{}\t{}", decision, class);
+                    }
+                    return Ok(());
+                }
+                Some("allow") => Some(Trust::Allow),
+                Some("deny") => Some(Trust::Deny),
+                Some("forget") => None,
+                Some(other) => return Err(format!("trust takes allow, deny or forget, not {}", other).into()),
+            };
+            let class = args.get(3).ok_or("which application? Give its WM_CLASS")?;
+            client.set_trust(tarpc::context::current(), class.clone(), trust).await?.map_err(Failure::Refused)?;
+        }
+        Some("bind") => match (args.get(2), args.get(3)) {
+            (Some(key), Some(action)) => {
+                client.bind(tarpc::context::current(), key.clone(), action.clone()).await?.map_err(Failure::Refused)?
+            }
+            _ => {
+                for (key, action) in client.bindings(tarpc::context::current()).await? {
+                    println!("{}\t{}", key, action.name());
+                }
+            }
+        },
+        Some("tag") if args.get(2).map(String::as_str) == Some("current") => {
+            let tag = args.get(3).ok_or("which tag?")?.clone();
+            let id = client.tag_current(tarpc::context::current(), tag).await?.map_err(Failure::Refused)?;
+            println!("tagged clip {}", id);
+        }
+        Some(command @ ("tag" | "untag")) => {
+            let id = args.get(2).ok_or("which clip? Give the id rpt list shows")?.parse()?;
+            let tag = args.get(3).ok_or("which tag?")?.clone();
+            if command == "tag" {
+                client.tag(tarpc::context::current(), id, tag).await?.map_err(Failure::Refused)?
+            } else {
+                client.untag(tarpc::context::current(), id, tag).await?.map_err(Failure::Refused)?
+            }
+        }
+        Some("promote") => {
+            let id = args.get(2).ok_or("which clip? Give the id rpt list shows")?.parse()?;
+            let to = flag(args, "--to").map(|to| to.parse()).transpose()?;
+            client.promote(tarpc::context::current(), id, to).await?.map_err(Failure::Refused)?
+        }
+        Some("import") if flag(args, "--from").is_none() => {
+            let path = positional(args, &[]).ok_or("which file? Give one rpt export wrote, or --from for another clipboard manager's")?;
+            let export: persist::Export = serde_json::from_str(&std::fs::read_to_string(&path)?)
+                .map_err(|e| format!("{} isn't a history rpt export wrote: {}", path, e))?;
+            let total = export.clips.len();
+            let added = client.import_history(tarpc::context::current(), export).await?.map_err(Failure::Refused)?;
+            println!("imported {} of {} clips from {}", added, total, path);
+        }
+        Some("export") => {
+            let export = client.export_history(tarpc::context::current()).await?.map_err(Failure::Refused)?;
+            println!("{}", serde_json::to_string_pretty(&export)?);
+        }
+        Some("import") => {
+            let format: import::Format = flag(args, "--from").ok_or("import needs --from clipman|greenclip|copyq")?.parse()?;
+            let path = positional(args, &["--from"]).ok_or("which file?")?;
+            let contents = std::fs::read_to_string(&path)?;
+            let modified = std::fs::metadata(&path)?.modified()?;
+            let clips = import::saved(import::parse(format, &contents)?, modified);
+            let total = clips.len();
+            let mut added = 0;
+            // newest first, since each batch goes behind what's in the history
+            for batch in clips.rchunks(import::BATCH) {
+                let export = persist::Export::new(persist::HistoryFile { selection: None, next_id: 0, clips: batch.to_vec() })?;
+                added += client.import_history(tarpc::context::current(), export).await?.map_err(Failure::Refused)?;
+            }
+            println!("imported {} of {} clips from {}", added, total, path);
+        }
+        Some("add") => {
+            let text = match positional(args, &["--app", "--tags", "--ttl"]) {
+                Some(text) if text != "-" => text,
+                _ => std::io::read_to_string(std::io::stdin())?,
+            };
+            let app = flag(args, "--app").map(|app| app.to_owned());
+            let tags = flag(args, "--tags").map(|t| t.split(',').map(|t| t.to_owned()).collect()).unwrap_or_default();
+            let ttl = flag(args, "--ttl").map(|ttl| ttl.parse()).transpose()?;
+            let id = client.add_clip(tarpc::context::current(), text, app, tags, ttl).await?.map_err(Failure::Refused)?;
+            println!("{}", id);
+        }
+        Some("monitor") => {
+            let selection = args.get(2).ok_or("which selection? primary, secondary or clipboard")?.parse()?;
+            let enabled = match args.get(3).map(|a| a.as_ref()) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err("monitor takes a selection and on or off".into()),
+            };
+            client.monitor(tarpc::context::current(), selection, enabled).await?.map_err(Failure::Refused)?
+        }
+        Some("resync") => client.resync(tarpc::context::current()).await?.map_err(Failure::Refused)?,
+        Some("sync") => {
+            let mode = args.get(2).ok_or("sync takes off, to-primary or both")?.parse()?;
+            client.sync(tarpc::context::current(), mode).await?.map_err(Failure::Refused)?
+        }
+        Some(command @ ("pin" | "unpin")) => {
+            let id = args.get(2).ok_or("which clip? Give the id rpt list shows")?.parse()?;
+            client.pin(tarpc::context::current(), id, command == "pin").await?.map_err(Failure::Refused)?
+        }
+        Some("ttl") => {
+            let id = args.get(2).ok_or("which clip? Give the id rpt list shows")?.parse()?;
+            let ttl = match args.get(3).map(|s| s.as_str()) {
+                Some("off") => None,
+                Some(secs) => Some(secs.parse().map_err(|_| format!("{} isn't a number of seconds", secs))?),
+                None => return Err("ttl takes a number of seconds, or off".into()),
+            };
+            client.expire(tarpc::context::current(), id, ttl).await?.map_err(Failure::Refused)?
+        }
+        Some("incognito") => {
+            let enabled = match args.get(2).map(|s| s.as_str()) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err("incognito takes on or off".into()),
+            };
+            client.incognito(tarpc::context::current(), enabled).await?.map_err(Failure::Refused)?
+        }
+        Some(command @ ("archive" | "unarchive")) => {
+            let id = args.get(2).ok_or("which clip? Give the id rpt list shows")?.parse()?;
+            client.archive(tarpc::context::current(), id, command == "archive").await?.map_err(Failure::Refused)?
+        }
+        Some("status") => {
+            let status = client.status(tarpc::context::current()).await?;
+            println!("clips      {}", status.clips);
+            match status.store {
+                None => println!("store      ok"),
+                Some(problem) => println!("store      degraded: {}", problem),
+            }
+            if status.incognito {
+                println!("incognito  on");
+            }
+            let atoms = status.atoms;
+            println!(
+                "atoms      {} cached, {} of {} lookups from the cache",
+                atoms.cached,
+                atoms.hits,
+                atoms.hits + atoms.misses
+            );
+            for (class, method) in status.paste_methods {
+                println!("pasting    into {} with {}", class, method);
+            }
+        }
+        Some("unbind") => {
+            let key = args.get(2).ok_or("which key?")?;
+            client.unbind(tarpc::context::current(), key.clone()).await?.map_err(Failure::Refused)?
+        }
+        Some(command) => return Err(format!("{} isn't a command", command).into()),
+        None => return Err("which command?".into()),
+    }
+    Ok(())
+}
+
+// Checks that the files in the data directory can be loaded, and tells which
+// ones will be migrated
+fn db_check() -> Result<(), Box<dyn Error>> {
+    let dir = options::data_dir();
+    let checks = [
+        (snippets::FILE, snippets::SCHEMA.check::<snippets::SnippetFile>(&dir.join(snippets::FILE))),
+        (trust::FILE, trust::SCHEMA.check::<trust::TrustFile>(&dir.join(trust::FILE))),
+        (persist::FILE, persist::SCHEMA.check::<persist::HistoryFile>(&dir.join(persist::FILE))),
+    ];
+    let mut broken = 0;
+    for (file, health) in checks {
+        match health {
+            Health::Missing => println!("{}: not created yet", file),
+            Health::Current => println!("{}: ok", file),
+            Health::Outdated(version) => {
+                println!("{}: ok, will be migrated from version {} when loaded", file, version)
+            }
+            Health::Broken(e) => {
+                println!("{}: broken: {}", file, e);
+                broken += 1;
+            }
+        }
+    }
+    if broken > 0 {
+        return Err(format!("{} broken files in {}", broken, dir.display()).into());
+    }
+    Ok(())
+}
+
+// Looks for the problems that keep rpt from working as configured
+async fn doctor() -> Result<(), Box<dyn Error>> {
+    let mut problems = 0;
+    let options = match options::Options::load() {
+        Ok(options) => {
+            println!("config: ok");
+            options
+        }
+        Err(e) => {
+            println!("config: {}", e);
+            problems += 1;
+            options::Options::default()
+        }
+    };
+    match crate::ui::font_or_fallback(options.font_name.as_deref()) {
+        Ok((_, None)) => println!("font: ok"),
+        Ok((_, Some(problem))) => {
+            println!("font: {}", problem);
+            problems += 1;
+        }
+        Err(e) => {
+            println!("font: {}", e);
+            problems += 1;
+        }
+    }
+    match rpc::create_client(options::socket_path()).await {
+        Ok(_) => println!("daemon: running"),
+        Err(e) => {
+            println!("daemon: not reachable: {}", e);
+            problems += 1;
+        }
+    }
+    if problems > 0 {
+        return Err(format!("found {} problem(s)", problems).into());
+    }
+    Ok(())
+}
+
+fn print_clip(clip: &ListedClip, preview_lines: usize, color: bool) {
+    let paint = |code: &str, text: &str| {
+        if color {
+            format!("{}{}{}", code, text, RESET)
+        } else {
+            text.to_owned()
+        }
+    };
+
+    let mut header = format!(
+        "{} {} {}",
+        paint(DIM, &format!("{:>3}", clip.id)),
+        paint(CYAN, &format!("[{}]", clip.source)),
+        paint(DIM, &format_age(clip.age))
+    );
+    for tag in &clip.tags {
+        header.push(' ');
+        header.push_str(&paint(CYAN, &format!("#{}", tag)));
+    }
+    let line_count = clip.lines;
+    let mut offset = 0;
+    for (i, line) in clip.text.lines().enumerate() {
+        let len = line.chars().count();
+        if i < preview_lines.max(1) {
+            let mut highlighted = String::new();
+            for (j, c) in line.chars().enumerate() {
+                if clip.matched.contains(&(offset + j)) {
+                    highlighted.push_str(&paint(BOLD_YELLOW, &c.to_string()));
+                } else {
+                    highlighted.push(c);
+                }
+            }
+            if i == 0 {
+                println!("{} {}", header, highlighted);
+            } else {
+                println!("    {}", highlighted);
+            }
+        } else {
+            println!("    {}", paint(DIM, &format!("... + {} rows", line_count - i)));
+            break;
+        }
+        // +1 for the line break
+        offset += len + 1;
+    }
+    if line_count == 0 {
+        println!("{}", header);
+    }
+}
+
+fn print_inspected(clip: &InspectedClip) {
+    println!("id         {}", clip.id);
+    println!("source     {}", clip.source);
+    println!("copied     {} ago", format_age(clip.age));
+    println!(
+        "size       {} bytes, {} lines, {} words",
+        clip.counts.bytes, clip.counts.lines, clip.counts.words
+    );
+    println!("uses       {}", clip.uses);
+    if clip.pinned {
+        println!("pinned     yes");
+    }
+    if clip.archived {
+        println!("archived   yes");
+    }
+    if let Some(secs) = clip.expires_in {
+        println!("forgotten  in {}", format_age(secs));
+    }
+    if !clip.tags.is_empty() {
+        println!("tags       {}", clip.tags.join(", "));
+    }
+    if let Some(app) = &clip.app {
+        println!("added by   {}", app);
+    }
+    if let Some(origin) = &clip.origin {
+        let class = origin.class.as_deref().unwrap_or("unknown");
+        match &origin.title {
+            Some(title) => println!("window     {:#x}, {}: {}", origin.window, class, title),
+            None => println!("window     {:#x}, {}", origin.window, class),
+        }
+    }
+    if let Some((url, title)) = &clip.page {
+        match title {
+            Some(title) => println!("page       {} <{}>", title, url),
+            None => println!("page       {}", url),
+        }
+    }
+    if !clip.chain.is_empty() {
+        let ids: Vec<String> = clip.chain.iter().map(|id| id.to_string()).collect();
+        println!("edited     from {}", ids.join(" <- "));
+    }
+    match &clip.trace {
+        None => println!("trace      none, the clip wasn't fetched from another application"),
+        Some(trace) => {
+            println!("selection  {}", trace.selection);
+            println!("offered    {}", trace.targets.join(", "));
+            println!("chosen     {}", trace.target);
+            let transfer = match trace.transfer {
+                Transfer::Direct => "direct",
+                Transfer::Incremental => "incremental (INCR)",
+            };
+            println!("transfer   {}", transfer);
+            println!("took       {} ms", trace.duration_ms);
+            println!("received   {} bytes", trace.size);
+            if let Some(app) = &trace.focused_app {
+                println!("focused    {}", app);
+            }
+        }
+    }
+}
+
+/// Colors are used with `--color=always`, or with `--color=auto` (the default)
+/// when writing to a terminal and `NO_COLOR` isn't set.
+fn use_color(setting: Option<&str>) -> Result<bool, Box<dyn Error>> {
+    match setting.unwrap_or("auto") {
+        "always" => Ok(true),
+        "never" => Ok(false),
+        "auto" => Ok(env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            && std::io::stdout().is_terminal()),
+        other => Err(format!("--color has to be always, never or auto, not {}", other).into()),
+    }
+}
+
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        s if s < 60 => format!("{}s", s),
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (24 * 60 * 60)),
+    }
+}
+
+// Value of a `--name value` or `--name=value` command line flag
+pub fn flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == name {
+            return iter.next().map(|v| v.as_str());
+        } else if let Some(value) = arg.strip_prefix(name).and_then(|v| v.strip_prefix('=')) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// The first argument after the command that isn't a flag or the value of one
+// of the given flags
+fn positional(args: &[String], flags_with_values: &[&str]) -> Option<String> {
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        if flags_with_values.contains(&arg.as_str()) {
+            iter.next();
+        } else if !arg.starts_with("--") {
+            return Some(arg.clone());
+        }
+    }
+    None
+}
@@ -1,32 +1,353 @@
 use std::borrow::Cow;
-use crate::clipboard::GetState::{GetTargets, GetText};
+use crate::clipboard::GetState::{Incremental, GetHtml, GetTargets, GetText, GetTimestamp};
 use crate::db;
-use crate::db::{Clip, ClipContents, Database};
+use crate::incognito;
+use crate::inject::Paster;
+use crate::notify;
+use crate::redact;
+use crate::wm;
+use crate::db::{Clip, ClipContents, Database, Html, Origin, Trace, Transfer};
+use crate::options::{self, AppPattern, ExtraTarget, Options};
+use crate::store::Spool;
+use crate::trust::{Decision, TrustStore};
 use breadx::prelude::*;
-use breadx::protocol::xfixes::{SelectionEventMask};
+use breadx::protocol::xfixes::{SelectionEvent, SelectionEventMask};
 use breadx::protocol::xproto::{AtomEnum, EventMask, SelectionRequestEvent, SelectionNotifyEvent};
 use breadx::protocol::{xproto, Event};
 use log::{debug, error, info, trace, warn};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
 
-const SELECTIONS: &'static [&'static str] = &["PRIMARY", "SECONDARY", "CLIPBOARD"];
-const TARGETS: &'static str = "TARGETS";
+const SELECTIONS: &[&str] = &["PRIMARY", "SECONDARY", "CLIPBOARD"];
+const TARGETS: &str = "TARGETS";
+const TIMESTAMP: &str = "TIMESTAMP";
+const MULTIPLE: &str = "MULTIPLE";
+const HTML: &str = "text/html";
+// Files copied in a file manager. GNOME's list starts with whether they were
+// copied or cut.
+const URI_LIST: &str = "text/uri-list";
+const GNOME_FILES: &str = "x-special/gnome-copied-files";
+const INCR: &str = "INCR";
+// Applications hand their clipboard over to the owner of this selection when
+// they exit, by asking it to convert to SAVE_TARGETS
+const CLIPBOARD_MANAGER: &str = "CLIPBOARD_MANAGER";
+const SAVE_TARGETS: &str = "SAVE_TARGETS";
+// Offered by KeePassXC and other password managers along with passwords
+const PASSWORD_HINT: &str = "x-kde-passwordManagerHint";
+// The text targets served, preferred first. TEXT lets us pick the encoding,
+// and STRING is latin-1 for older applications like xterm.
+const TEXT_TARGETS: &[&str] = &["UTF8_STRING", "text/plain;charset=utf-8", "STRING", "TEXT"];
+// How long after serving a paste a capture of the same text counts as an echo
+const ECHO_WINDOW: Duration = Duration::from_secs(3);
+// How long after a paste copying a changed version of it from the same
+// application makes the two a chain
+const CHAIN_WINDOW: Duration = Duration::from_secs(30 * 60);
+// How long a command generating an extra target may take
+const GENERATOR_TIMEOUT: Duration = Duration::from_secs(2);
+// How long an owner may take to answer a conversion, or to send the next
+// chunk of an incremental one
+const CONVERSION_TIMEOUT: Duration = Duration::from_secs(5);
+// The properties on the getter window that conversions are fetched into are
+// named this with a number after it
+const GETTER_PROPERTY: &str = "REPEAT_";
+// How many of those there are, and so how many fetches can be going on at
+// once. A few selections, each with a fetch or two, need far fewer.
+const GETTER_PROPERTIES: usize = 16;
+// The atoms nearly every capture or paste needs, interned at startup in one
+// round trip
+const PREWARMED: &[&str] = &[
+    "PRIMARY", "SECONDARY", "CLIPBOARD", TARGETS, TIMESTAMP, MULTIPLE, INCR, SAVE_TARGETS, CLIPBOARD_MANAGER,
+    "UTF8_STRING", "text/plain;charset=utf-8", "STRING", "TEXT", HTML, URI_LIST, GNOME_FILES,
+    "image/png", "image/jpeg", "image/bmp", "WM_CLIENT_LEADER", "_NET_WM_NAME",
+];
+
+static ATOMS_CACHED: AtomicUsize = AtomicUsize::new(0);
+static ATOM_HITS: AtomicU64 = AtomicU64::new(0);
+static ATOM_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// How well the atom cache is doing.
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct AtomStats {
+    pub cached: usize,
+    /// Atoms and atom names found in the cache
+    pub hits: u64,
+    /// Ones that had to be asked of the X server
+    pub misses: u64,
+}
+
+pub fn atom_stats() -> AtomStats {
+    AtomStats {
+        cached: ATOMS_CACHED.load(Ordering::Relaxed),
+        hits: ATOM_HITS.load(Ordering::Relaxed),
+        misses: ATOM_MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether a clip captured from one of PRIMARY and CLIPBOARD is put into the
+/// other as well, like autocutsel does.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+    Off,
+    /// Copies from CLIPBOARD also go into PRIMARY
+    ToPrimary,
+    /// Each selection follows the other
+    Both,
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SyncMode, String> {
+        match s {
+            "off" => Ok(SyncMode::Off),
+            "to-primary" => Ok(SyncMode::ToPrimary),
+            "both" => Ok(SyncMode::Both),
+            _ => Err(format!("unknown sync mode {}, only off, to-primary or both", s)),
+        }
+    }
+}
+
+impl SyncMode {
+    // The selection a clip captured from `source` is copied to, if any
+    fn target(self, source: db::Source) -> Option<db::Source> {
+        match (self, source) {
+            (SyncMode::ToPrimary | SyncMode::Both, db::Source::Clipboard) => Some(db::Source::Primary),
+            (SyncMode::Both, db::Source::Primary) => Some(db::Source::Clipboard),
+            _ => None,
+        }
+    }
+}
 
 pub struct Clipboard {
+    root: xproto::Window,
     getter: xproto::Window,
     setter: xproto::Window,
-    get_states: HashMap<xproto::Atom, GetState>,
+    fetches: Fetches,
+    // what happened so far while fetching into each property
+    traces: HashMap<xproto::Atom, PendingTrace>,
+    // incremental transfers in progress, by property
+    spools: HashMap<xproto::Atom, Spool>,
+    // conversions being sent with INCR, by requestor and property
+    outgoing: HashMap<(xproto::Window, xproto::Atom), Outgoing>,
+    // the most data one ChangeProperty request can carry
+    max_property_bytes: usize,
+    // selections that changed while they were being fetched or while no
+    // property was free, fetched once they can be
+    changed: HashSet<xproto::Atom>,
+    // the owner of each polled selection and when it took it, as of the
+    // last poll, to only fetch it again once either changes
+    poll_times: HashMap<xproto::Atom, (xproto::Window, xproto::Timestamp)>,
     atoms: HashMap<String, xproto::Atom>,
     database: Arc<Database>,
     running: bool,
+    serve_count: u64,
+    last_served: Option<(u64, Instant)>,
+    // the clip last pasted and the class of the application it went to, to
+    // tell when it comes back edited
+    last_paste: Option<(Clip, String, Instant)>,
+    cut_buffer: bool,
+    extra_targets: Vec<ExtraTarget>,
+    // applications whose copies aren't kept
+    ignore_apps: Vec<AppPattern>,
+    // ask before serving applications that haven't been trusted yet
+    confirm_new_apps: bool,
+    trust: Arc<TrustStore>,
+    // requests waiting for the user to decide about the application
+    pending: Vec<(String, SelectionRequestEvent)>,
+    // which selections choosing a clip owns
+    own_selections: Vec<db::Source>,
+    // the selections we currently own, and about when we got them
+    owned: HashMap<xproto::Atom, xproto::Timestamp>,
+    // the latest server time seen in an event
+    last_time: xproto::Timestamp,
+    // the selections clips are captured from
+    monitored: Vec<db::Source>,
+    // the selections whose clips are dropped while capture is running
+    paused: Vec<db::Source>,
+    // CLIPBOARD_MANAGER, if we got to own it
+    manager: Option<xproto::Atom>,
+    sync: SyncMode,
+    // whether the server tells about selection changes, or we have to poll
+    xfixes: bool,
+    // a hash of what polling last found in each selection, by name
+    polled: HashMap<String, u64>,
+    // the clip each selection's current owner offered, by name, to serve
+    // ourselves if the owner goes away
+    offered: HashMap<String, Clip>,
+    // makes applications paste, in a way that works with them
+    paster: Paster,
+}
+
+/// The fetches going on, each into a getter property of its own until it's
+/// over, one way or another.
+struct Fetches {
+    states: HashMap<xproto::Atom, GetState>,
+    // when each fetch is given up on
+    deadlines: HashMap<xproto::Atom, Instant>,
+    properties: GetterProperties,
+}
+
+impl Fetches {
+    fn new(properties: Vec<xproto::Atom>) -> Fetches {
+        Fetches { states: HashMap::new(), deadlines: HashMap::new(), properties: GetterProperties::new(properties) }
+    }
+
+    // A property for a new fetch, in use until `finish`, or `tidy` if the
+    // fetch never gets going
+    fn take(&mut self) -> Option<xproto::Atom> {
+        self.properties.take()
+    }
+
+    fn any_free(&self) -> bool {
+        self.properties.any_free()
+    }
+
+    fn get(&self, property: xproto::Atom) -> Option<&GetState> {
+        self.states.get(&property)
+    }
+
+    // Waits for the owner to answer into the property, until the deadline
+    fn wait(&mut self, property: xproto::Atom, state: GetState, deadline: Instant) {
+        self.properties.hold(property);
+        self.states.insert(property, state);
+        self.deadlines.insert(property, deadline);
+    }
+
+    // Puts off giving up on the fetch, like when a chunk of it arrived
+    fn extend(&mut self, property: xproto::Atom, deadline: Instant) {
+        if self.states.contains_key(&property) {
+            self.deadlines.insert(property, deadline);
+        }
+    }
+
+    // Ends the fetch into the property, returning what it was, and frees
+    // the property for the next one
+    fn finish(&mut self, property: xproto::Atom) -> Option<GetState> {
+        self.deadlines.remove(&property);
+        self.properties.release(property);
+        self.states.remove(&property)
+    }
+
+    // The fetches whose deadline is past at `now`, the longest overdue first
+    fn overdue(&self, now: Instant) -> Vec<xproto::Atom> {
+        let mut overdue: Vec<_> = self.deadlines.iter().filter(|(_, at)| **at <= now).collect();
+        overdue.sort_by_key(|(_, at)| **at);
+        overdue.into_iter().map(|(property, _)| *property).collect()
+    }
+
+    // Frees the properties held for fetches that aren't going on, like ones
+    // whose conversion couldn't even be asked for, and returns them
+    fn tidy(&mut self) -> Vec<xproto::Atom> {
+        let states = &self.states;
+        self.deadlines.retain(|property, _| states.contains_key(property));
+        let idle: Vec<_> = self.properties.held().filter(|property| !states.contains_key(property)).collect();
+        for property in &idle {
+            self.properties.release(*property);
+        }
+        idle
+    }
+
+    fn waiting(&self) -> bool {
+        !self.deadlines.is_empty()
+    }
+}
+
+/// A fixed set of properties on the getter window to fetch conversions into,
+/// reused from one fetch to the next. Each is in use from when a conversion
+/// into it is asked for until the fetch is over, one way or another.
+struct GetterProperties {
+    atoms: Vec<xproto::Atom>,
+    in_use: HashSet<xproto::Atom>,
+}
+
+impl GetterProperties {
+    fn new(atoms: Vec<xproto::Atom>) -> GetterProperties {
+        GetterProperties { atoms, in_use: HashSet::new() }
+    }
+
+    // The first one that's free, so the same few are used over and over
+    fn take(&mut self) -> Option<xproto::Atom> {
+        let atom = *self.atoms.iter().find(|atom| !self.in_use.contains(atom))?;
+        self.in_use.insert(atom);
+        Some(atom)
+    }
+
+    // Keeps one in use, like for a fetch that goes on in the same property.
+    // Returns whether it was free.
+    fn hold(&mut self, atom: xproto::Atom) -> bool {
+        self.atoms.contains(&atom) && self.in_use.insert(atom)
+    }
+
+    // Returns whether it was in use
+    fn release(&mut self, atom: xproto::Atom) -> bool {
+        self.in_use.remove(&atom)
+    }
+
+    fn held(&self) -> impl Iterator<Item = xproto::Atom> + '_ {
+        self.in_use.iter().copied()
+    }
+
+    fn any_free(&self) -> bool {
+        self.in_use.len() < self.atoms.len()
+    }
+}
+
+struct PendingTrace {
+    selection: String,
+    started: Instant,
+    targets: Vec<String>,
+    // the SAVE_TARGETS request to answer once the fetch is over
+    save: Option<SelectionRequestEvent>,
+    // the text/html rendition, fetched before the plain text
+    html: Option<String>,
+    // the target the clip is fetched as
+    target: String,
+    // the owner's class and title
+    origin: Option<Origin>,
 }
 
 #[derive(Debug)]
 enum GetState {
     GetTargets(xproto::Atom),
     GetText(xproto::Atom),
+    /// Fetching text/html, after which the plain text is fetched
+    GetHtml(xproto::Atom),
+    /// Receiving text in chunks with the INCR protocol, each chunk announced
+    /// by a PropertyNotify and the end by an empty one
+    Incremental(xproto::Atom),
+    /// Asking the owner when it took the selection, see `poll`
+    GetTimestamp(xproto::Atom),
+}
+
+// What serving a target amounted to
+enum Conversion {
+    /// A target we don't know
+    Refused,
+    /// Something other than the clip's text, like its targets
+    Data,
+    /// The clip's text, i.e. a paste
+    Text,
+}
+
+// A conversion too big for one request, sent a chunk at a time with the INCR
+// protocol. The requestor deleting the property asks for the next chunk, and
+// an empty one ends it.
+struct Outgoing {
+    kind: xproto::Atom,
+    data: Vec<u8>,
+    sent: usize,
+    deadline: Instant,
 }
 
 // Note: To get around Void not being implemented for &[u8]
@@ -44,26 +365,24 @@ impl Clipboard {
     pub async fn new<D: AsyncDisplay>(
         dpy: &mut D,
         database: Arc<Database>,
+        trust: Arc<TrustStore>,
+        options: &Options,
     ) -> Result<Clipboard, Box<dyn Error>> {
+        // nested and forwarded X servers can lack it
+        let xfixes = match dpy.xfixes_query_version_immediate(5, 0).await {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("XFIXES isn't available ({}), looking at the selections every {} ms instead", e, options.poll_interval);
+                false
+            }
+        };
+
         // create window
-        dpy.xfixes_query_version_immediate(5, 0).await?;
 
         let def_screen = dpy.default_screen();
         let root = def_screen.root;
         let visual = def_screen.root_visual;
 
-        for name in SELECTIONS {
-            let selection = dpy.intern_atom_immediate(false, name).await?;
-            dpy.xfixes_select_selection_input(
-                root,
-                selection.atom,
-                SelectionEventMask::SET_SELECTION_OWNER
-                    | SelectionEventMask::SELECTION_CLIENT_CLOSE
-                    | SelectionEventMask::SELECTION_WINDOW_DESTROY,
-            )
-                .await?;
-        }
-
         let mask = xproto::CreateWindowAux::new()
             .event_mask(EventMask::STRUCTURE_NOTIFY | EventMask::PROPERTY_CHANGE);
         let getter = dpy.generate_xid().await?;
@@ -97,26 +416,168 @@ impl Clipboard {
         )
             .await?;
 
+        // in 4-byte units, far more than the core protocol's 256 KiB with
+        // BIG-REQUESTS. The request's own fields take up the rest.
+        let max_request = dpy.try_with(|dpy, cx| dpy.try_maximum_request_length(cx)).await?;
+        let max_property_bytes = max_request.saturating_mul(4).saturating_sub(32);
+
         let mut c = Clipboard {
+            root,
             getter,
             setter,
-            get_states: HashMap::new(),
+            fetches: Fetches::new(Vec::new()),
+            traces: HashMap::new(),
+            spools: HashMap::new(),
+            changed: HashSet::new(),
+            poll_times: HashMap::new(),
+            outgoing: HashMap::new(),
+            max_property_bytes,
             atoms: HashMap::new(),
             database,
             running: true,
+            serve_count: 0,
+            last_served: None,
+            last_paste: None,
+            cut_buffer: options.cut_buffer,
+            extra_targets: options.extra_targets.clone(),
+            ignore_apps: options.ignore_apps.clone(),
+            confirm_new_apps: options.confirm_new_apps,
+            trust,
+            pending: Vec::new(),
+            own_selections: options.own_selections.clone(),
+            owned: HashMap::new(),
+            last_time: 0,
+            monitored: Vec::new(),
+            paused: Vec::new(),
+            manager: None,
+            sync: options.sync,
+            xfixes,
+            polled: HashMap::new(),
+            offered: HashMap::new(),
+            paster: Paster::new(dpy, root).await,
         };
-        c.fetch_initial(dpy).await?;
+        c.prewarm_atoms(dpy).await?;
+        // before anything is monitored, so no other events can be missed
+        c.last_time = c.server_time(dpy).await?;
+        for source in &options.monitor_selections {
+            c.set_monitored(dpy, *source, true).await?;
+        }
+        // a manager that captures nothing couldn't keep what it's handed
+        if !options.monitor_selections.is_empty() {
+            c.become_manager(dpy).await?;
+        }
         Ok(c)
     }
 
-    async fn fetch_initial<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
-        for sel in SELECTIONS {
-            let atom = self.get_atom(dpy, sel, false).await?;
-            self.get_targets(dpy, atom).await?;
+    // Asks the server what time it is, by changing a property of our own and
+    // waiting for the notification. Every other event is dropped meanwhile.
+    async fn server_time<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<xproto::Timestamp, Box<dyn Error>> {
+        let property = self.get_atom(dpy, "_RPT_TIMESTAMP", false).await?;
+        let string = xproto::Atom::from(AtomEnum::STRING);
+        dpy.change_property_checked(xproto::PropMode::APPEND, self.setter, property, string, 8, 0, &[0u8; 0][..]).await?;
+        loop {
+            match dpy.wait_for_event().await? {
+                Event::PropertyNotify(pn) if pn.window == self.setter && pn.atom == property => return Ok(pn.time),
+                event => trace!("dropping {:?} while waiting for the server time", event),
+            }
+        }
+    }
+
+    /// Remembers the server time of the event, the best there is to
+    /// convert and take selections with. Called for every event, before any
+    /// of them is acted upon.
+    pub fn note_time(&mut self, event: &Event) {
+        let time = match event {
+            Event::SelectionRequest(sr) => sr.time,
+            Event::SelectionNotify(sn) => sn.time,
+            Event::XfixesSelectionNotify(sn) => sn.timestamp,
+            Event::PropertyNotify(pn) => pn.time,
+            Event::KeyPress(kp) => kp.time,
+            Event::KeyRelease(kr) => kr.time,
+            Event::ButtonPress(bp) => bp.time,
+            _ => 0,
+        };
+        // 0 is CurrentTime, which tells nothing, and events can arrive out
+        // of order
+        if time != 0 && time.wrapping_sub(self.last_time) < u32::MAX / 2 {
+            self.last_time = time;
+        }
+    }
+
+    // Owns CLIPBOARD_MANAGER, unless another clipboard manager already does
+    async fn become_manager<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        let manager = self.get_atom(dpy, CLIPBOARD_MANAGER, false).await?;
+        let owner = dpy.get_selection_owner_immediate(manager).await?;
+        if owner.owner != 0 {
+            info!("another clipboard manager is running, not taking {}", CLIPBOARD_MANAGER);
+            return Ok(());
+        }
+        set_selection_owner(dpy, self.setter, manager, self.last_time).await?;
+        if dpy.get_selection_owner_immediate(manager).await?.owner == self.setter {
+            self.manager = Some(manager);
+        }
+        Ok(())
+    }
+
+    /// Starts or stops capturing clips from the selection. Starting fetches
+    /// what's in it right away.
+    pub async fn set_monitored<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        source: db::Source,
+        enabled: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.monitored.contains(&source) == enabled {
+            return Ok(());
+        }
+        info!("{} monitoring {}", if enabled { "starting" } else { "stopping" }, source.name());
+        let selection = self.get_atom(dpy, source.selection(), false).await?;
+        let mask = if enabled {
+            SelectionEventMask::SET_SELECTION_OWNER
+                | SelectionEventMask::SELECTION_CLIENT_CLOSE
+                | SelectionEventMask::SELECTION_WINDOW_DESTROY
+        } else {
+            SelectionEventMask::default()
+        };
+        if self.xfixes {
+            dpy.xfixes_select_selection_input(self.root, selection, mask).await?;
+        }
+        if enabled {
+            self.monitored.push(source);
+            self.get_targets(dpy, selection, None).await?;
+        } else {
+            self.monitored.retain(|s| *s != source);
+        }
+        Ok(())
+    }
+
+    // Interns the common atoms and the getter properties, sending every
+    // request before waiting for any of the replies
+    async fn prewarm_atoms<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        let getter_properties = (0..GETTER_PROPERTIES).map(|n| format!("{}{}", GETTER_PROPERTY, n));
+        let names: Vec<String> = PREWARMED.iter().map(|name| name.to_string()).chain(getter_properties).collect();
+        let mut cookies = Vec::with_capacity(names.len());
+        for name in &names {
+            cookies.push(dpy.intern_atom(false, name).await?);
         }
+        let mut properties = Vec::with_capacity(GETTER_PROPERTIES);
+        for (name, cookie) in names.into_iter().zip(cookies) {
+            let reply = dpy.wait_for_reply(cookie).await?;
+            if name.starts_with(GETTER_PROPERTY) {
+                properties.push(reply.atom);
+            }
+            self.cache_atom(name, reply.atom);
+        }
+        self.fetches.properties = GetterProperties::new(properties);
+        debug!("interned {} atoms up front", self.atoms.len());
         Ok(())
     }
 
+    fn cache_atom(&mut self, name: String, atom: xproto::Atom) {
+        self.atoms.insert(name, atom);
+        ATOMS_CACHED.store(self.atoms.len(), Ordering::Relaxed);
+    }
+
     async fn get_atom_name<D: AsyncDisplay>(
         &mut self,
         dpy: &mut D,
@@ -126,12 +587,14 @@ impl Clipboard {
             if *a != atom {
                 continue;
             } else {
+                ATOM_HITS.fetch_add(1, Ordering::Relaxed);
                 return Ok(name.to_owned());
             }
         }
+        ATOM_MISSES.fetch_add(1, Ordering::Relaxed);
         let reply = dpy.get_atom_name_immediate(atom).await?;
         let name = String::from_utf8_lossy(&reply.name).to_string();
-        self.atoms.insert(name.clone(), atom);
+        self.cache_atom(name.clone(), atom);
         Ok(name)
     }
 
@@ -143,29 +606,73 @@ impl Clipboard {
     ) -> Result<xproto::Atom, Box<dyn Error>> {
         match self.atoms.get(name) {
             None => {
+                ATOM_MISSES.fetch_add(1, Ordering::Relaxed);
                 let reply = dpy.intern_atom_immediate(only_if_exists, name).await?;
-                self.atoms.insert(name.to_owned(), reply.atom);
+                self.cache_atom(name.to_owned(), reply.atom);
                 Ok(reply.atom)
             }
-            Some(a) => Ok(a.clone()),
+            Some(a) => {
+                ATOM_HITS.fetch_add(1, Ordering::Relaxed);
+                Ok(*a)
+            }
         }
     }
 
-    async fn get_free_getter_property<D: AsyncDisplay>(
-        &mut self,
-        dpy: &mut D,
-    ) -> Result<xproto::Atom, Box<dyn Error>> {
-        let mut num = 0;
-        loop {
-            let name = format!("REPEAT_{}", num);
-            let atom = self.get_atom(dpy, &name, false).await?;
-            num = num + 1;
-            if self.get_states.contains_key(&atom) {
+    // A getter property for a new fetch, in use until `finish_fetch`
+    fn take_getter_property(&mut self) -> Result<xproto::Atom, Box<dyn Error>> {
+        self.fetches
+            .take()
+            .ok_or_else(|| format!("all {} properties to fetch into are in use", GETTER_PROPERTIES).into())
+    }
+
+    fn fetching(&self, selection: &str) -> bool {
+        self.traces.values().any(|trace| trace.selection == selection)
+    }
+
+    // Fetches the selections that changed while they couldn't be
+    async fn fetch_changed<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        for selection in self.changed.clone() {
+            if !self.fetches.any_free() {
+                break;
+            }
+            let name = self.get_atom_name(dpy, selection).await?;
+            if !self.fetching(&name) {
+                self.changed.remove(&selection);
+                self.get_targets(dpy, selection, None).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Ends the fetch into the property, returning what it was, and frees
+    // the property for the next one
+    fn finish_fetch(&mut self, property: xproto::Atom) -> Option<GetState> {
+        self.fetches.finish(property)
+    }
+
+    /// Tidies up after fetches: deletes the properties on the getter window
+    /// that no fetch is waiting on any more, like ones owners filled in after
+    /// they were given up on, drops what's kept about fetches that are over,
+    /// and frees getter properties whose fetch failed before it got going.
+    pub async fn clean_up<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        let listed = dpy.list_properties_immediate(self.getter).await?;
+        for atom in listed.atoms {
+            if self.fetches.get(atom).is_some() {
                 continue;
-            } else {
-                return Ok(atom);
+            }
+            let name = self.get_atom_name(dpy, atom).await?;
+            if name.starts_with(GETTER_PROPERTY) {
+                debug!("deleting leftover property {}", name);
+                dpy.delete_property_checked(self.getter, atom).await?;
             }
         }
+        let states = &self.fetches.states;
+        self.traces.retain(|property, _| states.contains_key(property));
+        self.spools.retain(|property, _| states.contains_key(property));
+        for property in self.fetches.tidy() {
+            debug!("freed property {}, which no fetch was using", property);
+        }
+        Ok(())
     }
 
     async fn fetch_string<D: AsyncDisplay>(
@@ -173,21 +680,43 @@ impl Clipboard {
         dpy: &mut D,
         selection: xproto::Atom,
         target: xproto::Atom,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<xproto::Atom, Box<dyn Error>> {
         let property = self.get_selection_property(dpy, selection, target).await?;
         debug!("fetching string to property {}", property);
-        self.get_states.insert(property, GetText(property));
-        Ok(())
+        self.await_property(property, GetText(property));
+        Ok(property)
     }
 
-    async fn fetch_image<D: AsyncDisplay>(
-        &mut self,
-        _dpy: &mut D,
-        _selection: xproto::Atom,
-        _property: xproto::Atom,
-    ) -> Result<(), Box<dyn Error>> {
-        warn!("image not supported yet");
-        Ok(())
+    // Waits for the owner to answer into the property, until the deadline
+    fn await_property(&mut self, property: xproto::Atom, state: GetState) {
+        self.fetches.wait(property, state, Instant::now() + CONVERSION_TIMEOUT);
+    }
+
+    /// Whether any conversions may be waiting for their owners.
+    pub fn converting(&self) -> bool {
+        self.fetches.waiting() || !self.outgoing.is_empty()
+    }
+
+    /// Gives up on the conversions whose owners didn't answer in time,
+    /// freeing their properties, and fetches the selections another way
+    /// where there is one, see `fall_back`.
+    pub async fn expire_conversions<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        for property in self.fetches.overdue(Instant::now()) {
+            let Some(state) = self.finish_fetch(property) else {
+                continue;
+            };
+            let trace = self.traces.remove(&property);
+            self.spools.remove(&property);
+            dpy.delete_property_checked(self.getter, property).await?;
+            self.fall_back(dpy, &state, trace, "didn't answer in time").await?;
+        }
+        let now = Instant::now();
+        let stalled: Vec<_> = self.outgoing.iter().filter(|(_, outgoing)| outgoing.deadline <= now).map(|(key, _)| *key).collect();
+        for (requestor, property) in stalled {
+            warn!("{} stopped taking chunks of a paste", requestor);
+            self.end_outgoing(dpy, requestor, property).await?;
+        }
+        self.fetch_changed(dpy).await
     }
 
     async fn get_selection_property<D: AsyncDisplay>(
@@ -196,68 +725,327 @@ impl Clipboard {
         selection: xproto::Atom,
         target: xproto::Atom,
     ) -> Result<xproto::Atom, Box<dyn Error>> {
-        let property = self.get_free_getter_property(dpy).await?;
+        let property = self.take_getter_property()?;
         trace!("queued getter {}", property);
         dpy.delete_property_checked(self.getter, property).await?;
-        dpy.convert_selection_checked(
-            self.getter,
-            selection,
-            target,
-            property,
-            0, // TODO: Use something else than 0
-        )
-            .await?;
+        // as of the latest event rather than CurrentTime, see set_selection_owner
+        let cookie = dpy.send_void_request(
+            xproto::ConvertSelectionRequest {
+                requestor: self.getter,
+                selection,
+                target,
+                property,
+                time: self.last_time,
+            },
+            true,
+        ).await?;
+        dpy.wait_for_reply(cookie).await?;
         Ok(property)
     }
 
-    pub fn pause(&mut self) {
-        debug!("pausing capture");
-        self.running = false;
+    /// Fetches what's in the monitored selections again, for clips that were
+    /// missed, like ones copied before rpt started whose owners didn't
+    /// answer then. Selections being fetched already are left to it.
+    pub async fn resync<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        for source in self.monitored.clone() {
+            if self.fetching(source.selection()) {
+                continue;
+            }
+            let selection = self.get_atom(dpy, source.selection(), false).await?;
+            self.get_targets(dpy, selection, None).await?;
+        }
+        Ok(())
+    }
+
+    /// How many pastes have been served.
+    pub fn served(&self) -> u64 {
+        self.serve_count
+    }
+
+    /// Whether any of the selections are still ours.
+    pub fn owns_selection(&self) -> bool {
+        !self.owned.is_empty()
+    }
+
+    /// Makes the application `window` belongs to paste the selection, see
+    /// `Paster`.
+    pub async fn paste<D: AsyncDisplay>(&mut self, dpy: &mut D, window: xproto::Window) -> Result<(), Box<dyn Error>> {
+        let class = self.window_class(dpy, window).await?;
+        self.paster.paste(dpy, window, class, self.last_time).await
+    }
+
+    /// Whether a paste is waiting for the application to ask for the clip.
+    pub fn pasting(&self) -> bool {
+        self.paster.waiting()
+    }
+
+    /// Pastes another way if the application didn't ask for the clip in time.
+    pub async fn check_paste<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        self.paster.check(dpy, Instant::now()).await
+    }
+
+    /// Whether the selections have to be polled, without XFIXES.
+    pub fn polling(&self) -> bool {
+        !self.xfixes
+    }
+
+    /// Fetches what's in the monitored selections that other applications
+    /// own, for when the server can't tell when they change. Owners are
+    /// asked when they took the selection first, and it's only fetched if
+    /// that or the owner changed since the last poll.
+    pub async fn poll<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        for source in self.monitored.clone() {
+            // still waiting for the last look
+            if self.fetching(source.selection()) {
+                continue;
+            }
+            let selection = self.get_atom(dpy, source.selection(), false).await?;
+            let owner = dpy.get_selection_owner_immediate(selection).await?.owner;
+            if owner == 0 {
+                self.orphaned(dpy, selection).await?;
+            } else if !self.is_ours(owner) {
+                self.get_timestamp(dpy, selection).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Asks the owner of the selection for the TIMESTAMP it took it at, see
+    // `poll`
+    async fn get_timestamp<D: AsyncDisplay>(&mut self, dpy: &mut D, selection: xproto::Atom) -> Result<(), Box<dyn Error>> {
+        if !self.fetches.any_free() {
+            debug!("all {} properties to fetch into are in use, polling {} next time", GETTER_PROPERTIES, selection);
+            return Ok(());
+        }
+        let target = self.get_atom(dpy, TIMESTAMP, false).await?;
+        let property = self.get_selection_property(dpy, selection, target).await?;
+        self.await_property(property, GetTimestamp(property));
+        let trace = PendingTrace {
+            selection: self.get_atom_name(dpy, selection).await?,
+            started: Instant::now(),
+            targets: Vec::new(),
+            save: None,
+            html: None,
+            target: TIMESTAMP.to_owned(),
+            origin: None,
+        };
+        self.traces.insert(property, trace);
+        Ok(())
+    }
+
+    pub fn set_sync(&mut self, sync: SyncMode) {
+        info!("syncing selections: {:?}", sync);
+        self.sync = sync;
     }
 
-    pub fn start(&mut self) {
-        debug!("starting to capture");
-        self.running = true;
+    /// Stops capturing from the selection, or from all of them. Pausing
+    /// what's paused already does nothing.
+    pub fn pause(&mut self, selection: Option<db::Source>) {
+        match selection {
+            None if !self.running => debug!("capturing is paused already"),
+            None => {
+                debug!("pausing capture");
+                self.running = false;
+            }
+            Some(source) if !self.running || self.paused.contains(&source) => {
+                debug!("capturing from {} is paused already", source.name());
+            }
+            Some(source) => {
+                debug!("pausing capture from {}", source.name());
+                self.paused.push(source);
+            }
+        }
+    }
+
+    /// Starts capturing from the selection again, or from all of them.
+    /// Starting what isn't paused does nothing.
+    pub fn start(&mut self, selection: Option<db::Source>) {
+        match selection {
+            None if self.running && self.paused.is_empty() => debug!("capturing isn't paused"),
+            None => {
+                debug!("starting to capture");
+                self.running = true;
+                self.paused.clear();
+            }
+            Some(source) if !self.running => {
+                // the others stay paused
+                debug!("starting to capture from {} only", source.name());
+                self.running = true;
+                self.paused = [db::Source::Primary, db::Source::Secondary, db::Source::Clipboard]
+                    .into_iter()
+                    .filter(|s| *s != source)
+                    .collect();
+            }
+            Some(source) if !self.paused.contains(&source) => {
+                debug!("capturing from {} isn't paused", source.name());
+            }
+            Some(source) => {
+                debug!("starting to capture from {}", source.name());
+                self.paused.retain(|s| *s != source);
+            }
+        }
+    }
+
+    // Whether clips fetched for the trace are kept
+    fn capturing(&self, trace: Option<&PendingTrace>) -> bool {
+        let source = trace.and_then(|t| t.selection.parse::<db::Source>().ok());
+        self.running && !incognito::enabled() && !source.is_some_and(|source| self.paused.contains(&source))
     }
 
     async fn get_targets<D: AsyncDisplay>(
         &mut self,
         dpy: &mut D,
         selection: xproto::Atom,
+        save: Option<SelectionRequestEvent>,
     ) -> Result<(), Box<dyn Error>> {
-        let targets = self.get_atom(dpy, TARGETS, true).await?;
+        // interned for real, since on a fresh server nobody may have yet
+        let targets = self.get_atom(dpy, TARGETS, false).await?;
+        let owner = dpy.get_selection_owner_immediate(selection).await?.owner;
+        if owner == 0 && save.is_none() {
+            debug!("not fetching selection {}, which nobody owns", selection);
+            return Ok(());
+        }
+        // we may have taken it since the change that got us here
+        if save.is_none() && self.is_ours(owner) {
+            debug!("not fetching selection {}, which is ours", selection);
+            return Ok(());
+        }
+        // asked now, while the owner is sure to be around
+        let origin = match owner {
+            0 => None,
+            owner => self.origin(dpy, owner).await.map_err(|e| debug!("unable to tell who owns the selection: {}", e)).ok(),
+        };
+        let class = origin.as_ref().and_then(|origin| origin.class.as_deref());
+        if let Some(class) = class.filter(|class| self.ignore_apps.iter().any(|app| app.matches(class))) {
+            info!("not keeping a clip copied in {}", class);
+            let name = self.get_atom_name(dpy, selection).await?;
+            self.offered.remove(&name);
+            if let Some(request) = save {
+                self.notify(dpy, &request, 0).await?;
+            }
+            return Ok(());
+        }
+        let name = self.get_atom_name(dpy, selection).await?;
+        if save.is_none() && self.fetching(&name) {
+            debug!("selection {} changed while being fetched, fetching it again after", name);
+            self.changed.insert(selection);
+            return Ok(());
+        }
+        if !self.fetches.any_free() {
+            warn!("all {} properties to fetch into are in use, putting off fetching {}", GETTER_PROPERTIES, name);
+            match save {
+                Some(request) => self.notify(dpy, &request, 0).await?,
+                None => {
+                    self.changed.insert(selection);
+                }
+            }
+            return Ok(());
+        }
         let property = self.get_selection_property(dpy, selection, targets).await?;
-        self.get_states.insert(property, GetTargets(property));
+        self.await_property(property, GetTargets(property));
+        let trace = PendingTrace {
+            selection: name,
+            started: Instant::now(),
+            targets: Vec::new(),
+            save,
+            html: None,
+            target: "UTF8_STRING".to_owned(),
+            origin,
+        };
+        self.traces.insert(property, trace);
         Ok(())
     }
 
     async fn take_selection<D: AsyncDisplay>(&mut self, dpy: &mut D, selection: xproto::Atom, mut tries: u8) -> Result<(), Box<dyn Error>> {
+        let mut time = self.last_time;
         while tries > 0 {
-            dpy.set_selection_owner_checked(self.setter, selection, 0).await?;
+            set_selection_owner(dpy, self.setter, selection, time).await?;
             let current_owner = dpy.get_selection_owner_immediate(selection).await?;
-            if current_owner.owner != self.setter {
-                if tries > 0 {
-                    warn!("unable to get ownership of selection {}, retrying", selection);
-                    tries -= 1;
-                } else {
-                    error!("unable to get ownership of selection {}, giving up", selection);
-                    break;
-                }
-            } else {
-                break;
+            if current_owner.owner == self.setter {
+                // at CurrentTime the latest time seen is the best guess
+                self.owned.insert(selection, self.last_time);
+                self.database.set_offered(true);
+                return Ok(());
             }
+            // the owner may have taken it after the latest event we saw, say
+            // without XFIXES to tell, so fall back to CurrentTime
+            warn!("unable to get ownership of selection {} at {}, retrying", selection, time);
+            time = 0;
+            tries -= 1;
         }
+        error!("unable to get ownership of selection {}, giving up", selection);
         Ok(())
     }
 
+    /// Copies the clip into CUT_BUFFER0, if enabled, for ancient clients.
+    /// Clips too big for one request are left out, as cut buffers have no
+    /// INCR to send them in parts.
+    async fn mirror_to_cut_buffer<D: AsyncDisplay>(&mut self, dpy: &mut D, clip: &Clip) {
+        if !self.cut_buffer || matches!(clip.contents.as_ref(), ClipContents::Binary { .. }) {
+            return;
+        }
+        // cut buffers are STRING, i.e. latin-1
+        let data = latin1(&clip.contents.text());
+        if data.len() > self.max_property_bytes {
+            debug!("not copying {} bytes into CUT_BUFFER0, more than the {} that fit", data.len(), self.max_property_bytes);
+            return;
+        }
+        let d = WrappedU8 { data };
+        let changed = dpy.change_property_checked(
+            xproto::PropMode::REPLACE,
+            self.root,
+            xproto::Atom::from(AtomEnum::CUT_BUFFE_R0),
+            xproto::Atom::from(AtomEnum::STRING),
+            8,
+            d.data.len() as u32,
+            &d,
+        ).await;
+        if let Err(e) = changed {
+            error!("unable to copy the clip into CUT_BUFFER0: {}", e);
+        }
+    }
+
     pub async fn take_ownership<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
         info!("taking ownership");
-        let primary = self.get_atom(dpy, "PRIMARY", true).await?;
-        let clipboard = self.get_atom(dpy, "CLIPBOARD", true).await?;
+        if let Some(clip) = self.database.selection() {
+            self.mirror_to_cut_buffer(dpy, &clip).await;
+        }
+        for source in self.own_selections.clone() {
+            let selection = self.get_atom(dpy, source.selection(), false).await?;
+            self.take_selection(dpy, selection, 3).await?;
+        }
+        Ok(())
+    }
 
-        self.take_selection(dpy, primary, 3).await?;
-        self.take_selection(dpy, clipboard, 3).await?;
+    /// Makes the clip the current one and owns just the given selection with
+    /// it, e.g. to paste a mouse selection with Ctrl-V.
+    pub async fn promote<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        clip: Clip,
+        to: db::Source,
+    ) -> Result<(), Box<dyn Error>> {
+        info!("promoting a {} clip to {}", clip.source.name(), to.name());
+        self.database.select_clip(clip);
+        let selection = self.get_atom(dpy, to.selection(), false).await?;
+        self.take_selection(dpy, selection, 3).await
+    }
 
+    /// Gives up the selections we own, leaving them without an owner, and
+    /// forgets the current clip so nothing more of it can be served.
+    pub async fn disown<D: AsyncDisplay>(&mut self, dpy: &mut D) -> Result<(), Box<dyn Error>> {
+        self.database.clear_selection();
+        self.database.set_offered(false);
+        for selection in std::mem::take(&mut self.owned).into_keys() {
+            let owner = dpy.get_selection_owner_immediate(selection).await?;
+            if owner.owner == self.setter {
+                // 0 is None, leaving the selection without an owner
+                set_selection_owner(dpy, 0, selection, self.last_time).await?;
+            }
+        }
+        if self.cut_buffer {
+            dpy.delete_property_checked(self.root, xproto::Atom::from(AtomEnum::CUT_BUFFE_R0)).await?;
+        }
         Ok(())
     }
 
@@ -265,38 +1053,374 @@ impl Clipboard {
         &mut self,
         dpy: &mut D,
         request: &SelectionRequestEvent,
+    ) -> Result<(), Box<dyn Error>> {
+        if Some(request.selection) == self.manager {
+            return self.handle_manager_request(dpy, request).await;
+        }
+        if !self.owned.contains_key(&request.selection) {
+            debug!("refusing a request for selection {}, which we don't own", request.selection);
+            return self.refuse_request(dpy, request).await;
+        }
+        if self.is_ours(request.requestor) {
+            // capturing what we serve would add it again, and again
+            debug!("refusing to convert selection {} for ourselves", request.selection);
+            return self.refuse_request(dpy, request).await;
+        }
+        let targets_atom = self.get_atom(dpy, TARGETS, true).await?;
+        // listing the targets gives nothing away, so only ask about the data
+        if !self.confirm_new_apps || request.target == targets_atom {
+            return self.serve_request(dpy, request).await;
+        }
+        let class = self.requestor_class(dpy, request.requestor).await?;
+        match self.trust.allowed(&class) {
+            Some(true) => self.serve_request(dpy, request).await,
+            Some(false) => {
+                debug!("refusing to serve {}", class);
+                self.refuse_request(dpy, request).await
+            }
+            None => {
+                debug!("asking whether to serve {}", class);
+                self.pending.push((class, *request));
+                Ok(())
+            }
+        }
+    }
+
+    // A request to CLIPBOARD_MANAGER, most likely an exiting application
+    // asking us to save what it has in CLIPBOARD
+    async fn handle_manager_request<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        request: &SelectionRequestEvent,
     ) -> Result<(), Box<dyn Error>> {
         let targets_atom = self.get_atom(dpy, TARGETS, true).await?;
-        let string_atom = self.get_atom(dpy, "UTF8_STRING", false).await?;
+        let save_targets = self.get_atom(dpy, SAVE_TARGETS, false).await?;
         if request.target == targets_atom {
-            // it wants to know what we serve
-            match self.database.selection() {
-                None => {
-                    debug!("requested - but nothing available");
-                    // we serve nothing
-                    let d = WrappedU8 { data: Vec::new() };
-                    dpy.change_property_checked(
-                        xproto::PropMode::REPLACE,
-                        request.requestor,
-                        0,
-                        xproto::Atom::from(AtomEnum::ATOM),
-                        0,
-                        0,
-                        &d,
-                    )
-                        .await?;
-                }
-                Some(clip) => {
-                    let property = match &clip.contents.as_ref() {
-                        &ClipContents::Text(_) => {
-                            string_atom
-                        }
-                    };
+            let data = [targets_atom, save_targets];
+            dpy.change_property_checked(
+                xproto::PropMode::REPLACE,
+                request.requestor,
+                request.property,
+                xproto::Atom::from(AtomEnum::ATOM),
+                32,
+                data.len() as u32,
+                &data[..],
+            ).await?;
+            self.notify(dpy, request, request.property).await
+        } else if request.target == save_targets {
+            info!("saving the clipboard of an exiting application");
+            let clipboard = self.get_atom(dpy, "CLIPBOARD", false).await?;
+            self.get_targets(dpy, clipboard, Some(*request)).await
+        } else {
+            self.refuse_request(dpy, request).await
+        }
+    }
+
+    // Tells an application that asked us to save its clipboard whether we did
+    async fn answer_save<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        trace: Option<&PendingTrace>,
+        saved: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(request) = trace.and_then(|t| t.save) {
+            debug!("answering a save request, saved: {}", saved);
+            let property = if saved { request.property } else { 0 };
+            self.notify(dpy, &request, property).await?;
+        }
+        Ok(())
+    }
+
+    /// The application that should be asked about, if any requests are
+    /// waiting for a decision.
+    pub fn awaiting_confirmation(&self) -> Option<&str> {
+        self.pending.first().map(|(class, _)| class.as_str())
+    }
+
+    /// Records the decision about the application and answers the requests
+    /// from it that were waiting.
+    pub async fn confirm<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        class: &str,
+        decision: Decision,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = self.trust.decide(class, decision) {
+            warn!("unable to save the decision for {}: {}", class, e);
+            // at least not asked again until rpt restarts
+            if decision == Decision::Always {
+                self.trust.decide(class, Decision::Once)?;
+            }
+        }
+        let (answered, waiting) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|(c, _)| c == class);
+        self.pending = waiting;
+        for (_, request) in answered {
+            match decision {
+                Decision::Once | Decision::Always => self.serve_request(dpy, &request).await?,
+                Decision::Deny | Decision::Never => self.refuse_request(dpy, &request).await?,
+            }
+        }
+        Ok(())
+    }
+
+    // A property of the window, or of its client leader since toolkits tend
+    // to use hidden windows without one for the clipboard
+    async fn client_property<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        mut window: xproto::Window,
+        property: xproto::Atom,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let leader = self.get_atom(dpy, "WM_CLIENT_LEADER", false).await?;
+        for _ in 0..2 {
+            let reply = dpy.get_property_immediate(false, window, property, 0u8, 0, 1024).await?;
+            if !reply.value.is_empty() {
+                return Ok(Some(reply.value));
+            }
+            let reply = dpy.get_property_immediate(false, window, leader, 0u8, 0, 1).await?;
+            match reply.value.get(..4) {
+                Some(bytes) => window = u32::from_le_bytes(bytes.try_into().unwrap()),
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    // The WM_CLASS class of the window
+    pub async fn window_class<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        window: xproto::Window,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let wm_class = self.client_property(dpy, window, xproto::Atom::from(AtomEnum::WM_CLASS)).await?;
+        // "instance\0class\0"
+        Ok(wm_class
+            .as_ref()
+            .and_then(|value| value.split(|b| *b == 0).nth(1))
+            .filter(|class| !class.is_empty())
+            .map(|class| String::from_utf8_lossy(class).to_string()))
+    }
+
+    async fn requestor_class<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        requestor: xproto::Window,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(self.window_class(dpy, requestor).await?.unwrap_or_else(|| "unknown".to_owned()))
+    }
+
+    // The class and title of the window that owns a selection
+    async fn origin<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        window: xproto::Window,
+    ) -> Result<Origin, Box<dyn Error>> {
+        let class = self.window_class(dpy, window).await?;
+        let net_wm_name = self.get_atom(dpy, "_NET_WM_NAME", false).await?;
+        let title = match self.client_property(dpy, window, net_wm_name).await? {
+            Some(title) => Some(String::from_utf8_lossy(&title).to_string()),
+            // WM_NAME is usually latin-1
+            None => self
+                .client_property(dpy, window, xproto::Atom::from(AtomEnum::WM_NAME))
+                .await?
+                .map(|title| title.iter().map(|&b| char::from(b)).collect()),
+        };
+        Ok(Origin { window, class, title })
+    }
+
+    // Tells the requestor that the conversion failed
+    async fn refuse_request<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        request: &SelectionRequestEvent,
+    ) -> Result<(), Box<dyn Error>> {
+        self.notify(dpy, request, 0).await
+    }
+
+    async fn notify<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        request: &SelectionRequestEvent,
+        property: xproto::Atom,
+    ) -> Result<(), Box<dyn Error>> {
+        let notify_event = SelectionNotifyEvent {
+            response_type: xproto::SELECTION_NOTIFY_EVENT,
+            sequence: 0,
+            time: request.time,
+            requestor: request.requestor,
+            selection: request.selection,
+            target: request.target,
+            property,
+        };
+        let event = xproto::SendEventRequest {
+            propagate: false,
+            destination: request.requestor,
+            event_mask: 0,
+            event: Cow::Owned(notify_event.into()),
+        };
+        info!("sent notification: {:?}", notify_event);
+        dpy.send_void_request(event, false).await?;
+        Ok(())
+    }
+
+    async fn serve_request<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        request: &SelectionRequestEvent,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(clip) = self.database.selection().filter(|clip| !clip.intact()) {
+            let id = self.database.id_of(&clip).map_or("the selected clip".to_owned(), |id| format!("clip {}", id));
+            error!("refusing to paste {}, which doesn't match its checksum", id);
+            notify::problem("rpt refused a paste", &format!("{} is corrupted, choose another one", id));
+            return self.refuse_request(dpy, request).await;
+        }
+        let multiple = self.get_atom(dpy, MULTIPLE, false).await?;
+        let conversion = if request.target == multiple && request.property != 0 {
+            self.serve_multiple(dpy, request).await?
+        } else {
+            match self.convert(dpy, request, request.target, request.property).await {
+                Err(e) if !disconnected(&*e) => {
+                    warn!("unable to convert for {}: {}", request.requestor, e);
+                    Conversion::Refused
+                }
+                conversion => conversion?,
+            }
+        };
+        match conversion {
+            Conversion::Refused => return self.refuse_request(dpy, request).await,
+            Conversion::Data => {}
+            Conversion::Text => {
+                let class = self.requestor_class(dpy, request.requestor).await?;
+                self.paster.requested(&class);
+                if let Some(clip) = self.database.selection() {
+                    self.last_paste = Some((clip, class, Instant::now()));
+                }
+                // every served paste moves the paste queue forward
+                self.serve_count += 1;
+                if self.database.advance_queue().is_some() {
+                    debug!(
+                        "served paste {}, moved to the next queued clip ({} left)",
+                        self.serve_count,
+                        self.database.queue_len()
+                    );
+                }
+            }
+        }
+        self.notify(dpy, request, request.property).await
+        //dpy.send_event_checked(false, sr.requestor, EventMask::default(), notify_event).await?;
+    }
+
+    // Converts to several targets in one request. The property holds pairs of
+    // a target and the property to put it into, and pairs that can't be
+    // converted get their property replaced by None.
+    async fn serve_multiple<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        request: &SelectionRequestEvent,
+    ) -> Result<Conversion, Box<dyn Error>> {
+        let reply = dpy
+            .get_property_immediate(false, request.requestor, request.property, 0u8, 0, u32::MAX)
+            .await?;
+        // a list of atoms, as anything else can't be one
+        if reply.format != 32 || reply.type_ == 0 {
+            warn!("refusing a MULTIPLE request whose property isn't a list of atom pairs");
+            return Ok(Conversion::Refused);
+        }
+        let mut pairs: Vec<u32> = reply
+            .value
+            .chunks_exact(4)
+            .map(|atom| u32::from_le_bytes(atom.try_into().unwrap()))
+            .collect();
+        debug!("converting to {} targets at once", pairs.len() / 2);
+        let mut conversion = Conversion::Data;
+        for pair in pairs.chunks_exact_mut(2) {
+            if pair[0] == 0 || pair[1] == 0 {
+                pair[1] = 0;
+                continue;
+            }
+            // a pair the requestor made up, like with an atom that doesn't
+            // exist, fails on its own
+            match self.convert(dpy, request, pair[0], pair[1]).await {
+                Ok(Conversion::Refused) => pair[1] = 0,
+                Ok(Conversion::Data) => {}
+                Ok(Conversion::Text) => conversion = Conversion::Text,
+                Err(e) if disconnected(&*e) => return Err(e),
+                Err(e) => {
+                    warn!("unable to convert to {} as part of MULTIPLE: {}", pair[0], e);
+                    pair[1] = 0;
+                }
+            }
+        }
+        dpy.change_property_checked(
+            xproto::PropMode::REPLACE,
+            request.requestor,
+            request.property,
+            reply.type_,
+            32,
+            pairs.len() as u32,
+            &pairs[..],
+        ).await?;
+        Ok(conversion)
+    }
+
+    // Puts the current clip into the requestor's property as the target
+    async fn convert<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        request: &SelectionRequestEvent,
+        target: xproto::Atom,
+        property: xproto::Atom,
+    ) -> Result<Conversion, Box<dyn Error>> {
+        let targets_atom = self.get_atom(dpy, TARGETS, true).await?;
+        let string_atom = self.get_atom(dpy, "UTF8_STRING", false).await?;
+        let target_name = self.get_atom_name(dpy, target).await?;
+        if target == targets_atom {
+            // it wants to know what we serve
+            match self.database.selection() {
+                None => {
+                    debug!("requested - but nothing available");
+                    // we serve nothing
+                    let d = WrappedU8 { data: Vec::new() };
+                    dpy.change_property_checked(
+                        xproto::PropMode::REPLACE,
+                        request.requestor,
+                        0,
+                        xproto::Atom::from(AtomEnum::ATOM),
+                        0,
+                        0,
+                        &d,
+                    )
+                        .await?;
+                }
+                Some(clip) => {
                     debug!("requested - sending targets");
-                    // TODO: Decide what properties to actually have / clip
-                    let data: &[u32] = &[targets_atom, property];
+                    let timestamp = self.get_atom(dpy, TIMESTAMP, false).await?;
+                    let multiple = self.get_atom(dpy, MULTIPLE, false).await?;
+                    let mut data: Vec<u32> = vec![targets_atom, timestamp, multiple];
+                    let mut names: Vec<&str> = match clip.contents.as_ref() {
+                        // only what it was copied as
+                        ClipContents::Binary { mime, .. } => vec![mime],
+                        ClipContents::Files(_) => TEXT_TARGETS.iter().copied().chain([URI_LIST, GNOME_FILES]).collect(),
+                        ClipContents::Text(_) => TEXT_TARGETS.to_vec(),
+                    };
+                    if clip.html.is_some() {
+                        names.push(HTML);
+                    }
+                    // requestors choose by what the owner offered, so offer
+                    // the same where we still can
+                    if let Some(trace) = &clip.trace {
+                        names = replayed(&trace.targets, &names);
+                    }
+                    for name in names {
+                        data.push(self.get_atom(dpy, name, false).await?);
+                    }
+                    for extra in self.extra_targets.clone() {
+                        data.push(self.get_atom(dpy, &extra.target, false).await?);
+                    }
                     let mut data_u8: Vec<u8> = Vec::with_capacity(data.len() * 4);
-                    for item in data {
+                    for item in &data {
                         data_u8.extend(&item.to_le_bytes());
                     }
                     debug!("sending data: {:?}", data_u8);
@@ -304,7 +1428,7 @@ impl Clipboard {
                     dpy.change_property_checked(
                         xproto::PropMode::REPLACE,
                         request.requestor,
-                        request.property,
+                        property,
                         xproto::Atom::from(AtomEnum::ATOM),
                         32,
                         data.len().try_into().expect("too many elements"),
@@ -313,48 +1437,172 @@ impl Clipboard {
                         .await?;
                 }
             }
-        } else if request.target == string_atom {
+        } else if target_name == TIMESTAMP {
+            let time = self.owned.get(&request.selection).copied().unwrap_or_default();
+            dpy.change_property_checked(
+                xproto::PropMode::REPLACE,
+                request.requestor,
+                property,
+                xproto::Atom::from(AtomEnum::INTEGER),
+                32,
+                1,
+                &[time][..],
+            ).await?;
+        } else if TEXT_TARGETS.contains(&target_name.as_str()) {
             let str = match self.database.selection() {
                 None => {
                     "n/a".to_owned()
                 }
-                Some(clip) => {
-                    match clip.contents.as_ref() {
-                        ClipContents::Text(txt) => txt.to_owned()
-                    }
+                Some(clip) if matches!(clip.contents.as_ref(), ClipContents::Binary { .. }) => {
+                    return Ok(Conversion::Refused);
                 }
+                Some(clip) => clip.contents.text().into_owned(),
             };
-            let d = WrappedU8 { data: Vec::from(str) };
-            dpy.change_property_checked(
-                xproto::PropMode::REPLACE,
-                request.requestor,
-                request.property,
-                string_atom,
-                8,
-                d.data.len() as u32,
-                &d,
-            ).await?;
+            self.last_served = Some((echo_hash(&str), Instant::now()));
+            // TEXT is answered as UTF8_STRING, every other target as itself
+            let (kind, data) = match target_name.as_str() {
+                "STRING" => (target, latin1(&str)),
+                "TEXT" => (string_atom, Vec::from(str)),
+                _ => (target, Vec::from(str)),
+            };
+            self.put_data(dpy, request.requestor, property, kind, data).await?;
+            return Ok(Conversion::Text);
+        } else if target_name == URI_LIST || target_name == GNOME_FILES {
+            let uris = match self.database.selection().and_then(|clip| clip.contents.uri_list()) {
+                Some(uris) => uris,
+                None => return Ok(Conversion::Refused),
+            };
+            // a file manager copying them again would offer the same list
+            self.last_served = Some((echo_hash(&uris), Instant::now()));
+            let data = if target_name == GNOME_FILES {
+                format!("copy\n{}", uris.replace("\r\n", "\n").trim_end())
+            } else {
+                uris
+            };
+            self.put_data(dpy, request.requestor, property, target, data.into_bytes()).await?;
+            return Ok(Conversion::Text);
+        } else if let Some(clip) = self.database.selection().filter(|clip| {
+            matches!(clip.contents.as_ref(), ClipContents::Binary { mime, .. } if *mime == target_name)
+        }) {
+            self.last_served = Some((echo_hash(&clip.contents.text()), Instant::now()));
+            self.put_data(dpy, request.requestor, property, target, clip.contents.data().into_owned()).await?;
+            return Ok(Conversion::Text);
+        } else if target_name == HTML {
+            let (text, html) = match self.database.selection() {
+                Some(Clip { contents, html: Some(html), .. }) => (contents, html),
+                _ => return Ok(Conversion::Refused),
+            };
+            // what comes back as an echo is the plain text
+            self.last_served = Some((echo_hash(&text.text()), Instant::now()));
+            self.put_data(dpy, request.requestor, property, target, html.0.as_bytes().to_vec()).await?;
+            return Ok(Conversion::Text);
+        } else if let Some(extra) = self.extra_target(dpy, target).await? {
+            let data = match self.database.selection() {
+                None => Vec::new(),
+                Some(clip) => generate_extra_target(&extra, &clip).await.unwrap_or_else(|e| {
+                    warn!("unable to generate target {}: {}", extra.target, e);
+                    Vec::new()
+                }),
+            };
+            self.put_data(dpy, request.requestor, property, target, data).await?;
+        } else {
+            debug!("refusing to convert to unknown target {}", target_name);
+            return Ok(Conversion::Refused);
         }
-        let notify_event = SelectionNotifyEvent {
-            response_type: xproto::SELECTION_NOTIFY_EVENT,
-            sequence: 0,
-            time: request.time,
-            requestor: request.requestor,
-            selection: request.selection,
-            target: request.target,
-            property: request.property,
-        };
-        let event = xproto::SendEventRequest {
-            propagate: false,
-            destination: request.requestor,
-            event_mask: 0,
-            event: Cow::Owned(notify_event.into()),
+        Ok(Conversion::Data)
+    }
+
+    // Puts the data into the requestor's property, with INCR if it's too big
+    // for one request
+    async fn put_data<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        requestor: xproto::Window,
+        property: xproto::Atom,
+        kind: xproto::Atom,
+        data: Vec<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        if data.len() <= self.max_property_bytes {
+            dpy.change_property_checked(xproto::PropMode::REPLACE, requestor, property, kind, 8, data.len() as u32, &data[..])
+                .await?;
+            return Ok(());
+        }
+        debug!("sending {} bytes to {} in chunks", data.len(), requestor);
+        // told about the property being deleted, which asks for a chunk
+        let mask = xproto::ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+        dpy.change_window_attributes_checked(requestor, mask).await?;
+        let incr = self.get_atom(dpy, INCR, false).await?;
+        // the size is only a lower bound, which a u32 holds
+        let size = u32::try_from(data.len()).unwrap_or(u32::MAX);
+        dpy.change_property_checked(xproto::PropMode::REPLACE, requestor, property, incr, 32, 1, &[size][..]).await?;
+        let deadline = Instant::now() + CONVERSION_TIMEOUT;
+        self.outgoing.insert((requestor, property), Outgoing { kind, data, sent: 0, deadline });
+        Ok(())
+    }
+
+    // The requestor deleted the property, so the next chunk goes in
+    async fn send_chunk<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        requestor: xproto::Window,
+        property: xproto::Atom,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(outgoing) = self.outgoing.get_mut(&(requestor, property)) else {
+            return Ok(());
         };
-        info!("sent notification: {:?}", notify_event);
-        dpy.send_void_request(event, false).await?;
+        let chunk = next_chunk(&outgoing.data, outgoing.sent, self.max_property_bytes).to_vec();
+        let kind = outgoing.kind;
+        outgoing.sent += chunk.len();
+        outgoing.deadline = Instant::now() + CONVERSION_TIMEOUT;
+        let sent = dpy
+            .change_property_checked(xproto::PropMode::REPLACE, requestor, property, kind, 8, chunk.len() as u32, &chunk[..])
+            .await;
+        match sent {
+            Err(e) if disconnected(&e) => Err(e.into()),
+            Err(e) => {
+                warn!("unable to send the next chunk to {}: {}", requestor, e);
+                self.end_outgoing(dpy, requestor, property).await
+            }
+            // the empty chunk ends it
+            Ok(()) if chunk.is_empty() => {
+                debug!("sent all of it to {}", requestor);
+                self.end_outgoing(dpy, requestor, property).await
+            }
+            Ok(()) => Ok(()),
+        }
+    }
 
+    async fn end_outgoing<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        requestor: xproto::Window,
+        property: xproto::Atom,
+    ) -> Result<(), Box<dyn Error>> {
+        self.outgoing.remove(&(requestor, property));
+        if self.outgoing.keys().any(|(window, _)| *window == requestor) {
+            return Ok(());
+        }
+        let mask = xproto::ChangeWindowAttributesAux::new().event_mask(EventMask::default());
+        // it may well be gone by now
+        if let Err(e) = dpy.change_window_attributes_checked(requestor, mask).await {
+            if disconnected(&e) {
+                return Err(e.into());
+            }
+        }
         Ok(())
-        //dpy.send_event_checked(false, sr.requestor, EventMask::default(), notify_event).await?;
+    }
+
+    async fn extra_target<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        target: xproto::Atom,
+    ) -> Result<Option<ExtraTarget>, Box<dyn Error>> {
+        for extra in self.extra_targets.clone() {
+            if self.get_atom(dpy, &extra.target, false).await? == target {
+                return Ok(Some(extra));
+            }
+        }
+        Ok(None)
     }
 
     async fn handle_notify<D: AsyncDisplay>(
@@ -362,7 +1610,7 @@ impl Clipboard {
         dpy: &mut D,
         notification: &SelectionNotifyEvent,
     ) -> Result<(), Box<dyn Error>> {
-        match self.get_states.get(&notification.property) {
+        match self.fetches.get(notification.property) {
             None => {
                 warn!("some other unhandled property changed: {}", notification.property);
             }
@@ -387,40 +1635,122 @@ impl Clipboard {
                     let name = self.get_atom_name(dpy, atom as xproto::Atom).await?;
                     properties.push(name);
                 }
-                self.get_states.remove(&property);
+                self.finish_fetch(property);
+                let trace = self.traces.remove(&property);
 
                 debug!("available properties: {:?}", properties);
-                if properties.contains(&"UTF8_STRING".to_owned()) {
-                    let target = self.get_atom(dpy, &"UTF8_STRING", true).await?;
-                    self.fetch_string(dpy, notification.selection, target).await?;
-                } else {
-                    let images: Vec<&String> = properties
-                        .iter()
-                        .filter(|p| p.starts_with("image/"))
-                        .collect();
-                    if images.len() > 0 {
-                        // TODO: Chose the less lossy one
-                        let target =
-                            self.get_atom(dpy, images.first().unwrap(), true).await?;
-                        self.fetch_image(dpy, notification.selection, target).await?;
+                if is_secret(&properties) {
+                    info!("not keeping a clip marked as a password");
+                    if let Some(trace) = trace.as_ref() {
+                        self.offered.remove(&trace.selection);
+                    }
+                    return self.answer_save(dpy, trace.as_ref(), false).await;
+                }
+                if properties.iter().any(|p| p == URI_LIST) {
+                    let target = self.get_atom(dpy, URI_LIST, false).await?;
+                    let property = self.fetch_string(dpy, notification.selection, target).await?;
+                    if let Some(mut trace) = trace {
+                        trace.targets = properties;
+                        trace.target = URI_LIST.to_owned();
+                        self.traces.insert(property, trace);
                     }
+                } else if properties.contains(&"UTF8_STRING".to_owned()) {
+                    let html = properties.iter().any(|p| p == HTML);
+                    let property = if html {
+                        let target = self.get_atom(dpy, HTML, false).await?;
+                        let property = self.get_selection_property(dpy, notification.selection, target).await?;
+                        debug!("fetching html to property {}", property);
+                        self.await_property(property, GetHtml(property));
+                        property
+                    } else {
+                        let target = self.get_atom(dpy, "UTF8_STRING", true).await?;
+                        self.fetch_string(dpy, notification.selection, target).await?
+                    };
+                    if let Some(mut trace) = trace {
+                        trace.targets = properties;
+                        self.traces.insert(property, trace);
+                    }
+                } else if properties.iter().any(|p| p == "STRING") {
+                    // older applications only offer latin-1
+                    let target = xproto::Atom::from(AtomEnum::STRING);
+                    let property = self.fetch_string(dpy, notification.selection, target).await?;
+                    if let Some(mut trace) = trace {
+                        trace.targets = properties;
+                        trace.target = "STRING".to_owned();
+                        self.traces.insert(property, trace);
+                    }
+                } else if let Some(mime) = binary_target(&properties).map(|mime| mime.to_owned()) {
+                    let target = self.get_atom(dpy, &mime, false).await?;
+                    let property = self.fetch_string(dpy, notification.selection, target).await?;
+                    if let Some(mut trace) = trace {
+                        trace.targets = properties;
+                        trace.target = mime;
+                        self.traces.insert(property, trace);
+                    }
+                } else {
+                    self.answer_save(dpy, trace.as_ref(), false).await?;
                 }
             }
             Some(&GetText(property)) => {
-                if self.running {
-                    let value_reply = dpy
-                        .get_property_immediate(true, notification.requestor, notification.property, 0, 0, u32::MAX)
-                        .await?;
-                    let value = String::from_utf8_lossy(&value_reply.value).to_string();
-                    info!("property {} value ({}): {:?}", property, value.len(), value);
-                    let contents = ClipContents::Text(value);
-                    self.database.add_clip(Clip::new(
-                        db::Source::Primary,
-                        contents,
-                    ));
-                    self.get_states.remove(&property);
-                } else {
+                // an incremental transfer carries on in it, see `await_property`
+                self.finish_fetch(property);
+                let trace = self.traces.remove(&property);
+                // deleting the property also tells an INCR owner to start sending
+                let value_reply = dpy
+                    .get_property_immediate(true, notification.requestor, notification.property, 0, 0, u32::MAX)
+                    .await?;
+                if !self.capturing(trace.as_ref()) {
                     debug!("got a potential clip - but we're paused so ignoring.");
+                    self.answer_save(dpy, trace.as_ref(), false).await?;
+                } else if value_reply.type_ == self.get_atom(dpy, INCR, false).await? {
+                    debug!("property {} is coming incrementally", property);
+                    match Spool::create(&options::spool_dir()) {
+                        Ok(spool) => {
+                            self.spools.insert(property, spool);
+                            self.await_property(property, Incremental(property));
+                            if let Some(trace) = trace {
+                                self.traces.insert(property, trace);
+                            }
+                        }
+                        Err(e) => {
+                            error!("unable to spool an incremental transfer, dropping it: {}", e);
+                            self.answer_save(dpy, trace.as_ref(), false).await?;
+                        }
+                    }
+                } else {
+                    self.captured(dpy, value_reply.value, trace, Transfer::Direct).await?;
+                }
+            }
+            Some(&GetHtml(property)) => {
+                self.finish_fetch(property);
+                let mut trace = self.traces.remove(&property);
+                let reply = dpy
+                    .get_property_immediate(true, notification.requestor, notification.property, 0, 0, u32::MAX)
+                    .await?;
+                if reply.type_ == self.get_atom(dpy, INCR, false).await? {
+                    // only the plain text is worth a transfer that long
+                    debug!("html is coming incrementally, skipping it");
+                } else if let Some(trace) = trace.as_mut() {
+                    trace.html = Some(String::from_utf8_lossy(&reply.value).to_string());
+                }
+                self.fetch_text_after_html(dpy, notification.selection, trace).await?;
+            }
+            Some(&Incremental(property)) => {
+                warn!("unexpected selection notify while receiving {} incrementally", property);
+            }
+            Some(&GetTimestamp(property)) => {
+                self.finish_fetch(property);
+                self.traces.remove(&property);
+                let reply = dpy
+                    .get_property_immediate(true, self.getter, property, 0, 0, u32::MAX)
+                    .await?;
+                let owner = dpy.get_selection_owner_immediate(notification.selection).await?.owner;
+                // a time that can't be read is taken for a change
+                let time = reply.value.get(..4).map(|time| u32::from_le_bytes(time.try_into().unwrap()));
+                if polled_again(&mut self.poll_times, notification.selection, owner, time) {
+                    self.get_targets(dpy, notification.selection, None).await?;
+                } else {
+                    trace!("selection {} is the same as last time", notification.selection);
                 }
             }
         }
@@ -428,6 +1758,256 @@ impl Clipboard {
         Ok(())
     }
 
+    // Goes on to fetch the plain text once the html is in or refused, or in
+    // place of another target the owner didn't answer in time
+    async fn fetch_text_after_html<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        selection: xproto::Atom,
+        trace: Option<PendingTrace>,
+    ) -> Result<(), Box<dyn Error>> {
+        let target = self.get_atom(dpy, "UTF8_STRING", true).await?;
+        let property = self.fetch_string(dpy, selection, target).await?;
+        if let Some(trace) = trace {
+            self.traces.insert(property, trace);
+        }
+        Ok(())
+    }
+
+    // The owner refused a conversion, which comes with no property to tell
+    // which fetch it was about, so it's told by the selection and target
+    async fn handle_refusal<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        notification: &SelectionNotifyEvent,
+    ) -> Result<(), Box<dyn Error>> {
+        let selection = self.get_atom_name(dpy, notification.selection).await?;
+        let target = self.get_atom_name(dpy, notification.target).await?;
+        let refused = self.fetches.states.iter().find_map(|(property, state)| {
+            let trace = self.traces.get(property).filter(|t| t.selection == selection)?;
+            let asked = match state {
+                GetTargets(_) => TARGETS,
+                GetHtml(_) => HTML,
+                GetText(_) => trace.target.as_str(),
+                GetTimestamp(_) => TIMESTAMP,
+                Incremental(_) => return None,
+            };
+            (asked == target).then_some(*property)
+        });
+        let Some((property, state)) = refused.and_then(|property| Some((property, self.finish_fetch(property)?))) else {
+            debug!("owner of {} refused a conversion to {}", selection, target);
+            return Ok(());
+        };
+        let trace = self.traces.remove(&property);
+        self.fall_back(dpy, &state, trace, &format!("refused to give {}", target)).await
+    }
+
+    // Fetches the selection another way after the owner failed to convert
+    // it, see `fallback`
+    async fn fall_back<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        state: &GetState,
+        trace: Option<PendingTrace>,
+        why: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let selection = trace.as_ref().map_or("a selection", |t| t.selection.as_str()).to_owned();
+        if let (GetTimestamp(_), Some(_)) = (state, trace.as_ref()) {
+            // with no time to tell by, it's fetched on every poll
+            debug!("owner of {} {}, fetching it anyway", selection, why);
+            let atom = self.get_atom(dpy, &selection, false).await?;
+            return self.get_targets(dpy, atom, None).await;
+        }
+        let Some(fallback) = trace.as_ref().and_then(|trace| fallback(state, trace)) else {
+            warn!("owner of {} {}, giving up on {:?}", selection, why, state);
+            // so that polling tries again, even if it doesn't change
+            if let Some(atom) = self.atoms.get(&selection) {
+                self.poll_times.remove(atom);
+            }
+            return self.answer_save(dpy, trace.as_ref(), false).await;
+        };
+        info!("owner of {} {}, fetching {} instead", selection, why, fallback);
+        let atom = self.get_atom(dpy, &selection, false).await?;
+        let target = self.get_atom(dpy, fallback, false).await?;
+        let property = self.fetch_string(dpy, atom, target).await?;
+        if let Some(mut trace) = trace {
+            trace.target = fallback.to_owned();
+            self.traces.insert(property, trace);
+        }
+        Ok(())
+    }
+
+    // A chunk of an INCR transfer arrived in the property
+    async fn receive_chunk<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        property: xproto::Atom,
+    ) -> Result<(), Box<dyn Error>> {
+        // deleting it asks for the next chunk
+        let chunk = dpy
+            .get_property_immediate(true, self.getter, property, 0, 0, u32::MAX)
+            .await?;
+        if !chunk.value.is_empty() {
+            trace!("received {} bytes into {}", chunk.value.len(), property);
+            self.fetches.extend(property, Instant::now() + CONVERSION_TIMEOUT);
+            if let Some(spool) = self.spools.get_mut(&property) {
+                if let Err(e) = spool.append(&chunk.value) {
+                    error!("unable to spool an incremental transfer, dropping it: {}", e);
+                    self.spools.remove(&property);
+                    self.finish_fetch(property);
+                    let trace = self.traces.remove(&property);
+                    self.answer_save(dpy, trace.as_ref(), false).await?;
+                }
+            }
+            return Ok(());
+        }
+
+        // an empty chunk ends the transfer
+        self.finish_fetch(property);
+        let trace = self.traces.remove(&property);
+        if let Some(spool) = self.spools.remove(&property) {
+            debug!("incremental transfer into {} done, {} bytes", property, spool.len());
+            match spool.finish() {
+                Ok(data) if self.capturing(trace.as_ref()) => {
+                    self.captured(dpy, data, trace, Transfer::Incremental).await?;
+                }
+                Ok(_) => self.answer_save(dpy, trace.as_ref(), false).await?,
+                Err(e) => {
+                    error!("unable to read back an incremental transfer, dropping it: {}", e);
+                    self.answer_save(dpy, trace.as_ref(), false).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Adds what was fetched from a selection owner to the history
+    async fn captured<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        data: Vec<u8>,
+        mut trace: Option<PendingTrace>,
+        transfer: Transfer,
+    ) -> Result<(), Box<dyn Error>> {
+        let size = data.len();
+        let target = trace.as_ref().map_or("UTF8_STRING", |t| t.target.as_str()).to_owned();
+        let contents = if target == "STRING" {
+            ClipContents::Text(from_latin1(&data))
+        } else if target == URI_LIST || TEXT_TARGETS.contains(&target.as_str()) {
+            let value = String::from_utf8_lossy(&data).to_string();
+            // a list of anything but local files is kept as text
+            match db::parse_uri_list(&value).filter(|_| target == URI_LIST) {
+                Some(paths) => ClipContents::Files(paths),
+                None => ClipContents::Text(value),
+            }
+        } else {
+            ClipContents::Binary { mime: target.clone(), data }
+        };
+        // files are compared the way they're served
+        let value = match contents.uri_list() {
+            Some(uris) => Cow::Owned(uris),
+            None => contents.text(),
+        };
+        let save = trace.as_ref().and_then(|t| t.save);
+        if let (false, Some(trace), None) = (self.xfixes, trace.as_ref(), save) {
+            let hash = contents.stable_hash();
+            if self.polled.insert(trace.selection.clone(), hash) == Some(hash) {
+                trace!("{} hasn't changed since it was last polled", trace.selection);
+                return Ok(());
+            }
+        }
+        info!("captured {}", redact::body(&value));
+        if self.is_echo(&value) && save.is_none() {
+            debug!("ignoring a re-selection of the clip we just pasted");
+            return Ok(());
+        }
+        let source = trace
+            .as_ref()
+            .and_then(|t| t.selection.parse().ok())
+            .unwrap_or(db::Source::Primary);
+        let mut clip = Clip::new(source, contents);
+        clip.html = trace.as_mut().and_then(|t| t.html.take()).map(|html| Arc::new(Html(html)));
+        clip.origin = trace.as_mut().and_then(|t| t.origin.take()).map(Arc::new);
+        let focused_app = wm::focused().await.and_then(|f| f.app);
+        clip.trace = trace.map(|t| Arc::new(Trace {
+            selection: t.selection,
+            targets: t.targets,
+            target,
+            transfer,
+            duration_ms: t.started.elapsed().as_millis() as u64,
+            size,
+            focused_app,
+        }));
+        if let Some((original, class, at)) = self.last_paste.clone() {
+            if at.elapsed() < CHAIN_WINDOW
+                && db::related(&original.contents.text(), &clip.contents.text())
+                && class != "unknown"
+                && clip.origin.as_ref().and_then(|origin| origin.class.as_deref()) == Some(class.as_str())
+            {
+                debug!("the clip is an edited version of the one pasted into {}", class);
+                self.database.link(&original, &clip);
+            }
+        }
+        if self.database.add_clip(clip.clone()).is_some() {
+            self.mirror_to_cut_buffer(dpy, &clip).await;
+        }
+        if save.is_none() {
+            self.offered.insert(source.selection().to_owned(), clip.clone());
+        }
+        if let (Some(to), None) = (self.sync.target(source), save) {
+            debug!("syncing the clip into {}", to.name());
+            self.database.select_clip(clip.clone());
+            let selection = self.get_atom(dpy, to.selection(), false).await?;
+            self.take_selection(dpy, selection, 3).await?;
+        }
+        if let Some(request) = save {
+            // the application is going away, so serve its clipboard ourselves
+            self.database.select_clip(clip);
+            let clipboard = self.get_atom(dpy, "CLIPBOARD", false).await?;
+            self.take_selection(dpy, clipboard, 3).await?;
+            self.notify(dpy, &request, request.property).await?;
+        }
+        Ok(())
+    }
+
+    // The owner of the selection went away without handing it over, as
+    // applications that quit do, so serve what it offered ourselves
+    async fn orphaned<D: AsyncDisplay>(&mut self, dpy: &mut D, selection: xproto::Atom) -> Result<(), Box<dyn Error>> {
+        let name = self.get_atom_name(dpy, selection).await?;
+        let Some(clip) = self.offered.remove(&name) else {
+            return Ok(());
+        };
+        // someone may have taken it already
+        if dpy.get_selection_owner_immediate(selection).await?.owner != 0 {
+            return Ok(());
+        }
+        // every selection we own serves the selected clip, which this would
+        // change for the others
+        let serving_another = self.database.selection().is_some_and(|selected| selected.contents != clip.contents);
+        if serving_another && !self.owned.is_empty() {
+            info!("the owner of {} went away, but we serve another clip in the other selections", name);
+            return Ok(());
+        }
+        info!("the owner of {} went away, serving its clip", name);
+        self.database.select_clip(clip);
+        self.take_selection(dpy, selection, 3).await
+    }
+
+    /// Whether the window is one of those we own selections and fetch them
+    /// with.
+    fn is_ours(&self, window: xproto::Window) -> bool {
+        window == self.setter || window == self.getter
+    }
+
+    /// Whether the text is what we served moments ago, most likely re-selected
+    /// by the application we pasted into.
+    fn is_echo(&self, text: &str) -> bool {
+        match self.last_served {
+            Some((hash, at)) => at.elapsed() < ECHO_WINDOW && hash == echo_hash(text),
+            None => false,
+        }
+    }
+
     pub async fn handle_event<D: AsyncDisplay>(
         &mut self,
         dpy: &mut D,
@@ -436,31 +2016,64 @@ impl Clipboard {
         match event {
             Event::SelectionRequest(sr) =>
                 self.handle_request(dpy, sr).await?,
-            Event::XfixesSelectionNotify(sn) => {
-                if sn.owner != self.setter {
-                    self.get_targets(dpy, sn.selection).await?;
+            Event::XfixesSelectionNotify(sn) if self.is_ours(sn.owner) => {
+                // the server's time for taking it, better than our guess
+                debug!("selection {} is ours since {}", sn.selection, sn.selection_timestamp);
+                if let Some(time) = self.owned.get_mut(&sn.selection) {
+                    *time = sn.selection_timestamp;
                 }
             }
+            Event::XfixesSelectionNotify(sn) if sn.subtype != SelectionEvent::SET_SELECTION_OWNER => {
+                self.orphaned(dpy, sn.selection).await?;
+            }
+            Event::XfixesSelectionNotify(sn) => {
+                // what the new owner offers is only known once it's fetched
+                let name = self.get_atom_name(dpy, sn.selection).await?;
+                self.offered.remove(&name);
+                self.get_targets(dpy, sn.selection, None).await?;
+            }
+            Event::SelectionNotify(sn) if sn.property == 0 =>
+                self.handle_refusal(dpy, sn).await?,
             Event::SelectionNotify(sn) =>
                 self.handle_notify(dpy, sn).await?,
-            Event::PropertyNotify(pn) => {
-                if pn.window == self.getter {
-                    if pn.state == xproto::Property::NEW_VALUE {
-                        let target_reply = dpy
-                            .get_property_immediate(false, pn.window, pn.atom, 0, 0, u32::MAX)
-                            .await?;
-                        trace!(
-                            "new property notify (atom:{}) value: {:?}",
-                            pn.atom,
-                            target_reply.value
-                        );
-                    }
+            Event::SelectionClear(sc) if sc.owner == self.setter => {
+                debug!("lost selection {}", sc.selection);
+                self.owned.remove(&sc.selection);
+                self.database.set_offered(!self.owned.is_empty());
+                if Some(sc.selection) == self.manager {
+                    info!("another clipboard manager took over {}", CLIPBOARD_MANAGER);
+                    self.manager = None;
                 }
             }
+            Event::PropertyNotify(pn)
+                if pn.state == xproto::Property::DELETE && self.outgoing.contains_key(&(pn.window, pn.atom)) =>
+            {
+                self.send_chunk(dpy, pn.window, pn.atom).await?;
+            }
+            Event::PropertyNotify(pn)
+                if pn.window == self.getter
+                    && pn.state == xproto::Property::NEW_VALUE
+                    && matches!(self.fetches.get(pn.atom), Some(Incremental(_))) =>
+            {
+                self.receive_chunk(dpy, pn.atom).await?;
+            }
+            Event::PropertyNotify(pn)
+                if pn.window == self.getter && pn.state == xproto::Property::NEW_VALUE =>
+            {
+                let target_reply = dpy
+                    .get_property_immediate(false, pn.window, pn.atom, 0, 0, u32::MAX)
+                    .await?;
+                trace!(
+                    "new property notify (atom:{}) value: {}",
+                    pn.atom,
+                    redact::bytes(&target_reply.value)
+                );
+            }
 
             _ => {}
         }
-        Ok(())
+        // a fetch that just ended may have freed a property or a selection
+        self.fetch_changed(dpy).await
     }
 
     pub async fn print_owners<D: AsyncDisplay>(dpy: &mut D) -> Result<(), Box<dyn Error>> {
@@ -473,3 +2086,385 @@ impl Clipboard {
         Ok(())
     }
 }
+
+// Hashes the text ignoring surrounding whitespace, which applications tend to
+// add or drop when the pasted text is selected again
+fn echo_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.trim().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether the error is the X connection going away, rather than a request
+/// the server refused, like one with an atom that doesn't exist.
+pub fn disconnected(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<breadx::Error>() {
+        Some(e) => e.as_io_error().is_some(),
+        None => e.is::<std::io::Error>(),
+    }
+}
+
+// The properties whose deadlines have passed, oldest first
+// What goes in the property next, once `sent` bytes were. It's empty once
+// all of it was sent.
+fn next_chunk(data: &[u8], sent: usize, max: usize) -> &[u8] {
+    &data[sent.min(data.len())..data.len().min(sent.saturating_add(max))]
+}
+
+// breadx's helper only takes CurrentTime, which ICCCM-strict owners refuse
+async fn set_selection_owner<D: AsyncDisplay>(
+    dpy: &mut D,
+    owner: xproto::Window,
+    selection: xproto::Atom,
+    time: xproto::Timestamp,
+) -> breadx::Result<()> {
+    let cookie = dpy.send_void_request(xproto::SetSelectionOwnerRequest { owner, selection, time }, true).await?;
+    dpy.wait_for_reply(cookie).await
+}
+
+// The targets the owner offered that can be served, in its order, or all that
+// can be served if it offered none of them
+fn replayed<'a>(offered: &[String], servable: &[&'a str]) -> Vec<&'a str> {
+    let mut replayed = Vec::new();
+    for target in offered {
+        if let Some(name) = servable.iter().find(|name| **name == target) {
+            if !replayed.contains(name) {
+                replayed.push(*name);
+            }
+        }
+    }
+    if replayed.is_empty() {
+        servable.to_vec()
+    } else {
+        replayed
+    }
+}
+
+// The target to fetch a selection as after the owner failed to convert it:
+// text instead of a richer target, or when its targets couldn't be had, and
+// STRING, which older applications offer, instead of UTF8_STRING. Targets
+// the owner didn't list aren't tried, unless the list is unknown.
+// Records the owner of the polled selection and the time it took it at,
+// returning whether either changed since the last poll, so that it's
+// fetched again
+fn polled_again(
+    times: &mut HashMap<xproto::Atom, (xproto::Window, xproto::Timestamp)>,
+    selection: xproto::Atom,
+    owner: xproto::Window,
+    time: Option<xproto::Timestamp>,
+) -> bool {
+    let Some(time) = time else {
+        times.remove(&selection);
+        return true;
+    };
+    times.insert(selection, (owner, time)) != Some((owner, time))
+}
+
+fn fallback(state: &GetState, trace: &PendingTrace) -> Option<&'static str> {
+    let offered = |target: &&str| trace.targets.is_empty() || trace.targets.iter().any(|t| t == target);
+    match state {
+        GetTargets(_) => Some("UTF8_STRING"),
+        GetHtml(_) => Some("UTF8_STRING").filter(offered),
+        GetText(_) if trace.target == "UTF8_STRING" => Some("STRING").filter(offered),
+        GetText(_) if trace.target != "STRING" => Some("UTF8_STRING").filter(offered),
+        _ => None,
+    }
+}
+
+// The target to keep a selection as when the owner offers no text: PNG if
+// there's a choice of images, or else any other type of data
+fn binary_target(targets: &[String]) -> Option<&str> {
+    let data: Vec<&str> = targets
+        .iter()
+        .map(|target| target.as_str())
+        .filter(|target| target.contains('/') && !target.starts_with("text/") && !target.starts_with("x-special/"))
+        .collect();
+    data.iter()
+        .find(|target| **target == "image/png")
+        .or_else(|| data.iter().find(|target| target.starts_with("image/")))
+        .or(data.first())
+        .copied()
+}
+
+// Whether the owner marked the selection as a password
+fn is_secret(targets: &[String]) -> bool {
+    targets.iter().any(|t| t == PASSWORD_HINT)
+}
+
+// The text in latin-1 for the STRING target, with characters it can't hold
+// replaced by question marks
+fn latin1(text: &str) -> Vec<u8> {
+    text.chars().map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?')).collect()
+}
+
+// Every latin-1 byte is the Unicode code point of the same value
+fn from_latin1(data: &[u8]) -> String {
+    data.iter().map(|&b| char::from(b)).collect()
+}
+
+async fn generate_extra_target(extra: &ExtraTarget, clip: &Clip) -> Result<Vec<u8>, Box<dyn Error>> {
+    let command = match (&extra.data, &extra.command) {
+        (Some(data), _) => return Ok(data.clone().into_bytes()),
+        (None, Some(command)) => command,
+        (None, None) => return Err("neither data nor command is set".into()),
+    };
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let input = clip.contents.data().into_owned();
+    let mut stdin = child.stdin.take().ok_or("no stdin")?;
+    let output = tokio::time::timeout(GENERATOR_TIMEOUT, async move {
+        stdin.write_all(&input).await?;
+        drop(stdin);
+        child.wait_with_output().await
+    }).await??;
+    if !output.status.success() {
+        return Err(format!("{} exited with {}", command, output.status).into());
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::time::{Duration, Instant};
+
+    use crate::clipboard::GetState::{GetHtml, GetTargets, GetText, GetTimestamp, Incremental};
+    use crate::clipboard::{binary_target, disconnected, fallback, from_latin1, Fetches, GetterProperties, is_secret, latin1, next_chunk, polled_again, replayed, PendingTrace, SyncMode};
+    use crate::db::Source;
+
+    #[test]
+    fn latin1_strings() {
+        assert_eq!(latin1("plain"), b"plain");
+        assert_eq!(latin1("café"), b"caf\xe9");
+        assert_eq!(latin1("€5 → ok"), b"?5 ? ok");
+        assert_eq!(from_latin1(b"caf\xe9 \xa35"), "café £5");
+        assert_eq!(from_latin1(&latin1("naïve")), "naïve");
+    }
+
+    #[test]
+    fn password_hint() {
+        let targets = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(!is_secret(&targets(&["TARGETS", "UTF8_STRING"])));
+        assert!(is_secret(&targets(&["TARGETS", "UTF8_STRING", "x-kde-passwordManagerHint"])));
+    }
+
+    #[test]
+    fn sync_targets() {
+        assert_eq!(SyncMode::Off.target(Source::Clipboard), None);
+        assert_eq!(SyncMode::ToPrimary.target(Source::Clipboard), Some(Source::Primary));
+        assert_eq!(SyncMode::ToPrimary.target(Source::Primary), None);
+        assert_eq!(SyncMode::Both.target(Source::Primary), Some(Source::Clipboard));
+        assert_eq!(SyncMode::Both.target(Source::Secondary), None);
+        assert_eq!("to-primary".parse::<SyncMode>().unwrap(), SyncMode::ToPrimary);
+        assert!("sideways".parse::<SyncMode>().is_err());
+    }
+
+    #[test]
+    fn disconnections() {
+        let lost: Box<dyn Error> = std::io::Error::from(std::io::ErrorKind::BrokenPipe).into();
+        assert!(disconnected(&*lost));
+        let refused: Box<dyn Error> = "BadAtom".into();
+        assert!(!disconnected(&*refused));
+    }
+
+    #[test]
+    fn chunks() {
+        let data = b"0123456789";
+        assert_eq!(next_chunk(data, 0, 4), b"0123");
+        assert_eq!(next_chunk(data, 8, 4), b"89");
+        assert_eq!(next_chunk(data, 10, 4), b"");
+        assert_eq!(next_chunk(data, 0, usize::MAX), data);
+    }
+
+    #[test]
+    fn overdue_conversions() {
+        let now = Instant::now();
+        let mut fetches = Fetches::new((1..=4).collect());
+        assert!(fetches.overdue(now).is_empty());
+        fetches.wait(1, GetText(1), now + Duration::from_secs(1));
+        fetches.wait(2, GetText(2), now - Duration::from_secs(2));
+        fetches.wait(3, GetText(3), now - Duration::from_secs(1));
+        fetches.wait(4, GetText(4), now);
+        assert_eq!(fetches.overdue(now), vec![2, 3, 4]);
+        // a chunk arrived in time
+        fetches.extend(3, now + Duration::from_secs(1));
+        assert_eq!(fetches.overdue(now), vec![2, 4]);
+    }
+
+    #[test]
+    fn getter_properties() {
+        let mut properties = GetterProperties::new(vec![100, 101, 102]);
+        assert_eq!(properties.take(), Some(100));
+        assert_eq!(properties.take(), Some(101));
+        assert!(properties.release(100));
+        assert!(!properties.release(100));
+        // the free ones are reused, lowest first
+        assert_eq!(properties.take(), Some(100));
+        assert_eq!(properties.take(), Some(102));
+        assert!(!properties.any_free());
+        assert_eq!(properties.take(), None);
+
+        assert!(properties.release(101));
+        assert!(properties.any_free());
+        assert!(properties.hold(101));
+        assert!(!properties.hold(101));
+        assert!(!properties.hold(7));
+
+        // 102 was taken, but its conversion was never asked for
+        let mut fetches = Fetches::new(vec![100, 101, 102]);
+        let now = Instant::now();
+        assert_eq!(fetches.take(), Some(100));
+        fetches.wait(100, GetText(100), now);
+        assert_eq!(fetches.take(), Some(101));
+        fetches.wait(101, Incremental(101), now);
+        assert_eq!(fetches.take(), Some(102));
+        assert_eq!(fetches.tidy(), vec![102]);
+        assert!(fetches.any_free());
+        assert!(fetches.tidy().is_empty());
+    }
+
+    // Runs random sequences of calls into the fetch bookkeeping, the ones
+    // the event handlers make as fetches start, are answered, carry on
+    // incrementally, fail before they're asked for, time out and are cleaned
+    // up after, and checks that no property is ever handed to two fetches or
+    // lost for good. The X side of the handlers isn't part of it.
+    #[test]
+    fn fetch_sequences() {
+        const POOL: u32 = 4;
+        let timeout = Duration::from_secs(5);
+        for seed in 1..=200u64 {
+            // xorshift, so a failure can be replayed from its seed
+            let mut state = seed;
+            let mut random = move |n: u64| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state % n
+            };
+            let mut fetches = Fetches::new((0..POOL).collect());
+            let mut now = Instant::now();
+
+            for step in 0..100 {
+                let busy: Vec<u32> = fetches.states.keys().copied().collect();
+                match random(7) {
+                    0 | 1 => match fetches.take() {
+                        Some(property) => {
+                            assert!(fetches.get(property).is_none(), "seed {} step {}: {} handed out twice", seed, step, property);
+                            // sometimes the conversion can't be asked for
+                            if random(4) > 0 {
+                                fetches.wait(property, GetText(property), now + timeout);
+                            }
+                        }
+                        None => assert!(!fetches.any_free(), "seed {} step {}: none free", seed, step),
+                    },
+                    2 if !busy.is_empty() => {
+                        let property = busy[random(busy.len() as u64) as usize];
+                        let was = fetches.finish(property);
+                        // a large clip carries on in the same property
+                        if matches!(was, Some(GetText(_))) && random(3) == 0 {
+                            fetches.wait(property, Incremental(property), now + timeout);
+                        }
+                    }
+                    3 if !busy.is_empty() => {
+                        // a chunk arrived
+                        let property = busy[random(busy.len() as u64) as usize];
+                        fetches.extend(property, now + timeout);
+                    }
+                    4 => {
+                        now += Duration::from_secs(random(4));
+                        for property in fetches.overdue(now) {
+                            assert!(fetches.finish(property).is_some());
+                        }
+                    }
+                    5 => {
+                        fetches.tidy();
+                    }
+                    _ => {}
+                }
+                for property in fetches.states.keys() {
+                    assert!(fetches.properties.held().any(|held| held == *property), "seed {} step {}: {} in use but free", seed, step, property);
+                    assert!(fetches.deadlines.contains_key(property), "seed {} step {}: {} can't time out", seed, step, property);
+                }
+            }
+
+            // everything still going times out, and the cleanup gets the rest
+            for property in fetches.overdue(now + timeout) {
+                fetches.finish(property);
+            }
+            fetches.tidy();
+            assert!(fetches.states.is_empty(), "seed {}: fetches stuck", seed);
+            assert!(!fetches.waiting(), "seed {}: deadlines left", seed);
+            assert_eq!(fetches.properties.held().count(), 0, "seed {}: properties leaked", seed);
+        }
+    }
+
+    #[test]
+    fn fallbacks() {
+        let trace = |target: &str, targets: &[&str]| PendingTrace {
+            selection: "CLIPBOARD".to_owned(),
+            started: Instant::now(),
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            save: None,
+            html: None,
+            target: target.to_owned(),
+            origin: None,
+        };
+        // the targets couldn't be had, so the text is tried blindly
+        assert_eq!(fallback(&GetTargets(1), &trace("UTF8_STRING", &[])), Some("UTF8_STRING"));
+        assert_eq!(fallback(&GetText(1), &trace("UTF8_STRING", &[])), Some("STRING"));
+        assert_eq!(fallback(&GetText(1), &trace("STRING", &[])), None);
+
+        let rich = ["TARGETS", "text/html", "UTF8_STRING", "text/uri-list"];
+        assert_eq!(fallback(&GetHtml(1), &trace("UTF8_STRING", &rich)), Some("UTF8_STRING"));
+        assert_eq!(fallback(&GetText(1), &trace("text/uri-list", &rich)), Some("UTF8_STRING"));
+        assert_eq!(fallback(&GetText(1), &trace("UTF8_STRING", &rich)), None);
+        assert_eq!(fallback(&GetText(1), &trace("image/png", &["image/png"])), None);
+        assert_eq!(fallback(&Incremental(1), &trace("UTF8_STRING", &rich)), None);
+        // fetched whole instead, see `fall_back`
+        assert_eq!(fallback(&GetTimestamp(1), &trace("TIMESTAMP", &[])), None);
+    }
+
+    #[test]
+    fn poll_times() {
+        let mut times = HashMap::new();
+        assert!(polled_again(&mut times, 1, 10, Some(100)));
+        assert!(!polled_again(&mut times, 1, 10, Some(100)));
+        // copied again in the same window
+        assert!(polled_again(&mut times, 1, 10, Some(200)));
+        // or in another one
+        assert!(polled_again(&mut times, 1, 11, Some(200)));
+        assert!(!polled_again(&mut times, 1, 11, Some(200)));
+        assert!(polled_again(&mut times, 2, 11, Some(200)));
+        // an owner that can't tell is fetched every time
+        assert!(polled_again(&mut times, 1, 11, None));
+        assert!(polled_again(&mut times, 1, 11, None));
+    }
+
+    #[test]
+    fn binary_targets() {
+        let targets = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let offered = targets(&["TARGETS", "image/bmp", "image/png", "application/x-qt-image"]);
+        assert_eq!(binary_target(&offered), Some("image/png"));
+        let offered = targets(&["TIMESTAMP", "application/x-qt-image", "image/jpeg"]);
+        assert_eq!(binary_target(&offered), Some("image/jpeg"));
+        let offered = targets(&["TARGETS", "application/pdf"]);
+        assert_eq!(binary_target(&offered), Some("application/pdf"));
+        assert_eq!(binary_target(&targets(&["TARGETS", "text/plain", "x-special/gnome-copied-files"])), None);
+    }
+
+    #[test]
+    fn replayed_targets() {
+        let targets = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let servable = ["UTF8_STRING", "text/plain;charset=utf-8", "STRING", "TEXT", "text/html"];
+        let offered = targets(&["TARGETS", "text/html", "UTF8_STRING", "image/png", "UTF8_STRING"]);
+        assert_eq!(replayed(&offered, &servable), vec!["text/html", "UTF8_STRING"]);
+        // nothing it offered is kept, so everything that can be served is
+        assert_eq!(replayed(&targets(&["TARGETS", "image/png"]), &servable), servable.to_vec());
+    }
+}
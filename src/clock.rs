@@ -0,0 +1,121 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A time on the wall clock, in the local time zone.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct LocalTime {
+    pub year: i32,
+    /// 1 to 12
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl LocalTime {
+    /// The time in the time zone set by `TZ` or the system, or in UTC if it
+    /// can't be told.
+    pub fn of(time: SystemTime) -> LocalTime {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        // SAFETY: localtime_r only writes to the tm it's given, which is plain
+        // integers and a pointer it sets to static storage.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&(secs as libc::time_t), &mut tm) }.is_null() {
+            return LocalTime::utc(secs);
+        }
+        LocalTime {
+            year: tm.tm_year + 1900,
+            month: (tm.tm_mon + 1) as u8,
+            day: tm.tm_mday as u8,
+            hour: tm.tm_hour as u8,
+            minute: tm.tm_min as u8,
+            second: tm.tm_sec as u8,
+        }
+    }
+
+    // In UTC, after Howard Hinnant's civil_from_days
+    fn utc(secs: i64) -> LocalTime {
+        let days = secs.div_euclid(86400);
+        let time = secs.rem_euclid(86400);
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        LocalTime {
+            year: year as i32,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Like `14:03:59`
+    pub fn time(&self) -> String {
+        format!("{:02}:{:02}:{:02}", self.hour, self.minute, self.second)
+    }
+
+    /// Like `2026-10-15`
+    pub fn date(&self) -> String {
+        format!("{}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    /// Whether both are in the same hour of the same day.
+    pub fn same_hour(&self, other: &LocalTime) -> bool {
+        (self.year, self.month, self.day, self.hour) == (other.year, other.month, other.day, other.hour)
+    }
+}
+
+/// How long ago something was, in words, like `3 minutes ago`.
+pub fn ago(elapsed: Duration) -> String {
+    let (n, unit) = match elapsed.as_secs() {
+        s if s < 60 => return "just now".to_owned(),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 24 * 60 * 60 => (s / (60 * 60), "hour"),
+        s => (s / (24 * 60 * 60), "day"),
+    };
+    match n {
+        1 => format!("1 {} ago", unit),
+        n => format!("{} {}s ago", n, unit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::{ago, LocalTime};
+
+    #[test]
+    fn civil_dates() {
+        let at = |year, month, day, hour, minute, second| LocalTime { year, month, day, hour, minute, second };
+        assert_eq!(LocalTime::utc(0), at(1970, 1, 1, 0, 0, 0));
+        assert_eq!(LocalTime::utc(951_782_400), at(2000, 2, 29, 0, 0, 0));
+        assert_eq!(LocalTime::utc(1_791_986_645), at(2026, 10, 14, 14, 4, 5));
+        assert_eq!(LocalTime::utc(-1), at(1969, 12, 31, 23, 59, 59));
+
+        let time = at(2026, 10, 14, 14, 4, 5);
+        assert_eq!(time.time(), "14:04:05");
+        assert_eq!(time.date(), "2026-10-14");
+        assert!(time.same_hour(&at(2026, 10, 14, 14, 59, 0)));
+        assert!(!time.same_hour(&at(2026, 10, 15, 14, 4, 5)));
+    }
+
+    #[test]
+    fn ago_in_words() {
+        assert_eq!(ago(Duration::from_secs(59)), "just now");
+        assert_eq!(ago(Duration::from_secs(60)), "1 minute ago");
+        assert_eq!(ago(Duration::from_secs(3 * 60 + 59)), "3 minutes ago");
+        assert_eq!(ago(Duration::from_secs(2 * 60 * 60)), "2 hours ago");
+        assert_eq!(ago(Duration::from_secs(24 * 60 * 60)), "1 day ago");
+    }
+}
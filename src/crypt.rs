@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::secrets;
+
+const EXTENSION: &str = "age";
+const IDENTITY_PREFIX: &str = "AGE-SECRET-KEY-1";
+
+/// Where the file at `path` is kept when it's encrypted.
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".");
+    name.push(EXTENSION);
+    path.with_file_name(name)
+}
+
+/// An age identity that files are encrypted with, by running `age`. It's
+/// locked into memory like clips are, and wiped when dropped.
+pub struct Key {
+    identity: String,
+    recipient: String,
+}
+
+impl Key {
+    /// Runs the shell command for the identity, say one that looks it up in
+    /// the keyring or decrypts a file with a passphrase. It prints the
+    /// identity, optionally with the comments `age-keygen` writes.
+    pub fn from_command(command: &str) -> Result<Key, Box<dyn Error>> {
+        let mut output = run(Command::new("sh").arg("-c").arg(command), &[])?;
+        let found = std::str::from_utf8(&output).ok().and_then(identity).map(str::to_owned);
+        secrets::release_vec(&mut output);
+        let identity = found.ok_or_else(|| format!("{} didn't print an age identity", command))?;
+        secrets::lock_string(&identity);
+        let recipient = run(Command::new("age-keygen").arg("-y"), identity.as_bytes())?;
+        let recipient = String::from_utf8(recipient)?.trim().to_owned();
+        Ok(Key { identity, recipient })
+    }
+
+    pub fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        run(Command::new("age").args(["--encrypt", "--recipient", &self.recipient]), plain)
+    }
+
+    /// Decrypts the file at `path`. The identity goes to `age` through its
+    /// stdin, so it's never on disk or in the command line.
+    pub fn decrypt(&self, path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+        let plain = run(Command::new("age").args(["--decrypt", "--identity", "-"]).arg(path), self.identity.as_bytes())?;
+        secrets::lock_vec(&plain);
+        Ok(plain)
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        secrets::release_string(&mut self.identity);
+    }
+}
+
+// The identity in what a key command printed: the first line that isn't a
+// comment
+fn identity(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter(|line| line.starts_with(IDENTITY_PREFIX))
+}
+
+// Runs the command with `input` on its stdin, returning what it printed
+fn run(command: &mut Command, input: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let program: OsString = command.get_program().to_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("unable to run {}: {}", program.to_string_lossy(), e))?;
+    let mut stdin = child.stdin.take().ok_or("no stdin")?;
+    // written from a thread of its own, since age starts writing out before
+    // it has read everything in
+    let output = std::thread::scope(|scope| {
+        scope.spawn(move || {
+            // one that exits without reading all of it fails anyway
+            let _ = stdin.write_all(input);
+        });
+        child.wait_with_output()
+    })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} exited with {}: {}", program.to_string_lossy(), output.status, stderr.trim()).into());
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::process::Command;
+
+    use crate::crypt::{encrypted_path, identity, run};
+
+    #[test]
+    fn identities() {
+        let keygen = "# created: 2026-10-15T09:00:00Z\n# public key: age1xyz\nAGE-SECRET-KEY-1QQQ\n";
+        assert_eq!(identity(keygen), Some("AGE-SECRET-KEY-1QQQ"));
+        assert_eq!(identity("  AGE-SECRET-KEY-1QQQ  \n"), Some("AGE-SECRET-KEY-1QQQ"));
+        // a passphrase rather than an identity
+        assert_eq!(identity("hunter2\nAGE-SECRET-KEY-1QQQ\n"), None);
+        assert_eq!(identity(""), None);
+
+        assert_eq!(encrypted_path(Path::new("/data/clips.toml")), Path::new("/data/clips.toml.age"));
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(run(&mut Command::new("cat"), b"piped through").unwrap(), b"piped through");
+        let failed = run(Command::new("sh").args(["-c", "echo nope >&2; exit 3"]), &[]).unwrap_err();
+        assert!(failed.to_string().contains("nope"), "{}", failed);
+        assert!(run(&mut Command::new("/nonexistent/age"), &[]).is_err());
+    }
+}
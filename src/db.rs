@@ -1,65 +1,629 @@
-use std::collections::VecDeque;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use guardian::ArcMutexGuardian;
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use fuzzy_matcher::clangd::fuzzy_match;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use feruca::Collator;
+use crate::crypt::{self, Key};
+use crate::matcher::{Matcher, MatcherKind};
+use crate::options::TagRule;
+use crate::persist::{self, HistoryFile, SavedClip};
+use crate::redact;
+use crate::secrets;
+use crate::store::{self, Writer};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 
-const MAX_CLIPS: usize = 100;
+/// How many clips are kept unless configured otherwise.
+pub const DEFAULT_MAX_CLIPS: usize = 100;
 
 pub struct Database {
     clips: Arc<Mutex<VecDeque<Clip>>>,
     selection: Arc<Mutex<Option<Clip>>>,
-    start_idx: AtomicUsize,
+    // whether the selection is still offered, see `set_offered`
+    offered: AtomicBool,
+    queue: Mutex<VecDeque<Clip>>,
+    uses: Mutex<HashMap<Arc<ClipContents>, usize>>,
+    matcher: Box<dyn Matcher>,
+    // each clip's id, which stays the same while it's in the history
+    ids: Mutex<HashMap<Arc<ClipContents>, usize>>,
+    next_id: AtomicUsize,
+    preview_bytes: usize,
+    max_clips: usize,
+    // how long clips without a time to live of their own are kept
+    ttl: Option<Duration>,
+    tags: Mutex<HashMap<Arc<ClipContents>, BTreeSet<String>>>,
+    tag_rules: Vec<TagRule>,
+    pinned: Mutex<HashSet<Arc<ClipContents>>>,
+    // clips kept out of the way but never evicted, see `archive`
+    archived: Mutex<HashSet<Arc<ClipContents>>>,
+    // the clip each edited clip was made from, see `link`
+    parents: Mutex<HashMap<Arc<ClipContents>, Arc<ClipContents>>>,
+    // when clips that were added with a time to live are forgotten
+    expiry: Mutex<HashMap<Arc<ClipContents>, SystemTime>>,
+    // where the history is saved whenever it changes, see `open_store`
+    store: OnceLock<Store>,
+}
+
+struct Store {
+    path: PathBuf,
+    writer: Arc<Writer>,
+    // what it's encrypted with, if it is
+    key: Option<Arc<Key>>,
+    // the checksums of the clips whose contents have been handed to the
+    // writer, see `persist::clip_path`
+    written: Mutex<HashSet<u64>>,
+}
+
+// The history as it was at some point, see `Database::snapshot`
+struct Snapshot {
+    clips: Vec<Clip>,
+    selection: Option<Clip>,
+    ids: HashMap<Arc<ClipContents>, usize>,
+    next_id: usize,
+    uses: HashMap<Arc<ClipContents>, usize>,
+    tags: HashMap<Arc<ClipContents>, BTreeSet<String>>,
+    pinned: HashSet<Arc<ClipContents>>,
+    archived: HashSet<Arc<ClipContents>>,
+    parents: HashMap<Arc<ClipContents>, Arc<ClipContents>>,
+    expiry: HashMap<Arc<ClipContents>, SystemTime>,
+}
+
+impl Snapshot {
+    fn file(&self) -> HistoryFile {
+        self.history(SavedClip::from_clip)
+    }
+
+    // The history as it's saved, with the contents of each clip in a file of
+    // its own
+    fn index(&self, encrypted: bool) -> HistoryFile {
+        self.history(|clip| SavedClip::stored(clip, encrypted))
+    }
+
+    fn history(&self, saved: impl Fn(&Clip) -> SavedClip) -> HistoryFile {
+        let index: HashMap<&Arc<ClipContents>, usize> =
+            self.clips.iter().enumerate().map(|(i, clip)| (&clip.contents, i)).collect();
+        HistoryFile {
+            selection: self.selection.as_ref().and_then(|clip| index.get(&clip.contents).copied()),
+            next_id: self.next_id,
+            clips: self
+                .clips
+                .iter()
+                .map(|clip| {
+                    let contents = &clip.contents;
+                    SavedClip {
+                        id: self.ids.get(contents).copied(),
+                        tags: self.tags.get(contents).map(|tags| tags.iter().cloned().collect()).unwrap_or_default(),
+                        uses: self.uses.get(contents).cloned().unwrap_or(0),
+                        pinned: self.pinned.contains(contents),
+                        archived: self.archived.contains(contents),
+                        expires: self.expiry.get(contents).map(|at| persist::seconds(*at)),
+                        parent: self.parents.get(contents).and_then(|parent| index.get(parent).copied()),
+                        ..saved(clip)
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Tags can't be empty or have spaces, since `tag:` in a search ends at a space.
+pub fn check_tag(tag: &str) -> Result<(), String> {
+    if tag.is_empty() || tag.contains(char::is_whitespace) {
+        Err(format!("{:?} can't be a tag, tags have to be a single word", tag))
+    } else {
+        Ok(())
+    }
+}
+
+/// How much of a clip is searched and previewed unless configured otherwise.
+pub const DEFAULT_PREVIEW_BYTES: usize = 64 * 1024;
+
+/// The order clips are listed in when not searching.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortMode {
+    Recency,
+    Frequency,
+    /// Often and recently used first
+    Frecency,
+    /// Pinned clips first, the rest by recency
+    Pinned,
+    Alphabetical,
+    Size,
+}
+
+impl SortMode {
+    pub fn next(self) -> SortMode {
+        match self {
+            SortMode::Recency => SortMode::Frequency,
+            SortMode::Frequency => SortMode::Frecency,
+            SortMode::Frecency => SortMode::Pinned,
+            SortMode::Pinned => SortMode::Alphabetical,
+            SortMode::Alphabetical => SortMode::Size,
+            SortMode::Size => SortMode::Recency,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortMode::Recency => "recency",
+            SortMode::Frequency => "frequency",
+            SortMode::Frecency => "frecency",
+            SortMode::Pinned => "pinned",
+            SortMode::Alphabetical => "alphabetical",
+            SortMode::Size => "size",
+        }
+    }
+}
+
+impl FromStr for SortMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SortMode, String> {
+        match s {
+            "recency" => Ok(SortMode::Recency),
+            "frequency" => Ok(SortMode::Frequency),
+            "frecency" => Ok(SortMode::Frecency),
+            "pinned" => Ok(SortMode::Pinned),
+            "alphabetical" => Ok(SortMode::Alphabetical),
+            "size" => Ok(SortMode::Size),
+            _ => Err(format!("unknown sort mode {}", s)),
+        }
+    }
 }
 
 impl Database {
     pub fn new() -> Database {
+        Database::with_matcher(MatcherKind::Clangd)
+    }
+
+    pub fn with_matcher(matcher: MatcherKind) -> Database {
         Database {
             clips: Arc::new(Mutex::new(VecDeque::new())),
             selection: Arc::new(Mutex::new(None)),
-            start_idx: AtomicUsize::new(0),
+            offered: AtomicBool::new(false),
+            queue: Mutex::new(VecDeque::new()),
+            uses: Mutex::new(HashMap::new()),
+            matcher: matcher.matcher(),
+            ids: Mutex::new(HashMap::new()),
+            next_id: AtomicUsize::new(0),
+            preview_bytes: DEFAULT_PREVIEW_BYTES,
+            max_clips: DEFAULT_MAX_CLIPS,
+            ttl: None,
+            tags: Mutex::new(HashMap::new()),
+            tag_rules: Vec::new(),
+            pinned: Mutex::new(HashSet::new()),
+            archived: Mutex::new(HashSet::new()),
+            parents: Mutex::new(HashMap::new()),
+            expiry: Mutex::new(HashMap::new()),
+            store: OnceLock::new(),
+        }
+    }
+
+    /// Loads the history saved at `path`, and saves it there through the
+    /// writer whenever it changes, so it outlives restarts.
+    pub fn with_store(self, path: PathBuf, writer: Arc<Writer>) -> Database {
+        self.open_store(path, writer, None);
+        self
+    }
+
+    /// Like `with_store`, but on a history that may be in use already, and
+    /// with a key it's encrypted with, if any. Clips copied before it's
+    /// opened stay newer than the ones loaded. A history that can't be read
+    /// or decrypted is left alone, rather than replaced by what's copied from
+    /// now on.
+    pub fn open_store(&self, path: PathBuf, writer: Arc<Writer>, key: Option<Arc<Key>>) {
+        match persist::load(&path, key.as_deref()) {
+            Ok(file) => self.restore(file.unwrap_or_default(), true),
+            Err(e) => {
+                let reading = if key.is_some() { "decrypt" } else { "read" };
+                store::degrade(format!(
+                    "unable to {} the history {}, so it isn't saved until rpt is restarted: {}",
+                    reading,
+                    path.display(),
+                    e
+                ));
+                return;
+            }
+        }
+        let encrypted = crypt::encrypted_path(&path);
+        let written = persist::stored_clips(&persist::clips_dir(&path), key.is_some());
+        let store_path = if key.is_some() { encrypted.clone() } else { path.clone() };
+        let plain = key.is_some().then(|| persist::stored_clips(&persist::clips_dir(&path), false));
+        let _ = self.store.set(Store { path: store_path, writer: writer.clone(), key, written: Mutex::new(written) });
+        // clips copied meanwhile, and ones kept in the history before they
+        // had files of their own
+        self.save();
+        let Some(plain) = plain.filter(|plain| path.exists() || !plain.is_empty()) else { return };
+        // once it's safely encrypted
+        writer.flush();
+        if store::degraded().is_some() || !encrypted.exists() {
+            return;
+        }
+        for hash in plain {
+            let clip = persist::clip_path(&path, hash, false);
+            if let Err(e) = std::fs::remove_file(&clip) {
+                warn!("unable to remove the unencrypted clip {}: {}", clip.display(), e);
+            }
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => info!("encrypted the history into {}", encrypted.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("unable to remove the unencrypted history {}: {}", path.display(), e),
+        }
+    }
+
+    /// Loads the history saved at `path` without ever saving it, so that
+    /// changes stay in memory.
+    pub fn with_history(self, path: &Path, key: Option<&Key>) -> Result<Database, Box<dyn std::error::Error>> {
+        if let Some(file) = persist::load(path, key)? {
+            self.restore(file, true);
+        }
+        Ok(self)
+    }
+
+    /// The history with what's known about each clip, oldest first.
+    pub fn export(&self) -> HistoryFile {
+        self.snapshot().file()
+    }
+
+    /// Adds the clips of an exported history that aren't in it already, as
+    /// older than the ones that are, with their tags, pins and the rest.
+    /// Returns how many were added.
+    pub fn import(&self, file: HistoryFile) -> usize {
+        let before = self.clips.lock().unwrap().len();
+        self.restore(file, false);
+        // the imported ones are the oldest, so they're the first evicted
+        let added = self.clips.lock().unwrap().len().saturating_sub(before);
+        self.save();
+        added
+    }
+
+    // Evicts the oldest clips that aren't archived or pinned until there are
+    // no more than `max_clips`.
+    fn trim(&self, clips: &mut VecDeque<Clip>) {
+        while clips.len() > self.max_clips {
+            let evictable = {
+                let archived = self.archived.lock().unwrap();
+                let pinned = self.pinned.lock().unwrap();
+                clips.iter().position(|c| !archived.contains(&c.contents) && !pinned.contains(&c.contents))
+            };
+            let Some(pos) = evictable else { break };
+            if let Some(evicted) = clips.remove(pos) {
+                self.forget(&evicted.contents);
+            }
+        }
+    }
+
+    // Takes over a saved history, skipping clips that can't be read. Its
+    // clips keep their ids if `keep_ids`, unless one is taken by a clip
+    // copied since.
+    fn restore(&self, file: HistoryFile, keep_ids: bool) {
+        let mut saved_ids = HashMap::new();
+        let mut restored: Vec<Option<Clip>> = Vec::new();
+        for saved in &file.clips {
+            match saved.to_clip() {
+                Ok(clip) => restored.push(Some(clip)),
+                Err(e) => {
+                    error!("unable to restore a clip from the history: {}", e);
+                    restored.push(None);
+                }
+            }
+        }
+        for (saved, clip) in file.clips.iter().zip(&restored) {
+            let Some(clip) = clip else { continue };
+            let contents = &clip.contents;
+            if let Some(id) = saved.id.filter(|_| keep_ids) {
+                saved_ids.insert(contents.clone(), id);
+            }
+            // merged with what's known about the same clip already in it, so
+            // importing the same history twice changes nothing
+            if saved.uses > 0 {
+                let mut uses = self.uses.lock().unwrap();
+                let uses = uses.entry(contents.clone()).or_insert(0);
+                *uses = (*uses).max(saved.uses);
+            }
+            if !saved.tags.is_empty() {
+                self.tags.lock().unwrap().entry(contents.clone()).or_default().extend(saved.tags.iter().cloned());
+            }
+            if saved.pinned {
+                self.pinned.lock().unwrap().insert(contents.clone());
+            }
+            if saved.archived {
+                self.archived.lock().unwrap().insert(contents.clone());
+            }
+            if let Some(secs) = saved.expires {
+                // ones that ran out while rpt wasn't running go on the next `remove_expired`
+                let at = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+                self.expiry.lock().unwrap().insert(contents.clone(), at);
+            }
+            if let Some(Some(parent)) = saved.parent.and_then(|i| restored.get(i)) {
+                self.parents.lock().unwrap().insert(contents.clone(), parent.contents.clone());
+            }
+        }
+        let selection = file.selection.and_then(|i| restored.get(i).cloned().flatten());
+        let mut current = self.selection.lock().unwrap();
+        // unless something has been chosen since
+        if current.is_none() {
+            *current = selection;
+        }
+        drop(current);
+        let mut restored: Vec<Clip> = restored.into_iter().flatten().collect();
+        // an imported history may have the same clip twice, the newer one counts
+        let mut seen = HashSet::new();
+        restored.reverse();
+        restored.retain(|clip| seen.insert(clip.contents.clone()));
+        restored.reverse();
+        restored.iter().for_each(Clip::lock_memory);
+        let mut clips = self.clips.lock().unwrap();
+        // copied again since, so the copy is the newer one, keeping what's
+        // known about it
+        restored.retain(|clip| !clips.iter().any(|c| c.contents == clip.contents));
+        let mut ids = self.ids.lock().unwrap();
+        if keep_ids {
+            let newest = saved_ids.values().max().map_or(0, |id| id + 1);
+            self.next_id.fetch_max(newest.max(file.next_id), Ordering::AcqRel);
+        }
+        let mut taken: HashSet<usize> = ids.values().copied().collect();
+        for clip in &restored {
+            let id = match saved_ids.get(&clip.contents) {
+                Some(id) if taken.insert(*id) => *id,
+                _ => self.next_id.fetch_add(1, Ordering::AcqRel),
+            };
+            ids.insert(clip.contents.clone(), id);
+        }
+        drop(ids);
+        for clip in restored.into_iter().rev() {
+            clips.push_front(clip);
+        }
+        // a history saved with a higher max_clips
+        self.trim(&mut clips);
+    }
+
+    // What's needed to save the history, taken one lock at a time, since
+    // `chain` takes them in another order
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            clips: self.clips.lock().unwrap().iter().cloned().collect(),
+            selection: self.selection.lock().unwrap().clone(),
+            ids: self.ids.lock().unwrap().clone(),
+            next_id: self.next_id.load(Ordering::Acquire),
+            uses: self.uses.lock().unwrap().clone(),
+            tags: self.tags.lock().unwrap().clone(),
+            pinned: self.pinned.lock().unwrap().clone(),
+            archived: self.archived.lock().unwrap().clone(),
+            parents: self.parents.lock().unwrap().clone(),
+            expiry: self.expiry.lock().unwrap().clone(),
         }
     }
 
+    // Hands the history to the writer, if it's kept on disk. It's encoded
+    // when it's written, so a long history isn't encoded on every change.
+    // The contents of each clip are only written when it's new, and removed
+    // once it's gone. Has to be called without holding any of the locks.
+    fn save(&self) {
+        let Some(store) = self.store.get() else { return };
+        let snapshot = self.snapshot();
+        let key = store.key.clone();
+        let encrypted = key.is_some();
+        let mut written = store.written.lock().unwrap();
+        let kept: HashSet<u64> = snapshot.clips.iter().map(|clip| clip.checksum).collect();
+        for clip in &snapshot.clips {
+            if written.insert(clip.checksum) {
+                let (clip, key) = (clip.clone(), key.clone());
+                let path = persist::clip_path(&store.path, clip.checksum, encrypted);
+                store.writer.write_with(&path, Box::new(move || persist::encode_clip(&clip, key.as_deref())));
+            }
+        }
+        written.retain(|checksum| {
+            if !kept.contains(checksum) {
+                store.writer.remove(&persist::clip_path(&store.path, *checksum, encrypted));
+            }
+            kept.contains(checksum)
+        });
+        drop(written);
+        store.writer.write_with(&store.path, Box::new(move || persist::encode(&snapshot.index(encrypted), key.as_deref())));
+    }
+
+    /// Tags new clips that match the rules.
+    pub fn with_tag_rules(mut self, rules: Vec<TagRule>) -> Database {
+        self.tag_rules = rules;
+        self
+    }
+
+    /// Only searches and previews the first `bytes` of each clip, so that
+    /// huge clips don't slow down every keystroke.
+    pub fn with_preview_bytes(mut self, bytes: usize) -> Database {
+        self.preview_bytes = bytes;
+        self
+    }
+
+    pub fn preview_bytes(&self) -> usize {
+        self.preview_bytes
+    }
+
+    /// Keeps the newest `max` clips that aren't archived or pinned, evicting
+    /// older ones.
+    pub fn with_max_clips(mut self, max: usize) -> Database {
+        self.max_clips = max;
+        self
+    }
+
+    pub fn max_clips(&self) -> usize {
+        self.max_clips
+    }
+
+    /// Forgets clips `ttl` after they were captured, unless they're pinned,
+    /// archived or have a time to live of their own. See `remove_expired`.
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Database {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// Adds the clip as the newest one, and returns its id. A clip that's in
+    /// the history already is moved up to be the newest instead, counting as
+    /// copied again, and `None` is returned. It keeps its id, and when it was
+    /// first captured, so copying it again doesn't extend its time to live.
     pub fn add_clip(&self, clip: Clip) -> Option<usize> {
+        let id = self.insert(clip);
+        self.save();
+        id
+    }
+
+    fn insert(&self, clip: Clip) -> Option<usize> {
         let mut clips = self.clips.lock().unwrap();
 
         // see if it's a greater version of the previous clip
         let replace = match clips.back() {
             None => false,
-            Some(latest_clip) => clip.contains(latest_clip),
+            Some(latest_clip) => {
+                clip.contents != latest_clip.contents
+                    && clip.contains(latest_clip)
+                    && !self.is_archived(latest_clip)
+                    && !self.is_pinned(latest_clip)
+            }
         };
+        // which it takes the id of, as a selection that grew is the same one
+        let mut id = None;
         if replace {
-            clips.pop_back();
+            if let Some(replaced) = clips.pop_back() {
+                id = self.ids.lock().unwrap().remove(&replaced.contents);
+                self.forget(&replaced.contents);
+            }
         }
 
-        // see if it's already in the database
-        if clips.iter().find(|c| c.contents.eq(&clip.contents)).is_some() {
+        // see if it's already in the database, which makes it the newest
+        // again, keeping what's known about it
+        if let Some(pos) = clips.iter().position(|c| c.contents.eq(&clip.contents)) {
+            self.record_use(&clip.contents);
+            if let Some(mut existing) = clips.remove(pos) {
+                // where it came from, if that wasn't known before
+                if existing.app.is_none() {
+                    existing.app = clip.app;
+                }
+                clips.push_back(existing);
+            }
             return None;
         }
 
+        self.apply_tag_rules(&clip);
+        clip.lock_memory();
+        let id = id.unwrap_or_else(|| self.next_id.fetch_add(1, Ordering::AcqRel));
+        self.ids.lock().unwrap().insert(clip.contents.clone(), id);
+        let contents = clip.contents.clone();
         clips.push_back(clip);
-        if clips.len() > MAX_CLIPS {
-            clips.pop_front();
-            self.start_idx.fetch_add(1, Ordering::Acquire);
+        self.trim(&mut clips);
+        // unless it was evicted right away, with nothing else to evict
+        self.ids.lock().unwrap().get(&contents).copied()
+    }
+
+    // Drops what's known about clips that are no longer in the history
+    fn forget(&self, contents: &Arc<ClipContents>) {
+        self.ids.lock().unwrap().remove(contents);
+        self.uses.lock().unwrap().remove(contents);
+        self.tags.lock().unwrap().remove(contents);
+        self.pinned.lock().unwrap().remove(contents);
+        self.parents.lock().unwrap().remove(contents);
+        self.expiry.lock().unwrap().remove(contents);
+    }
+
+    /// Forgets the clip at `at`, see `remove_expired`.
+    pub fn expire_at(&self, clip: &Clip, at: SystemTime) {
+        self.expiry.lock().unwrap().insert(clip.contents.clone(), at);
+        self.save();
+    }
+
+    /// Takes away the clip's own time to live. Returns whether it had one.
+    pub fn keep(&self, clip: &Clip) -> bool {
+        let kept = self.expiry.lock().unwrap().remove(&clip.contents).is_some();
+        if kept {
+            self.save();
+        }
+        kept
+    }
+
+    /// When the clip will be forgotten, if it will.
+    pub fn expires(&self, clip: &Clip) -> Option<SystemTime> {
+        if let Some(at) = self.expiry.lock().unwrap().get(&clip.contents) {
+            return Some(*at);
+        }
+        if self.is_pinned(clip) || self.is_archived(clip) {
+            return None;
+        }
+        self.ttl.and_then(|ttl| clip.captured.checked_add(ttl))
+    }
+
+    /// Removes the clips whose time to live has run out by `now`, unless
+    /// they were archived. Clips without one of their own get the history's,
+    /// unless they're pinned. Stops offering the current clip if it was one.
+    /// Returns how many were removed.
+    pub fn remove_expired(&self, now: SystemTime) -> usize {
+        let archived = self.archived.lock().unwrap().clone();
+        let pinned = self.pinned.lock().unwrap().clone();
+        let expiry = self.expiry.lock().unwrap().clone();
+        let mut expired: HashSet<Arc<ClipContents>> = expiry
+            .iter()
+            .filter(|(contents, at)| **at <= now && !archived.contains(*contents))
+            .map(|(contents, _)| contents.clone())
+            .collect();
+        if let Some(ttl) = self.ttl {
+            let clips = self.clips.lock().unwrap();
+            expired.extend(
+                clips
+                    .iter()
+                    .filter(|c| !expiry.contains_key(&c.contents) && !archived.contains(&c.contents) && !pinned.contains(&c.contents))
+                    .filter(|c| c.captured.checked_add(ttl).is_some_and(|at| at <= now))
+                    .map(|c| c.contents.clone()),
+            );
+        }
+        if expired.is_empty() {
+            return 0;
+        }
+        let removed = {
+            let mut clips = self.clips.lock().unwrap();
+            let before = clips.len();
+            clips.retain(|clip| !expired.contains(&clip.contents));
+            before - clips.len()
+        };
+        self.queue.lock().unwrap().retain(|clip| !expired.contains(&clip.contents));
+        {
+            let mut selection = self.selection.lock().unwrap();
+            if selection.as_ref().is_some_and(|clip| expired.contains(&clip.contents)) {
+                *selection = None;
+            }
+        }
+        for contents in &expired {
+            self.forget(contents);
         }
-        Some(clips.len() + (self.start_idx.load(Ordering::Acquire)) - 1)
+        self.save();
+        removed
     }
 
     pub fn clips(&self) -> ArcMutexGuardian<VecDeque<Clip>> {
         ArcMutexGuardian::take(Arc::clone(&self.clips)).unwrap()
     }
 
-    pub fn at(&self, idx: usize) -> Option<Clip> {
+    /// The clip with the id, if it's still in the history.
+    pub fn at(&self, id: usize) -> Option<Clip> {
         let clips = self.clips.lock().unwrap();
-        let start = self.start_idx.load(Ordering::Acquire);
-        if idx < start {
-            None
-        } else {
-            clips
-                .get(idx - start)
-                .cloned()
-        }
+        let ids = self.ids.lock().unwrap();
+        // most lookups are for recent clips
+        clips.iter().rev().find(|clip| ids.get(&clip.contents) == Some(&id)).cloned()
+    }
+
+    /// The clip whose contents have the `stable_hash`.
+    pub fn find_by_hash(&self, hash: u64) -> Option<Clip> {
+        self.clips.lock().unwrap().iter().find(|clip| clip.contents.stable_hash() == hash).cloned()
     }
 
     pub fn selection(&self) -> Option<Clip> {
@@ -67,7 +631,258 @@ impl Database {
     }
 
     pub fn select_clip(&self, clip: Clip) {
-        *self.selection.lock().unwrap() = Some(clip)
+        self.record_use(&clip.contents);
+        *self.selection.lock().unwrap() = Some(clip);
+        self.save();
+    }
+
+    /// Forgets the current clip, and the paste queue that would replace it.
+    pub fn clear_selection(&self) {
+        *self.selection.lock().unwrap() = None;
+        self.queue.lock().unwrap().clear();
+        self.save();
+    }
+
+    fn record_use(&self, contents: &Arc<ClipContents>) {
+        *self.uses.lock().unwrap().entry(contents.clone()).or_insert(0) += 1;
+    }
+
+    /// The id `at` finds the clip by, if it's still in the history.
+    pub fn id_of(&self, clip: &Clip) -> Option<usize> {
+        self.ids.lock().unwrap().get(&clip.contents).copied()
+    }
+
+    /// The ids of clips from the history, like `id_of` for each but in one
+    /// pass, for listing long histories.
+    pub fn ids_of(&self, listed: &[Clip]) -> Vec<Option<usize>> {
+        let ids = self.ids.lock().unwrap();
+        listed.iter().map(|clip| ids.get(&clip.contents).copied()).collect()
+    }
+
+    /// How many times the clip has been copied again or chosen.
+    pub fn uses(&self, clip: &Clip) -> usize {
+        self.uses.lock().unwrap().get(&clip.contents).cloned().unwrap_or(0)
+    }
+
+    fn apply_tag_rules(&self, clip: &Clip) {
+        let text = clip.contents.preview(self.preview_bytes);
+        let matching: Vec<&TagRule> = self.tag_rules.iter().filter(|rule| rule.pattern.is_match(&text)).collect();
+        if !matching.is_empty() {
+            let mut tags = self.tags.lock().unwrap();
+            let clip_tags = tags.entry(clip.contents.clone()).or_default();
+            clip_tags.extend(matching.iter().map(|rule| rule.tag.clone()));
+        }
+    }
+
+    /// Tags the clip with the id. Returns whether there is such a clip.
+    pub fn tag(&self, id: usize, tag: &str) -> bool {
+        match self.at(id) {
+            Some(clip) => {
+                self.tags.lock().unwrap().entry(clip.contents).or_default().insert(tag.to_owned());
+                self.save();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the tag from the clip with the id. Returns whether it had it.
+    pub fn untag(&self, id: usize, tag: &str) -> bool {
+        let clip = match self.at(id) {
+            Some(clip) => clip,
+            None => return false,
+        };
+        let removed = {
+            let mut tags = self.tags.lock().unwrap();
+            match tags.get_mut(&clip.contents) {
+                Some(clip_tags) => {
+                    let removed = clip_tags.remove(tag);
+                    if clip_tags.is_empty() {
+                        tags.remove(&clip.contents);
+                    }
+                    removed
+                }
+                None => false,
+            }
+        };
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// Pins the clip with the id, so it's never evicted and the picker lists
+    /// it first. Returns whether there is such a clip.
+    pub fn pin(&self, id: usize) -> bool {
+        match self.at(id) {
+            Some(clip) => {
+                self.pinned.lock().unwrap().insert(clip.contents);
+                self.save();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether the clip with the id was pinned.
+    pub fn unpin(&self, id: usize) -> bool {
+        let unpinned = match self.at(id) {
+            Some(clip) => self.pinned.lock().unwrap().remove(&clip.contents),
+            None => false,
+        };
+        if unpinned {
+            self.save();
+        }
+        unpinned
+    }
+
+    /// The pinned clips that aren't archived, newest first.
+    pub fn pinned_clips(&self) -> Vec<Clip> {
+        let clips = self.clips.lock().unwrap();
+        let archived = self.archived.lock().unwrap();
+        let pinned = self.pinned.lock().unwrap();
+        clips
+            .iter()
+            .rev()
+            .filter(|c| pinned.contains(&c.contents) && !archived.contains(&c.contents))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_pinned(&self, clip: &Clip) -> bool {
+        self.pinned.lock().unwrap().contains(&clip.contents)
+    }
+
+    /// Archives the clip with the id, or takes it out of the archive.
+    /// Archived clips are left out of listings and searches unless asked for
+    /// with `archived:`, and are never evicted or expired. Returns whether
+    /// that changed anything.
+    pub fn archive(&self, id: usize, archived: bool) -> bool {
+        let changed = match self.at(id) {
+            Some(clip) if archived => self.archived.lock().unwrap().insert(clip.contents),
+            Some(clip) => self.archived.lock().unwrap().remove(&clip.contents),
+            None => false,
+        };
+        if changed {
+            self.save();
+        }
+        changed
+    }
+
+    pub fn is_archived(&self, clip: &Clip) -> bool {
+        self.archived.lock().unwrap().contains(&clip.contents)
+    }
+
+    /// The part of a search that is matched against the text, without
+    /// filters.
+    pub fn search_text(&self, pattern: &str) -> String {
+        let tags = self.tags.lock().unwrap();
+        Filter::extract(pattern, &|tag| tags.values().any(|tags| tags.contains(tag))).1
+    }
+
+    /// The clip being offered, or else the newest one: the one a user would
+    /// think of as what they last copied.
+    pub fn current(&self) -> Option<Clip> {
+        let offered = self.offered.load(Ordering::Acquire).then(|| self.selection()).flatten();
+        offered.or_else(|| self.sorted(SortMode::Recency, 1).pop())
+    }
+
+    /// Records whether a selection is still owned with the selected clip.
+    /// Once another application took them all, what was copied there is
+    /// newer than the selection.
+    pub fn set_offered(&self, offered: bool) {
+        self.offered.store(offered, Ordering::Release);
+    }
+
+    /// The clip's tags, sorted.
+    pub fn tags(&self, clip: &Clip) -> Vec<String> {
+        self.tags
+            .lock()
+            .unwrap()
+            .get(&clip.contents)
+            .map(|tags| tags.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Records that `edited` was made by changing `original`, say by pasting
+    /// it into an editor and copying it again after some changes.
+    pub fn link(&self, original: &Clip, edited: &Clip) {
+        if original.contents != edited.contents {
+            self.parents.lock().unwrap().insert(edited.contents.clone(), original.contents.clone());
+            self.save();
+        }
+    }
+
+    /// The clip and the earlier versions it was made from that are still in
+    /// the history, newest first.
+    pub fn chain(&self, clip: &Clip) -> Vec<Clip> {
+        // in the same order as insert, which forgets parents as it evicts
+        let clips = self.clips.lock().unwrap();
+        let parents = self.parents.lock().unwrap();
+        let mut chain = vec![clip.clone()];
+        let mut contents = &clip.contents;
+        while let Some(parent) = parents.get(contents) {
+            // a clip can be edited back into an earlier version
+            if chain.iter().any(|c| c.contents == *parent) {
+                break;
+            }
+            match clips.iter().find(|c| c.contents == *parent) {
+                Some(original) => chain.push(original.clone()),
+                None => break,
+            }
+            contents = parent;
+        }
+        chain
+    }
+
+    pub fn matcher(&self) -> &dyn Matcher {
+        self.matcher.as_ref()
+    }
+
+    /// The newest clips in the given order, leaving out archived ones. Ties
+    /// are broken by recency.
+    pub fn sorted(&self, mode: SortMode, max: usize) -> Vec<Clip> {
+        let clips = self.clips.lock().unwrap();
+        let archived = self.archived.lock().unwrap();
+        let newest = clips.iter().rev().filter(|c| !archived.contains(&c.contents));
+        // only the clips that are listed get copied, which matters with long
+        // histories
+        let mut listed: Vec<&Clip> = match mode {
+            SortMode::Recency => newest.take(max).collect(),
+            _ => newest.collect(),
+        };
+        drop(archived);
+        match mode {
+            SortMode::Recency => {}
+            SortMode::Frequency => {
+                let uses = self.uses.lock().unwrap();
+                listed.sort_by_key(|c| std::cmp::Reverse(uses.get(&c.contents).cloned().unwrap_or(0)));
+            }
+            SortMode::Frecency => {
+                let uses = self.uses.lock().unwrap();
+                let now = SystemTime::now();
+                // every use counts, but less the longer ago the clip was copied
+                let score = |c: &Clip| {
+                    let hours = now.duration_since(c.captured).unwrap_or_default().as_secs_f64() / 3600.0;
+                    (uses.get(&c.contents).cloned().unwrap_or(0) + 1) as f64 / (1.0 + hours)
+                };
+                listed.sort_by(|a, b| score(b).total_cmp(&score(a)));
+            }
+            SortMode::Pinned => {
+                let pinned = self.pinned.lock().unwrap();
+                listed.sort_by_key(|c| !pinned.contains(&c.contents));
+            }
+            SortMode::Alphabetical => {
+                let mut collator = Collator::default();
+                let max = self.preview_bytes;
+                listed.sort_by(|a, b| collator.collate(a.contents.preview(max).as_bytes(), b.contents.preview(max).as_bytes()));
+            }
+            SortMode::Size => {
+                listed.sort_by_key(|c| std::cmp::Reverse(c.counts.bytes));
+            }
+        }
+        listed.truncate(max);
+        listed.into_iter().cloned().collect()
     }
 
     pub fn select(&self, idx: usize) -> bool {
@@ -75,91 +890,769 @@ impl Database {
             None => false,
             Some(clip) => {
                 *self.selection.lock().unwrap() = Some(clip);
+                self.save();
                 true
             }
         }
     }
 
-    pub fn search(&self, pattern: &str, max: usize) -> Vec<Clip> {
-        let clips = self.clips.lock().unwrap();
-        let mut matched_clips: Vec<(usize, i64)> = clips.iter().enumerate().filter_map(|(idx, clip)| {
-            match &clip.contents.as_ref() {
-                ClipContents::Text(content) => {
-                    match fuzzy_match(content, pattern) {
-                        None => { None }
-                        Some(score) => { Some((idx, score)) }
-                    }
-                }
-            }
-        }).collect();
-        matched_clips.sort_by_key(|(_, score)| { *score });
-        matched_clips.iter().rev().take(max)
-            .flat_map(|(idx, _)| { clips.get(*idx).cloned() })
-            .collect()
+    /// Adds the clip to the paste queue, or removes it if it's already queued.
+    /// Returns whether the clip is queued afterwards.
+    pub fn toggle_queued(&self, clip: Clip) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.iter().position(|c| c.equal(&clip)) {
+            Some(pos) => {
+                queue.remove(pos);
+                false
+            }
+            None => {
+                queue.push_back(clip);
+                true
+            }
+        }
+    }
+
+    pub fn is_queued(&self, clip: &Clip) -> bool {
+        self.queue.lock().unwrap().iter().any(|c| c.equal(clip))
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// The clip the paste queue would give next, leaving it queued.
+    pub fn next_queued(&self) -> Option<Clip> {
+        self.queue.lock().unwrap().front().cloned()
+    }
+
+    /// Makes the next clip in the paste queue the selection.
+    pub fn advance_queue(&self) -> Option<Clip> {
+        let next = self.queue.lock().unwrap().pop_front()?;
+        self.select_clip(next.clone());
+        Some(next)
+    }
+
+    /// Clips matching the pattern, best first. Words like `len:>1000`,
+    /// `tag:work`, `app:firefox` or `age:<1h` in the pattern filter on size,
+    /// tags, where the clip came from or when instead of being matched. So
+    /// does `#work`, if any clip is tagged `work`. Archived clips are
+    /// only searched, and then alone, with `archived:`.
+    pub fn search(&self, pattern: &str, max: usize) -> Vec<Clip> {
+        let clips = self.clips.lock().unwrap();
+        let tags = self.tags.lock().unwrap();
+        let (filters, pattern) = Filter::extract(pattern, &|tag| tags.values().any(|tags| tags.contains(tag)));
+        let in_archive = filters.contains(&Filter::Archived);
+        let archived = self.archived.lock().unwrap();
+        let mut matched_clips: Vec<(usize, i64)> = clips.iter().enumerate().filter_map(|(idx, clip)| {
+            if archived.contains(&clip.contents) != in_archive {
+                return None;
+            }
+            let clip_tags = tags.get(&clip.contents);
+            if !filters.iter().all(|f| f.matches(clip, clip_tags)) {
+                return None;
+            }
+            if pattern.is_empty() {
+                return Some((idx, 0));
+            }
+            let content = clip.contents.preview(self.preview_bytes);
+            self.matcher.score(&content, &pattern).map(|score| (idx, score))
+        }).collect();
+        // best score first, and the most recent clip first on ties
+        let order = |(a_idx, a_score): &(usize, i64), (b_idx, b_score): &(usize, i64)| {
+            b_score.cmp(a_score).then(b_idx.cmp(a_idx))
+        };
+        // only the best ones need to be in order
+        if max < matched_clips.len() {
+            matched_clips.select_nth_unstable_by(max, order);
+            matched_clips.truncate(max);
+        }
+        matched_clips.sort_by(order);
+        matched_clips.iter().take(max)
+            .flat_map(|(idx, _)| { clips.get(*idx).cloned() })
+            .collect()
+    }
+}
+
+/// Whether `edited` looks like a changed version of `original`: one has the
+/// other in it, or they share a beginning and an end that make up at least
+/// half of the longer one.
+pub fn related(original: &str, edited: &str) -> bool {
+    if original.is_empty() || edited.is_empty() {
+        return false;
+    }
+    if original.contains(edited) || edited.contains(original) {
+        return true;
+    }
+    let prefix = original.chars().zip(edited.chars()).take_while(|(a, b)| a == b).count();
+    let suffix = original
+        .chars()
+        .rev()
+        .zip(edited.chars().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let shorter = original.chars().count().min(edited.chars().count());
+    let longer = original.chars().count().max(edited.chars().count());
+    // the prefix and suffix can overlap in the shorter text
+    (prefix + suffix).min(shorter) * 2 >= longer
+}
+
+/// Whether the texts are over 90% the same, by edit distance over all of
+/// them. What they start and end with in common is skipped first, so a small
+/// change anywhere in a long text is quick to find.
+pub fn similar(a: &str, b: &str) -> bool {
+    if a.len().abs_diff(b.len()) * 10 > a.len().max(b.len()) {
+        return false;
+    }
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let max = a.len().max(b.len()) / 10;
+    let prefix = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+    // the suffix can't overlap the prefix
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (a, b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    a.len().max(b.len()) <= max || within_distance(a, b, max)
+}
+
+// The texts `similar` could find to be near duplicates of a text of `len`
+// bytes are at most one bucket away from its own, since their lengths are
+// within 10% of each other.
+fn similar_bucket(len: usize) -> i64 {
+    ((len as f64 + 1.0).ln() / (10.0_f64 / 9.0).ln()) as i64
+}
+
+/// For each of the texts, the first one before it that it's a near duplicate
+/// of, as `similar` tells, or its own index. A text is only compared with
+/// the ones kept as their own, and only those of about the same length.
+pub fn first_similar(texts: &[&str]) -> Vec<usize> {
+    let mut kept: HashMap<i64, Vec<usize>> = HashMap::new();
+    let mut firsts = Vec::with_capacity(texts.len());
+    for (i, text) in texts.iter().enumerate() {
+        let bucket = similar_bucket(text.len());
+        let first = (bucket - 1..=bucket + 1)
+            .filter_map(|b| kept.get(&b))
+            .flatten()
+            .copied()
+            .filter(|&k| similar(texts[k], text))
+            .min();
+        match first {
+            Some(k) => firsts.push(k),
+            None => {
+                kept.entry(bucket).or_default().push(i);
+                firsts.push(i);
+            }
+        }
+    }
+    firsts
+}
+
+// Whether the edit distance is at most `max`. Only the band of the table
+// within `max` of the diagonal can be that close, so only it is filled in.
+fn within_distance(a: &[char], b: &[char], max: usize) -> bool {
+    let far = max + 1;
+    let mut previous: Vec<usize> = (0..=b.len()).map(|j| j.min(far)).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![far; b.len() + 1];
+        current[0] = i.min(far);
+        let mut closest = current[0];
+        for j in i.saturating_sub(max).max(1)..=(i + max).min(b.len()) {
+            let substitute = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            current[j] = substitute.min(previous[j] + 1).min(current[j - 1] + 1).min(far);
+            closest = closest.min(current[j]);
+        }
+        if closest > max {
+            return false;
+        }
+        previous = current;
+    }
+    previous[b.len()] <= max
+}
+
+/// A word in a search that filters clips rather than being matched.
+#[derive(Clone, PartialEq, Debug)]
+enum Filter {
+    Size(SizeFilter),
+    /// `tag:work`, or `#work` for a tag some clip has
+    Tag(String),
+    /// `app:firefox`, matching part of the application's name in any case
+    App(String),
+    /// `archived:`, searching the archived clips instead of the rest
+    Archived,
+    /// `age:<1h` for clips copied in the last hour, `age:>2d` for older ones
+    Age(AgeFilter),
+}
+
+impl Filter {
+    fn parse(word: &str, known_tag: &dyn Fn(&str) -> bool) -> Option<Filter> {
+        // otherwise it's text, like `#include`
+        if let Some(tag) = word.strip_prefix('#').filter(|tag| known_tag(tag)) {
+            return Some(Filter::Tag(tag.to_owned()));
+        }
+        if word == "archived:" {
+            return Some(Filter::Archived);
+        }
+        if let Some(app) = word.strip_prefix("app:").filter(|app| !app.is_empty()) {
+            return Some(Filter::App(app.to_lowercase()));
+        }
+        if let Some(age) = AgeFilter::parse(word) {
+            return Some(Filter::Age(age));
+        }
+        match word.strip_prefix("tag:") {
+            Some(tag) if !tag.is_empty() => Some(Filter::Tag(tag.to_owned())),
+            _ => SizeFilter::parse(word).map(Filter::Size),
+        }
+    }
+
+    /// Splits the filters out of a search, returning them and the rest of it.
+    /// `known_tag` tells whether any clip has a tag.
+    fn extract(pattern: &str, known_tag: &dyn Fn(&str) -> bool) -> (Vec<Filter>, String) {
+        let mut filters = Vec::new();
+        let mut rest = Vec::new();
+        for word in pattern.split(' ') {
+            match Filter::parse(word, known_tag) {
+                Some(filter) => filters.push(filter),
+                None => rest.push(word),
+            }
+        }
+        if filters.is_empty() {
+            // leave the pattern untouched, spaces and all
+            return (filters, pattern.to_owned());
+        }
+        (filters, rest.join(" ").trim().to_owned())
+    }
+
+    fn matches(&self, clip: &Clip, tags: Option<&BTreeSet<String>>) -> bool {
+        match self {
+            Filter::Size(size) => size.matches(clip.counts.bytes),
+            Filter::Tag(tag) => tags.is_some_and(|tags| tags.contains(tag)),
+            Filter::App(app) => clip.app_name().is_some_and(|name| name.to_lowercase().contains(app.as_str())),
+            // checked by `search`, which knows what's archived
+            Filter::Archived => true,
+            Filter::Age(age) => age.matches(clip.age(SystemTime::now())),
+        }
+    }
+}
+
+/// An `age:` search filter on how long ago the clip was copied, in seconds
+/// or with a unit of `s`, `m`, `h` or `d`, e.g. `age:<10m` or `age:>1d`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum AgeFilter {
+    Newer(Duration),
+    Older(Duration),
+}
+
+impl AgeFilter {
+    fn parse(word: &str) -> Option<AgeFilter> {
+        let spec = word.strip_prefix("age:")?;
+        let (make, spec): (fn(Duration) -> AgeFilter, &str) = if let Some(n) = spec.strip_prefix('<') {
+            (AgeFilter::Newer, n)
+        } else {
+            (AgeFilter::Older, spec.strip_prefix('>')?)
+        };
+        let (number, unit) = match spec.char_indices().last()? {
+            (i, unit) if unit.is_ascii_alphabetic() => (&spec[..i], unit),
+            _ => (spec, 's'),
+        };
+        let scale = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        let secs = number.parse::<u64>().ok()?.checked_mul(scale)?;
+        Some(make(Duration::from_secs(secs)))
+    }
+
+    fn matches(&self, age: Duration) -> bool {
+        match *self {
+            AgeFilter::Newer(limit) => age < limit,
+            AgeFilter::Older(limit) => age > limit,
+        }
+    }
+}
+
+/// A `len:` search filter on the size in bytes, e.g. `len:>1000`, `len:<=80`
+/// or `len:42`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SizeFilter {
+    Less(usize),
+    LessEq(usize),
+    Greater(usize),
+    GreaterEq(usize),
+    Equal(usize),
+}
+
+impl SizeFilter {
+    fn parse(word: &str) -> Option<SizeFilter> {
+        let spec = word.strip_prefix("len:")?;
+        let (make, number): (fn(usize) -> SizeFilter, &str) = if let Some(n) = spec.strip_prefix(">=") {
+            (SizeFilter::GreaterEq, n)
+        } else if let Some(n) = spec.strip_prefix("<=") {
+            (SizeFilter::LessEq, n)
+        } else if let Some(n) = spec.strip_prefix('>') {
+            (SizeFilter::Greater, n)
+        } else if let Some(n) = spec.strip_prefix('<') {
+            (SizeFilter::Less, n)
+        } else {
+            (SizeFilter::Equal, spec.strip_prefix('=').unwrap_or(spec))
+        };
+        number.parse().ok().map(make)
+    }
+
+    fn matches(&self, size: usize) -> bool {
+        match *self {
+            SizeFilter::Less(n) => size < n,
+            SizeFilter::LessEq(n) => size <= n,
+            SizeFilter::Greater(n) => size > n,
+            SizeFilter::GreaterEq(n) => size >= n,
+            SizeFilter::Equal(n) => size == n,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Clip {
+    pub source: Source,
+    pub contents: Arc<ClipContents>,
+    pub captured: SystemTime,
+    pub counts: Counts,
+    /// The `stable_hash` of the contents when the clip was captured, to tell
+    /// whether they were corrupted since
+    pub checksum: u64,
+    /// How the clip was fetched from its owner, if it was
+    pub trace: Option<Arc<Trace>>,
+    /// The clip as formatted text, if the owner offered that too
+    pub html: Option<Arc<Html>>,
+    /// The application that added the clip, if it said
+    pub app: Option<String>,
+    /// The web page the clip was copied from, as told by the browser
+    pub page: Option<Arc<Page>>,
+    /// The window the clip was copied from
+    pub origin: Option<Arc<Origin>>,
+}
+
+/// A `text/html` rendition of a clip.
+#[derive(Clone, PartialEq)]
+pub struct Html(pub String);
+
+impl std::fmt::Debug for Html {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Html({})", redact::body(&self.0))
+    }
+}
+
+impl Drop for Html {
+    fn drop(&mut self) {
+        secrets::release_string(&mut self.0);
+    }
+}
+
+/// The window that owned a selection when a clip was captured from it.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Origin {
+    pub window: u32,
+    /// The WM_CLASS class, like `firefox`
+    pub class: Option<String>,
+    pub title: Option<String>,
+}
+
+// Titles tend to name the document being edited
+impl std::fmt::Debug for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.title {
+            Some(title) => write!(f, "Origin({:#x}, {:?}, {})", self.window, self.class, redact::body(title)),
+            None => write!(f, "Origin({:#x}, {:?})", self.window, self.class),
+        }
+    }
+}
+
+/// Where in a browser a clip was copied.
+#[derive(Clone, PartialEq)]
+pub struct Page {
+    pub url: String,
+    pub title: Option<String>,
+}
+
+// URLs can have tokens in them
+impl std::fmt::Debug for Page {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Page({})", redact::body(&self.url))
+    }
+}
+
+/// How a clip was negotiated with the application that owned it, for
+/// figuring out why a paste came out wrong.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Trace {
+    pub selection: String,
+    /// The targets the owner offered
+    pub targets: Vec<String>,
+    /// The target that was asked for
+    pub target: String,
+    pub transfer: Transfer,
+    /// From asking for the targets until the data arrived
+    pub duration_ms: u64,
+    /// Bytes received
+    pub size: usize,
+    /// What the window manager said was focused when the clip arrived
+    #[serde(default)]
+    pub focused_app: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transfer {
+    /// All of the data in one property
+    Direct,
+    /// In chunks with the INCR protocol
+    Incremental,
+}
+
+/// Line, word and byte counts of a clip, computed when it's captured.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct Counts {
+    pub lines: usize,
+    pub words: usize,
+    pub bytes: usize,
+}
+
+impl Counts {
+    pub fn of(contents: &ClipContents) -> Counts {
+        match contents {
+            ClipContents::Text(text) => Counts {
+                lines: text.lines().count(),
+                words: text.split_whitespace().count(),
+                bytes: text.len(),
+            },
+            ClipContents::Files(paths) => Counts {
+                lines: paths.len(),
+                words: paths.len(),
+                bytes: contents.size(),
+            },
+            ClipContents::Binary { data, .. } => Counts { lines: 0, words: 0, bytes: data.len() },
+        }
+    }
+}
+
+impl Clip {
+    pub fn new(source: Source, contents: ClipContents) -> Clip {
+        let counts = Counts::of(&contents);
+        let checksum = contents.stable_hash();
+        Clip {
+            source,
+            contents: Arc::new(contents),
+            captured: SystemTime::now(),
+            counts,
+            checksum,
+            trace: None,
+            html: None,
+            app: None,
+            page: None,
+            origin: None,
+        }
+    }
+
+    /// How long before `now` the clip was captured.
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.captured).unwrap_or_default()
+    }
+
+    /// Whether the contents still match the checksum taken at capture.
+    pub fn intact(&self) -> bool {
+        self.contents.stable_hash() == self.checksum
+    }
+
+    pub fn contains(&self, other: &Clip) -> bool {
+        self.contents.contains(&other.contents)
+    }
+
+    /// Locks the contents and their HTML into memory, see `secrets::lock`.
+    pub fn lock_memory(&self) {
+        self.contents.lock_memory();
+        if let Some(html) = &self.html {
+            secrets::lock_string(&html.0);
+        }
+    }
+
+    /// The application the clip came from: the class of the window it was
+    /// copied from, or the name a tool gave when adding it.
+    pub fn app_name(&self) -> Option<&str> {
+        self.origin
+            .as_ref()
+            .and_then(|origin| origin.class.as_deref())
+            .or(self.app.as_deref())
+    }
+
+    pub fn equal(&self, other: &Clip) -> bool {
+        self.contents.equal(&other.contents)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum ClipContents {
+    Text(String),
+    /// Files copied in a file manager, offered as `text/uri-list`
+    Files(Vec<PathBuf>),
+    /// Data offered only as some other target, such as `image/png` or
+    /// `application/pdf`, which is served back as that target
+    Binary { mime: String, data: Vec<u8> },
+}
+
+// Clips end up in logs through the Debug output of what holds them
+impl std::fmt::Debug for ClipContents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClipContents::Text(text) => write!(f, "Text({})", redact::body(text)),
+            ClipContents::Files(_) => write!(f, "Files({})", redact::body(&self.text())),
+            ClipContents::Binary { mime, data } => write!(f, "Binary({}, {} bytes)", mime, data.len()),
+        }
+    }
+}
+
+// Wiped once the last clip holding them is gone, when clips are protected
+impl Drop for ClipContents {
+    fn drop(&mut self) {
+        match self {
+            ClipContents::Text(text) => secrets::release_string(text),
+            ClipContents::Files(paths) => {
+                for path in paths {
+                    let mut bytes = std::mem::take(path).into_os_string().into_vec();
+                    secrets::release_vec(&mut bytes);
+                }
+            }
+            ClipContents::Binary { data, .. } => secrets::release_vec(data),
+        }
+    }
+}
+
+impl ClipContents {
+    /// Locks the contents into memory when clips are protected, see
+    /// `secrets::lock`. They're unlocked when dropped.
+    pub fn lock_memory(&self) {
+        match self {
+            ClipContents::Text(text) => secrets::lock_string(text),
+            ClipContents::Files(paths) => {
+                for path in paths {
+                    secrets::lock(path.as_os_str().as_bytes().as_ptr(), path.capacity());
+                }
+            }
+            ClipContents::Binary { data, .. } => secrets::lock_vec(data),
+        }
+    }
+
+    /// The clip as text. Files are their paths, one per line, and binary data
+    /// is described rather than shown.
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            ClipContents::Text(text) => Cow::Borrowed(text),
+            ClipContents::Files(paths) => {
+                let paths: Vec<Cow<str>> = paths.iter().map(|path| path.to_string_lossy()).collect();
+                Cow::Owned(paths.join("\n"))
+            }
+            ClipContents::Binary { mime, data } => {
+                Cow::Owned(format!("<binary: {}, {} KB>", mime, data.len().div_ceil(1024)))
+            }
+        }
+    }
+
+    /// The text cut down to at most `max_bytes`, on a character boundary.
+    pub fn preview(&self, max_bytes: usize) -> Cow<'_, str> {
+        match self.text() {
+            Cow::Borrowed(text) => Cow::Borrowed(truncate(text, max_bytes)),
+            Cow::Owned(text) => Cow::Owned(truncate(&text, max_bytes).to_owned()),
+        }
+    }
+
+    /// Whether the clip most likely isn't text, i.e. it has NULs or bytes that
+    /// weren't valid UTF-8 when captured.
+    pub fn looks_binary(&self) -> bool {
+        match self {
+            ClipContents::Text(text) => text.contains(['\0', char::REPLACEMENT_CHARACTER]),
+            ClipContents::Files(_) => false,
+            ClipContents::Binary { .. } => true,
+        }
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            ClipContents::Text(text) => text.len(),
+            ClipContents::Files(_) => self.text().len(),
+            ClipContents::Binary { data, .. } => data.len(),
+        }
+    }
+
+    pub fn contains(&self, other: &ClipContents) -> bool {
+        match (self, other) {
+            (ClipContents::Text(my_str), ClipContents::Text(their_str)) => my_str.contains(their_str),
+            (ClipContents::Files(mine), ClipContents::Files(theirs)) => theirs.iter().all(|path| mine.contains(path)),
+            (ClipContents::Binary { .. }, ClipContents::Binary { .. }) => self == other,
+            _ => false,
+        }
+    }
+
+    pub fn equal(&self, other: &ClipContents) -> bool {
+        self == other
+    }
+
+    /// A hash of the contents that stays the same across restarts, versions
+    /// and machines, unlike ids which only hold within one history.
+    pub fn stable_hash(&self) -> u64 {
+        // 64 bit FNV-1a, over the kind of clip (or the mime type of binary
+        // data) and the data
+        let kind: &[u8] = match self {
+            ClipContents::Text(_) => b"text",
+            ClipContents::Files(_) => b"files",
+            ClipContents::Binary { mime, .. } => mime.as_bytes(),
+        };
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &byte in kind.iter().chain(b"\0").chain(self.data().iter()) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    /// What is served for the clip: the text as UTF-8, or the binary data.
+    pub fn data(&self) -> Cow<'_, [u8]> {
+        match self {
+            ClipContents::Binary { data, .. } => Cow::Borrowed(data),
+            _ => match self.text() {
+                Cow::Borrowed(text) => Cow::Borrowed(text.as_bytes()),
+                Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+            },
+        }
+    }
+
+    /// The files as a `text/uri-list`, if the clip is files.
+    pub fn uri_list(&self) -> Option<String> {
+        match self {
+            ClipContents::Text(_) | ClipContents::Binary { .. } => None,
+            ClipContents::Files(paths) => Some(paths.iter().map(|path| file_uri(path) + "\r\n").collect()),
+        }
     }
 }
 
-#[derive(Clone, PartialEq, Debug)]
-pub struct Clip {
-    pub source: Source,
-    pub contents: Arc<ClipContents>,
+// The start of the text, cut on a character boundary
+fn truncate(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
 }
 
-impl Clip {
-    pub fn new(source: Source, contents: ClipContents) -> Clip {
-        Clip { source, contents: Arc::new(contents) }
+/// The paths in a `text/uri-list`, or the `x-special/gnome-copied-files` that
+/// extends it with a first line saying whether they were copied or cut. None
+/// unless all of them are local files.
+pub fn parse_uri_list(list: &str) -> Option<Vec<PathBuf>> {
+    let mut lines = list.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')).peekable();
+    if matches!(lines.peek(), Some(&"copy") | Some(&"cut")) {
+        lines.next();
     }
+    let paths: Option<Vec<PathBuf>> = lines
+        .map(|uri| {
+            // file:///path, or file://host/path which is local as far as we can tell
+            let path = uri.strip_prefix("file://")?;
+            let path = &path[path.find('/')?..];
+            Some(PathBuf::from(OsString::from_vec(percent_decode(path)?)))
+        })
+        .collect();
+    paths.filter(|paths| !paths.is_empty())
+}
 
-    pub fn contains(&self, other: &Clip) -> bool {
-        self.contents.contains(&other.contents)
+/// A `file://` URI for the path, escaping what a URI can't hold.
+pub fn file_uri(path: &Path) -> String {
+    let mut uri = "file://".to_owned();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~!$&'()*+,;=:@".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
     }
+    uri
+}
 
-    pub fn equal(&self, other: &Clip) -> bool {
-        self.contents.equal(&other.contents)
+fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
     }
+    Some(bytes)
 }
 
-#[derive(Clone, PartialEq, Debug)]
-pub enum ClipContents {
-    Text(String),
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Primary,
+    Secondary,
+    Clipboard,
 }
 
-impl ClipContents {
-    pub fn contains(&self, other: &ClipContents) -> bool {
+impl Source {
+    pub fn name(&self) -> &'static str {
         match self {
-            ClipContents::Text(my_str) => {
-                match other {
-                    ClipContents::Text(their_str) => {
-                        my_str.contains(their_str)
-                    }
-                }
-            }
+            Source::Primary => "primary",
+            Source::Secondary => "secondary",
+            Source::Clipboard => "clipboard",
         }
     }
 
-    pub fn equal(&self, other: &ClipContents) -> bool {
+    /// The selection's atom name, like `CLIPBOARD`.
+    pub fn selection(&self) -> &'static str {
         match self {
-            ClipContents::Text(my_str) => {
-                match other {
-                    ClipContents::Text(their_str) => {
-                        my_str.eq(their_str)
-                    }
-                }
-            }
+            Source::Primary => "PRIMARY",
+            Source::Secondary => "SECONDARY",
+            Source::Clipboard => "CLIPBOARD",
+        }
+    }
+
+    /// The selection a clip copied from here is promoted to by default:
+    /// mouse selections to the clipboard, and anything else to PRIMARY.
+    pub fn other(&self) -> Source {
+        match self {
+            Source::Primary => Source::Clipboard,
+            Source::Secondary | Source::Clipboard => Source::Primary,
         }
     }
 }
 
-#[derive(Clone, PartialEq, Debug)]
-pub enum Source {
-    Primary,
-    Secondary,
-    Clipboard,
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Source, String> {
+        [Source::Primary, Source::Secondary, Source::Clipboard]
+            .into_iter()
+            .find(|source| source.name().eq_ignore_ascii_case(s) || source.selection() == s)
+            .ok_or_else(|| format!("unknown selection {}, use primary, secondary or clipboard", s))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::db::{Clip, ClipContents, Database, MAX_CLIPS, Source};
+    use crate::db::{check_tag, first_similar, parse_uri_list, related, similar, Clip, ClipContents, Counts, Database, Origin, DEFAULT_MAX_CLIPS, SortMode, Source};
+    use crate::import;
+    use crate::matcher::MatcherKind;
+    use crate::options::Options;
+    use crate::persist::{self, HistoryFile, SavedClip};
+    use crate::store::{self, Writer};
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, SystemTime};
 
     #[test]
     fn creating() {
@@ -181,6 +1674,7 @@ mod tests {
         assert_eq!(db.at(fst_idx).unwrap(), fst);
         assert_eq!(db.at(snd_idx).unwrap(), snd);
         assert!(db.at(2).is_none());
+        assert_eq!(db.id_of(&snd), Some(snd_idx));
     }
 
     #[test]
@@ -197,17 +1691,31 @@ mod tests {
         assert_eq!(db.selection().unwrap(), fst);
     }
 
+    #[test]
+    fn current() {
+        let db = Database::new();
+        let fst = db.add_clip(Clip::new(Source::Primary, ClipContents::Text("fst string".to_owned()))).unwrap();
+        let snd = db.add_clip(Clip::new(Source::Secondary, ClipContents::Text("second string".to_owned()))).unwrap();
+        assert!(db.select(fst));
+
+        db.set_offered(true);
+        assert_eq!(db.id_of(&db.current().unwrap()), Some(fst));
+        // copied in another application since
+        db.set_offered(false);
+        assert_eq!(db.id_of(&db.current().unwrap()), Some(snd));
+    }
+
     #[test]
     fn rolling() {
         let db = Database::new();
-        for i in 1..(MAX_CLIPS * 3) {
+        for i in 1..(DEFAULT_MAX_CLIPS * 3) {
             let clip = Clip::new(Source::Primary, ClipContents::Text(format!("clip {}", i)));
             let idx = db.add_clip(clip.clone()).unwrap();
             assert_eq!(db.at(idx).unwrap(), clip);
         }
 
         assert!(db.at(0).is_none());
-        assert_eq!(db.clips().iter().count(), MAX_CLIPS);
+        assert_eq!(db.clips().iter().count(), DEFAULT_MAX_CLIPS);
     }
 
     #[test]
@@ -218,7 +1726,7 @@ mod tests {
         let fst_idx = db.add_clip(fst.clone()).unwrap();
         assert!(db.select(fst_idx));
 
-        for i in 1..(MAX_CLIPS * 2) {
+        for i in 1..(DEFAULT_MAX_CLIPS * 2) {
             let clip = Clip::new(Source::Primary, ClipContents::Text(format!("clip {}", i)));
             let idx = db.add_clip(clip.clone()).unwrap();
             assert_eq!(db.at(idx).unwrap(), clip);
@@ -227,6 +1735,116 @@ mod tests {
         assert_eq!(db.selection().unwrap(), fst);
     }
 
+    #[test]
+    fn queue() {
+        let db = Database::new();
+        let fst = Clip::new(Source::Primary, ClipContents::Text("fst string".to_owned()));
+        let snd = Clip::new(Source::Secondary, ClipContents::Text("second string".to_owned()));
+
+        assert!(db.toggle_queued(fst.clone()));
+        assert!(db.toggle_queued(snd.clone()));
+        assert!(db.is_queued(&fst));
+        assert_eq!(db.queue_len(), 2);
+
+        assert_eq!(db.advance_queue().unwrap(), fst);
+        assert_eq!(db.selection().unwrap(), fst);
+        assert!(!db.is_queued(&fst));
+
+        assert!(!db.toggle_queued(snd.clone()));
+        assert!(db.advance_queue().is_none());
+        assert_eq!(db.selection().unwrap(), fst);
+
+        db.toggle_queued(snd.clone());
+        db.clear_selection();
+        assert!(db.selection().is_none());
+        assert_eq!(db.queue_len(), 0);
+    }
+
+    #[test]
+    fn sorting() {
+        fn clip(s: &str) -> Clip {
+            Clip::new(Source::Primary, ClipContents::Text(s.to_owned()))
+        }
+
+        fn texts(clips: Vec<Clip>) -> Vec<String> {
+            clips.iter().map(|c| c.contents.text().into_owned()).collect()
+        }
+
+        let db = Database::new();
+        for s in ["Émile", "Peng", "a longer clip", "Ernie", "Peña"] {
+            db.add_clip(clip(s));
+        }
+        db.select_clip(clip("Peng"));
+        db.add_clip(clip("Peng"));
+        db.select_clip(clip("Ernie"));
+
+        // copied again, so it's the newest
+        assert_eq!(texts(db.sorted(SortMode::Recency, 2)), vec!["Peng", "Peña"]);
+        assert_eq!(texts(db.sorted(SortMode::Frequency, 3)), vec!["Peng", "Ernie", "Peña"]);
+        assert_eq!(
+            texts(db.sorted(SortMode::Alphabetical, 5)),
+            vec!["a longer clip", "Émile", "Ernie", "Peña", "Peng"]
+        );
+        assert_eq!(texts(db.sorted(SortMode::Size, 1)), vec!["a longer clip"]);
+        assert_eq!(texts(db.sorted(SortMode::Frecency, 3)), vec!["Peng", "Ernie", "Peña"]);
+
+        assert!(db.pin(0));
+        assert!(db.is_pinned(&clip("Émile")));
+        assert_eq!(texts(db.sorted(SortMode::Pinned, 3)), vec!["Émile", "Peng", "Peña"]);
+        assert!(db.unpin(0));
+        assert!(!db.unpin(0));
+        assert_eq!(texts(db.sorted(SortMode::Pinned, 1)), vec!["Peng"]);
+
+        let mut mode = SortMode::Recency;
+        for _ in 0..6 {
+            assert_eq!(mode.name().parse::<SortMode>().unwrap(), mode);
+            mode = mode.next();
+        }
+        assert_eq!(mode, SortMode::Recency);
+    }
+
+    #[test]
+    fn copied_again() {
+        let db = Database::new();
+        let clip = |s: &str| Clip::new(Source::Primary, ClipContents::Text(s.to_owned()));
+        let mut old = clip("copied again");
+        old.captured = SystemTime::now() - Duration::from_secs(60 * 60);
+        assert_eq!(db.add_clip(old.clone()), Some(0));
+        assert_eq!(db.add_clip(clip("in between")), Some(1));
+        assert!(db.tag(0, "work"));
+
+        let again = clip("copied again");
+        assert_eq!(db.add_clip(again.clone()), None);
+        assert_eq!(db.clips().len(), 2);
+        assert_eq!(db.clips().back(), Some(&old));
+        assert_eq!(db.id_of(&again), Some(0));
+        assert_eq!(db.id_of(&clip("in between")), Some(1));
+        let moved = db.at(0).unwrap();
+        // so its time to live isn't extended
+        assert_eq!(moved.captured, old.captured);
+        assert_eq!(db.tags(&moved), vec!["work"]);
+        assert_eq!(db.uses(&moved), 1);
+    }
+
+    #[test]
+    fn grown_selection() {
+        let db = Database::new();
+        let clip = |s: &str| Clip::new(Source::Primary, ClipContents::Text(s.to_owned()));
+        assert_eq!(db.add_clip(clip("Main")), Some(0));
+        assert!(db.tag(0, "work"));
+        // copying the newest clip again isn't growing it
+        assert_eq!(db.add_clip(clip("Main")), None);
+        assert_eq!(db.tags(&clip("Main")), vec!["work"]);
+        assert_eq!(db.uses(&clip("Main")), 1);
+
+        assert_eq!(db.add_clip(clip("Main street")), Some(0));
+        assert_eq!(db.clips().len(), 1);
+        // and nothing is kept about the shorter one
+        assert!(db.tags(&clip("Main")).is_empty());
+        assert_eq!(db.uses(&clip("Main")), 0);
+        assert!(db.tags(&clip("Main street")).is_empty());
+    }
+
     #[test]
     fn search() {
         let db = Database::new();
@@ -255,6 +1873,139 @@ mod tests {
     }
 
     #[test]
+    fn search_ties_by_recency() {
+        let db = Database::with_matcher(MatcherKind::Substring);
+        for s in ["abc one", "abc two", "xyz", "abc three"] {
+            db.add_clip(Clip::new(Source::Primary, ClipContents::Text(s.to_owned())));
+        }
+
+        let matches: Vec<String> = db.search("abc", 5).iter()
+            .map(|c| c.contents.text().into_owned())
+            .collect();
+        assert_eq!(matches, vec!["abc three", "abc two", "abc one"]);
+    }
+
+    #[test]
+    fn counts() {
+        let clip = Clip::new(Source::Primary, ClipContents::Text("two words\nand åne more\n".to_owned()));
+        assert_eq!(clip.counts, Counts { lines: 2, words: 5, bytes: 24 });
+    }
+
+    #[test]
+    fn search_by_size() {
+        let db = Database::with_matcher(MatcherKind::Substring);
+        for s in ["short", "a bit longer", "much longer than the others"] {
+            db.add_clip(Clip::new(Source::Primary, ClipContents::Text(s.to_owned())));
+        }
+
+        let search = |pattern: &str| -> Vec<String> {
+            db.search(pattern, 5).iter().map(|c| c.contents.text().into_owned()).collect()
+        };
+        assert_eq!(search("len:>5"), vec!["much longer than the others", "a bit longer"]);
+        assert_eq!(search("longer len:<20"), vec!["a bit longer"]);
+        assert_eq!(search("len:<=5 len:>=5"), vec!["short"]);
+        assert_eq!(search("len:12"), vec!["a bit longer"]);
+        assert!(search("len:>100").is_empty());
+        // not a valid filter, so it's searched for
+        assert!(search("len:>x").is_empty());
+    }
+
+    #[test]
+    fn search_by_age() {
+        let db = Database::with_matcher(MatcherKind::Substring);
+        let now = SystemTime::now();
+        for (text, minutes) in [("last week", 7 * 24 * 60), ("this morning", 3 * 60), ("just now", 0)] {
+            let mut clip = Clip::new(Source::Primary, ClipContents::Text(text.to_owned()));
+            clip.captured = now - Duration::from_secs(minutes * 60);
+            db.add_clip(clip);
+        }
+
+        let search = |pattern: &str| -> Vec<String> {
+            db.search(pattern, 5).iter().map(|c| c.contents.text().into_owned()).collect()
+        };
+        assert_eq!(search("age:<10m"), vec!["just now"]);
+        assert_eq!(search("age:>1h"), vec!["this morning", "last week"]);
+        assert_eq!(search("age:>1d"), vec!["last week"]);
+        assert_eq!(search("age:<1d age:>3600"), vec!["this morning"]);
+        assert_eq!(search("morning age:<1h"), Vec::<String>::new());
+        // not valid filters, so they're searched for
+        assert!(search("age:1h").is_empty());
+        assert!(search("age:<1w").is_empty());
+    }
+
+    #[test]
+    fn tags() {
+        let rules = Options::parse("[[tag_rules]]\npattern = \"^https?://\"\ntag = \"url\"\n").unwrap().tag_rules;
+        let db = Database::with_matcher(MatcherKind::Substring).with_tag_rules(rules);
+        let link = db.add_clip(Clip::new(Source::Primary, ClipContents::Text("https://example.com".to_owned()))).unwrap();
+        let note = db.add_clip(Clip::new(Source::Primary, ClipContents::Text("example notes".to_owned()))).unwrap();
+
+        assert_eq!(db.tags(&db.at(link).unwrap()), vec!["url"]);
+        assert!(db.tag(note, "work"));
+        assert!(db.tag(link, "work"));
+        assert!(!db.tag(99, "work"));
+
+        let search = |pattern: &str| -> Vec<String> {
+            db.search(pattern, 5).iter().map(|c| c.contents.text().into_owned()).collect()
+        };
+        assert_eq!(search("tag:url"), vec!["https://example.com"]);
+        assert_eq!(search("tag:work notes"), vec!["example notes"]);
+        assert_eq!(search("tag:work tag:url"), vec!["https://example.com"]);
+        assert!(search("tag:home").is_empty());
+        assert_eq!(db.search_text("tag:work notes"), "notes");
+
+        assert!(db.untag(note, "work"));
+        assert!(!db.untag(note, "work"));
+        assert_eq!(search("tag:work"), vec!["https://example.com"]);
+        assert!(check_tag("two words").is_err());
+    }
+
+    #[test]
+    fn hash_tags() {
+        let db = Database::with_matcher(MatcherKind::Substring);
+        let code = db.add_clip(Clip::new(Source::Primary, ClipContents::Text("#include <stdio.h>".to_owned()))).unwrap();
+        let note = db.add_clip(Clip::new(Source::Primary, ClipContents::Text("meeting notes".to_owned()))).unwrap();
+        assert_eq!(db.id_of(&db.current().unwrap()), Some(note));
+        assert!(db.tag(note, "work"));
+
+        let search = |pattern: &str| -> Vec<String> {
+            db.search(pattern, 5).iter().map(|c| c.contents.text().into_owned()).collect()
+        };
+        assert_eq!(search("#work"), vec!["meeting notes"]);
+        assert_eq!(search("#work notes"), vec!["meeting notes"]);
+        assert!(search("#work stdio").is_empty());
+        // no clip is tagged `include`, so it's text
+        assert_eq!(search("#include"), vec!["#include <stdio.h>"]);
+        assert_eq!(db.search_text("#work #include"), "#include");
+
+        assert!(db.tag(code, "include"));
+        assert_eq!(search("#include"), vec!["#include <stdio.h>"]);
+        assert_eq!(db.search_text("#work #include"), "");
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!("clipboard".parse::<Source>().unwrap(), Source::Clipboard);
+        assert_eq!("PRIMARY".parse::<Source>().unwrap(), Source::Primary);
+        assert!("primry".parse::<Source>().is_err());
+        assert_eq!(Source::Primary.other(), Source::Clipboard);
+        assert_eq!(Source::Clipboard.other(), Source::Primary);
+    }
+
+    #[test]
+    fn search_only_the_preview() {
+        let db = Database::with_matcher(MatcherKind::Substring).with_preview_bytes(8);
+        db.add_clip(Clip::new(Source::Primary, ClipContents::Text("héllo wörld".to_owned())));
+
+        assert_eq!(db.search("llo", 5).len(), 1);
+        assert!(db.search("wörld", 5).is_empty());
+        // cut on a character boundary
+        assert_eq!(ClipContents::Text("héllo".to_owned()).preview(2), "h");
+        assert_eq!(ClipContents::Text("héllo".to_owned()).preview(100), "héllo");
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn replace_smaller_text() {
         fn clip(s: &str) -> Clip {
             Clip::new(Source::Primary, ClipContents::Text(s.to_owned()))
@@ -271,4 +2022,511 @@ mod tests {
         assert_eq!(bigger_around.contains(&small), true);
         assert_eq!(smaller.contains(&small), false);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn chains() {
+        let db = Database::with_matcher(MatcherKind::Substring);
+        let text = |t: &str| Clip::new(Source::Clipboard, ClipContents::Text(t.to_owned()));
+        let first = text("let x = 1;");
+        let second = text("let x = 2;");
+        let third = text("let mut x = 2;");
+        for clip in [&first, &second, &third] {
+            db.add_clip(clip.clone());
+        }
+        db.link(&first, &second);
+        db.link(&second, &third);
+        let chain: Vec<_> = db.chain(&third).iter().map(|c| c.contents.text().into_owned()).collect();
+        assert_eq!(chain, vec!["let mut x = 2;", "let x = 2;", "let x = 1;"]);
+        assert_eq!(db.chain(&first).len(), 1);
+
+        // edited back into the first version
+        db.link(&third, &first);
+        assert_eq!(db.chain(&first).len(), 3);
+
+        assert!(related("let x = 1;", "let x = 2;"));
+        assert!(related("hello", "hello world"));
+        assert!(!related("let x = 1;", "something else entirely"));
+        assert!(!related("", "anything"));
+    }
+
+    #[test]
+    fn chains_while_evicting() {
+        let db = Database::with_matcher(MatcherKind::Substring).with_max_clips(3);
+        let text = |t: String| Clip::new(Source::Clipboard, ClipContents::Text(t));
+        let first = text("version 0 of the text".to_owned());
+        db.add_clip(first.clone());
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut previous = first.clone();
+                for i in 1..10_000 {
+                    let edited = text(format!("version {} of the text", i));
+                    db.add_clip(edited.clone());
+                    db.link(&previous, &edited);
+                    previous = edited;
+                }
+                done.store(true, Ordering::Release);
+            });
+            while !done.load(Ordering::Acquire) {
+                assert!(db.chain(&first).len() <= 3);
+            }
+        });
+        let newest = db.clips().back().cloned().unwrap();
+        assert_eq!(db.chain(&newest).len(), 3);
+    }
+
+    #[test]
+    fn near_duplicates() {
+        let base = "SELECT id, name FROM users WHERE created_at > now() - interval '1 day'";
+        assert!(similar(base, base));
+        assert!(similar(base, &base.replace("1 day", "2 days")));
+        assert!(!similar(base, "SELECT * FROM orders"));
+        assert!(!similar("abc", "xyz"));
+        assert!(similar("", ""));
+        let long = "x".repeat(10_000);
+        assert!(similar(&long, &format!("{}y", long)));
+        assert!(!similar(&long, &"x".repeat(5_000)));
+        // the same start and length, but different after that
+        let start = "x".repeat(300);
+        assert!(!similar(&format!("{}{}", start, "a".repeat(5_000)), &format!("{}{}", start, "b".repeat(5_000))));
+        assert!(similar(&format!("{}a{}", start, long), &format!("{}b{}", start, long)));
+        assert!(similar(&"x".repeat(20), &"x".repeat(21)));
+
+        let texts = ["let x = 1;", "something else", "let x = 2;", &long, "let y = 1;", &format!("{}y", long)];
+        assert_eq!(first_similar(&texts), vec![0, 1, 0, 3, 0, 3]);
+        assert!(first_similar(&[]).is_empty());
+    }
+
+    #[test]
+    fn expiring() {
+        let db = Database::new();
+        let text = |t: &str| Clip::new(Source::Clipboard, ClipContents::Text(t.to_owned()));
+        let (kept, temporary, newest) = (text("kept"), text("one time code"), text("newest"));
+        for clip in [&kept, &temporary, &newest] {
+            db.add_clip(clip.clone());
+        }
+        db.tag(1, "otp");
+        db.select_clip(temporary.clone());
+        let now = SystemTime::now();
+        db.expire_at(&temporary, now + Duration::from_secs(60));
+
+        assert_eq!(db.remove_expired(now), 0);
+        assert_eq!(db.remove_expired(now + Duration::from_secs(60)), 1);
+        assert_eq!(db.clips().len(), 2);
+        assert_eq!(db.selection(), None);
+        assert!(db.tags(&temporary).is_empty());
+        // the newer clip keeps its id
+        assert_eq!(db.at(1), None);
+        assert_eq!(db.at(2).map(|c| c.contents), Some(newest.contents));
+    }
+
+    #[test]
+    fn archiving() {
+        let db = Database::with_matcher(MatcherKind::Substring);
+        let key = Clip::new(Source::Clipboard, ClipContents::Text("license key ABCD-1234".to_owned()));
+        db.add_clip(key.clone());
+        assert!(db.archive(0, true));
+        assert!(!db.archive(0, true));
+        assert!(!db.archive(1, true));
+        db.expire_at(&key, SystemTime::now());
+        assert_eq!(db.remove_expired(SystemTime::now()), 0);
+
+        for i in 1..(DEFAULT_MAX_CLIPS * 2) {
+            db.add_clip(Clip::new(Source::Primary, ClipContents::Text(format!("clip {}", i))));
+        }
+        assert_eq!(db.clips().len(), DEFAULT_MAX_CLIPS);
+        assert_eq!(db.at(0), Some(key.clone()));
+        assert!(db.is_archived(&key));
+        assert!(!db.sorted(SortMode::Recency, usize::MAX).contains(&key));
+        assert!(db.search("license", 10).is_empty());
+        assert_eq!(db.search("archived: license", 10), vec![key.clone()]);
+        assert_eq!(db.search("archived:", 10), vec![key.clone()]);
+
+        assert!(db.archive(0, false));
+        assert_eq!(db.search("license", 10), vec![key]);
+    }
+
+    #[test]
+    fn pinned_clips_stay() {
+        let db = Database::with_matcher(MatcherKind::Substring).with_max_clips(3);
+        let address = Clip::new(Source::Clipboard, ClipContents::Text("Main street 1".to_owned()));
+        db.add_clip(address.clone());
+        assert!(db.pin(0));
+        for i in 1..10 {
+            db.add_clip(Clip::new(Source::Primary, ClipContents::Text(format!("clip {}", i))));
+        }
+        assert_eq!(db.clips().len(), 3);
+        assert_eq!(db.at(0), Some(address.clone()));
+        assert_eq!(db.sorted(SortMode::Pinned, 1), vec![address.clone()]);
+        assert_eq!(db.pinned_clips(), vec![address.clone()]);
+
+        // unpinned, it's the oldest one
+        assert!(db.unpin(0));
+        db.add_clip(Clip::new(Source::Primary, ClipContents::Text("clip 10".to_owned())));
+        assert!(db.id_of(&address).is_none());
+    }
+
+    #[test]
+    fn pinned_clips_not_replaced() {
+        let db = Database::with_matcher(MatcherKind::Substring);
+        let street = Clip::new(Source::Primary, ClipContents::Text("Main street".to_owned()));
+        db.add_clip(street.clone());
+        assert!(db.pin(0));
+        // selecting more of it keeps the pinned clip
+        let address = Clip::new(Source::Primary, ClipContents::Text("Main street 1".to_owned()));
+        assert_eq!(db.add_clip(address.clone()), Some(1));
+        assert_eq!(db.at(0), Some(street.clone()));
+        assert_eq!(db.pinned_clips(), vec![street]);
+    }
+
+    #[test]
+    fn time_to_live() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        let db = Database::with_matcher(MatcherKind::Substring).with_max_clips(DEFAULT_MAX_CLIPS).with_ttl(Some(day));
+        let old = |text: &str| {
+            let mut clip = Clip::new(Source::Clipboard, ClipContents::Text(text.to_owned()));
+            clip.captured = now - 2 * day;
+            clip
+        };
+        let (stale, pinned, archived, kept) = (old("stale"), old("pinned"), old("archived"), old("kept"));
+        for clip in [&stale, &pinned, &archived, &kept] {
+            db.add_clip(clip.clone());
+        }
+        let fresh = Clip::new(Source::Clipboard, ClipContents::Text("fresh".to_owned()));
+        db.add_clip(fresh.clone());
+        assert!(db.pin(1));
+        assert!(db.archive(2, true));
+        db.expire_at(&kept, now + day);
+        assert_eq!(db.expires(&stale), Some(now - day));
+        assert_eq!(db.expires(&pinned), None);
+        assert_eq!(db.expires(&kept), Some(now + day));
+
+        assert_eq!(db.remove_expired(now), 1);
+        assert!(db.id_of(&stale).is_none());
+        assert_eq!(db.clips().len(), 4);
+
+        // without its own, it gets the history's
+        assert!(db.keep(&kept));
+        assert!(!db.keep(&kept));
+        assert_eq!(db.remove_expired(now), 1);
+        assert!(db.id_of(&kept).is_none());
+        assert!(db.id_of(&fresh).is_some());
+    }
+
+    #[test]
+    fn file_lists() {
+        let list = "# copied in Thunar\r\nfile:///home/me/My%20Notes.txt\r\nfile://laptop/tmp/a\r\n";
+        let paths = parse_uri_list(list).unwrap();
+        assert_eq!(paths, vec![PathBuf::from("/home/me/My Notes.txt"), PathBuf::from("/tmp/a")]);
+        assert_eq!(parse_uri_list("copy\nfile:///tmp/a"), Some(vec![PathBuf::from("/tmp/a")]));
+        // links aren't files
+        assert_eq!(parse_uri_list("https://example.com/\r\n"), None);
+        assert_eq!(parse_uri_list("file:///tmp/%zz"), None);
+        assert_eq!(parse_uri_list(""), None);
+
+        let files = ClipContents::Files(paths);
+        assert_eq!(files.text(), "/home/me/My Notes.txt\n/tmp/a");
+        assert_eq!(files.uri_list().unwrap(), "file:///home/me/My%20Notes.txt\r\nfile:///tmp/a\r\n");
+        assert_eq!(parse_uri_list(&files.uri_list().unwrap()), Some(vec![PathBuf::from("/home/me/My Notes.txt"), PathBuf::from("/tmp/a")]));
+        assert_eq!(Counts::of(&files).lines, 2);
+        assert!(files.contains(&ClipContents::Files(vec![PathBuf::from("/tmp/a")])));
+        assert!(!files.contains(&ClipContents::Text("/tmp/a".to_owned())));
+    }
+
+    #[test]
+    fn binary_data() {
+        let pdf = ClipContents::Binary { mime: "application/pdf".to_owned(), data: vec![0; 24 * 1024] };
+        assert_eq!(pdf.text(), "<binary: application/pdf, 24 KB>");
+        assert_eq!(pdf.data().len(), 24 * 1024);
+        assert_eq!(pdf.size(), 24 * 1024);
+        assert!(pdf.looks_binary());
+        assert_eq!(pdf.uri_list(), None);
+        assert_eq!(Counts::of(&pdf), Counts { lines: 0, words: 0, bytes: 24 * 1024 });
+
+        // the same bytes as another type are another clip
+        let other = ClipContents::Binary { mime: "image/png".to_owned(), data: vec![0; 24 * 1024] };
+        assert_ne!(pdf.stable_hash(), other.stable_hash());
+        assert!(!pdf.contains(&other));
+        assert!(pdf.contains(&pdf.clone()));
+    }
+
+    #[test]
+    fn stable_hashes() {
+        let db = Database::new();
+        let clip = Clip::new(Source::Primary, ClipContents::Text("register".to_owned()));
+        db.add_clip(clip.clone());
+        // FNV-1a doesn't change between runs, so editors can keep the hashes
+        assert_eq!(clip.contents.stable_hash(), ClipContents::Text("register".to_owned()).stable_hash());
+        assert_eq!(clip.contents.stable_hash(), 0x0670cff92a33d083);
+        assert_ne!(clip.contents.stable_hash(), ClipContents::Files(vec![PathBuf::from("register")]).stable_hash());
+        assert_eq!(db.find_by_hash(clip.contents.stable_hash()), Some(clip.clone()));
+        assert_eq!(db.find_by_hash(0), None);
+
+        assert!(clip.intact());
+        let mut corrupted = clip.clone();
+        corrupted.contents = Arc::new(ClipContents::Text("regist\u{0}r".to_owned()));
+        assert!(!corrupted.intact());
+    }
+
+    #[test]
+    fn search_by_app() {
+        let db = Database::with_matcher(MatcherKind::Substring);
+        let mut browser = Clip::new(Source::Clipboard, ClipContents::Text("from the web".to_owned()));
+        browser.origin = Some(Arc::new(Origin { window: 0x3a00004, class: Some("Firefox".to_owned()), title: None }));
+        let mut script = Clip::new(Source::Clipboard, ClipContents::Text("from a script".to_owned()));
+        script.app = Some("my-script".to_owned());
+        for clip in [browser, script, Clip::new(Source::Primary, ClipContents::Text("from nowhere".to_owned()))] {
+            db.add_clip(clip);
+        }
+
+        let search = |pattern: &str| -> Vec<String> {
+            db.search(pattern, 5).iter().map(|c| c.contents.text().into_owned()).collect()
+        };
+        assert_eq!(search("app:firefox"), vec!["from the web"]);
+        assert_eq!(search("app:script from"), vec!["from a script"]);
+        assert!(search("app:xterm").is_empty());
+    }
+
+    #[test]
+    fn persisted_history() {
+        let path = std::env::temp_dir().join(format!("repeat-db-test-{}", std::process::id())).join("clips.toml");
+        let writer = Arc::new(Writer::batched());
+        let db = Database::with_matcher(MatcherKind::Substring).with_store(path.clone(), writer.clone());
+        let fst = Clip::new(Source::Clipboard, ClipContents::Text("fst".to_owned()));
+        let snd = Clip::new(Source::Primary, ClipContents::Binary { mime: "image/png".to_owned(), data: vec![1, 2, 3] });
+        let edited = Clip::new(Source::Clipboard, ClipContents::Text("fst, edited".to_owned()));
+        db.add_clip(fst.clone());
+        db.add_clip(snd.clone());
+        db.add_clip(edited.clone());
+        db.link(&fst, &edited);
+        assert!(db.tag(0, "work"));
+        assert!(db.pin(1));
+        assert!(db.archive(0, true));
+        db.select_clip(snd.clone());
+        writer.flush();
+
+        let loaded = Database::with_matcher(MatcherKind::Substring).with_store(path.clone(), writer);
+        let clips: Vec<Clip> = loaded.clips().iter().cloned().collect();
+        assert_eq!(clips.iter().map(|c| c.contents.clone()).collect::<Vec<_>>(), vec![fst.contents.clone(), snd.contents.clone(), edited.contents.clone()]);
+        assert!(clips.iter().all(Clip::intact));
+        assert_eq!(loaded.tags(&fst), vec!["work".to_owned()]);
+        assert!(loaded.is_pinned(&snd));
+        assert!(loaded.is_archived(&fst));
+        assert_eq!(loaded.uses(&snd), 1);
+        assert_eq!(loaded.selection().unwrap().contents, snd.contents);
+        assert_eq!(loaded.chain(&edited).len(), 2);
+
+        // read without a store, changes aren't saved
+        let read_only = Database::with_matcher(MatcherKind::Substring).with_history(&path, None).unwrap();
+        read_only.add_clip(Clip::new(Source::Clipboard, ClipContents::Text("thd".to_owned())));
+        let reloaded = Database::with_matcher(MatcherKind::Substring).with_history(&path, None).unwrap();
+        assert_eq!(reloaded.clips().len(), 3);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn export_and_import() {
+        let old = Database::with_matcher(MatcherKind::Substring);
+        let png = Clip::new(Source::Clipboard, ClipContents::Binary { mime: "image/png".to_owned(), data: vec![1, 2, 3] });
+        old.add_clip(png.clone());
+        old.add_clip(Clip::new(Source::Clipboard, ClipContents::Text("shared".to_owned())));
+        assert!(old.tag(1, "work"));
+        assert!(old.pin(0));
+
+        let db = Database::with_matcher(MatcherKind::Substring);
+        let shared = Clip::new(Source::Primary, ClipContents::Text("shared".to_owned()));
+        db.add_clip(shared.clone());
+        db.add_clip(Clip::new(Source::Primary, ClipContents::Text("local".to_owned())));
+        assert!(db.tag(0, "mine"));
+
+        assert_eq!(db.import(old.export()), 1);
+        let texts: Vec<String> = db.clips().iter().map(|c| c.contents.text().into_owned()).collect();
+        assert_eq!(texts[1..], ["shared", "local"]);
+        assert_eq!(db.clips()[0].contents, png.contents);
+        assert!(db.is_pinned(&png));
+        assert_eq!(db.tags(&shared), vec!["mine".to_owned(), "work".to_owned()]);
+
+        // already there
+        assert_eq!(db.import(old.export()), 0);
+        assert_eq!(db.clips().len(), 3);
+    }
+
+    #[test]
+    fn import_up_to_max_clips() {
+        let old = Database::with_matcher(MatcherKind::Substring);
+        for text in ["a", "b", "c", "d"] {
+            old.add_clip(Clip::new(Source::Clipboard, ClipContents::Text(text.to_owned())));
+        }
+        assert!(old.pin(0));
+
+        let db = Database::with_matcher(MatcherKind::Substring).with_max_clips(3);
+        db.add_clip(Clip::new(Source::Primary, ClipContents::Text("local".to_owned())));
+        // the oldest imported ones make room, but the pinned one stays
+        assert_eq!(db.import(old.export()), 2);
+        let texts: Vec<String> = db.clips().iter().map(|c| c.contents.text().into_owned()).collect();
+        assert_eq!(texts, ["a", "d", "local"]);
+    }
+
+    #[test]
+    fn import_in_batches() {
+        let newest = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let texts = ["a", "b", "a", "c"].map(str::to_owned).to_vec();
+        let clips = import::saved(texts, newest);
+        let db = Database::with_matcher(MatcherKind::Substring);
+        db.add_clip(Clip::new(Source::Primary, ClipContents::Text("c".to_owned())));
+
+        let added: usize = clips.rchunks(2).map(|batch| db.import(HistoryFile { selection: None, next_id: 0, clips: batch.to_vec() })).sum();
+        assert_eq!(added, 2);
+        let texts: Vec<String> = db.clips().iter().map(|c| c.contents.text().into_owned()).collect();
+        assert_eq!(texts, ["b", "a", "c"]);
+        let captured: Vec<SystemTime> = db.clips().iter().take(2).map(|c| c.captured).collect();
+        assert_eq!(captured, [newest - Duration::from_secs(2), newest - Duration::from_secs(1)]);
+    }
+
+    #[test]
+    fn restored_up_to_max_clips() {
+        let path = std::env::temp_dir().join(format!("repeat-db-trim-test-{}", std::process::id())).join("clips.toml");
+        let writer = Arc::new(Writer::immediate());
+        let saved = Database::with_matcher(MatcherKind::Substring).with_store(path.clone(), writer.clone());
+        for text in ["a", "b", "c", "d"] {
+            saved.add_clip(Clip::new(Source::Clipboard, ClipContents::Text(text.to_owned())));
+        }
+
+        // opened with a lower max_clips than it was saved with
+        let db = Database::with_matcher(MatcherKind::Substring).with_max_clips(2).with_store(path.clone(), writer);
+        let texts: Vec<String> = db.clips().iter().map(|c| c.contents.text().into_owned()).collect();
+        assert_eq!(texts, ["c", "d"]);
+        let newest = db.clips()[1].clone();
+        assert_eq!(db.id_of(&newest), Some(3));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn clips_in_their_own_files() {
+        let path = std::env::temp_dir().join(format!("repeat-db-files-test-{}", std::process::id())).join("clips.toml");
+        let writer = Arc::new(Writer::batched());
+        let db = Database::with_matcher(MatcherKind::Substring).with_store(path.clone(), writer.clone());
+        let clip = |s: &str| Clip::new(Source::Clipboard, ClipContents::Text(s.to_owned()));
+        db.add_clip(clip("fst"));
+        db.add_clip(clip("snd"));
+        writer.flush();
+        let dir = persist::clips_dir(&path);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("snd"));
+
+        // only the new one is written along with the history, and a
+        // forgotten one is removed
+        db.add_clip(clip("thd"));
+        assert_eq!(writer.pending(), 2);
+        db.expire_at(&clip("fst"), SystemTime::UNIX_EPOCH);
+        assert_eq!(db.remove_expired(SystemTime::now()), 1);
+        writer.flush();
+        assert_eq!(persist::stored_clips(&dir, false), HashSet::from([clip("snd").checksum, clip("thd").checksum]));
+        let reloaded = Database::with_matcher(MatcherKind::Substring).with_history(&path, None).unwrap();
+        let texts: Vec<String> = reloaded.clips().iter().map(|c| c.contents.text().into_owned()).collect();
+        assert_eq!(texts, ["snd", "thd"]);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn clips_moved_out_of_the_history() {
+        let path = std::env::temp_dir().join(format!("repeat-db-moved-test-{}", std::process::id())).join("clips.toml");
+        let old = Clip::new(Source::Clipboard, ClipContents::Text("kept in the history".to_owned()));
+        let file = HistoryFile { selection: None, next_id: 0, clips: vec![SavedClip::from_clip(&old)] };
+        store::create_private_dir(path.parent().unwrap()).unwrap();
+        // as written before clips had files of their own
+        std::fs::write(&path, toml::to_string(&file).unwrap()).unwrap();
+
+        let writer = Arc::new(Writer::immediate());
+        let db = Database::with_matcher(MatcherKind::Substring).with_store(path.clone(), writer);
+        assert_eq!(db.clips().len(), 1);
+        assert!(persist::clip_path(&path, old.checksum, false).exists());
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("kept in the history"));
+        let reloaded = Database::with_matcher(MatcherKind::Substring).with_history(&path, None).unwrap();
+        assert_eq!(reloaded.clips()[0].contents, old.contents);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn stable_ids() {
+        let path = std::env::temp_dir().join(format!("repeat-db-ids-test-{}", std::process::id())).join("clips.toml");
+        let writer = Arc::new(Writer::immediate());
+        let db = Database::with_matcher(MatcherKind::Substring).with_max_clips(2).with_store(path.clone(), writer.clone());
+        let clip = |s: &str| Clip::new(Source::Clipboard, ClipContents::Text(s.to_owned()));
+        for text in ["a", "b", "c"] {
+            db.add_clip(clip(text));
+        }
+        // evicting the oldest one leaves the others as they were
+        assert_eq!(db.at(0), None);
+        assert_eq!(db.id_of(&clip("b")), Some(1));
+        assert_eq!(db.add_clip(clip("b")), None);
+        assert_eq!(db.at(1).unwrap().contents, clip("b").contents);
+        assert_eq!(db.id_of(&clip("c")), Some(2));
+
+        // and an id isn't handed out again, even after a restart
+        db.expire_at(&clip("b"), SystemTime::UNIX_EPOCH);
+        assert_eq!(db.remove_expired(SystemTime::now()), 1);
+        let reloaded = Database::with_matcher(MatcherKind::Substring).with_store(path.clone(), writer);
+        assert_eq!(reloaded.id_of(&clip("c")), Some(2));
+        assert_eq!(reloaded.add_clip(clip("d")), Some(3));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn opened_late() {
+        let path = std::env::temp_dir().join(format!("repeat-db-late-test-{}", std::process::id())).join("clips.toml");
+        let writer = Arc::new(Writer::batched());
+        let saved = Database::with_matcher(MatcherKind::Substring).with_store(path.clone(), writer.clone());
+        saved.add_clip(Clip::new(Source::Clipboard, ClipContents::Text("old".to_owned())));
+        saved.add_clip(Clip::new(Source::Clipboard, ClipContents::Text("copied again".to_owned())));
+        assert!(saved.tag(1, "work"));
+        writer.flush();
+
+        // clips copied while the key is being fetched
+        let db = Database::with_matcher(MatcherKind::Substring);
+        let again = Clip::new(Source::Primary, ClipContents::Text("copied again".to_owned()));
+        db.add_clip(again.clone());
+        db.add_clip(Clip::new(Source::Primary, ClipContents::Text("new".to_owned())));
+        db.select_clip(again.clone());
+        db.open_store(path.clone(), writer.clone(), None);
+        let texts: Vec<String> = db.clips().iter().map(|c| c.contents.text().into_owned()).collect();
+        assert_eq!(texts, vec!["old", "copied again", "new"]);
+        assert_eq!(db.tags(&again), vec!["work".to_owned()]);
+        assert_eq!(db.selection().unwrap().source, Source::Primary);
+
+        // and saved from then on
+        db.add_clip(Clip::new(Source::Primary, ClipContents::Text("another".to_owned())));
+        writer.flush();
+        let reloaded = Database::with_matcher(MatcherKind::Substring).with_history(&path, None).unwrap();
+        assert_eq!(reloaded.clips().len(), 4);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn long_history() {
+        let db = Database::with_matcher(MatcherKind::Substring).with_max_clips(10_000);
+        for i in 0..10_005 {
+            db.add_clip(Clip::new(Source::Clipboard, ClipContents::Text(format!("clip number {}", i))));
+        }
+        assert_eq!(db.clips().len(), 10_000);
+        let newest = db.sorted(SortMode::Recency, 3);
+        assert_eq!(newest[0].contents.text(), "clip number 10004");
+        assert_eq!(newest.len(), 3);
+        assert_eq!(db.ids_of(&newest), vec![Some(10_004), Some(10_003), Some(10_002)]);
+        assert_eq!(db.id_of(&newest[2]), Some(10_002));
+
+        let found = db.search("number 999", 5);
+        assert_eq!(found.len(), 5);
+        // the newest first on ties
+        assert_eq!(found[0].contents.text(), "clip number 9999");
+        assert_eq!(db.sorted(SortMode::Size, 1)[0].counts.bytes, "clip number 10000".len());
+
+        let small = Database::with_matcher(MatcherKind::Substring).with_max_clips(2);
+        for i in 0..3 {
+            small.add_clip(Clip::new(Source::Clipboard, ClipContents::Text(i.to_string())));
+        }
+        assert_eq!(small.clips().len(), 2);
+        assert_eq!(small.at(0), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use breadx::prelude::*;
+use breadx::protocol::Event;
+use futures::future::LocalBoxFuture;
+use log::debug;
+
+use crate::clipboard::Clipboard;
+use crate::options::Options;
+use crate::store::Writer;
+use crate::ui::{Closed, Prompt, Surface, Surfaces};
+
+/// What consumers act on besides the event itself.
+pub struct Context<'a, D> {
+    pub display: &'a mut D,
+    pub clipboard: &'a mut Clipboard,
+    pub surfaces: &'a mut Surfaces,
+}
+
+/// A part of rpt that acts on X events.
+pub trait Consumer<D> {
+    fn consume<'a>(&'a mut self, cx: &'a mut Context<'_, D>, event: &'a Event) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>>;
+}
+
+/// Hands every X event to the consumers, in the order they were added, so
+/// later ones see what earlier ones made of it.
+pub struct Bus<D> {
+    consumers: Vec<Box<dyn Consumer<D>>>,
+}
+
+impl<D> Default for Bus<D> {
+    fn default() -> Bus<D> {
+        Bus { consumers: Vec::new() }
+    }
+}
+
+impl<D> Bus<D> {
+    pub fn add(&mut self, consumer: impl Consumer<D> + 'static) {
+        self.consumers.push(Box::new(consumer));
+    }
+
+    pub async fn dispatch(&mut self, cx: &mut Context<'_, D>, event: &Event) -> Result<(), Box<dyn Error>> {
+        for consumer in &mut self.consumers {
+            consumer.consume(cx, event).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the server time the clipboard converts and takes selections at.
+/// Goes first, so the time is known before anything acts on the event.
+pub struct ServerTime;
+
+impl<D> Consumer<D> for ServerTime {
+    fn consume<'a>(&'a mut self, cx: &'a mut Context<'_, D>, event: &'a Event) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        cx.clipboard.note_time(event);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Routes events to the open windows, and acts on the ones that closed.
+pub struct Windows {
+    pub writer: Arc<Writer>,
+    /// Write changes to disk when a clip was chosen in the picker
+    pub flush_on_select: bool,
+}
+
+impl<D: AsyncDisplay> Consumer<D> for Windows {
+    fn consume<'a>(&'a mut self, cx: &'a mut Context<'_, D>, event: &'a Event) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            for closed in cx.surfaces.handle_event(cx.display, event, cx.clipboard).await? {
+                match closed {
+                    Closed::Picker(_) => {
+                        debug!("closing window");
+                        if self.flush_on_select {
+                            self.writer.flush();
+                        }
+                    }
+                    Closed::Prompt { class, decision } => cx.clipboard.confirm(cx.display, &class, decision).await?,
+                    Closed::Watcher => debug!("watch window closed"),
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Captures and serves clips, and asks about applications that want to be
+/// served for the first time.
+pub struct Selections {
+    pub options: Options,
+}
+
+impl<D: AsyncDisplay> Consumer<D> for Selections {
+    fn consume<'a>(&'a mut self, cx: &'a mut Context<'_, D>, event: &'a Event) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move {
+            cx.clipboard.handle_event(cx.display, event).await?;
+            if !cx.surfaces.has_prompt() {
+                if let Some(class) = cx.clipboard.awaiting_confirmation() {
+                    let prompt = Prompt::create(cx.display, class, &self.options).await?;
+                    cx.surfaces.open(Surface::Prompt(prompt));
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Shows clips captured in the meantime in the watch windows. Goes after
+/// `Selections`, so clips captured by the event are in.
+pub struct Watching;
+
+impl<D: AsyncDisplay> Consumer<D> for Watching {
+    fn consume<'a>(&'a mut self, cx: &'a mut Context<'_, D>, _: &'a Event) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>> {
+        Box::pin(async move { cx.surfaces.refresh(cx.display).await })
+    }
+}
@@ -0,0 +1,107 @@
+use log::warn;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::store::Writer;
+
+const MAX_SEARCHES: usize = 100;
+
+/// Previously used picker queries, newest last, saved one per line.
+pub struct SearchHistory {
+    path: Option<PathBuf>,
+    writer: Arc<Writer>,
+    queries: Mutex<Vec<String>>,
+}
+
+impl SearchHistory {
+    pub fn load(path: PathBuf, writer: Arc<Writer>) -> SearchHistory {
+        let queries = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(|l| l.to_owned()).collect(),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("unable to read search history {}: {}", path.display(), e);
+                }
+                Vec::new()
+            }
+        };
+        SearchHistory {
+            path: Some(path),
+            writer,
+            queries: Mutex::new(queries),
+        }
+    }
+
+    pub fn in_memory() -> SearchHistory {
+        SearchHistory {
+            path: None,
+            writer: Arc::new(Writer::immediate()),
+            queries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Remembers the query as the newest one.
+    pub fn add(&self, query: &str) {
+        if query.is_empty() || query.contains('\n') {
+            return;
+        }
+        let mut queries = self.queries.lock().unwrap();
+        queries.retain(|q| q != query);
+        queries.push(query.to_owned());
+        if queries.len() > MAX_SEARCHES {
+            queries.remove(0);
+        }
+
+        if let Some(path) = &self.path {
+            self.writer.write(path, queries.join("\n").into_bytes());
+        }
+    }
+
+    /// The query `back` steps back in history, where 0 is the newest.
+    pub fn get(&self, back: usize) -> Option<String> {
+        let queries = self.queries.lock().unwrap();
+        queries.iter().rev().nth(back).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queries.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::history::SearchHistory;
+    use crate::store::Writer;
+
+    #[test]
+    fn newest_first_without_duplicates() {
+        let history = SearchHistory::in_memory();
+        history.add("fst");
+        history.add("snd");
+        history.add("fst");
+        history.add("");
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0).unwrap(), "fst");
+        assert_eq!(history.get(1).unwrap(), "snd");
+        assert!(history.get(2).is_none());
+    }
+
+    #[test]
+    fn persisted() {
+        let path = std::env::temp_dir()
+            .join(format!("repeat-test-{}", std::process::id()))
+            .join("searches");
+        let writer = Arc::new(Writer::batched());
+        let history = SearchHistory::load(path.clone(), writer.clone());
+        history.add("fst");
+        history.add("snd");
+        writer.flush();
+
+        let loaded = SearchHistory::load(path.clone(), writer);
+        assert_eq!(loaded.get(0).unwrap(), "snd");
+        assert_eq!(loaded.get(1).unwrap(), "fst");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use crate::db::{Clip, ClipContents, Source};
+use crate::persist::SavedClip;
+
+/// How many clips are sent to the daemon at a time, so that a long history
+/// isn't one huge message.
+pub const BATCH: usize = 500;
+
+/// Clipboard managers whose history can be imported.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    /// clipman's `clipman.json`, a JSON array of strings, oldest first
+    Clipman,
+    /// The output of `greenclip print`, one clip per line, newest first.
+    /// greenclip's history file itself is in a private binary format.
+    Greenclip,
+    /// Items separated by NUL bytes, newest first, as printed by
+    /// `copyq eval -- "for (i = 0; i < size(); ++i) print(str(read(i)) + '\0')"`.
+    /// CopyQ's own tab files are in Qt's binary format.
+    Copyq,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "clipman" => Ok(Format::Clipman),
+            "greenclip" => Ok(Format::Greenclip),
+            "copyq" => Ok(Format::Copyq),
+            _ => Err(format!("can't import from {}, only clipman, greenclip or copyq", s)),
+        }
+    }
+}
+
+/// The clips in an exported history, oldest first so they can be added in
+/// order. Empty clips are skipped.
+pub fn parse(format: Format, contents: &str) -> Result<Vec<String>, String> {
+    let mut clips: Vec<String> = match format {
+        Format::Clipman => {
+            let mut clips: Vec<String> =
+                serde_json::from_str(contents).map_err(|e| format!("not a clipman history: {}", e))?;
+            clips.reverse();
+            clips
+        }
+        Format::Greenclip => contents.lines().map(|line| line.to_owned()).collect(),
+        Format::Copyq => contents.split('\0').map(|item| item.to_owned()).collect(),
+    };
+    clips.retain(|clip| !clip.is_empty());
+    clips.reverse();
+    Ok(clips)
+}
+
+/// The clips as saved ones to import, oldest first. None of the formats
+/// record when clips were copied, so the newest one is taken to be from
+/// `newest`, when the file was written, and each of the others a second
+/// before the next, which keeps them in order.
+pub fn saved(clips: Vec<String>, newest: SystemTime) -> Vec<SavedClip> {
+    let count = clips.len();
+    clips
+        .into_iter()
+        .enumerate()
+        .map(|(i, text)| {
+            let mut clip = Clip::new(Source::Clipboard, ClipContents::Text(text));
+            let age = Duration::from_secs((count - 1 - i) as u64);
+            clip.captured = newest.checked_sub(age).unwrap_or(SystemTime::UNIX_EPOCH);
+            SavedClip::from_clip(&clip)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::import::{parse, saved, Format};
+
+    #[test]
+    fn formats() {
+        let clipman = parse(Format::Clipman, r#"["oldest", "multi\nline", "newest"]"#).unwrap();
+        assert_eq!(clipman, vec!["oldest", "multi\nline", "newest"]);
+        assert!(parse(Format::Clipman, "not json").is_err());
+
+        let greenclip = parse(Format::Greenclip, "newest\n\nolder\noldest\n").unwrap();
+        assert_eq!(greenclip, vec!["oldest", "older", "newest"]);
+
+        let copyq = parse(Format::Copyq, "newest\0multi\nline\0").unwrap();
+        assert_eq!(copyq, vec!["multi\nline", "newest"]);
+
+        assert_eq!("copyq".parse::<Format>().unwrap(), Format::Copyq);
+        assert!("ditto".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn captured_in_order() {
+        let newest = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let clips = saved(vec!["oldest".to_owned(), "newest".to_owned()], newest);
+        assert_eq!(clips.iter().map(|clip| clip.captured).collect::<Vec<_>>(), vec![999, 1_000]);
+        assert_eq!(clips[0].text.as_deref(), Some("oldest"));
+        assert!(saved(Vec::new(), newest).is_empty());
+    }
+}
@@ -0,0 +1,17 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::info;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns incognito mode on or off. While it's on nothing is captured, and the
+/// picker and `list` show none of the history, say while sharing the screen.
+/// It's off again whenever rpt starts.
+pub fn set(enabled: bool) {
+    info!("incognito {}", if enabled { "on" } else { "off" });
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,320 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use breadx::prelude::*;
+use breadx::protocol::xproto::{self, EventMask, ModMask, SendEventRequest};
+use log::{info, warn};
+
+use crate::clipboard;
+
+/// How long a paste has to make the application ask for the clip before the
+/// next way of pasting is tried.
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_millis(500);
+
+// The keys pressed to paste, Shift+Insert
+const KEYSYM_SHIFT_L: xproto::Keysym = 0xffe1;
+const KEYSYM_INSERT: xproto::Keysym = 0xff63;
+
+/// A way of making the focused application paste, by pressing Shift+Insert
+/// in it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Method {
+    /// A synthetic key event sent to the focused window. Some applications
+    /// ignore those, and some servers refuse to send them.
+    SendEvent,
+    /// A synthetic key event sent to wherever the input focus is, which X
+    /// delivers to the child window under the pointer. Reaches toolkits that
+    /// focus a window inside the one that looks focused, without XTEST.
+    InputFocus,
+    /// A fake key press through XTEST, which applications can't tell from a
+    /// real one
+    XTest,
+}
+
+// In the order they're tried
+const METHODS: [Method; 3] = [Method::SendEvent, Method::InputFocus, Method::XTest];
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Method::SendEvent => "send-event",
+            Method::InputFocus => "input-focus",
+            Method::XTest => "xtest",
+        })
+    }
+}
+
+// The method that made each application paste, by WM_CLASS class
+static LEARNED: Mutex<Option<HashMap<String, Method>>> = Mutex::new(None);
+
+/// The applications a way of pasting has been found for, by class.
+pub fn learned() -> Vec<(String, Method)> {
+    let learned = LEARNED.lock().unwrap();
+    let mut learned: Vec<(String, Method)> = learned.iter().flatten().map(|(class, method)| (class.clone(), *method)).collect();
+    learned.sort_by(|a, b| a.0.cmp(&b.0));
+    learned
+}
+
+fn learned_for(class: &str) -> Option<Method> {
+    LEARNED.lock().unwrap().as_ref()?.get(class).copied()
+}
+
+fn learn(class: &str, method: Option<Method>) {
+    let mut learned = LEARNED.lock().unwrap();
+    let learned = learned.get_or_insert_with(HashMap::new);
+    match method {
+        Some(method) => learned.insert(class.to_owned(), method),
+        None => learned.remove(class),
+    };
+}
+
+// A paste waiting for the application to ask for the clip
+struct Attempt {
+    window: xproto::Window,
+    class: Option<String>,
+    method: Method,
+    // the methods left to try if it doesn't
+    rest: Vec<Method>,
+    // the server time it was sent at
+    time: xproto::Timestamp,
+    // whether the application asked for the clip since
+    confirmed: bool,
+    deadline: Instant,
+}
+
+/// Pastes into applications with a method that works with them. Which one
+/// does is told by whether the application asks for the clip afterwards, and
+/// remembered by its class, so it's only found out once.
+pub struct Paster {
+    root: xproto::Window,
+    xtest: bool,
+    attempt: Option<Attempt>,
+}
+
+impl Paster {
+    pub async fn new<D: AsyncDisplay>(dpy: &mut D, root: xproto::Window) -> Paster {
+        let xtest = match dpy.xtest_get_version_immediate(2, 2).await {
+            Ok(_) => true,
+            Err(e) => {
+                info!("XTEST isn't available ({}), pasting with synthetic events only", e);
+                false
+            }
+        };
+        Paster { root, xtest, attempt: None }
+    }
+
+    /// Whether a paste is waiting to be confirmed.
+    pub fn waiting(&self) -> bool {
+        self.attempt.is_some()
+    }
+
+    /// Pastes into `window` of the application `class`, with key events
+    /// from the server time `time`.
+    pub async fn paste<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        window: xproto::Window,
+        class: Option<String>,
+        time: xproto::Timestamp,
+    ) -> Result<(), Box<dyn Error>> {
+        let methods = match class.as_deref().and_then(learned_for) {
+            // trying others could paste twice into a slow application
+            Some(method) => vec![method],
+            None => METHODS.iter().copied().filter(|m| *m != Method::XTest || self.xtest).collect(),
+        };
+        self.attempt = None;
+        self.try_methods(dpy, window, class, methods, time).await
+    }
+
+    /// Notes that an application of the class asked for the clip, which
+    /// confirms the paste into it. With no class known for the window pasted
+    /// into, any application does.
+    pub fn requested(&mut self, class: &str) {
+        if let Some(attempt) = &mut self.attempt {
+            if attempt.class.as_deref().is_none_or(|pasted| pasted == class) {
+                attempt.confirmed = true;
+            }
+        }
+    }
+
+    // Sends the first of the methods that can be sent, leaving the rest for
+    // if it doesn't work
+    async fn try_methods<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        window: xproto::Window,
+        class: Option<String>,
+        mut methods: Vec<Method>,
+        time: xproto::Timestamp,
+    ) -> Result<(), Box<dyn Error>> {
+        let app = class.as_deref().unwrap_or("the focused window");
+        while !methods.is_empty() {
+            let method = methods.remove(0);
+            match self.send(dpy, window, method, time).await {
+                Ok(()) => {
+                    self.attempt = Some(Attempt {
+                        window,
+                        class,
+                        method,
+                        rest: methods,
+                        time,
+                        confirmed: false,
+                        deadline: Instant::now() + CONFIRM_TIMEOUT,
+                    });
+                    return Ok(());
+                }
+                // the connection is gone, rather than the method refused
+                Err(e) if clipboard::disconnected(&*e) => return Err(e),
+                Err(e) => warn!("unable to paste into {} with {}: {}", app, method, e),
+            }
+        }
+        warn!("none of the ways of pasting made {} ask for the clip", app);
+        if let Some(class) = &class {
+            learn(class, None);
+        }
+        Ok(())
+    }
+
+    /// Checks on the paste waiting to be confirmed, see `requested`. If it
+    /// isn't, the next method is tried after `CONFIRM_TIMEOUT`.
+    pub async fn check<D: AsyncDisplay>(&mut self, dpy: &mut D, now: Instant) -> Result<(), Box<dyn Error>> {
+        let Some(attempt) = self.attempt.take() else { return Ok(()) };
+        if attempt.confirmed {
+            if let Some(class) = &attempt.class {
+                if learned_for(class) != Some(attempt.method) {
+                    info!("pasting into {} with {}", class, attempt.method);
+                    learn(class, Some(attempt.method));
+                }
+            }
+            return Ok(());
+        }
+        if now < attempt.deadline {
+            self.attempt = Some(attempt);
+            return Ok(());
+        }
+        let app = attempt.class.as_deref().unwrap_or("the focused window");
+        warn!("{} didn't ask for the clip after a paste with {}", app, attempt.method);
+        self.try_methods(dpy, attempt.window, attempt.class, attempt.rest, attempt.time).await
+    }
+
+    async fn send<D: AsyncDisplay>(
+        &mut self,
+        dpy: &mut D,
+        window: xproto::Window,
+        method: Method,
+        time: xproto::Timestamp,
+    ) -> Result<(), Box<dyn Error>> {
+        // looked up every time, since the keyboard can change
+        let min_keycode = dpy.setup().min_keycode;
+        let count = dpy.setup().max_keycode - min_keycode + 1;
+        let mapping = dpy.get_keyboard_mapping_immediate(min_keycode, count).await?;
+        let keycode = |sym| {
+            keycode_for(&mapping.keysyms, mapping.keysyms_per_keycode, min_keycode, sym)
+                .ok_or_else(|| format!("no key on the keyboard has the keysym {:#x}", sym))
+        };
+        let insert = keycode(KEYSYM_INSERT)?;
+        match method {
+            Method::SendEvent => send_key(dpy, window, window, self.root, insert, ModMask::SHIFT, time).await?,
+            // InputFocus, as a destination
+            Method::InputFocus => send_key(dpy, 1, window, self.root, insert, ModMask::SHIFT, time).await?,
+            Method::XTest => {
+                let shift = keycode(KEYSYM_SHIFT_L)?;
+                for (type_, key) in [
+                    (xproto::KEY_PRESS_EVENT, shift),
+                    (xproto::KEY_PRESS_EVENT, insert),
+                    (xproto::KEY_RELEASE_EVENT, insert),
+                    (xproto::KEY_RELEASE_EVENT, shift),
+                ] {
+                    dpy.xtest_fake_input_checked(type_, key, 0, self.root, 0, 0, 0).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The first keycode with the keysym in a keyboard mapping, which lists
+// `per_keycode` keysyms for each keycode from `min_keycode` on
+fn keycode_for(keysyms: &[xproto::Keysym], per_keycode: u8, min_keycode: xproto::Keycode, sym: xproto::Keysym) -> Option<xproto::Keycode> {
+    let position = keysyms.chunks(usize::from(per_keycode).max(1)).position(|syms| syms.contains(&sym))?;
+    min_keycode.checked_add(u8::try_from(position).ok()?)
+}
+
+// Sends a synthetic press and release of the key to `destination`, as if it
+// happened in `window`
+async fn send_key<D: AsyncDisplay>(
+    dpy: &mut D,
+    destination: xproto::Window,
+    window: xproto::Window,
+    root: xproto::Window,
+    key: xproto::Keycode,
+    modmask: ModMask,
+    time: xproto::Timestamp,
+) -> breadx::Result<()> {
+    let mut event = xproto::KeyPressEvent {
+        response_type: xproto::KEY_PRESS_EVENT,
+        detail: key,
+        sequence: 0,
+        time,
+        root,
+        event: window,
+        child: 0,
+        root_x: 1,
+        root_y: 1,
+        event_x: 1,
+        event_y: 1,
+        state: modmask.into(),
+        same_screen: true,
+    };
+    let press_request = SendEventRequest {
+        propagate: true,
+        destination,
+        event_mask: EventMask::KEY_PRESS.into(),
+        event: Cow::Owned(event.into()),
+    };
+    let press_cookie = dpy.send_void_request(press_request, false).await?;
+    dpy.wait_for_reply(press_cookie).await?;
+
+    event.response_type = xproto::KEY_RELEASE_EVENT;
+    let release_request = SendEventRequest {
+        propagate: true,
+        destination,
+        event_mask: EventMask::KEY_RELEASE.into(),
+        event: Cow::Owned(event.into()),
+    };
+    let release_cookie = dpy.send_void_request(release_request, false).await?;
+    dpy.wait_for_reply(release_cookie).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inject::{keycode_for, learn, learned, learned_for, Method, KEYSYM_INSERT, KEYSYM_SHIFT_L};
+
+    #[test]
+    fn learned_by_class() {
+        learn("XTerm", Some(Method::XTest));
+        learn("Emacs", Some(Method::InputFocus));
+        learn("firefox", Some(Method::SendEvent));
+        assert_eq!(learned_for("XTerm"), Some(Method::XTest));
+        assert_eq!(learned_for("xterm"), None);
+
+        learn("firefox", None);
+        assert_eq!(learned(), vec![("Emacs".to_owned(), Method::InputFocus), ("XTerm".to_owned(), Method::XTest)]);
+        assert_eq!(Method::InputFocus.to_string(), "input-focus");
+    }
+
+    #[test]
+    fn keycodes() {
+        // a, Shift_L, Return and Insert, with two keysyms for each keycode
+        // from 8 on
+        let mapping = [0x61, 0x41, KEYSYM_SHIFT_L, 0, 0xff0d, 0, KEYSYM_INSERT, 0];
+        assert_eq!(keycode_for(&mapping, 2, 8, KEYSYM_SHIFT_L), Some(9));
+        assert_eq!(keycode_for(&mapping, 2, 8, KEYSYM_INSERT), Some(11));
+        assert_eq!(keycode_for(&mapping, 2, 8, 0xffff), None);
+        assert_eq!(keycode_for(&mapping, 0, 8, 0x61), Some(8));
+    }
+}
@@ -0,0 +1,461 @@
+// breadx_keysyms is deprecated in favour of xkeysym, but still does what we need
+#![allow(deprecated)]
+
+use breadx::display::AsyncDisplay;
+use breadx_keysyms::{is_keypad_key, keysyms, KeyboardState};
+use serde::de::{MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+// Named keys that can be bound, besides single characters
+const NAMED_KEYS: &[(&str, u32)] = &[
+    ("Return", keysyms::KEY_Return),
+    ("Escape", keysyms::KEY_Escape),
+    ("Tab", keysyms::KEY_Tab),
+    ("BackSpace", keysyms::KEY_BackSpace),
+    ("Delete", keysyms::KEY_Delete),
+    ("Insert", keysyms::KEY_Insert),
+    ("Up", keysyms::KEY_Up),
+    ("Down", keysyms::KEY_Down),
+    ("Left", keysyms::KEY_Left),
+    ("Right", keysyms::KEY_Right),
+    ("Home", keysyms::KEY_Home),
+    ("End", keysyms::KEY_End),
+    ("Page_Up", keysyms::KEY_Page_Up),
+    ("Page_Down", keysyms::KEY_Page_Down),
+    ("space", keysyms::KEY_space),
+    ("F1", keysyms::KEY_F1),
+    ("F2", keysyms::KEY_F2),
+    ("F3", keysyms::KEY_F3),
+    ("F4", keysyms::KEY_F4),
+    ("F5", keysyms::KEY_F5),
+    ("F6", keysyms::KEY_F6),
+    ("F7", keysyms::KEY_F7),
+    ("F8", keysyms::KEY_F8),
+    ("F9", keysyms::KEY_F9),
+    ("F10", keysyms::KEY_F10),
+    ("F11", keysyms::KEY_F11),
+    ("F12", keysyms::KEY_F12),
+];
+
+// Modifier bits in the state of key events. Num Lock is Mod2 on practically
+// every setup.
+const SHIFT_MASK: u16 = 1 << 0;
+const LOCK_MASK: u16 = 1 << 1;
+const NUM_LOCK_MASK: u16 = 1 << 4;
+
+// Keypad keys that do the same as a key elsewhere on the keyboard
+const KEYPAD_ALIASES: &[(u32, u32)] = &[
+    (keysyms::KEY_KP_Enter, keysyms::KEY_Return),
+    (keysyms::KEY_KP_Tab, keysyms::KEY_Tab),
+    (keysyms::KEY_KP_Space, keysyms::KEY_space),
+    (keysyms::KEY_KP_Home, keysyms::KEY_Home),
+    (keysyms::KEY_KP_End, keysyms::KEY_End),
+    (keysyms::KEY_KP_Left, keysyms::KEY_Left),
+    (keysyms::KEY_KP_Right, keysyms::KEY_Right),
+    (keysyms::KEY_KP_Up, keysyms::KEY_Up),
+    (keysyms::KEY_KP_Down, keysyms::KEY_Down),
+    (keysyms::KEY_KP_Page_Up, keysyms::KEY_Page_Up),
+    (keysyms::KEY_KP_Page_Down, keysyms::KEY_Page_Down),
+    (keysyms::KEY_KP_Insert, keysyms::KEY_Insert),
+    (keysyms::KEY_KP_Delete, keysyms::KEY_Delete),
+    (keysyms::KEY_KP_Equal, '=' as u32),
+    (keysyms::KEY_KP_Multiply, '*' as u32),
+    (keysyms::KEY_KP_Add, '+' as u32),
+    (keysyms::KEY_KP_Separator, ',' as u32),
+    (keysyms::KEY_KP_Subtract, '-' as u32),
+    (keysyms::KEY_KP_Decimal, '.' as u32),
+    (keysyms::KEY_KP_Divide, '/' as u32),
+];
+
+/// Looks up the keysym of a pressed key the way Xlib does: Shift picks the
+/// second column, and Num Lock swaps the columns of keypad keys.
+pub async fn symbol<D: AsyncDisplay>(
+    keyboard: &mut KeyboardState,
+    display: &mut D,
+    keycode: u8,
+    state: u16,
+) -> Result<u32, Box<dyn Error>> {
+    let shift = state & SHIFT_MASK != 0;
+    let sym = if state & NUM_LOCK_MASK != 0 && is_keypad_key(keyboard.symbol_async(display, keycode, 1).await?) {
+        keyboard.symbol_async(display, keycode, if shift { 0 } else { 1 }).await?
+    } else {
+        keyboard.symbol_async(display, keycode, if shift { 1 } else { 0 }).await?
+    };
+    // Caps Lock only changes letters, so it isn't a column of its own
+    Ok(match char::from_u32(sym) {
+        Some(c) if state & LOCK_MASK != 0 && c.is_lowercase() && c.to_uppercase().count() == 1 => {
+            c.to_uppercase().next().unwrap() as u32
+        }
+        _ => canonical(sym),
+    })
+}
+
+/// The key that a keypad key stands for, so that `KP_Enter` is `Return` and
+/// `KP_1` is `1`.
+pub fn canonical(sym: u32) -> u32 {
+    match sym {
+        keysyms::KEY_KP_0..=keysyms::KEY_KP_9 => '0' as u32 + (sym - keysyms::KEY_KP_0),
+        _ => KEYPAD_ALIASES
+            .iter()
+            .find(|(keypad, _)| *keypad == sym)
+            .map(|(_, main)| *main)
+            .unwrap_or(sym),
+    }
+}
+
+/// The character a keysym types, if any. Latin-1 keysyms are their own code
+/// points and other characters are offset by 0x1000000.
+pub fn typed_char(sym: u32) -> Option<char> {
+    match canonical(sym) {
+        sym @ (0x20..=0x7e | 0xa0..=0xff) => char::from_u32(sym),
+        sym @ 0x1000100..=0x110ffff => char::from_u32(sym - 0x1000000),
+        _ => None,
+    }
+}
+
+/// What a key does in the picker.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Close,
+    /// Move up, or recall an older search if nothing matches
+    Up,
+    /// Move down, or recall a newer search if nothing matches
+    Down,
+    HistoryBack,
+    HistoryForward,
+    CycleSort,
+    Clear,
+    Delete,
+    SaveSnippet,
+    ToggleQueue,
+    /// Choose the clip and paste it
+    Paste,
+    /// Choose the clip without pasting it
+    Copy,
+    /// Own the other selection than the one the clip was copied from with it
+    Promote,
+    FontLarger,
+    FontSmaller,
+    /// List the earlier versions the chosen clip was edited from, or go back
+    Chain,
+    /// Show collapsed near duplicates, or collapse them again
+    Expand,
+    /// List the archived clips instead of the rest, or go back
+    Archived,
+    /// Lay the clips out on a timeline, or go back to the list
+    Timeline,
+    /// Hide the history, or show it again after pressing it twice
+    Incognito,
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Close => "close",
+            Action::Up => "up",
+            Action::Down => "down",
+            Action::HistoryBack => "history-back",
+            Action::HistoryForward => "history-forward",
+            Action::CycleSort => "cycle-sort",
+            Action::Clear => "clear",
+            Action::Delete => "delete",
+            Action::SaveSnippet => "save-snippet",
+            Action::ToggleQueue => "toggle-queue",
+            Action::Paste => "paste",
+            Action::Copy => "copy",
+            Action::Promote => "promote",
+            Action::FontLarger => "font-larger",
+            Action::FontSmaller => "font-smaller",
+            Action::Chain => "chain",
+            Action::Expand => "expand",
+            Action::Archived => "archived",
+            Action::Timeline => "timeline",
+            Action::Incognito => "incognito",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Action, String> {
+        Action::deserialize(serde::de::value::StrDeserializer::<serde::de::value::Error>::new(s))
+            .map_err(|_| format!("unknown action {}", s))
+    }
+}
+
+/// A key with or without Ctrl, like `ctrl+k` or `Return`. Shift is part of
+/// the character, so `K` is shift+k.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct KeyCombo {
+    pub ctrl: bool,
+    pub sym: u32,
+}
+
+impl KeyCombo {
+    pub fn new(ctrl: bool, sym: u32) -> KeyCombo {
+        // letters with Ctrl come in either case depending on the keymap
+        let sym = match char::from_u32(sym) {
+            Some(c) if ctrl && c.is_ascii_uppercase() => c.to_ascii_lowercase() as u32,
+            _ => sym,
+        };
+        KeyCombo { ctrl, sym }
+    }
+
+    // Whether pressing it would otherwise type into the search. Keysyms from
+    // 0xff00 are function keys like Return.
+    fn types(&self) -> bool {
+        !self.ctrl && self.sym < 0xff00
+    }
+}
+
+impl FromStr for KeyCombo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<KeyCombo, String> {
+        let (ctrl, key) = match s.split_once('+') {
+            Some((modifier, key)) if modifier.eq_ignore_ascii_case("ctrl") && !key.is_empty() => (true, key),
+            Some((modifier, key)) if !modifier.is_empty() && !key.is_empty() => {
+                return Err(format!("unknown modifier {} in {}, only ctrl is supported", modifier, s))
+            }
+            _ => (false, s),
+        };
+        let mut chars = key.chars();
+        let sym = match (chars.next(), chars.next()) {
+            (Some(c), None) => c as u32,
+            _ => NAMED_KEYS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, sym)| *sym)
+                .ok_or_else(|| format!("unknown key {}", key))?,
+        };
+        Ok(KeyCombo::new(ctrl, sym))
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "ctrl+")?;
+        }
+        match NAMED_KEYS.iter().find(|(_, sym)| *sym == self.sym) {
+            Some((name, _)) => write!(f, "{}", name),
+            None => match char::from_u32(self.sym) {
+                Some(c) => write!(f, "{}", c),
+                None => write!(f, "{:#x}", self.sym),
+            },
+        }
+    }
+}
+
+/// The picker's key bindings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    bindings: HashMap<KeyCombo, Action>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let bindings = [
+            ("Escape", Action::Close),
+            ("Up", Action::Up),
+            ("ctrl+k", Action::Up),
+            ("Down", Action::Down),
+            ("ctrl+j", Action::Down),
+            ("ctrl+p", Action::HistoryBack),
+            ("ctrl+n", Action::HistoryForward),
+            ("ctrl+o", Action::CycleSort),
+            ("ctrl+u", Action::Clear),
+            ("BackSpace", Action::Delete),
+            ("ctrl+s", Action::SaveSnippet),
+            ("Tab", Action::ToggleQueue),
+            ("Return", Action::Paste),
+            ("ctrl+Return", Action::Copy),
+            ("ctrl+y", Action::Promote),
+            ("ctrl++", Action::FontLarger),
+            // + needs shift on many layouts
+            ("ctrl+=", Action::FontLarger),
+            ("ctrl+-", Action::FontSmaller),
+            ("ctrl+l", Action::Chain),
+            ("ctrl+e", Action::Expand),
+            ("ctrl+a", Action::Archived),
+            ("ctrl+t", Action::Timeline),
+            ("ctrl+i", Action::Incognito),
+        ];
+        Keymap {
+            bindings: bindings
+                .iter()
+                .map(|(key, action)| (key.parse().unwrap(), *action))
+                .collect(),
+        }
+    }
+}
+
+impl Keymap {
+    pub fn action(&self, key: KeyCombo) -> Option<Action> {
+        self.bindings.get(&key).cloned()
+    }
+
+    /// Binds the key, replacing what it did before. Keys that type
+    /// characters can't be bound, since they'd no longer reach the search.
+    pub fn bind(&mut self, key: KeyCombo, action: Action) -> Result<(), String> {
+        if key.types() {
+            return Err(format!("{} types into the search, bind it with ctrl instead", key));
+        }
+        self.bindings.insert(key, action);
+        Ok(())
+    }
+
+    /// Makes the keys that paste copy instead, for a picker whose choice
+    /// shouldn't be pasted.
+    pub fn without_paste(mut self) -> Keymap {
+        for action in self.bindings.values_mut() {
+            if *action == Action::Paste {
+                *action = Action::Copy;
+            }
+        }
+        self
+    }
+
+    /// Returns whether the key was bound.
+    pub fn unbind(&mut self, key: KeyCombo) -> bool {
+        self.bindings.remove(&key).is_some()
+    }
+
+    /// The first key, in sorted order, that does the action.
+    pub fn key_for(&self, action: Action) -> Option<String> {
+        self.all().into_iter().find(|(_, a)| *a == action).map(|(key, _)| key)
+    }
+
+    /// All bindings, sorted by key.
+    pub fn all(&self) -> Vec<(String, Action)> {
+        let mut all: Vec<(String, Action)> =
+            self.bindings.iter().map(|(key, action)| (key.to_string(), *action)).collect();
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        all
+    }
+}
+
+// A `[keys]` table in the config, which changes the default bindings. Keys
+// that end up the same, like `ctrl+k` and `Ctrl+K`, conflict.
+impl<'de> Deserialize<'de> for Keymap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Keymap, D::Error> {
+        struct KeymapVisitor;
+
+        impl<'de> Visitor<'de> for KeymapVisitor {
+            type Value = Keymap;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a table of keys and actions")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Keymap, A::Error> {
+                let mut keymap = Keymap::default();
+                let mut seen: HashMap<KeyCombo, String> = HashMap::new();
+                while let Some(name) = map.next_key::<String>()? {
+                    let action: Action = map.next_value()?;
+                    let key: KeyCombo = name.parse().map_err(serde::de::Error::custom)?;
+                    if let Some(other) = seen.insert(key, name.clone()) {
+                        return Err(serde::de::Error::custom(format!(
+                            "{} and {} are the same key",
+                            other, name
+                        )));
+                    }
+                    keymap.bind(key, action).map_err(serde::de::Error::custom)?;
+                }
+                Ok(keymap)
+            }
+        }
+
+        deserializer.deserialize_map(KeymapVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use breadx_keysyms::keysyms;
+
+    use crate::keys::{canonical, typed_char, Action, KeyCombo, Keymap};
+
+    #[test]
+    fn parse_keys() {
+        let ctrl_k: KeyCombo = "ctrl+k".parse().unwrap();
+        assert_eq!(ctrl_k, "Ctrl+K".parse().unwrap());
+        assert_eq!(ctrl_k, KeyCombo::new(true, 'K' as u32));
+        assert_eq!("ctrl+page_up".parse::<KeyCombo>().unwrap().to_string(), "ctrl+Page_Up");
+        assert_eq!("+".parse::<KeyCombo>().unwrap().to_string(), "+");
+        assert_eq!("ctrl++".parse::<KeyCombo>().unwrap().to_string(), "ctrl++");
+        assert!("alt+k".parse::<KeyCombo>().is_err());
+        assert!("Nope".parse::<KeyCombo>().is_err());
+        assert_eq!("toggle-queue".parse::<Action>().unwrap(), Action::ToggleQueue);
+        for (_, action) in Keymap::default().all() {
+            assert_eq!(action.name().parse::<Action>().unwrap(), action);
+        }
+    }
+
+    #[test]
+    fn keypad() {
+        assert_eq!(canonical(keysyms::KEY_KP_Enter), keysyms::KEY_Return);
+        assert_eq!(canonical(keysyms::KEY_KP_Page_Up), keysyms::KEY_Page_Up);
+        assert_eq!(typed_char(keysyms::KEY_KP_7), Some('7'));
+        assert_eq!(typed_char(keysyms::KEY_KP_Decimal), Some('.'));
+        assert_eq!(typed_char('#' as u32), Some('#'));
+        assert_eq!(typed_char(0x10020ac), Some('€'));
+        assert_eq!(typed_char(keysyms::KEY_BackSpace), None);
+        assert_eq!(typed_char(keysyms::KEY_KP_Enter), None);
+    }
+
+    #[test]
+    fn remap() {
+        let mut keymap = Keymap::default();
+        let ret = "Return".parse().unwrap();
+        assert_eq!(keymap.action(ret), Some(Action::Paste));
+
+        keymap.bind(ret, Action::Copy).unwrap();
+        assert_eq!(keymap.action(ret), Some(Action::Copy));
+        assert!(keymap.unbind(ret));
+        assert!(!keymap.unbind(ret));
+        assert_eq!(keymap.action(ret), None);
+
+        assert!(keymap.bind("q".parse().unwrap(), Action::Close).is_err());
+        assert!(keymap.bind("space".parse().unwrap(), Action::Close).is_err());
+        assert!(keymap.bind("ctrl+q".parse().unwrap(), Action::Close).is_ok());
+        assert_eq!(keymap.key_for(Action::Close).as_deref(), Some("Escape"));
+        assert_eq!(keymap.key_for(Action::Up).as_deref(), Some("Up"));
+        assert_eq!(keymap.key_for(Action::Paste), None);
+    }
+
+    #[test]
+    fn conflicts_in_config() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            keys: Keymap,
+        }
+
+        let config: Config = toml::from_str("[keys]\n\"ctrl+q\" = \"close\"\n").unwrap();
+        assert_eq!(config.keys.action("ctrl+q".parse().unwrap()), Some(Action::Close));
+        assert_eq!(config.keys.action("Escape".parse().unwrap()), Some(Action::Close));
+
+        let error = toml::from_str::<Config>("[keys]\n\"ctrl+q\" = \"close\"\n\"Ctrl+Q\" = \"up\"\n")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("ctrl+q and Ctrl+Q are the same key"), "{}", error);
+
+        let error = toml::from_str::<Config>("[keys]\nq = \"close\"\n").err().unwrap().to_string();
+        assert!(error.contains("types into the search"), "{}", error);
+    }
+
+    #[test]
+    fn without_paste() {
+        let keymap = Keymap::default().without_paste();
+        assert_eq!(keymap.action("Return".parse().unwrap()), Some(Action::Copy));
+        assert_eq!(keymap.action("ctrl+Return".parse().unwrap()), Some(Action::Copy));
+        assert_eq!(keymap.key_for(Action::Paste), None);
+    }
+}
@@ -1,16 +1,39 @@
 #![allow(dead_code)]
 
+mod cli;
+mod clock;
 mod clipboard;
+mod crypt;
 mod db;
+mod events;
+mod history;
+mod import;
+mod incognito;
+mod inject;
+mod keys;
+mod matcher;
+mod native;
+mod notify;
+mod oneshot;
+mod persist;
 mod options;
+mod redact;
+mod secrets;
 mod rpc;
+mod snippets;
+mod store;
+mod substitute;
+mod trust;
 mod ui;
+mod watchdog;
+mod wm;
 
-use log::{debug, error, info, trace};
+use log::{error, info, trace, warn};
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::ui::Window;
+use crate::ui::{Surface, Watcher, Window};
 use breadx::prelude::*;
 use breadx::rt_support::tokio_support;
 use futures::StreamExt;
@@ -20,96 +43,345 @@ use tokio::sync::Mutex as AsyncMutex;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
-    let args: Vec<_> = env::args().collect();
-    info!("args: {:?}", args);
+    let started = Instant::now();
+    let mut args: Vec<_> = env::args().collect();
+    if let Some(pos) = args.iter().position(|a| a == redact::FLAG) {
+        args.remove(pos);
+        redact::log_bodies();
+    }
+    if let Err(e) = options::take_data_dir(&mut args) {
+        eprintln!("rpt: {}", e);
+        std::process::exit(cli::INVALID);
+    }
+    let max_clips = match options::take_max_clips(&mut args) {
+        Ok(max) => max,
+        Err(e) => {
+            eprintln!("rpt: {}", e);
+            std::process::exit(cli::INVALID);
+        }
+    };
+    // only the command, since the rest can be clips or keys
+    if let Some(command) = args.get(1) {
+        info!("running rpt {}", command);
+    }
     if args.len() > 1 {
-        let client = rpc::create_client("/tmp/repeat.socket").await?;
-        match args.get(1).map(|c| c.as_ref()) {
-            Some("show") => client.show(tarpc::context::current()).await?,
-            Some("pause") => client.pause(tarpc::context::current()).await?,
-            Some("start") => client.start(tarpc::context::current()).await?,
-            _ => {
-                error!("not a valid argument");
-            }
+        if let Err(e) = cli::run(&args).await {
+            eprintln!("rpt: {}", e);
+            std::process::exit(cli::exit_code(&*e));
         }
         return Ok(());
     }
 
-    let options = options::Options {
-        font_size: 20f32,
-        font_name: Some("Monospace".to_owned()),
+    let mut options = match options::Options::load() {
+        Ok(options) => options,
+        Err(e) => {
+            // the error points at the problem over several lines, which the
+            // Debug output of returning it would squash into one
+            eprintln!("invalid config: {}", e);
+            std::process::exit(1);
+        }
     };
 
-    let database = Arc::new(db::Database::new());
+    if let Some(max) = max_clips {
+        options.max_clips = max;
+    }
+
+    if options.wm_ipc {
+        wm::enable();
+    }
+    if options.lock_memory {
+        secrets::protect();
+    }
+
+    store::prepare_data_dir(&options::data_dir());
+    let leftovers = store::clean_spool(&options::spool_dir());
+    if leftovers > 0 {
+        info!("removed {} partial transfers left behind by a crash", leftovers);
+    }
+    let writer = Arc::new(store::Writer::batched());
+    let database = Arc::new(
+        db::Database::with_matcher(options.matcher)
+            .with_preview_bytes(options.preview_bytes)
+            .with_max_clips(options.max_clips)
+            .with_ttl((options.ttl > 0).then(|| Duration::from_secs(options.ttl)))
+            .with_tag_rules(options.tag_rules.clone()),
+    );
+    let history = options::data_dir().join(persist::FILE);
+    match options.encryption_key_command.clone() {
+        None => database.open_store(history, writer.clone(), None),
+        Some(command) => {
+            // the command may wait for the keyring to be unlocked, so clips are
+            // captured in the meantime and the history is decrypted once it's there
+            let database = database.clone();
+            let writer = writer.clone();
+            tokio::task::spawn_blocking(move || match crypt::Key::from_command(&command) {
+                Ok(key) => database.open_store(history, writer, Some(Arc::new(key))),
+                Err(e) => store::degrade(format!("unable to get the key to the history: {}", e)),
+            });
+        }
+    }
+    {
+        // encoding and writing a long history takes a while, so it's done on
+        // a blocking thread rather than holding up the event loop
+        let writer = writer.clone();
+        let mut interval = (options.flush_interval > 0)
+            .then(|| tokio::time::interval(Duration::from_secs(options.flush_interval)));
+        tokio::spawn(async move {
+            loop {
+                match interval.as_mut() {
+                    Some(interval) => {
+                        interval.tick().await;
+                    }
+                    // every change is written straight away
+                    None => writer.queued().await,
+                }
+                let writer = writer.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || writer.flush()).await {
+                    error!("unable to flush pending writes: {}", e);
+                }
+            }
+        });
+    }
+    {
+        // clips whose time to live ran out, including any that did while rpt
+        // wasn't running
+        let database = database.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                let removed = database.remove_expired(std::time::SystemTime::now());
+                if removed > 0 {
+                    info!("forgot {} expired clips", removed);
+                }
+            }
+        });
+    }
+    let search_history = Arc::new(history::SearchHistory::load(options::data_dir().join("searches"), writer.clone()));
+    let snippets = Arc::new(snippets::Snippets::load(options::data_dir().join(snippets::FILE)));
+    let trust = Arc::new(trust::TrustStore::load(options::data_dir().join(trust::FILE)));
+    let keymap = Arc::new(std::sync::Mutex::new(options.keys.clone()));
     let connection = Arc::new(AsyncMutex::new(tokio_support::connect(None).await?));
-    let window: Arc<Mutex<Option<Window>>> = Arc::new(Mutex::new(None));
+    // the picker opens sorted and sized the way it was last left
+    let mut surfaces = ui::Surfaces::new(ui::View { sort: options.sort, zoom: 0.0 });
+    // when the picker was last shown, to tell a double show for quick paste
+    let mut shown_at: Option<Instant> = None;
     let mut clipboard = {
         let mut dpy = connection.lock().await;
-        clipboard::Clipboard::new(&mut *dpy, database.clone()).await?
+        clipboard::Clipboard::new(&mut *dpy, database.clone(), trust.clone(), &options).await?
     };
 
-    let (rpc_sender, mut rpc_receiver) = futures::channel::mpsc::channel::<rpc::Message>(10);
+    info!("capturing clips {:?} after starting", started.elapsed());
+
+    // the picker's font takes a while to find, so do it now rather than when
+    // the picker is first shown, but without holding up capturing
+    let font_name = options.font_name.clone();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = ui::cached_font(font_name.as_deref()) {
+            error!("unable to load a font: {}", e);
+        }
+    });
+
+    let (rpc_sender, mut rpc_receiver) = futures::channel::mpsc::channel::<rpc::Command>(rpc::QUEUE_SIZE);
+
+    rpc::start_server(options::socket_path(), rpc_sender, database.clone(), trust.clone(), keymap.clone()).await?;
 
-    rpc::start_server("/tmp/repeat.socket", rpc_sender).await?;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    // only ticks without XFIXES
+    let mut poll_timer = tokio::time::interval(Duration::from_millis(options.poll_interval));
+    let mut conversion_timer = tokio::time::interval(Duration::from_secs(1));
+    let mut paste_timer = tokio::time::interval(Duration::from_millis(100));
+    let mut cleanup_timer = tokio::time::interval(Duration::from_secs(60));
+    let mut ping_timer = tokio::time::interval(watchdog::PING_INTERVAL);
+
+    // a hung main loop would otherwise leave rpt running but doing nothing,
+    // exiting at least lets whatever started it start it again
+    let watchdog = watchdog::Watchdog::new();
+    {
+        let connection = connection.clone();
+        let writer = writer.clone();
+        watchdog.watch(
+            move || connection.try_lock().is_err(),
+            move || {
+                writer.flush();
+                std::process::exit(1);
+            },
+        )?;
+    }
+
+    // what acts on X events, in order
+    let mut bus = events::Bus::default();
+    bus.add(events::ServerTime);
+    bus.add(events::Windows { writer: writer.clone(), flush_on_select: options.flush_on_select });
+    bus.add(events::Selections { options: options.clone() });
+    bus.add(events::Watching);
 
     loop {
         tokio::select! {
             // incoming X11 events
             ev = async { connection.lock().await.wait_for_event().await } => {
                 let event = ev?;
+                let _busy = watchdog.busy("handling an X event");
 
                 trace!("event: {:?}", event);
+                let mut c = connection.lock().await;
+                let mut cx = events::Context { display: &mut *c, clipboard: &mut clipboard, surfaces: &mut surfaces };
+                bus.dispatch(&mut cx, &event).await?;
+            }
 
-                // update any open windows
-                let mut locked_window = window.lock().unwrap();
-                let keep_open = match locked_window.as_mut() {
-                    Some(w) => {
-                        let mut c = connection.lock().await;
-                        match w.handle_event(&mut *c, &event, &mut clipboard).await? {
-                            ui::WindowAction::CloseWindow => false,
-                            ui::WindowAction::StayOpen => true,
-                        }
-                    },
-                    _ => true,
+            // RPC messages
+            command = rpc_receiver.next() => {
+                let rpc::Command { message, reply } = match command {
+                    Some(command) => command,
+                    None => {
+                        error!("rpc server shut down?");
+                        continue;
+                    }
                 };
-                if !keep_open {
-                    debug!("closing window");
-                    *locked_window = None;
+                trace!("got a command {:?}", message);
+                let _busy = watchdog.busy("carrying out a command");
+                // a failed command is reported to whoever sent it rather than
+                // stopping rpt, a lost X connection shows up with the next event
+                let result: Result<(), Box<dyn std::error::Error>> = async {
+                    match message {
+                        rpc::Message::Own => {
+                            clipboard.take_ownership(&mut *connection.lock().await).await?;
+                        }
+                        rpc::Message::Show => {
+                            let quick_paste = Duration::from_millis(options.quick_paste);
+                            let double = shown_at.is_some_and(|at| at.elapsed() < quick_paste);
+                            match surfaces.take_picker() {
+                                Some(w) if double => {
+                                    info!("shown twice, pasting the newest clip");
+                                    let mut c = connection.lock().await;
+                                    w.close(&mut *c).await?;
+                                    ui::paste_last(&mut *c, &database, &mut clipboard).await?;
+                                    shown_at = None;
+                                }
+                                picker => {
+                                    info!("showing window");
+                                    let w = match picker {
+                                        Some(w) => w,
+                                        None => Window::create(&mut *connection.lock().await, database.clone(), search_history.clone(), snippets.clone(), keymap.clone(), surfaces.view(), &options).await?,
+                                    };
+                                    surfaces.open(Surface::Picker(Box::new(w)));
+                                    shown_at = Some(Instant::now());
+                                }
+                            }
+                        }
+                        rpc::Message::PasteLast => {
+                            let mut c = connection.lock().await;
+                            if let Some(w) = surfaces.take_picker() {
+                                w.close(&mut *c).await?;
+                            }
+                            if !ui::paste_last(&mut *c, &database, &mut clipboard).await? {
+                                info!("nothing to paste");
+                            }
+                        }
+                        rpc::Message::Promote { clip, to } => {
+                            clipboard.promote(&mut *connection.lock().await, clip, to).await?;
+                        }
+                        rpc::Message::Monitor { selection, enabled } => {
+                            clipboard.set_monitored(&mut *connection.lock().await, selection, enabled).await?;
+                        }
+                        rpc::Message::Resync => {
+                            clipboard.resync(&mut *connection.lock().await).await?;
+                        }
+                        rpc::Message::Sync(mode) => {
+                            clipboard.set_sync(mode);
+                        }
+                        rpc::Message::Watch => {
+                            let mut c = connection.lock().await;
+                            match surfaces.take_watcher() {
+                                Some(w) => w.close(&mut *c).await?,
+                                None => surfaces.open(Surface::Watcher(Watcher::create(&mut *c, database.clone(), &options).await?)),
+                            }
+                        }
+                        rpc::Message::Pause(selection) => {
+                            clipboard.pause(selection);
+                        }
+                        rpc::Message::Start(selection) => {
+                            clipboard.start(selection);
+                        }
+                        rpc::Message::Panic { pause } => {
+                            info!("panic, dropping the selection");
+                            clipboard.disown(&mut *connection.lock().await).await?;
+                            if pause {
+                                clipboard.pause(None);
+                            }
+                        }
+                    }
+                    Ok(())
+                }.await;
+                if let Err(e) = &result {
+                    error!("unable to carry out a command: {}", e);
                 }
+                // the client may have given up waiting
+                let _ = reply.send(result.map_err(|e| e.to_string()));
+            }
 
-                // update clipboard
-                {
-                    let mut con = connection.lock().await;
-                    clipboard.handle_event(&mut *con, &event).await?;
-                }
+            _ = poll_timer.tick(), if clipboard.polling() => {
+                let _busy = watchdog.busy("polling the selections");
+                clipboard.poll(&mut *connection.lock().await).await?;
+            }
 
+            _ = conversion_timer.tick(), if clipboard.converting() => {
+                let _busy = watchdog.busy("expiring conversions");
+                clipboard.expire_conversions(&mut *connection.lock().await).await?;
             }
 
-            // RPC messages
-            command = rpc_receiver.next() => {
-                trace!("got a command {:?}", command);
-                match command {
-                    Some(rpc::Message::Own) => {
-                        clipboard.take_ownership(&mut *connection.lock().await).await?;
-                    }
-                    Some(rpc::Message::Show) => {
-                        info!("showing window");
-                        let mut locked_window = window.lock().unwrap();
-                        if locked_window.is_none() {
-                            *locked_window = Some(Window::create(&mut *connection.lock().await, database.clone(), &options).await?);
-                        };
-                    }
-                    Some(rpc::Message::Pause) => {
-                        clipboard.pause();
-                    }
-                    Some(rpc::Message::Start) => {
-                        clipboard.start();
+            _ = paste_timer.tick(), if clipboard.pasting() => {
+                let _busy = watchdog.busy("checking on a paste");
+                clipboard.check_paste(&mut *connection.lock().await).await?;
+            }
+
+            _ = cleanup_timer.tick() => {
+                let _busy = watchdog.busy("cleaning up after fetches");
+                clipboard.clean_up(&mut *connection.lock().await).await?;
+            }
+
+            _ = ping_timer.tick() => {
+                let _busy = watchdog.busy("pinging the X server");
+                let mut c = connection.lock().await;
+                match tokio::time::timeout(watchdog::PING_TIMEOUT, watchdog::ping(&mut *c)).await {
+                    Ok(answered) => {
+                        answered?;
+                        watchdog.answered();
                     }
-                    None => {
-                        error!("rpc server shut down?");
+                    Err(_) => {
+                        let missed = watchdog.missed();
+                        warn!("the X server didn't answer a ping within {:?}, {} missed in a row", watchdog::PING_TIMEOUT, missed);
+                        if missed >= watchdog::MAX_MISSED {
+                            writer.flush();
+                            return Err("the X server stopped answering".into());
+                        }
                     }
                 }
             }
+
+            _ = &mut shutdown => {
+                info!("shutting down");
+                writer.flush();
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Resolves on Ctrl-C or SIGTERM, so pending writes can be flushed first
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(e) => {
+            error!("unable to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
         }
     }
 }
@@ -0,0 +1,96 @@
+use fuzzy_matcher::clangd::ClangdMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use serde::Deserialize;
+
+/// Scores how well a clip matches a search. Higher is better, `None` is no match.
+pub trait Matcher: Send + Sync {
+    fn score(&self, text: &str, pattern: &str) -> Option<i64>;
+
+    /// Char indices in `text` of the characters matching the pattern.
+    fn indices(&self, text: &str, pattern: &str) -> Option<Vec<usize>>;
+}
+
+/// The matchers that can be chosen in the config.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatcherKind {
+    Skim,
+    Clangd,
+    Substring,
+}
+
+impl MatcherKind {
+    pub fn matcher(&self) -> Box<dyn Matcher> {
+        match self {
+            MatcherKind::Skim => Box::new(Fuzzy(SkimMatcherV2::default().smart_case())),
+            MatcherKind::Clangd => Box::new(Fuzzy(ClangdMatcher::default())),
+            MatcherKind::Substring => Box::new(Substring),
+        }
+    }
+}
+
+struct Fuzzy<M: FuzzyMatcher>(M);
+
+impl<M: FuzzyMatcher> Matcher for Fuzzy<M> {
+    fn score(&self, text: &str, pattern: &str) -> Option<i64> {
+        self.0.fuzzy_match(text, pattern)
+    }
+
+    fn indices(&self, text: &str, pattern: &str) -> Option<Vec<usize>> {
+        self.0.fuzzy_indices(text, pattern).map(|(_, indices)| indices)
+    }
+}
+
+/// Plain substring search, ignoring case unless the pattern has upper case
+/// letters. Earlier matches score higher.
+struct Substring;
+
+impl Substring {
+    // char index of the first match
+    fn find(text: &str, pattern: &str) -> Option<usize> {
+        if pattern.chars().any(char::is_uppercase) {
+            text.find(pattern).map(|p| text[..p].chars().count())
+        } else {
+            let lower = text.to_lowercase();
+            lower.find(&pattern.to_lowercase()).map(|p| lower[..p].chars().count())
+        }
+    }
+}
+
+impl Matcher for Substring {
+    fn score(&self, text: &str, pattern: &str) -> Option<i64> {
+        Substring::find(text, pattern).map(|p| -(p as i64))
+    }
+
+    fn indices(&self, text: &str, pattern: &str) -> Option<Vec<usize>> {
+        Substring::find(text, pattern).map(|p| (p..p + pattern.chars().count()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matcher::MatcherKind;
+
+    #[test]
+    fn substring() {
+        let matcher = MatcherKind::Substring.matcher();
+        assert!(matcher.score("hello world", "lo w").is_some());
+        assert!(matcher.score("hello world", "hw").is_none());
+        assert!(matcher.score("Hello", "hello").is_some());
+        assert!(matcher.score("hello", "Hello").is_none());
+        assert!(matcher.score("abc", "a") > matcher.score("abc", "c"));
+        assert_eq!(matcher.indices("åbcd", "CD"), None);
+        assert_eq!(matcher.indices("åbcd", "cd"), Some(vec![2, 3]));
+    }
+
+    #[test]
+    fn fuzzy() {
+        for kind in [MatcherKind::Skim, MatcherKind::Clangd] {
+            let matcher = kind.matcher();
+            assert!(matcher.score("hello world", "hwd").is_some());
+            assert!(matcher.score("hello world", "xyz").is_none());
+            assert_eq!(matcher.indices("hello world", "hwd"), Some(vec![0, 6, 10]));
+        }
+    }
+}
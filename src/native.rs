@@ -0,0 +1,165 @@
+use std::error::Error;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{options, rpc};
+
+// Chrome refuses to send more than this to a native host
+const MAX_MESSAGE: u32 = 64 * 1024 * 1024;
+
+/// What the browser extension sends for each copy made in a page.
+#[derive(Debug, PartialEq, Deserialize)]
+struct Copy {
+    text: String,
+    url: String,
+    #[serde(default)]
+    title: Option<String>,
+    /// Which browser, e.g. "firefox"
+    #[serde(default)]
+    browser: Option<String>,
+}
+
+/// The answer to each copy.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Reply {
+    /// The id of the clip in the history
+    Id(usize),
+    Error(String),
+}
+
+/// Whether the arguments are the ones a browser starts a native messaging
+/// host with: Chrome passes the extension's origin, and Firefox the path to
+/// the host's manifest and the extension's id, which the manifest has to
+/// allow.
+pub fn launched_by_browser(args: &[String]) -> bool {
+    match args.get(1..).unwrap_or_default() {
+        // on Windows, Chrome adds the window it was started from
+        [origin, rest @ ..] if chrome_origin(origin) => rest.iter().all(|arg| arg.starts_with("--parent-window=")),
+        [manifest, extension] => Path::new(manifest).is_absolute() && firefox_manifest_allows(Path::new(manifest), extension),
+        _ => false,
+    }
+}
+
+// An origin like chrome-extension://abcdefghijklmnopabcdefghijklmnop/, whose
+// extension id is 32 letters from a to p
+fn chrome_origin(arg: &str) -> bool {
+    arg.strip_prefix("chrome-extension://")
+        .and_then(|rest| rest.strip_suffix('/'))
+        .is_some_and(|id| id.len() == 32 && id.bytes().all(|b| (b'a'..=b'p').contains(&b)))
+}
+
+// Whether the file is a native messaging manifest for stdio that lets the
+// extension in
+fn firefox_manifest_allows(manifest: &Path, extension: &str) -> bool {
+    let Ok(contents) = std::fs::read_to_string(manifest) else { return false };
+    let Ok(manifest) = serde_json::from_str::<serde_json::Value>(&contents) else { return false };
+    manifest["type"] == "stdio"
+        && manifest["allowed_extensions"]
+            .as_array()
+            .is_some_and(|allowed| allowed.iter().any(|id| id == extension))
+}
+
+/// Speaks the native messaging protocol on stdin and stdout until the browser
+/// closes them, adding the copies the extension tells about to the history.
+pub async fn run() -> Result<(), Box<dyn Error>> {
+    let client = rpc::create_client(options::socket_path()).await?;
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    while let Some(message) = read_message(&mut stdin).await? {
+        let reply = match serde_json::from_slice::<Copy>(&message) {
+            Ok(copy) => {
+                let added = client
+                    .add_from_page(tarpc::context::current(), copy.text, copy.browser, copy.url, copy.title)
+                    .await?;
+                match added {
+                    Ok(id) => Reply::Id(id),
+                    Err(e) => Reply::Error(e),
+                }
+            }
+            Err(e) => Reply::Error(format!("not a copy: {}", e)),
+        };
+        write_message(&mut stdout, &serde_json::to_vec(&reply)?).await?;
+    }
+    Ok(())
+}
+
+// Each message is JSON after its length, as a 32 bit native endian integer.
+// None once the browser closes the pipe.
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_ne_bytes(len);
+    if len > MAX_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message of {} bytes is too long", len)));
+    }
+    let mut message = vec![0u8; len as usize];
+    reader.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    writer.write_all(&(message.len() as u32).to_ne_bytes()).await?;
+    writer.write_all(message).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::native::{launched_by_browser, read_message, write_message, Copy, Reply};
+
+    #[tokio::test]
+    async fn framing() {
+        let mut framed = Vec::new();
+        write_message(&mut framed, br#"{"text":"hi","url":"https://example.com"}"#).await.unwrap();
+        write_message(&mut framed, b"{}").await.unwrap();
+
+        let mut reader = &framed[..];
+        let first = read_message(&mut reader).await.unwrap().unwrap();
+        let copy: Copy = serde_json::from_slice(&first).unwrap();
+        assert_eq!(copy.text, "hi");
+        assert_eq!(copy.title, None);
+        assert_eq!(read_message(&mut reader).await.unwrap().unwrap(), b"{}");
+        assert_eq!(read_message(&mut reader).await.unwrap(), None);
+
+        // a length beyond what browsers send
+        let mut reader = &u32::MAX.to_ne_bytes()[..];
+        assert!(read_message(&mut reader).await.is_err());
+    }
+
+    #[test]
+    fn replies_and_arguments() {
+        assert_eq!(serde_json::to_string(&Reply::Id(4)).unwrap(), r#"{"id":4}"#);
+        assert_eq!(serde_json::to_string(&Reply::Error("no".to_owned())).unwrap(), r#"{"error":"no"}"#);
+
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let origin = "chrome-extension://abcdefghijklmnopabcdefghijklmnop/";
+        assert!(launched_by_browser(&args(&["rpt", origin])));
+        assert!(launched_by_browser(&args(&["rpt", origin, "--parent-window=0"])));
+        assert!(!launched_by_browser(&args(&["rpt", origin, "list"])));
+        assert!(!launched_by_browser(&args(&["rpt", "chrome-extension://abcdef/"])));
+        assert!(!launched_by_browser(&args(&["rpt", "list"])));
+        assert!(!launched_by_browser(&args(&["rpt"])));
+
+        let dir = std::env::temp_dir().join(format!("repeat-native-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("rpt.json");
+        std::fs::write(&manifest, r#"{"name": "rpt", "type": "stdio", "allowed_extensions": ["rpt@example.com"]}"#).unwrap();
+        let manifest = manifest.to_str().unwrap();
+        assert!(launched_by_browser(&args(&["rpt", manifest, "rpt@example.com"])));
+        assert!(!launched_by_browser(&args(&["rpt", manifest, "other@example.com"])));
+        // say `rpt import` of an export, which isn't a manifest
+        let export = dir.join("clips.json");
+        std::fs::write(&export, r#"{"schema_version": 1, "clips": []}"#).unwrap();
+        assert!(!launched_by_browser(&args(&["rpt", export.to_str().unwrap(), "rpt@example.com"])));
+        assert!(!launched_by_browser(&args(&["rpt", "import", "clips.json"])));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
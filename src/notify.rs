@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+// The same problem is told about at most this often
+const REPEAT_AFTER: Duration = Duration::from_secs(10 * 60);
+
+static SENT: LazyLock<Mutex<HashMap<String, Instant>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Shows a desktop notification about a problem the user should know about
+/// even without looking at the log, unless one with the same summary was
+/// shown recently.
+pub fn problem(summary: &str, body: &str) {
+    if !due(&mut SENT.lock().unwrap(), summary, Instant::now()) {
+        debug!("already told about {:?} recently", summary);
+        return;
+    }
+    let mut command = Command::new("notify-send");
+    command.args(["--app-name=rpt", "--urgency=critical", summary, body]);
+    // waited for on a thread of its own, so a slow notification daemon
+    // doesn't hold us up
+    std::thread::spawn(move || {
+        if let Err(e) = command.status() {
+            warn!("unable to show a notification with notify-send: {}", e);
+        }
+    });
+}
+
+// Whether a notification with the summary may be shown at `now`, recording
+// it if so
+fn due(sent: &mut HashMap<String, Instant>, summary: &str, now: Instant) -> bool {
+    match sent.get(summary) {
+        Some(at) if now.duration_since(*at) < REPEAT_AFTER => false,
+        _ => {
+            sent.insert(summary.to_owned(), now);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use crate::notify::{due, REPEAT_AFTER};
+
+    #[test]
+    fn rate_limited() {
+        let mut sent = HashMap::new();
+        let start = Instant::now();
+        assert!(due(&mut sent, "disk full", start));
+        assert!(!due(&mut sent, "disk full", start + Duration::from_secs(1)));
+        assert!(due(&mut sent, "something else", start + Duration::from_secs(1)));
+        assert!(due(&mut sent, "disk full", start + REPEAT_AFTER));
+    }
+}
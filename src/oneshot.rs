@@ -0,0 +1,122 @@
+use std::error::Error;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use breadx::prelude::*;
+use breadx::rt_support::tokio_support;
+use log::info;
+
+use crate::clipboard::Clipboard;
+use crate::crypt::Key;
+use crate::db::{ClipContents, Database};
+use crate::events::{self, Context};
+use crate::history::SearchHistory;
+use crate::options::{self, Options};
+use crate::persist;
+use crate::secrets;
+use crate::snippets::{self, Snippets};
+use crate::store::Writer;
+use crate::trust::{self, TrustStore};
+use crate::ui::{Surface, Surfaces, View, Window};
+
+/// How long to keep serving after a paste, for requests that go with it,
+/// like the rest of a large clip.
+const LINGER: Duration = Duration::from_secs(1);
+/// How often a paste is checked on, while nothing else happens.
+const PASTE_CHECK: Duration = Duration::from_millis(100);
+
+/// Shows the picker over the saved history without a daemon, then owns the
+/// selections with the chosen clip until it has been pasted, or someone else
+/// takes them. With `print` the chosen clip is printed instead. Nothing is
+/// captured, and the history on disk is left as it is.
+pub async fn run(print: bool) -> Result<(), Box<dyn Error>> {
+    let mut options = Options::load()?;
+    // capturing is the daemon's job
+    options.monitor_selections.clear();
+    if options.lock_memory {
+        secrets::protect();
+    }
+
+    let path = options::data_dir().join(persist::FILE);
+    let key = options.encryption_key_command.as_deref().map(Key::from_command).transpose()?;
+    let database = Arc::new(
+        Database::with_matcher(options.matcher)
+            .with_preview_bytes(options.preview_bytes)
+            .with_max_clips(options.max_clips)
+            .with_ttl((options.ttl > 0).then(|| Duration::from_secs(options.ttl)))
+            .with_history(&path, key.as_ref())?,
+    );
+    // the daemon may not have been running to forget them
+    database.remove_expired(SystemTime::now());
+    if database.clips().is_empty() {
+        return Err(format!("there are no saved clips in {} to choose from", path.display()).into());
+    }
+    // the one the daemon was offering isn't a choice made here
+    database.clear_selection();
+    let trust = Arc::new(TrustStore::load(options::data_dir().join(trust::FILE)));
+    let snippets = Arc::new(Snippets::load(options::data_dir().join(snippets::FILE)));
+    let keymap = match print {
+        true => options.keys.clone().without_paste(),
+        false => options.keys.clone(),
+    };
+
+    let mut dpy = tokio_support::connect(None).await?;
+    let mut clipboard = Clipboard::new(&mut dpy, database.clone(), trust, &options).await?;
+    let mut surfaces = Surfaces::new(View { sort: options.sort, zoom: 0.0 });
+    let picker = Window::create(
+        &mut dpy,
+        database.clone(),
+        Arc::new(SearchHistory::in_memory()),
+        snippets,
+        Arc::new(Mutex::new(keymap)),
+        surfaces.view(),
+        &options,
+    ).await?;
+    surfaces.open(Surface::Picker(Box::new(picker)));
+
+    let mut bus = events::Bus::default();
+    bus.add(events::ServerTime);
+    bus.add(events::Windows { writer: Arc::new(Writer::immediate()), flush_on_select: false });
+    bus.add(events::Selections { options: options.clone() });
+
+    while surfaces.picker().is_some() {
+        let event = dpy.wait_for_event().await?;
+        let mut cx = Context { display: &mut dpy, clipboard: &mut clipboard, surfaces: &mut surfaces };
+        bus.dispatch(&mut cx, &event).await?;
+    }
+    let clip = database.selection().ok_or("no clip was chosen")?;
+
+    if print {
+        let mut stdout = std::io::stdout();
+        match clip.contents.as_ref() {
+            ClipContents::Binary { data, .. } => stdout.write_all(data)?,
+            contents => stdout.write_all(contents.text().as_bytes())?,
+        }
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    info!("serving the chosen clip until it's pasted");
+    while clipboard.owns_selection() {
+        let event = match clipboard.served() {
+            // the paste may need another try
+            0 if clipboard.pasting() => match tokio::time::timeout(PASTE_CHECK, dpy.wait_for_event()).await {
+                Ok(event) => event?,
+                Err(_) => {
+                    clipboard.check_paste(&mut dpy).await?;
+                    continue;
+                }
+            },
+            0 => dpy.wait_for_event().await?,
+            // done once the paste has gone quiet
+            _ => match tokio::time::timeout(LINGER, dpy.wait_for_event()).await {
+                Ok(event) => event?,
+                Err(_) => break,
+            },
+        };
+        let mut cx = Context { display: &mut dpy, clipboard: &mut clipboard, surfaces: &mut surfaces };
+        bus.dispatch(&mut cx, &event).await?;
+    }
+    Ok(())
+}
@@ -1,16 +1,464 @@
-#[derive(Clone)]
+use crate::clipboard::SyncMode;
+use crate::keys::Keymap;
+use crate::db::{SortMode, Source};
+use crate::matcher::MatcherKind;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// Problems found while deserializing are reported by toml with the line and
+// column they're at, so validation happens there rather than afterwards
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Options {
+    #[serde(deserialize_with = "positive_f32")]
     pub font_size: f32,
     pub font_name: Option<String>,
-    //pub theme: Theme,
+    /// Columns a tab is expanded to
+    #[serde(deserialize_with = "positive_usize")]
+    pub tab_width: usize,
+    /// Show tabs as ⇥ and trailing spaces as ␣
+    pub show_whitespace: bool,
+    /// Show ¶ at the end of every line that is followed by a line break
+    pub show_line_ends: bool,
+    /// Mirror the latest clip into CUT_BUFFER0 on the root window for
+    /// applications that predate selections. Any client can read it.
+    pub cut_buffer: bool,
+    /// Which algorithm scores searches
+    pub matcher: MatcherKind,
+    /// Additional targets to offer when serving a clip
+    pub extra_targets: Vec<ExtraTarget>,
+    /// Ask before serving a clip to an application for the first time
+    pub confirm_new_apps: bool,
+    pub theme: Theme,
+    /// Changes to the picker's key bindings
+    pub keys: Keymap,
+    /// Read out the highlighted clip with speech-dispatcher
+    pub speech: bool,
+    /// Seconds between writing changes to disk, 0 writes them straight away
+    pub flush_interval: u64,
+    /// Also write changes when a clip is chosen in the picker
+    pub flush_on_select: bool,
+    /// Milliseconds within which showing the picker a second time pastes the
+    /// newest clip instead, or 0 to never do that
+    pub quick_paste: u64,
+    /// Settings for particular monitors, by RandR output name
+    pub monitor: HashMap<String, Monitor>,
+    /// How many bytes of each clip are searched and previewed
+    #[serde(deserialize_with = "positive_usize")]
+    pub preview_bytes: usize,
+    /// Seconds after which clips are forgotten, unless pinned or archived,
+    /// or 0 to keep them until they're evicted
+    pub ttl: u64,
+    /// Keep clips out of swap, and wipe them from memory once they're gone
+    pub lock_memory: bool,
+    /// A shell command printing the age identity the history is encrypted
+    /// with, or none to keep it unencrypted
+    pub encryption_key_command: Option<String>,
+    /// How many clips the history keeps, not counting archived ones
+    #[serde(deserialize_with = "positive_usize")]
+    pub max_clips: usize,
+    /// Tags given to new clips whose text matches a pattern
+    pub tag_rules: Vec<TagRule>,
+    /// How the picker first orders clips when nothing is searched
+    pub sort: SortMode,
+    /// List pinned clips above the rest in the picker, whatever the order
+    pub pinned_first: bool,
+    /// The selections a chosen clip is put into
+    #[serde(deserialize_with = "some_selections")]
+    pub own_selections: Vec<Source>,
+    /// The selections clips are captured from
+    pub monitor_selections: Vec<Source>,
+    /// Put clips captured from CLIPBOARD into PRIMARY too, or both ways
+    pub sync: SyncMode,
+    /// Ask i3 or sway which window is focused rather than X
+    pub wm_ipc: bool,
+    /// Show clips that are nearly the same as a newer one as part of it
+    pub collapse_similar: bool,
+    /// Milliseconds between looking at the selections when the X server
+    /// lacks XFIXES to tell about changes
+    #[serde(deserialize_with = "positive_u64")]
+    pub poll_interval: u64,
+    /// Pastes from one kind of application into another that have to be
+    /// confirmed by choosing the clip a second time
+    pub paste_guards: Vec<PasteGuard>,
+    /// Applications whose copies are never kept, by their WM_CLASS class
+    pub ignore_apps: Vec<AppPattern>,
+}
+
+/// Overrides for the picker when it opens on a given monitor, say a bigger
+/// font on a high DPI one.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Monitor {
+    #[serde(deserialize_with = "positive_f32")]
+    pub font_size: f32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            font_size: 20f32,
+            font_name: Some("Monospace".to_owned()),
+            tab_width: 4,
+            show_whitespace: false,
+            show_line_ends: false,
+            cut_buffer: false,
+            matcher: MatcherKind::Clangd,
+            extra_targets: Vec::new(),
+            confirm_new_apps: false,
+            theme: Theme::default(),
+            keys: Keymap::default(),
+            speech: false,
+            flush_interval: 5,
+            flush_on_select: true,
+            quick_paste: 0,
+            monitor: HashMap::new(),
+            preview_bytes: crate::db::DEFAULT_PREVIEW_BYTES,
+            max_clips: crate::db::DEFAULT_MAX_CLIPS,
+            ttl: 0,
+            lock_memory: false,
+            encryption_key_command: None,
+            tag_rules: Vec::new(),
+            sort: SortMode::Recency,
+            pinned_first: true,
+            own_selections: vec![Source::Primary, Source::Clipboard],
+            monitor_selections: vec![Source::Primary, Source::Secondary, Source::Clipboard],
+            sync: SyncMode::Off,
+            wm_ipc: false,
+            collapse_similar: false,
+            poll_interval: 500,
+            paste_guards: Vec::new(),
+            ignore_apps: Vec::new(),
+        }
+    }
+}
+
+/// A target that is advertised next to the clip's own, e.g. `text/x-moz-url`.
+/// Its data is either static, or the output of a shell command that gets the
+/// clip on stdin.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawExtraTarget")]
+pub struct ExtraTarget {
+    pub target: String,
+    pub data: Option<String>,
+    pub command: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawExtraTarget {
+    target: String,
+    data: Option<String>,
+    command: Option<String>,
+}
+
+impl TryFrom<RawExtraTarget> for ExtraTarget {
+    type Error = String;
+
+    fn try_from(raw: RawExtraTarget) -> Result<ExtraTarget, String> {
+        if raw.target.is_empty() {
+            return Err("the target name can't be empty".to_owned());
+        }
+        match (&raw.data, &raw.command) {
+            (Some(_), Some(_)) => Err(format!("target {} has both data and a command", raw.target)),
+            (None, None) => Err(format!("target {} needs either data or a command", raw.target)),
+            _ => Ok(ExtraTarget { target: raw.target, data: raw.data, command: raw.command }),
+        }
+    }
+}
+
+/// Tags clips matching the regex as they're captured.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawTagRule")]
+pub struct TagRule {
+    pub pattern: Regex,
+    pub tag: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTagRule {
+    pattern: String,
+    tag: String,
+}
+
+impl TryFrom<RawTagRule> for TagRule {
+    type Error = String;
+
+    fn try_from(raw: RawTagRule) -> Result<TagRule, String> {
+        crate::db::check_tag(&raw.tag)?;
+        let pattern = Regex::new(&raw.pattern).map_err(|e| format!("invalid pattern for tag {}: {}", raw.tag, e))?;
+        Ok(TagRule { pattern, tag: raw.tag })
+    }
+}
+
+/// Pasting a clip copied from one of the `from` applications into one of
+/// the `to` ones has to be confirmed, e.g. from a password manager into a
+/// browser. Applications are given by their WM_CLASS class, matched
+/// regardless of case.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawPasteGuard")]
+pub struct PasteGuard {
+    pub from: Vec<String>,
+    pub to: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPasteGuard {
+    from: Vec<String>,
+    to: Vec<String>,
+}
+
+impl TryFrom<RawPasteGuard> for PasteGuard {
+    type Error = String;
+
+    fn try_from(raw: RawPasteGuard) -> Result<PasteGuard, String> {
+        if raw.from.is_empty() || raw.to.is_empty() {
+            return Err("a paste guard needs at least one application on each side".to_owned());
+        }
+        if raw.from.iter().chain(&raw.to).any(|app| app.is_empty()) {
+            return Err("application names can't be empty".to_owned());
+        }
+        Ok(PasteGuard { from: raw.from, to: raw.to })
+    }
+}
+
+impl PasteGuard {
+    pub fn guards(&self, from: &str, to: &str) -> bool {
+        self.from.iter().any(|app| app.eq_ignore_ascii_case(from))
+            && self.to.iter().any(|app| app.eq_ignore_ascii_case(to))
+    }
+}
+
+/// A regex that has to match the whole WM_CLASS class of an application,
+/// regardless of case, so `KeePassXC` is just that one and `.*term` any
+/// terminal named so.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AppPattern(Regex);
+
+impl TryFrom<String> for AppPattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<AppPattern, String> {
+        Regex::new(&format!("(?i)^(?:{})$", pattern))
+            .map(AppPattern)
+            .map_err(|e| format!("invalid application pattern {}: {}", pattern, e))
+    }
+}
+
+impl AppPattern {
+    pub fn matches(&self, class: &str) -> bool {
+        self.0.is_match(class)
+    }
+}
+
+fn positive_f32<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let value = f32::deserialize(deserializer)?;
+    if value > 0.0 {
+        Ok(value)
+    } else {
+        Err(serde::de::Error::custom(format!("has to be greater than 0, not {}", value)))
+    }
+}
+
+fn fraction<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let value = f32::deserialize(deserializer)?;
+    if (0.0..=1.0).contains(&value) {
+        Ok(value)
+    } else {
+        Err(serde::de::Error::custom(format!("has to be between 0 and 1, not {}", value)))
+    }
+}
+
+fn some_selections<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Source>, D::Error> {
+    let selections = Vec::<Source>::deserialize(deserializer)?;
+    if selections.is_empty() {
+        return Err(serde::de::Error::custom("needs at least one selection, or nothing could be pasted"));
+    }
+    Ok(selections)
+}
+
+fn positive_usize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    match usize::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("has to be greater than 0")),
+        value => Ok(value),
+    }
+}
+
+fn positive_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("has to be greater than 0")),
+        value => Ok(value),
+    }
+}
+
+impl Options {
+    /// Reads the config file, falling back to the defaults if there is none.
+    pub fn load() -> Result<Options, Box<dyn Error>> {
+        let path = config_path();
+        if !path.exists() {
+            return Ok(Options::default());
+        }
+        let contents = std::fs::read_to_string(&path)?;
+        Options::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(contents: &str) -> Result<Options, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    /// The options with the overrides for the monitor applied, if it has any.
+    pub fn for_output(&self, output: Option<&str>) -> Options {
+        let mut options = self.clone();
+        if let Some(monitor) = output.and_then(|output| self.monitor.get(output)) {
+            options.font_size = monitor.font_size;
+        }
+        options
+    }
+}
+
+/// The flag that overrides `max_clips` for one run of the daemon.
+pub const MAX_CLIPS_FLAG: &str = "--max-clips";
+
+/// The flag that keeps state in another directory, for any command.
+pub const DATA_DIR_FLAG: &str = "--data-dir";
+
+// set with `--data-dir`
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// Takes the flag and the value after it out of the arguments, if it's there
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, String> {
+    let Some(pos) = args.iter().position(|a| a == flag) else {
+        return Ok(None);
+    };
+    args.remove(pos);
+    if pos >= args.len() {
+        return Err(format!("{} needs a value", flag));
+    }
+    Ok(Some(args.remove(pos)))
+}
+
+/// Takes `--max-clips <n>` out of the arguments, if it's there.
+pub fn take_max_clips(args: &mut Vec<String>) -> Result<Option<usize>, String> {
+    match take_flag(args, MAX_CLIPS_FLAG)? {
+        None => Ok(None),
+        Some(max) => match max.parse() {
+            Ok(max) if max > 0 => Ok(Some(max)),
+            _ => Err(format!("{} needs a number of clips above 0, not {}", MAX_CLIPS_FLAG, max)),
+        },
+    }
+}
+
+/// Takes `--data-dir <dir>` out of the arguments and makes `data_dir` the
+/// directory from then on.
+pub fn take_data_dir(args: &mut Vec<String>) -> Result<(), String> {
+    if let Some(dir) = take_flag(args, DATA_DIR_FLAG)? {
+        if dir.is_empty() {
+            return Err(format!("{} needs a directory", DATA_DIR_FLAG));
+        }
+        let dir = std::path::absolute(&dir).map_err(|e| format!("{} {}: {}", DATA_DIR_FLAG, dir, e))?;
+        let _ = DATA_DIR.set(dir);
+    }
+    Ok(())
+}
+
+// A base directory from the XDG variable, which has to be absolute to count,
+// or the default under the home directory
+fn xdg_dir(var: Option<OsString>, default: &str) -> PathBuf {
+    match var.map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        // falls back on the password database without HOME, rather than
+        // ending up relative to wherever rpt was started
+        _ => env::home_dir().unwrap_or_else(|| PathBuf::from("/")).join(default),
+    }
+}
+
+/// Where state such as the search history is kept: `--data-dir`, or
+/// `$XDG_DATA_HOME/repeat`.
+pub fn data_dir() -> PathBuf {
+    match DATA_DIR.get() {
+        Some(dir) => dir.clone(),
+        None => xdg_dir(env::var_os("XDG_DATA_HOME"), ".local/share").join("repeat"),
+    }
+}
+
+pub fn spool_dir() -> PathBuf {
+    data_dir().join("spool")
+}
+
+/// The daemon's socket, in `$XDG_RUNTIME_DIR/repeat`.
+pub fn socket_path() -> PathBuf {
+    // SAFETY: getuid can't fail
+    socket_dir(env::var_os("XDG_RUNTIME_DIR"), unsafe { libc::getuid() }).join("repeat.socket")
+}
+
+// Without a runtime directory, one of the user's own in the temporary
+// directory, which the daemon makes sure only the user can get into
+fn socket_dir(var: Option<OsString>, uid: u32) -> PathBuf {
+    match var.map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir.join("repeat"),
+        _ => env::temp_dir().join(format!("repeat-{}", uid)),
+    }
 }
 
+/// `$XDG_CONFIG_HOME/repeat/config.toml`
+pub fn config_path() -> PathBuf {
+    xdg_dir(env::var_os("XDG_CONFIG_HOME"), ".config").join("repeat").join("config.toml")
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
 pub struct Color {
     pub red: f32,
     pub green: f32,
     pub blue: f32,
 }
 
+impl FromStr for Color {
+    type Err = String;
+
+    /// Parses `#rrggbb`, or one of the named colors.
+    fn from_str(s: &str) -> Result<Color, String> {
+        match s {
+            "red" => return Ok(Color::red()),
+            "white" => return Ok(Color::white()),
+            "yellow" => return Ok(Color::yellow()),
+            "green" => return Ok(Color::green()),
+            _ => {}
+        }
+        let invalid = || format!("{:?} isn't a color, use #rrggbb or red, white, yellow or green", s);
+        let hex = s.strip_prefix('#').filter(|h| h.len() == 6).ok_or_else(invalid)?;
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                .map(f32::from)
+                .ok_or_else(invalid)
+        };
+        Ok(Color {
+            red: channel(0)?,
+            green: channel(2)?,
+            blue: channel(4)?,
+        })
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Color, String> {
+        s.parse()
+    }
+}
+
 impl Color {
     pub fn red() -> Color {
         Color {
@@ -28,6 +476,14 @@ impl Color {
         }
     }
 
+    pub fn yellow() -> Color {
+        Color {
+            red: 255f32,
+            green: 255f32,
+            blue: 0f32,
+        }
+    }
+
     pub fn green() -> Color {
         Color {
             red: 0f32,
@@ -37,8 +493,164 @@ impl Color {
     }
 }
 
+/// Colors of the picker.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Theme {
-    text: Color,
-    highlight: Color,
-    background: Color,
+    /// Clips
+    pub text: Color,
+    /// The chosen clip
+    pub highlight: Color,
+    /// Clips in the paste queue
+    pub queued: Color,
+    /// The search, prompts and the status bar
+    pub input: Color,
+    /// How dark the screen behind the picker is made, from 0 (not at all) to
+    /// 1 (black). Needs a compositor.
+    #[serde(deserialize_with = "fraction")]
+    pub backdrop: f32,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            text: Color::white(),
+            highlight: Color::green(),
+            queued: Color::yellow(),
+            input: Color::red(),
+            backdrop: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+
+    use crate::options::{socket_dir, take_max_clips, xdg_dir, Color, Options};
+
+    fn error(config: &str) -> String {
+        Options::parse(config).err().expect("should be invalid").to_string()
+    }
+
+    #[test]
+    fn valid() {
+        let options = Options::parse(
+            "tab_width = 8\n[theme]\nhighlight = \"#ff8000\"\n[[extra_targets]]\ntarget = \"x\"\ndata = \"y\"\n",
+        ).unwrap();
+        assert_eq!(options.tab_width, 8);
+        assert_eq!(options.theme.highlight, Color { red: 255.0, green: 128.0, blue: 0.0 });
+        assert_eq!(options.theme.text, Color::white());
+        assert_eq!(options.extra_targets.len(), 1);
+    }
+
+    #[test]
+    fn max_clips() {
+        assert_eq!(Options::parse("max_clips = 10000").unwrap().max_clips, 10000);
+        assert!(error("max_clips = 0").contains("max_clips"));
+
+        let mut args: Vec<String> = ["rpt", "--max-clips", "500"].iter().map(|a| a.to_string()).collect();
+        assert_eq!(take_max_clips(&mut args), Ok(Some(500)));
+        assert_eq!(args, vec!["rpt"]);
+        assert_eq!(take_max_clips(&mut args), Ok(None));
+        assert!(take_max_clips(&mut vec!["rpt".to_owned(), "--max-clips".to_owned()]).is_err());
+        assert!(take_max_clips(&mut vec!["rpt".to_owned(), "--max-clips".to_owned(), "0".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn socket_dirs() {
+        assert_eq!(socket_dir(Some(OsString::from("/run/user/1000")), 1000), PathBuf::from("/run/user/1000/repeat"));
+        for var in [None, Some(OsString::from("run"))] {
+            assert_eq!(socket_dir(var, 1000), env::temp_dir().join("repeat-1000"));
+        }
+    }
+
+    #[test]
+    fn xdg_dirs() {
+        assert_eq!(xdg_dir(Some(OsString::from("/xdg/data")), ".local/share"), PathBuf::from("/xdg/data"));
+        // relative ones don't count
+        for var in [None, Some(OsString::new()), Some(OsString::from("data"))] {
+            let dir = xdg_dir(var, ".local/share");
+            assert!(dir.is_absolute());
+            assert!(dir.ends_with(".local/share"));
+        }
+    }
+
+    #[test]
+    fn paste_guards() {
+        let options = Options::parse(
+            "[[paste_guards]]\nfrom = [\"KeePassXC\", \"Alacritty\"]\nto = [\"firefox\"]\n",
+        ).unwrap();
+        let guard = &options.paste_guards[0];
+        assert!(guard.guards("keepassxc", "Firefox"));
+        assert!(guard.guards("Alacritty", "firefox"));
+        assert!(!guard.guards("firefox", "KeePassXC"));
+        assert!(!guard.guards("Alacritty", "Chromium"));
+    }
+
+    #[test]
+    fn ignored_apps() {
+        let options = Options::parse("ignore_apps = [\"KeePassXC\", \".*term\"]").unwrap();
+        let ignored = |class: &str| options.ignore_apps.iter().any(|app| app.matches(class));
+        assert!(ignored("keepassxc"));
+        assert!(ignored("XTerm"));
+        assert!(!ignored("KeePassXC-Browser"));
+        assert!(!ignored("firefox"));
+    }
+
+    #[test]
+    fn monitors() {
+        let options = Options::parse("font_size = 20
+monitor.DP-1.font_size = 28
+").unwrap();
+        assert_eq!(options.for_output(Some("DP-1")).font_size, 28.0);
+        assert_eq!(options.for_output(Some("HDMI-1")).font_size, 20.0);
+        assert_eq!(options.for_output(None).font_size, 20.0);
+
+        let size = error("[monitor.DP-1]
+font_size = -1
+");
+        assert!(size.contains("line 2, column 13"), "{}", size);
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let unknown = error("font_size = 12.0\nfont_sise = 12.0\n");
+        assert!(unknown.contains("line 2, column 1"), "{}", unknown);
+        assert!(unknown.contains("unknown field `font_sise`"), "{}", unknown);
+
+        let color = error("[theme]\ntext = \"#12345g\"\n");
+        assert!(color.contains("line 2, column 8"), "{}", color);
+        assert!(color.contains("isn't a color"), "{}", color);
+
+        let tab_width = error("tab_width = 0");
+        assert!(tab_width.contains("line 1, column 13"), "{}", tab_width);
+
+        let target = error("[[extra_targets]]\ntarget = \"x\"\n");
+        assert!(target.contains("needs either data or a command"), "{}", target);
+
+        let own = Options::parse("own_selections = [\"clipboard\"]").unwrap().own_selections;
+        assert_eq!(own, vec![crate::db::Source::Clipboard]);
+        let monitored = Options::parse("monitor_selections = [\"clipboard\"]").unwrap().monitor_selections;
+        assert_eq!(monitored, vec![crate::db::Source::Clipboard]);
+        assert!(error("monitor_selections = [\"primry\"]").contains("unknown variant"));
+        let none = error("own_selections = []");
+        assert!(none.contains("at least one selection"), "{}", none);
+
+        let rule = error("[[tag_rules]]\npattern = \"(\"\ntag = \"x\"\n");
+        assert!(rule.contains("invalid pattern for tag x"), "{}", rule);
+        let tag = error("[[tag_rules]]\npattern = \"x\"\ntag = \"a b\"\n");
+        assert!(tag.contains("single word"), "{}", tag);
+
+        let guard = error("[[paste_guards]]\nfrom = [\"KeePassXC\"]\nto = []\n");
+        assert!(guard.contains("at least one application"), "{}", guard);
+
+        let app = error("ignore_apps = [\"KeePassXC\", \"(\"]");
+        assert!(app.contains("invalid application pattern ("), "{}", app);
+
+        let backdrop = error("[theme]\nbackdrop = 1.5\n");
+        assert!(backdrop.contains("between 0 and 1"), "{}", backdrop);
+    }
 }
@@ -0,0 +1,481 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::crypt::{self, Key};
+use crate::db::{self, Clip, ClipContents, Html, Origin, Page, Source, Trace};
+use crate::secrets;
+use crate::store::Schema;
+
+pub const FILE: &str = "clips.toml";
+// next to the history, with the contents of each clip, see `SavedClip::file`
+const CLIPS_DIR: &str = "clips";
+const CLIP_EXTENSION: &str = "toml";
+
+// version 1 keeps the contents of each clip in a file of its own, but still
+// reads ones kept in the history
+pub const SCHEMA: Schema = Schema {
+    name: "clips",
+    migrations: &[|_| Ok(())],
+};
+
+/// Reads the history kept at `path`, or with a key the encrypted one next to
+/// it. A history that hasn't been encrypted yet is read as it is.
+pub fn load(path: &Path, key: Option<&Key>) -> Result<Option<HistoryFile>, Box<dyn Error>> {
+    let encrypted = crypt::encrypted_path(path);
+    let Some(key) = key.filter(|_| encrypted.exists()) else {
+        if key.is_none() && encrypted.exists() {
+            warn!("{} is encrypted, but there's no encryption_key_command to read it with", encrypted.display());
+        }
+        let file: Option<HistoryFile> = SCHEMA.load(path)?;
+        return Ok(file.map(|file| read_clips(file, path, None)));
+    };
+    let mut plain = key.decrypt(&encrypted)?;
+    let file = match std::str::from_utf8(&plain) {
+        Ok(contents) => SCHEMA.decode(contents),
+        Err(e) => Err(e.into()),
+    };
+    secrets::release_vec(&mut plain);
+    file.map(|file| Some(read_clips(file, path, Some(key))))
+}
+
+// Fills in the contents of the clips kept in files of their own. Ones that
+// can't be read are left without, so they're skipped when restored.
+fn read_clips(mut file: HistoryFile, path: &Path, key: Option<&Key>) -> HistoryFile {
+    let dir = clips_dir(path);
+    for saved in &mut file.clips {
+        let Some(name) = saved.file.take() else { continue };
+        let contents = read_clip(&dir.join(&name), key);
+        match contents {
+            Ok(contents) => contents.fill(saved),
+            Err(e) => warn!("unable to read the clip in {}: {}", dir.join(&name).display(), e),
+        }
+    }
+    file
+}
+
+fn read_clip(path: &Path, key: Option<&Key>) -> Result<ClipFile, Box<dyn Error>> {
+    if path.extension().is_some_and(|extension| extension == CLIP_EXTENSION) {
+        let contents = std::fs::read_to_string(path)?;
+        return Ok(toml::from_str(&contents)?);
+    }
+    let key = key.ok_or("the clip is encrypted, but there's no key to read it with")?;
+    let mut plain = key.decrypt(path)?;
+    let contents = match std::str::from_utf8(&plain) {
+        Ok(contents) => toml::from_str(contents).map_err(Into::into),
+        Err(e) => Err(e.into()),
+    };
+    secrets::release_vec(&mut plain);
+    contents
+}
+
+/// The history as `load` reads it back, without the contents of the clips,
+/// encrypted with the key if there is one.
+pub fn encode(file: &HistoryFile, key: Option<&Key>) -> Result<Vec<u8>, Box<dyn Error>> {
+    encrypt(SCHEMA.encode(file)?, key)
+}
+
+/// The file the contents of the clip are kept in, see `clip_path`.
+pub fn encode_clip(clip: &Clip, key: Option<&Key>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let plain = toml::to_string(&ClipFile::from(SavedClip::from_clip(clip)))?.into_bytes();
+    encrypt(plain, key)
+}
+
+fn encrypt(mut plain: Vec<u8>, key: Option<&Key>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let Some(key) = key else { return Ok(plain) };
+    let encrypted = key.encrypt(&plain);
+    secrets::release_vec(&mut plain);
+    encrypted
+}
+
+/// The directory the contents of the clips of the history at `path` are
+/// kept in.
+pub fn clips_dir(path: &Path) -> PathBuf {
+    path.with_file_name(CLIPS_DIR)
+}
+
+/// Where the contents of the clip with the checksum are kept, encrypted or
+/// not. Clips are named by the checksum taken when they were captured, so
+/// their contents needn't be hashed again.
+pub fn clip_path(path: &Path, checksum: u64, encrypted: bool) -> PathBuf {
+    clips_dir(path).join(clip_name(checksum, encrypted))
+}
+
+fn clip_name(checksum: u64, encrypted: bool) -> String {
+    let plain = PathBuf::from(format!("{:016x}.{}", checksum, CLIP_EXTENSION));
+    let name = if encrypted { crypt::encrypted_path(&plain) } else { plain };
+    name.to_string_lossy().into_owned()
+}
+
+/// The checksums of the clips kept in the directory, either encrypted or not.
+pub fn stored_clips(dir: &Path, encrypted: bool) -> HashSet<u64> {
+    let Ok(entries) = std::fs::read_dir(dir) else { return HashSet::new() };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| {
+            let checksum = u64::from_str_radix(name.get(..16)?, 16).ok()?;
+            (clip_name(checksum, encrypted) == name).then_some(checksum)
+        })
+        .collect()
+}
+
+/// The history as it's kept in the data directory, oldest clip first.
+#[derive(Default, Serialize, Deserialize)]
+pub struct HistoryFile {
+    /// Index in `clips` of the clip that is pasted
+    #[serde(default)]
+    pub selection: Option<usize>,
+    /// The id the next clip gets, so ids of removed clips aren't reused
+    #[serde(default)]
+    pub next_id: usize,
+    #[serde(default)]
+    pub clips: Vec<SavedClip>,
+}
+
+/// A clip and what the history knows about it. Exactly one of `text`,
+/// `files` and `data` is set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedClip {
+    /// Its id in the history it was saved from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
+    pub source: Source,
+    /// Seconds since the epoch
+    pub captured: u64,
+    /// The checksum taken when it was captured, in hex since TOML's
+    /// integers are signed
+    pub checksum: String,
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Files as a `text/uri-list`, since paths needn't be UTF-8
+    #[serde(default)]
+    pub files: Option<String>,
+    #[serde(default)]
+    pub mime: Option<String>,
+    /// Binary data in hex
+    #[serde(default)]
+    pub data: Option<String>,
+    /// Binary data in base64 instead, as exported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base64: Option<String>,
+    #[serde(default)]
+    pub html: Option<String>,
+    #[serde(default)]
+    pub app: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub page_title: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub uses: usize,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub archived: bool,
+    /// When it's forgotten, in seconds since the epoch
+    #[serde(default)]
+    pub expires: Option<u64>,
+    /// Index in the history of the clip it was edited from
+    #[serde(default)]
+    pub parent: Option<usize>,
+    #[serde(default)]
+    pub origin: Option<Origin>,
+    #[serde(default)]
+    pub trace: Option<Trace>,
+    /// The file in the `clips` directory its contents are kept in, instead
+    /// of in the history
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+// The contents of a clip, kept apart from the history so that they're only
+// written once
+#[derive(Serialize, Deserialize)]
+struct ClipFile {
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    files: Option<String>,
+    #[serde(default)]
+    mime: Option<String>,
+    #[serde(default)]
+    data: Option<String>,
+    #[serde(default)]
+    html: Option<String>,
+}
+
+impl From<SavedClip> for ClipFile {
+    fn from(saved: SavedClip) -> ClipFile {
+        ClipFile { text: saved.text, files: saved.files, mime: saved.mime, data: saved.data, html: saved.html }
+    }
+}
+
+impl ClipFile {
+    fn fill(self, saved: &mut SavedClip) {
+        saved.text = self.text;
+        saved.files = self.files;
+        saved.mime = self.mime;
+        saved.data = self.data;
+        saved.html = self.html;
+    }
+}
+
+impl SavedClip {
+    /// The clip alone, without what the history knows about it.
+    pub fn from_clip(clip: &Clip) -> SavedClip {
+        let (text, files, mime, data) = match clip.contents.as_ref() {
+            ClipContents::Text(text) => (Some(text.clone()), None, None, None),
+            ClipContents::Files(_) => (None, clip.contents.uri_list(), None, None),
+            ClipContents::Binary { mime, data } => (None, None, Some(mime.clone()), Some(hex(data))),
+        };
+        SavedClip {
+            text,
+            files,
+            mime,
+            data,
+            html: clip.html.as_ref().map(|html| html.0.clone()),
+            ..SavedClip::about(clip)
+        }
+    }
+
+    /// Like `from_clip`, but with the contents left to the file `encode_clip`
+    /// makes.
+    pub fn stored(clip: &Clip, encrypted: bool) -> SavedClip {
+        SavedClip { file: Some(clip_name(clip.checksum, encrypted)), ..SavedClip::about(clip) }
+    }
+
+    // The clip without its contents
+    fn about(clip: &Clip) -> SavedClip {
+        SavedClip {
+            id: None,
+            source: clip.source,
+            captured: seconds(clip.captured),
+            checksum: format!("{:016x}", clip.checksum),
+            text: None,
+            files: None,
+            mime: None,
+            data: None,
+            base64: None,
+            html: None,
+            app: clip.app.clone(),
+            url: clip.page.as_ref().map(|page| page.url.clone()),
+            page_title: clip.page.as_ref().and_then(|page| page.title.clone()),
+            tags: Vec::new(),
+            uses: 0,
+            pinned: false,
+            archived: false,
+            expires: None,
+            parent: None,
+            origin: clip.origin.as_deref().cloned(),
+            trace: clip.trace.as_deref().cloned(),
+            file: None,
+        }
+    }
+
+    /// The clip as it was captured. Its checksum is the one taken then, so
+    /// a clip that changed on disk isn't `intact`.
+    pub fn to_clip(&self) -> Result<Clip, String> {
+        let data = match (&self.data, &self.base64) {
+            (Some(hex), None) => Some(unhex(hex)?),
+            (None, Some(base64)) => Some(unbase64(base64)?),
+            (None, None) => None,
+            (Some(_), Some(_)) => return Err("a clip can't have data in both hex and base64".to_owned()),
+        };
+        let contents = match (&self.text, &self.files, &self.mime, data) {
+            (Some(text), None, None, None) => ClipContents::Text(text.clone()),
+            (None, Some(files), None, None) => {
+                ClipContents::Files(db::parse_uri_list(files).ok_or_else(|| format!("{:?} isn't a list of files", files))?)
+            }
+            (None, None, Some(mime), Some(data)) => ClipContents::Binary { mime: mime.clone(), data },
+            _ => return Err("a clip needs either text, files, or a mime type and data".to_owned()),
+        };
+        let mut clip = Clip::new(self.source, contents);
+        clip.captured = SystemTime::UNIX_EPOCH + Duration::from_secs(self.captured);
+        clip.checksum = u64::from_str_radix(&self.checksum, 16).map_err(|_| format!("{:?} isn't a checksum", self.checksum))?;
+        clip.html = self.html.clone().map(|html| Arc::new(Html(html)));
+        clip.app = self.app.clone();
+        clip.page = self.url.clone().map(|url| Arc::new(Page { url, title: self.page_title.clone() }));
+        clip.origin = self.origin.clone().map(Arc::new);
+        clip.trace = self.trace.clone().map(Arc::new);
+        Ok(clip)
+    }
+}
+
+/// A history as `rpt export` writes it, in JSON: the saved clips, oldest
+/// first, with binary data in base64 rather than hex. Which clip was being
+/// pasted isn't part of it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Export {
+    pub schema_version: usize,
+    pub clips: Vec<SavedClip>,
+}
+
+impl Export {
+    pub fn new(file: HistoryFile) -> Result<Export, String> {
+        let clips = file
+            .clips
+            .into_iter()
+            .map(|mut clip| {
+                // they'd mean nothing to the history it's imported into
+                clip.id = None;
+                clip.base64 = clip.data.take().map(|hex| unhex(&hex).map(|data| base64(&data))).transpose()?;
+                Ok(clip)
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Export { schema_version: SCHEMA.version(), clips })
+    }
+
+    /// The history to import.
+    pub fn into_history(self) -> Result<HistoryFile, String> {
+        if self.schema_version > SCHEMA.version() {
+            return Err(format!(
+                "the export is version {}, but this version of repeat only knows up to {}",
+                self.schema_version, SCHEMA.version()
+            ));
+        }
+        Ok(HistoryFile { selection: None, next_id: 0, clips: self.clips })
+    }
+}
+
+pub fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("binary data has an odd number of hex digits".to_owned());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("binary data has {:?} in it", hex.get(i..i + 2).unwrap_or("")))
+        })
+        .collect()
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn unbase64(encoded: &str) -> Result<Vec<u8>, String> {
+    let encoded = encoded.trim_end_matches('=');
+    let mut data = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in encoded.chars() {
+        let value = BASE64
+            .iter()
+            .position(|b| char::from(*b) == c)
+            .ok_or_else(|| format!("binary data has {:?} in it", c))?;
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    // what's left over is padding, which is less than a byte
+    if count >= 6 {
+        return Err("binary data in base64 has a character too many".to_owned());
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::db::{Clip, ClipContents, Source};
+    use crate::persist::{base64, hex, unbase64, unhex, Export, HistoryFile, SavedClip, SCHEMA};
+
+    #[test]
+    fn hex_data() {
+        assert_eq!(hex(&[0, 0x7f, 0xff]), "007fff");
+        assert_eq!(unhex("007fff").unwrap(), vec![0, 0x7f, 0xff]);
+        assert!(unhex("0").is_err());
+        assert!(unhex("zz").is_err());
+        assert!(unhex("é0").is_err());
+    }
+
+    #[test]
+    fn base64_data() {
+        for (data, encoded) in [(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"\xff\x00\xfe\x10", "/wD+EA==")] {
+            assert_eq!(base64(data), encoded);
+            assert_eq!(unbase64(encoded).unwrap(), data);
+        }
+        assert_eq!(unbase64("Zm8").unwrap(), b"fo");
+        assert!(unbase64("Zm9v!").is_err());
+        assert!(unbase64("Zm9vZ").is_err());
+    }
+
+    #[test]
+    fn exports() {
+        let clips = [
+            Clip::new(Source::Clipboard, ClipContents::Text("text".to_owned())),
+            Clip::new(Source::Primary, ClipContents::Binary { mime: "image/png".to_owned(), data: vec![0x89, b'P', 0] }),
+        ];
+        let file = HistoryFile { selection: Some(1), next_id: 0, clips: clips.iter().map(SavedClip::from_clip).collect() };
+        let export = Export::new(file).unwrap();
+        assert_eq!(export.clips[1].data, None);
+        assert_eq!(export.clips[1].base64.as_deref(), Some("iVAA"));
+
+        let json = serde_json::to_string(&export).unwrap();
+        let imported: Export = serde_json::from_str(&json).unwrap();
+        let history = imported.into_history().unwrap();
+        assert_eq!(history.selection, None);
+        for (saved, clip) in history.clips.iter().zip(&clips) {
+            let restored = saved.to_clip().unwrap();
+            assert_eq!(restored.contents, clip.contents);
+            assert!(restored.intact());
+        }
+
+        let newer = Export { schema_version: SCHEMA.version() + 1, clips: Vec::new() };
+        assert!(newer.into_history().is_err());
+    }
+
+    #[test]
+    fn clips_round_trip() {
+        let clips = [
+            Clip::new(Source::Clipboard, ClipContents::Text("text\n\0with a nul".to_owned())),
+            Clip::new(Source::Primary, ClipContents::Files(vec![PathBuf::from("/tmp/a file")])),
+            Clip::new(Source::Clipboard, ClipContents::Binary { mime: "image/png".to_owned(), data: vec![0x89, b'P', 0] }),
+        ];
+        for clip in clips {
+            let restored = SavedClip::from_clip(&clip).to_clip().unwrap();
+            assert_eq!(restored.contents, clip.contents);
+            assert_eq!(restored.checksum, clip.checksum);
+            assert!(restored.intact());
+        }
+
+        let mut saved = SavedClip::from_clip(&Clip::new(Source::Clipboard, ClipContents::Text("original".to_owned())));
+        saved.text = Some("changed on disk".to_owned());
+        assert!(!saved.to_clip().unwrap().intact());
+        saved.files = Some("file:///tmp".to_owned());
+        assert!(saved.to_clip().is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+/// The flag that puts whole clips in the logs, for debugging.
+pub const FLAG: &str = "--log-clip-bodies";
+
+static LOG_BODIES: AtomicBool = AtomicBool::new(false);
+
+/// Logs clip contents as they are from now on, instead of redacted.
+pub fn log_bodies() {
+    LOG_BODIES.store(true, Ordering::Relaxed);
+}
+
+/// Clip contents as they should appear in logs: only their size and a hash,
+/// unless bodies are logged. The hash still tells whether two log lines of
+/// the same run are about the same clip. It's keyed with a random key each
+/// run, so that a short password can't be found from it by trying them all.
+pub struct Redacted<'a>(&'a [u8]);
+
+pub fn body(text: &str) -> Redacted<'_> {
+    Redacted(text.as_bytes())
+}
+
+pub fn bytes(data: &[u8]) -> Redacted<'_> {
+    Redacted(data)
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if LOG_BODIES.load(Ordering::Relaxed) {
+            return write!(f, "{:?}", String::from_utf8_lossy(self.0));
+        }
+        static KEY: OnceLock<RandomState> = OnceLock::new();
+        let hash = KEY.get_or_init(RandomState::new).hash_one(self.0);
+        write!(f, "<{} bytes, hash {:016x}>", self.0.len(), hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    use crate::redact::body;
+
+    #[test]
+    fn redacted() {
+        let secret = body("hunter2").to_string();
+        assert!(!secret.contains("hunter2"), "{}", secret);
+        assert!(secret.starts_with("<7 bytes, hash "), "{}", secret);
+        assert_eq!(secret, body("hunter2").to_string());
+        assert_ne!(secret, body("hunter3").to_string());
+
+        // not the well-known unkeyed hash
+        let mut hasher = DefaultHasher::new();
+        b"hunter2"[..].hash(&mut hasher);
+        assert!(!secret.contains(&format!("{:016x}", hasher.finish())), "{}", secret);
+    }
+}
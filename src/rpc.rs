@@ -1,83 +1,711 @@
 use std::error::Error;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use futures::channel::mpsc::Sender;
-use futures::{future, prelude::*};
-use log::debug;
-use tarpc::serde_transport::unix::listen;
+use futures::channel::oneshot;
+use futures::prelude::*;
+use log::{debug, info, warn};
 use tarpc::server::Channel;
 use tarpc::tokio_serde::formats::Json;
+use tarpc::tokio_util::codec::LengthDelimitedCodec;
 use tarpc::{client, context, server};
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixListener;
 use tokio::sync::Mutex as AsyncMutex;
 
+use crate::clipboard::{self, AtomStats, SyncMode};
+use crate::db::{self, Clip, ClipContents, Counts, Database, Origin, Page, SortMode, Source, Trace};
+use crate::persist::Export;
+use crate::incognito;
+use crate::inject;
+use crate::keys::{Action, KeyCombo, Keymap};
+use crate::store;
+use crate::trust::{Trust, TrustStore};
+
 #[tarpc::service]
 pub trait Manager {
-    async fn show();
-    async fn pause();
-    async fn start();
+    async fn show() -> Result<(), String>;
+    /// Stops capturing clips from the selection, or from all of them.
+    async fn pause(selection: Option<Source>) -> Result<(), String>;
+    /// Starts capturing clips from the selection again, or from all of them.
+    async fn start(selection: Option<Source>) -> Result<(), String>;
+    /// Gives up our selections and forgets the current clip, pausing capture
+    /// too if `pause` is set.
+    async fn panic(pause: bool) -> Result<(), String>;
+    /// Pastes the newest clip into the focused window without the picker.
+    async fn paste_last() -> Result<(), String>;
+    /// Opens the watch window, or closes it if it's open.
+    async fn watch() -> Result<(), String>;
+    /// Lists the history, or the clips matching `query` best first.
+    async fn list(sort: SortMode, query: Option<String>) -> Vec<ListedClip>;
+    /// Details about the clip with the id `list` gave it.
+    async fn inspect(id: usize) -> Option<InspectedClip>;
+    /// The remembered decisions about applications, by WM_CLASS.
+    async fn trusted() -> Vec<(String, Trust)>;
+    /// Remembers the decision about an application, or forgets it with `None`.
+    async fn set_trust(class: String, trust: Option<Trust>) -> Result<(), String>;
+    /// Makes a key in the picker do the action, like `bind("ctrl+q", "close")`.
+    async fn bind(key: String, action: String) -> Result<(), String>;
+    /// Makes a key in the picker do nothing.
+    async fn unbind(key: String) -> Result<(), String>;
+    /// The picker's key bindings.
+    async fn bindings() -> Vec<(String, Action)>;
+    /// Tags the clip with the id `list` gave it.
+    async fn tag(id: usize, tag: String) -> Result<(), String>;
+    /// Removes a tag from the clip with the id `list` gave it.
+    async fn untag(id: usize, tag: String) -> Result<(), String>;
+    /// Owns a selection with the clip with the id `list` gave it. Without
+    /// `to`, a clip from PRIMARY goes to CLIPBOARD and the others to PRIMARY.
+    async fn promote(id: usize, to: Option<Source>) -> Result<(), String>;
+    /// Adds a clip to the history as if it had been copied, for tools that
+    /// don't go through the X clipboard. `source_app` is shown as where it
+    /// came from, and the clip is forgotten after `ttl_secs` if given.
+    /// Returns the clip's id.
+    async fn add_clip(contents: String, source_app: Option<String>, tags: Vec<String>, ttl_secs: Option<u64>) -> Result<usize, String>;
+    /// Adds a clip copied in a browser, with the page it was copied from.
+    /// Returns the clip's id.
+    async fn add_from_page(contents: String, browser: Option<String>, url: String, title: Option<String>) -> Result<usize, String>;
+    /// Starts or stops capturing clips from a selection.
+    async fn monitor(selection: Source, enabled: bool) -> Result<(), String>;
+    /// Changes whether PRIMARY and CLIPBOARD are kept in sync.
+    async fn sync(mode: SyncMode) -> Result<(), String>;
+    /// Pins the clip with the id `list` gave it, or unpins it.
+    async fn pin(id: usize, pinned: bool) -> Result<(), String>;
+    /// The `n` newest clips in full, for editors showing them as registers.
+    async fn get_recent(n: usize) -> Vec<Register>;
+    /// The `n` best matches for the query in full, best first.
+    async fn search(query: String, n: usize) -> Vec<Register>;
+    /// Makes the clip with the hash a `Register` gave the one that is pasted.
+    async fn select_by_hash(hash: String) -> Result<(), String>;
+    /// How the daemon is doing.
+    async fn status() -> Status;
+    /// Archives the clip with the id `list` gave it, or takes it out of the
+    /// archive. Archived clips are only listed with `archived:` in the query.
+    async fn archive(id: usize, archived: bool) -> Result<(), String>;
+    /// Turns incognito mode on or off: while it's on, nothing is captured
+    /// and none of the history is shown or listed.
+    async fn incognito(enabled: bool) -> Result<(), String>;
+    /// Forgets the clip with the id `list` gave it after `ttl_secs`, or
+    /// takes away its own time to live with `None`.
+    async fn expire(id: usize, ttl_secs: Option<u64>) -> Result<(), String>;
+    /// Fetches what's in the monitored selections again, in case a clip
+    /// was missed.
+    async fn resync() -> Result<(), String>;
+    /// Tags the clip being offered, or else the newest one. Returns its id.
+    async fn tag_current(tag: String) -> Result<usize, String>;
+    /// The whole history with what's known about each clip, for backups.
+    async fn export_history() -> Result<Export, String>;
+    /// Adds the clips of an exported history that aren't in the history
+    /// yet, behind the ones that are. Returns how many were added.
+    async fn import_history(export: Export) -> Result<usize, String>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    /// Clips in the history
+    pub clips: usize,
+    /// Why changes to the data directory are only kept in memory, if they are
+    pub store: Option<String>,
+    #[serde(default)]
+    pub incognito: bool,
+    /// How pastes get into each application a way has been found for, by
+    /// WM_CLASS class
+    #[serde(default)]
+    pub paste_methods: Vec<(String, String)>,
+    #[serde(default)]
+    pub atoms: AtomStats,
+}
+
+/// A clip in full, with a hash of its contents to refer to it by, which
+/// unlike its id doesn't change while it's in the history.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Register {
+    /// 16 hex digits
+    pub hash: String,
+    pub id: usize,
+    pub source: String,
+    /// Seconds since the clip was captured
+    pub age: u64,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListedClip {
+    pub id: usize,
+    pub source: String,
+    /// Seconds since the clip was captured
+    pub age: u64,
+    /// The start of the clip, as much as the daemon previews
+    pub text: String,
+    /// Lines in the whole clip
+    pub lines: usize,
+    pub tags: Vec<String>,
+    /// Char indices of the characters matching the query
+    pub matched: Vec<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InspectedClip {
+    pub id: usize,
+    pub source: String,
+    /// Seconds since the clip was captured
+    pub age: u64,
+    pub counts: Counts,
+    pub uses: usize,
+    pub pinned: bool,
+    #[serde(default)]
+    pub archived: bool,
+    pub tags: Vec<String>,
+    pub trace: Option<Trace>,
+    /// The application that added the clip with `add_clip`
+    #[serde(default)]
+    pub app: Option<String>,
+    /// The URL and title of the page the clip was copied from
+    #[serde(default)]
+    pub page: Option<(String, Option<String>)>,
+    /// The window the clip was copied from
+    #[serde(default)]
+    pub origin: Option<Origin>,
+    /// Ids of the earlier versions the clip was edited from, newest first
+    #[serde(default)]
+    pub chain: Vec<usize>,
+    /// Seconds until the clip is forgotten, if it will be
+    #[serde(default)]
+    pub expires_in: Option<u64>,
 }
 
 #[derive(Clone)]
 struct Server {
-    sender: Arc<AsyncMutex<Sender<Message>>>,
+    sender: Arc<AsyncMutex<Sender<Command>>>,
+    database: Arc<Database>,
+    trust: Arc<TrustStore>,
+    keymap: Arc<Mutex<Keymap>>,
+}
+
+/// How long a command may wait for room in the queue to the main loop
+/// before the client is told the daemon is too busy.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(2);
+
+impl Server {
+    // Hands the message to the main loop and waits for it to be carried out
+    async fn send(&self, message: Message) -> Result<(), String> {
+        let (reply, replied) = oneshot::channel();
+        let command = Command { message, reply };
+        let mut sender = self.sender.lock().await;
+        match tokio::time::timeout(QUEUE_TIMEOUT, sender.send(command)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err("rpt is shutting down".to_owned()),
+            Err(_) => {
+                warn!("the command queue is full");
+                return Err(format!("rpt is busy, {} commands are queued already", QUEUE_SIZE));
+            }
+        }
+        drop(sender);
+        replied.await.unwrap_or_else(|_| Err("rpt stopped before carrying out the command".to_owned()))
+    }
+
+    // Whether the history is hidden from whoever asks, by incognito mode
+    fn hidden(&self) -> bool {
+        incognito::enabled()
+    }
+
+    fn registers(&self, clips: &[Clip]) -> Vec<Register> {
+        let now = SystemTime::now();
+        clips
+            .iter()
+            .zip(self.database.ids_of(clips))
+            .map(|(clip, id)| Register {
+                hash: format!("{:016x}", clip.contents.stable_hash()),
+                id: id.unwrap_or_default(),
+                source: clip.source.name().to_owned(),
+                age: now.duration_since(clip.captured).unwrap_or_default().as_secs(),
+                text: clip.contents.text().into_owned(),
+            })
+            .collect()
+    }
+
+    // Adds a clip from a tool rather than the clipboard, and tells its id.
+    // A clip that was in the history already keeps what it had.
+    fn add(&self, clip: Clip) -> Result<usize, String> {
+        if self.hidden() {
+            return Err("incognito mode is on, so nothing is added to the history".to_owned());
+        }
+        if clip.contents.size() == 0 {
+            return Err("can't add an empty clip".to_owned());
+        }
+        self.database.add_clip(clip.clone());
+        self.database.id_of(&clip).ok_or_else(|| "the clip was dropped from the history".to_owned())
+    }
+}
+
+// When a clip given a time to live of `ttl` seconds now is forgotten
+fn expiry(ttl: u64) -> Result<SystemTime, String> {
+    SystemTime::now()
+        .checked_add(Duration::from_secs(ttl))
+        .ok_or_else(|| format!("a time to live of {} seconds is too long", ttl))
+}
+
+/// The longest message either end takes, well above the default of 8 MiB,
+/// since an exported history with images in it is a single message.
+pub const MAX_FRAME_LENGTH: usize = 1 << 30;
+
+/// How many commands can wait for the main loop.
+pub const QUEUE_SIZE: usize = 10;
+
+/// A message for the main loop, and where to tell how carrying it out went.
+pub struct Command {
+    pub message: Message,
+    pub reply: oneshot::Sender<Result<(), String>>,
 }
 
 #[derive(Debug)]
 pub enum Message {
     Show,
     Own,
-    Pause,
-    Start,
+    Pause(Option<Source>),
+    Start(Option<Source>),
+    Panic { pause: bool },
+    PasteLast,
+    Promote { clip: Clip, to: Source },
+    Monitor { selection: Source, enabled: bool },
+    Resync,
+    Sync(SyncMode),
+    Watch,
 }
 
 #[tarpc::server]
 impl Manager for Server {
-    async fn show(self, _: context::Context) {
+    async fn show(self, _: context::Context) -> Result<(), String> {
         debug!("showing window");
-        let _ = self.sender.lock().await.send(Message::Show).await;
+        self.send(Message::Show).await?;
         debug!("showed window");
+        Ok(())
+    }
+
+    async fn pause(self, _: context::Context, selection: Option<Source>) -> Result<(), String> {
+        self.send(Message::Pause(selection)).await
+    }
+
+    async fn start(self, _: context::Context, selection: Option<Source>) -> Result<(), String> {
+        self.send(Message::Start(selection)).await
+    }
+
+    async fn panic(self, _: context::Context, pause: bool) -> Result<(), String> {
+        self.send(Message::Panic { pause }).await
+    }
+
+    async fn paste_last(self, _: context::Context) -> Result<(), String> {
+        self.send(Message::PasteLast).await
+    }
+
+    async fn watch(self, _: context::Context) -> Result<(), String> {
+        self.send(Message::Watch).await
+    }
+
+    async fn list(self, _: context::Context, sort: SortMode, query: Option<String>) -> Vec<ListedClip> {
+        if self.hidden() {
+            return Vec::new();
+        }
+        let clips = match &query {
+            Some(query) => self.database.search(query, usize::MAX),
+            None => self.database.sorted(sort, usize::MAX),
+        };
+        let now = SystemTime::now();
+        clips
+            .iter()
+            .zip(self.database.ids_of(&clips))
+            .map(|(clip, id)| {
+                let text = clip.contents.preview(self.database.preview_bytes()).into_owned();
+                let matched = query
+                    .as_ref()
+                    .and_then(|q| self.database.matcher().indices(&text, &self.database.search_text(q)))
+                    .unwrap_or_default();
+                ListedClip {
+                    id: id.unwrap_or_default(),
+                    source: clip.source.name().to_owned(),
+                    age: now.duration_since(clip.captured).unwrap_or_default().as_secs(),
+                    text,
+                    lines: clip.counts.lines,
+                    tags: self.database.tags(clip),
+                    matched,
+                }
+            })
+            .collect()
+    }
+
+    async fn inspect(self, _: context::Context, id: usize) -> Option<InspectedClip> {
+        if self.hidden() {
+            return None;
+        }
+        let clip = self.database.at(id)?;
+        Some(InspectedClip {
+            id,
+            source: clip.source.name().to_owned(),
+            age: SystemTime::now().duration_since(clip.captured).unwrap_or_default().as_secs(),
+            counts: clip.counts,
+            uses: self.database.uses(&clip),
+            pinned: self.database.is_pinned(&clip),
+            archived: self.database.is_archived(&clip),
+            tags: self.database.tags(&clip),
+            trace: clip.trace.as_deref().cloned(),
+            app: clip.app.clone(),
+            page: clip.page.as_ref().map(|page| (page.url.clone(), page.title.clone())),
+            origin: clip.origin.as_deref().cloned(),
+            chain: self.database.chain(&clip).iter().skip(1).filter_map(|c| self.database.id_of(c)).collect(),
+            expires_in: self
+                .database
+                .expires(&clip)
+                .map(|at| at.duration_since(SystemTime::now()).unwrap_or_default().as_secs()),
+        })
+    }
+
+    async fn trusted(self, _: context::Context) -> Vec<(String, Trust)> {
+        self.trust.all()
+    }
+
+    async fn set_trust(self, _: context::Context, class: String, trust: Option<Trust>) -> Result<(), String> {
+        self.trust.set(&class, trust).map_err(|e| e.to_string())
+    }
+
+    async fn bind(self, _: context::Context, key: String, action: String) -> Result<(), String> {
+        let key: KeyCombo = key.parse()?;
+        self.keymap.lock().unwrap().bind(key, action.parse()?)
+    }
+
+    async fn unbind(self, _: context::Context, key: String) -> Result<(), String> {
+        let combo: KeyCombo = key.parse()?;
+        if self.keymap.lock().unwrap().unbind(combo) {
+            Ok(())
+        } else {
+            Err(format!("{} isn't bound", key))
+        }
+    }
+
+    async fn bindings(self, _: context::Context) -> Vec<(String, Action)> {
+        self.keymap.lock().unwrap().all()
+    }
+
+    async fn tag(self, _: context::Context, id: usize, tag: String) -> Result<(), String> {
+        db::check_tag(&tag)?;
+        if self.database.tag(id, &tag) {
+            Ok(())
+        } else {
+            Err(format!("there is no clip {}", id))
+        }
+    }
+
+    async fn promote(self, _: context::Context, id: usize, to: Option<Source>) -> Result<(), String> {
+        let clip = self.database.at(id).ok_or_else(|| format!("there is no clip {}", id))?;
+        let to = to.unwrap_or_else(|| clip.source.other());
+        self.send(Message::Promote { clip, to }).await
+    }
+
+    async fn add_clip(
+        self,
+        _: context::Context,
+        contents: String,
+        source_app: Option<String>,
+        tags: Vec<String>,
+        ttl_secs: Option<u64>,
+    ) -> Result<usize, String> {
+        for tag in &tags {
+            db::check_tag(tag)?;
+        }
+        let expires = ttl_secs.map(expiry).transpose()?;
+        let mut clip = Clip::new(Source::Clipboard, ClipContents::Text(contents));
+        clip.app = source_app;
+        // one that's in the history already keeps how long it's kept
+        let existed = self.database.id_of(&clip).is_some();
+        let id = self.add(clip)?;
+        for tag in &tags {
+            self.database.tag(id, tag);
+        }
+        match expires {
+            Some(_) if existed => info!("not giving clip {} a time to live, it was in the history already", id),
+            Some(at) => {
+                let clip = self.database.at(id).ok_or("the clip was dropped from the history")?;
+                self.database.expire_at(&clip, at);
+            }
+            None => {}
+        }
+        Ok(id)
+    }
+
+    async fn add_from_page(
+        self,
+        _: context::Context,
+        contents: String,
+        browser: Option<String>,
+        url: String,
+        title: Option<String>,
+    ) -> Result<usize, String> {
+        let mut clip = Clip::new(Source::Clipboard, ClipContents::Text(contents));
+        clip.app = browser;
+        clip.page = Some(Arc::new(Page { url, title }));
+        self.add(clip)
+    }
+
+    async fn monitor(self, _: context::Context, selection: Source, enabled: bool) -> Result<(), String> {
+        self.send(Message::Monitor { selection, enabled }).await
+    }
+
+    async fn sync(self, _: context::Context, mode: SyncMode) -> Result<(), String> {
+        self.send(Message::Sync(mode)).await
+    }
+
+    async fn pin(self, _: context::Context, id: usize, pinned: bool) -> Result<(), String> {
+        let changed = if pinned { self.database.pin(id) } else { self.database.unpin(id) };
+        match (changed, pinned) {
+            (true, _) => Ok(()),
+            (false, true) => Err(format!("there is no clip {}", id)),
+            (false, false) => Err(format!("clip {} isn't pinned", id)),
+        }
+    }
+
+    async fn expire(self, _: context::Context, id: usize, ttl_secs: Option<u64>) -> Result<(), String> {
+        let clip = self.database.at(id).ok_or_else(|| format!("there is no clip {}", id))?;
+        match ttl_secs {
+            Some(ttl) => {
+                self.database.expire_at(&clip, expiry(ttl)?);
+                Ok(())
+            }
+            None if self.database.keep(&clip) => Ok(()),
+            None => Err(format!("clip {} has no time to live of its own", id)),
+        }
+    }
+
+    async fn resync(self, _: context::Context) -> Result<(), String> {
+        self.send(Message::Resync).await
+    }
+
+    async fn get_recent(self, _: context::Context, n: usize) -> Vec<Register> {
+        if self.hidden() {
+            return Vec::new();
+        }
+        self.registers(&self.database.sorted(SortMode::Recency, n))
+    }
+
+    async fn search(self, _: context::Context, query: String, n: usize) -> Vec<Register> {
+        if self.hidden() {
+            return Vec::new();
+        }
+        self.registers(&self.database.search(&query, n))
+    }
+
+    async fn select_by_hash(self, _: context::Context, hash: String) -> Result<(), String> {
+        let parsed = u64::from_str_radix(&hash, 16).map_err(|_| format!("{:?} isn't a hash of a clip", hash))?;
+        let clip = self.database.find_by_hash(parsed).ok_or_else(|| format!("there is no clip with the hash {}", hash))?;
+        self.database.select_clip(clip);
+        self.send(Message::Own).await
+    }
+
+    async fn status(self, _: context::Context) -> Status {
+        Status {
+            clips: self.database.clips().len(),
+            store: store::degraded(),
+            incognito: incognito::enabled(),
+            paste_methods: inject::learned().into_iter().map(|(class, method)| (class, method.to_string())).collect(),
+            atoms: clipboard::atom_stats(),
+        }
+    }
+
+    async fn archive(self, _: context::Context, id: usize, archived: bool) -> Result<(), String> {
+        match (self.database.archive(id, archived), self.database.at(id)) {
+            (true, _) => Ok(()),
+            (false, None) => Err(format!("there is no clip {}", id)),
+            (false, Some(_)) if archived => Err(format!("clip {} is archived already", id)),
+            (false, Some(_)) => Err(format!("clip {} isn't archived", id)),
+        }
+    }
+
+    async fn incognito(self, _: context::Context, enabled: bool) -> Result<(), String> {
+        incognito::set(enabled);
+        Ok(())
     }
 
-    async fn pause(self, _: context::Context) {
-        let _ = self.sender.lock().await.send(Message::Pause).await;
+    async fn untag(self, _: context::Context, id: usize, tag: String) -> Result<(), String> {
+        if self.database.untag(id, &tag) {
+            Ok(())
+        } else {
+            Err(format!("clip {} isn't tagged {}", id, tag))
+        }
     }
 
-    async fn start(self, _: context::Context) {
-        let _ = self.sender.lock().await.send(Message::Start).await;
+    async fn tag_current(self, _: context::Context, tag: String) -> Result<usize, String> {
+        db::check_tag(&tag)?;
+        if self.hidden() {
+            return Err("the history is hidden while in incognito mode".to_owned());
+        }
+        let id = self.database.current().and_then(|clip| self.database.id_of(&clip)).ok_or("there are no clips")?;
+        self.database.tag(id, &tag);
+        Ok(id)
+    }
+
+    async fn export_history(self, _: context::Context) -> Result<Export, String> {
+        if self.hidden() {
+            return Err("the history is hidden while in incognito mode".to_owned());
+        }
+        Export::new(self.database.export())
+    }
+
+    async fn import_history(self, _: context::Context, export: Export) -> Result<usize, String> {
+        Ok(self.database.import(export.into_history()?))
     }
 }
 
 pub async fn start_server<P: AsRef<Path>>(
     path: P,
-    sender: Sender<Message>,
+    sender: Sender<Command>,
+    database: Arc<Database>,
+    trust: Arc<TrustStore>,
+    keymap: Arc<Mutex<Keymap>>,
 ) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.as_ref().parent() {
+        prepare_socket_dir(dir)?;
+    }
     if path.as_ref().exists() {
         std::fs::remove_file(&path)?;
     }
 
     let asender = Arc::new(AsyncMutex::new(sender));
-    let listener = listen(path, Json::default).await?;
-    tokio::spawn(
-        listener
-            .filter_map(|r| future::ready(r.ok()))
-            .map(server::BaseChannel::with_defaults)
-            .map(move |channel| {
-                let server = Server {
-                    sender: asender.clone(),
-                };
-                channel.execute(server.serve())
-            })
-            .buffer_unordered(10)
-            .for_each(|_| async {}),
-    );
+    let listener = UnixListener::bind(&path)?;
+    // we just created the socket, so it's owned by the user running us, who
+    // is the only one let in
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    let owner = std::fs::metadata(&path)?.uid();
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("unable to accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let uid = match stream.peer_cred() {
+                Ok(cred) => cred.uid(),
+                Err(e) => {
+                    warn!("unable to tell who connected: {}", e);
+                    continue;
+                }
+            };
+            if uid != owner {
+                warn!("refusing a connection from uid {}", uid);
+                continue;
+            }
+            let server = Server {
+                sender: asender.clone(),
+                database: database.clone(),
+                trust: trust.clone(),
+                keymap: keymap.clone(),
+            };
+            let framed = LengthDelimitedCodec::builder().max_frame_length(MAX_FRAME_LENGTH).new_framed(stream);
+            let transport = tarpc::serde_transport::new(framed, Json::default());
+            tokio::spawn(server::BaseChannel::with_defaults(transport).execute(server.serve()));
+        }
+    });
 
     Ok(())
 }
 
+// Creates the directory the socket goes in, which has to be the user's own
+// and closed to others, since it could have been made by anyone when it's in
+// the temporary directory
+fn prepare_socket_dir(dir: &Path) -> Result<(), Box<dyn Error>> {
+    store::create_private_dir(dir).map_err(|e| format!("unable to create {}: {}", dir.display(), e))?;
+    let metadata = std::fs::symlink_metadata(dir)?;
+    // SAFETY: getuid can't fail
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } {
+        return Err(format!("{} isn't a directory of your own", dir.display()).into());
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(format!("others can get into {} (chmod 700 {} fixes that)", dir.display(), dir.display()).into());
+    }
+    Ok(())
+}
+
 pub async fn create_client<P: AsRef<Path>>(path: P) -> Result<ManagerClient, Box<dyn Error>> {
-    let transport = tarpc::serde_transport::unix::connect(path, Json::default);
+    let mut transport = tarpc::serde_transport::unix::connect(path, Json::default);
+    transport.config_mut().max_frame_length(MAX_FRAME_LENGTH);
     let client = ManagerClient::new(client::Config::default(), transport.await?).spawn();
 
     Ok(client)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::db::{Clip, ClipContents, Database, Source};
+    use crate::rpc::{Command, Manager, Message, Server};
+    use crate::trust::TrustStore;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use tarpc::context;
+    use tokio::sync::Mutex as AsyncMutex;
+
+    fn server(sender: mpsc::Sender<Command>) -> Server {
+        Server {
+            sender: Arc::new(AsyncMutex::new(sender)),
+            database: Arc::new(Database::new()),
+            trust: Arc::new(TrustStore::in_memory()),
+            keymap: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_are_answered() {
+        let (sender, mut receiver) = mpsc::channel::<Command>(1);
+        tokio::spawn(async move {
+            while let Some(Command { message, reply }) = receiver.next().await {
+                let result = match message {
+                    Message::Watch => Err("no display".to_owned()),
+                    _ => Ok(()),
+                };
+                let _ = reply.send(result);
+            }
+        });
+        let running = server(sender);
+        assert_eq!(running.send(Message::Show).await, Ok(()));
+        assert_eq!(running.send(Message::Watch).await, Err("no display".to_owned()));
+
+        let (sender, receiver) = mpsc::channel::<Command>(1);
+        drop(receiver);
+        assert!(server(sender).send(Message::Show).await.unwrap_err().contains("shutting down"));
+    }
+
+    #[tokio::test]
+    async fn added_clips() {
+        let (sender, _receiver) = mpsc::channel::<Command>(1);
+        let server = server(sender);
+        let ask = |contents: &str, app: Option<&str>, ttl| {
+            server.clone().add_clip(context::current(), contents.to_owned(), app.map(str::to_owned), Vec::new(), ttl)
+        };
+        assert!(ask("forever", None, Some(u64::MAX)).await.unwrap_err().contains("too long"));
+
+        let id = ask("pinned", None, None).await.unwrap();
+        server.database.pin(id);
+        // adding it again tells where it came from, but doesn't make it expire
+        let id = ask("pinned", Some("editor"), Some(60)).await.unwrap();
+        let clip = server.database.at(id).unwrap();
+        assert_eq!(clip.app.as_deref(), Some("editor"));
+        assert_eq!(server.database.expires(&clip), None);
+
+        let id = ask("for a minute", None, Some(60)).await.unwrap();
+        assert!(server.database.expires(&server.database.at(id).unwrap()).is_some());
+    }
+
+    #[tokio::test]
+    async fn expiring_clips() {
+        let (sender, _receiver) = mpsc::channel::<Command>(1);
+        let server = server(sender);
+        server.database.add_clip(Clip::new(Source::Primary, ClipContents::Text("short-lived".to_owned())));
+        assert!(server.clone().expire(context::current(), 0, Some(u64::MAX)).await.unwrap_err().contains("too long"));
+        assert_eq!(server.database.expires(&server.database.at(0).unwrap()), None);
+        assert_eq!(server.clone().expire(context::current(), 0, Some(60)).await, Ok(()));
+        assert!(server.database.expires(&server.database.at(0).unwrap()).is_some());
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use log::warn;
+
+static PROTECT: AtomicBool = AtomicBool::new(false);
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// The buffers that are locked into memory, by address and size, and how
+/// many of them each locked page holds. Locks on a page don't nest, so it's
+/// only unlocked once the last of them is released.
+#[derive(Default)]
+struct Locked {
+    buffers: HashSet<(usize, usize)>,
+    pages: HashMap<usize, usize>,
+}
+
+static LOCKED: Mutex<Option<Locked>> = Mutex::new(None);
+
+/// Keeps clip bodies out of swap from now on, and wipes them when they're
+/// dropped.
+pub fn protect() {
+    PROTECT.store(true, Ordering::Relaxed);
+}
+
+pub fn protected() -> bool {
+    PROTECT.load(Ordering::Relaxed)
+}
+
+fn page_size() -> usize {
+    static PAGE_SIZE: OnceLock<usize> = OnceLock::new();
+    *PAGE_SIZE.get_or_init(|| match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    })
+}
+
+// The pages the `len` bytes at `addr` are on
+fn pages(addr: usize, len: usize) -> impl Iterator<Item = usize> {
+    let size = page_size();
+    let first = addr / size * size;
+    (first..addr + len).step_by(size)
+}
+
+impl Locked {
+    // Locks the pages of the buffer that no other buffer locked yet
+    fn lock(&mut self, addr: usize, len: usize) {
+        if !self.buffers.insert((addr, len)) {
+            return;
+        }
+        for page in pages(addr, len) {
+            let count = self.pages.entry(page).or_insert(0);
+            *count += 1;
+            if *count == 1 && unsafe { libc::mlock(page as *const libc::c_void, page_size()) } != 0 && !WARNED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "unable to lock clips into memory, so they may be swapped out: {}; see `ulimit -l`",
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    // Unlocks the pages of the buffer that no other buffer still holds
+    fn unlock(&mut self, addr: usize, len: usize) {
+        if !self.buffers.remove(&(addr, len)) {
+            return;
+        }
+        for page in pages(addr, len) {
+            let Some(count) = self.pages.get_mut(&page) else { continue };
+            *count -= 1;
+            if *count == 0 {
+                self.pages.remove(&page);
+                unsafe { libc::munlock(page as *const libc::c_void, page_size()) };
+            }
+        }
+    }
+}
+
+/// Locks the `len` bytes at `ptr` into memory, if clips are protected, so
+/// they aren't written to swap. Failing to, say over `RLIMIT_MEMLOCK`, is
+/// only warned about, once.
+pub fn lock(ptr: *const u8, len: usize) {
+    if !protected() || len == 0 {
+        return;
+    }
+    LOCKED.lock().unwrap().get_or_insert_with(Locked::default).lock(ptr as usize, len);
+}
+
+/// Overwrites the `len` bytes at `ptr` with zeroes, if clips are protected,
+/// and unlocks them if they were locked. They have to be writable, and
+/// must not be read again.
+///
+/// # Safety
+///
+/// `ptr` has to be valid for writes of `len` bytes.
+pub unsafe fn release(ptr: *mut u8, len: usize) {
+    if !protected() || len == 0 {
+        return;
+    }
+    unsafe { wipe(ptr, len) };
+    if let Some(locked) = LOCKED.lock().unwrap().as_mut() {
+        locked.unlock(ptr as usize, len);
+    }
+}
+
+// Overwrites the `len` bytes at `ptr` with zeroes, in a way that isn't left
+// out for going unread.
+unsafe fn wipe(ptr: *mut u8, len: usize) {
+    for i in 0..len {
+        unsafe { std::ptr::write_volatile(ptr.add(i), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Locks the whole of `data`, spare capacity and all.
+pub fn lock_vec(data: &Vec<u8>) {
+    lock(data.as_ptr(), data.capacity());
+}
+
+/// Wipes and unlocks the whole of `data`, leaving it empty.
+pub fn release_vec(data: &mut Vec<u8>) {
+    let capacity = data.capacity();
+    data.clear();
+    // all of the capacity is allocated, written or not
+    unsafe { release(data.as_mut_ptr(), capacity) };
+}
+
+pub fn lock_string(text: &String) {
+    lock(text.as_ptr(), text.capacity());
+}
+
+pub fn release_string(text: &mut String) {
+    // zeroes are still UTF-8, and it's left empty anyway
+    release_vec(unsafe { text.as_mut_vec() });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secrets::{page_size, pages, wipe, Locked};
+
+    #[test]
+    fn page_counts() {
+        let size = page_size();
+        assert_eq!(pages(size, 1).collect::<Vec<_>>(), vec![size]);
+        assert_eq!(pages(size + 1, size).collect::<Vec<_>>(), vec![size, 2 * size]);
+        assert_eq!(pages(2 * size - 1, 1).collect::<Vec<_>>(), vec![size]);
+
+        // a table of its own, so clips other tests drop don't get in the way
+        let mut locked = Locked::default();
+        let mut data = vec![0xaa_u8; 3 * size];
+        let text = String::from("hunter2");
+        locked.lock(data.as_ptr() as usize, data.capacity());
+        locked.lock(text.as_ptr() as usize, text.capacity());
+        locked.lock(text.as_ptr() as usize, text.capacity());
+        assert_eq!(locked.buffers.len(), 2);
+        // a page both are on counts twice
+        let held: usize = locked.pages.values().sum();
+        assert_eq!(held, pages(data.as_ptr() as usize, data.capacity()).count() + pages(text.as_ptr() as usize, text.capacity()).count());
+
+        unsafe { wipe(data.as_mut_ptr(), data.capacity()) };
+        assert!(data.iter().all(|b| *b == 0));
+        locked.unlock(data.as_ptr() as usize, data.capacity());
+        locked.unlock(text.as_ptr() as usize, text.capacity());
+        assert!(locked.buffers.is_empty());
+        assert!(locked.pages.is_empty());
+    }
+}
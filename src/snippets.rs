@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::matcher::Matcher;
+use crate::store::{self, Schema};
+
+pub const FILE: &str = "snippets.toml";
+
+pub const SCHEMA: Schema = Schema {
+    name: "snippets",
+    migrations: &[],
+};
+
+/// A permanent, labelled clip.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Snippet {
+    pub label: String,
+    pub text: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct SnippetFile {
+    #[serde(default)]
+    snippets: Vec<Snippet>,
+}
+
+/// The snippet library, saved as TOML so it can also be edited by hand.
+pub struct Snippets {
+    path: Option<PathBuf>,
+    snippets: Mutex<Vec<Snippet>>,
+    // why the file isn't saved over, when it couldn't be read
+    read_only: Option<String>,
+}
+
+impl Snippets {
+    pub fn load(path: PathBuf) -> Snippets {
+        let (snippets, read_only) = match SCHEMA.load::<SnippetFile>(&path) {
+            Ok(file) => (file.unwrap_or_default().snippets, None),
+            Err(e) => {
+                store::degrade(format!("unable to read snippets {}: {}", path.display(), e));
+                let why = format!("{} couldn't be read, so it isn't changed: {}", path.display(), e);
+                (Vec::new(), Some(why))
+            }
+        };
+        Snippets {
+            path: Some(path),
+            snippets: Mutex::new(snippets),
+            read_only,
+        }
+    }
+
+    pub fn in_memory() -> Snippets {
+        Snippets {
+            path: None,
+            snippets: Mutex::new(Vec::new()),
+            read_only: None,
+        }
+    }
+
+    /// Adds the snippet, replacing any snippet with the same label.
+    pub fn add(&self, snippet: Snippet) -> Result<(), Box<dyn Error>> {
+        if let Some(why) = &self.read_only {
+            return Err(why.clone().into());
+        }
+        let mut snippets = self.snippets.lock().unwrap();
+        snippets.retain(|s| s.label != snippet.label);
+        snippets.push(snippet);
+
+        if let Some(path) = &self.path {
+            SCHEMA.save(path, &SnippetFile { snippets: snippets.clone() })?;
+        }
+        Ok(())
+    }
+
+    pub fn all(&self) -> Vec<Snippet> {
+        self.snippets.lock().unwrap().clone()
+    }
+
+    /// Snippets whose label or text matches, best first.
+    pub fn search(&self, pattern: &str, matcher: &dyn Matcher) -> Vec<Snippet> {
+        let snippets = self.snippets.lock().unwrap();
+        let mut matched: Vec<(i64, &Snippet)> = snippets
+            .iter()
+            .filter_map(|s| {
+                let label = matcher.score(&s.label, pattern);
+                let text = matcher.score(&s.text, pattern);
+                label.max(text).map(|score| (score, s))
+            })
+            .collect();
+        matched.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        matched.into_iter().map(|(_, s)| s.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matcher::MatcherKind;
+    use crate::snippets::{Snippet, Snippets};
+
+    fn snippet(label: &str, text: &str) -> Snippet {
+        Snippet {
+            label: label.to_owned(),
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn add_and_search() {
+        let snippets = Snippets::in_memory();
+        snippets.add(snippet("signature", "Best regards")).unwrap();
+        snippets.add(snippet("address", "Main street 1")).unwrap();
+        snippets.add(snippet("signature", "Cheers")).unwrap();
+
+        assert_eq!(snippets.all().len(), 2);
+        let matcher = MatcherKind::Substring.matcher();
+        assert_eq!(snippets.search("sign", matcher.as_ref()), vec![snippet("signature", "Cheers")]);
+        assert_eq!(snippets.search("street", matcher.as_ref()), vec![snippet("address", "Main street 1")]);
+    }
+
+    #[test]
+    fn persisted() {
+        let path = std::env::temp_dir()
+            .join(format!("repeat-snippets-test-{}", std::process::id()))
+            .join("snippets.toml");
+        Snippets::load(path.clone()).add(snippet("multi", "line\none")).unwrap();
+
+        assert_eq!(Snippets::load(path.clone()).all(), vec![snippet("multi", "line\none")]);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn unreadable_files_are_kept() {
+        let path = std::env::temp_dir()
+            .join(format!("repeat-snippets-unreadable-{}", std::process::id()))
+            .join("snippets.toml");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        // not UTF-8
+        std::fs::write(&path, b"[[snippets]]\nlabel = \"\xff\"\n").unwrap();
+        let snippets = Snippets::load(path.clone());
+        assert!(snippets.all().is_empty());
+        assert!(snippets.add(snippet("new", "one")).is_err());
+        assert!(snippets.all().is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), b"[[snippets]]\nlabel = \"\xff\"\n");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
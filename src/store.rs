@@ -0,0 +1,584 @@
+use log::{debug, error, info, warn};
+
+use crate::notify;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const VERSION_KEY: &str = "schema_version";
+// Files waiting to be written before the writer stops waiting for a flush
+const MAX_PENDING: usize = 16;
+const SPOOL_EXTENSION: &str = "part";
+
+static SPOOL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// What keeps the data directory from being relied on, if anything
+static DEGRADED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Records that a file in the data directory couldn't be read or written, and
+/// tells the user. Writes are still tried, and the ones that fail are kept to
+/// try again on the next flush.
+pub fn degrade(problem: String) {
+    warn!("{}", problem);
+    notify::problem("rpt can't save its data", &problem);
+    *DEGRADED.lock().unwrap() = Some(problem);
+}
+
+/// What's wrong with the data directory, if anything.
+pub fn degraded() -> Option<String> {
+    DEGRADED.lock().unwrap().clone()
+}
+
+fn recover() {
+    if let Some(problem) = DEGRADED.lock().unwrap().take() {
+        info!("writing to the data directory works again after: {}", problem);
+    }
+}
+
+/// Upgrades a document from the version before it to the next one.
+pub type Migration = fn(&mut toml::Table) -> Result<(), String>;
+
+/// The layout of a TOML file kept in the data directory. Files carry the
+/// version they were written with, and older ones are upgraded through the
+/// migrations when loaded, after a backup is taken.
+pub struct Schema {
+    pub name: &'static str,
+    /// Migration `i` upgrades version `i` to `i + 1`, so the current version
+    /// is the number of migrations. Version 0 is a file without a version.
+    pub migrations: &'static [Migration],
+}
+
+/// What `check` found out about a file.
+#[derive(Debug, PartialEq)]
+pub enum Health {
+    Missing,
+    Current,
+    /// Will be migrated from this version the next time it's loaded
+    Outdated(usize),
+    Broken(String),
+}
+
+impl Schema {
+    pub fn version(&self) -> usize {
+        self.migrations.len()
+    }
+
+    /// Reads the file, migrating it to the current version first if needed.
+    /// Returns `None` if there is no file. A file that can't be loaded is
+    /// copied to `<file>.broken` first, since saving would replace it.
+    pub fn load<T: DeserializeOwned>(&self, path: &Path) -> Result<Option<T>, Box<dyn Error>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match self.parse(path, &contents) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                let mut name = path.file_name().unwrap_or_default().to_owned();
+                name.push(".broken");
+                let broken = path.with_file_name(name);
+                std::fs::write(&broken, &contents)?;
+                Err(format!("{}, kept a copy in {}", e, broken.display()).into())
+            }
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(&self, path: &Path, contents: &str) -> Result<T, Box<dyn Error>> {
+        let mut table: toml::Table = contents.parse()?;
+        let version = self.file_version(&mut table)?;
+        if version < self.version() {
+            let backup = backup_path(path, version);
+            info!(
+                "migrating {} from version {} to {}, keeping a backup in {}",
+                self.name, version, self.version(), backup.display()
+            );
+            std::fs::copy(path, &backup)?;
+            self.migrate(&mut table, version)?;
+            self.write(path, table.clone())?;
+        }
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+
+    /// Reads a file that was read some other way, like by decrypting it.
+    /// Older versions are migrated in memory only, and written in the current
+    /// one the next time they're saved.
+    pub fn decode<T: DeserializeOwned>(&self, contents: &str) -> Result<T, Box<dyn Error>> {
+        let mut table: toml::Table = contents.parse()?;
+        let version = self.file_version(&mut table)?;
+        self.migrate(&mut table, version)?;
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+
+    pub fn save<T: Serialize>(&self, path: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+        self.write(path, toml::Table::try_from(value)?)
+    }
+
+    /// Whether the file can be loaded, without changing it.
+    pub fn check<T: DeserializeOwned>(&self, path: &Path) -> Health {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Health::Missing,
+            Err(e) => return Health::Broken(e.to_string()),
+        };
+        let checked = contents
+            .parse::<toml::Table>()
+            .map_err(|e| e.to_string())
+            .and_then(|mut table| {
+                let version = self.file_version(&mut table).map_err(|e| e.to_string())?;
+                self.migrate(&mut table, version)?;
+                toml::Value::Table(table).try_into::<T>().map_err(|e| e.to_string())?;
+                Ok(version)
+            });
+        match checked {
+            Ok(version) if version == self.version() => Health::Current,
+            Ok(version) => Health::Outdated(version),
+            Err(e) => Health::Broken(e),
+        }
+    }
+
+    // Takes the version out of the table
+    fn file_version(&self, table: &mut toml::Table) -> Result<usize, Box<dyn Error>> {
+        let version = match table.remove(VERSION_KEY) {
+            None => 0,
+            Some(toml::Value::Integer(v)) if v >= 0 => v as usize,
+            Some(v) => return Err(format!("{} is not a valid {}", v, VERSION_KEY).into()),
+        };
+        if version > self.version() {
+            return Err(format!(
+                "{} is version {}, but this version of repeat only knows up to {}",
+                self.name, version, self.version()
+            ).into());
+        }
+        Ok(version)
+    }
+
+    fn migrate(&self, table: &mut toml::Table, from: usize) -> Result<(), String> {
+        for (version, migration) in self.migrations.iter().enumerate().skip(from) {
+            migration(table).map_err(|e| format!("migrating {} from version {}: {}", self.name, version, e))?;
+        }
+        Ok(())
+    }
+
+    /// The file `save` would write, for handing to a `Writer`.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.versioned(toml::Table::try_from(value)?)
+    }
+
+    fn versioned(&self, mut table: toml::Table) -> Result<Vec<u8>, Box<dyn Error>> {
+        table.insert(VERSION_KEY.to_owned(), toml::Value::Integer(self.version() as i64));
+        Ok(toml::to_string(&table)?.into_bytes())
+    }
+
+    fn write(&self, path: &Path, table: toml::Table) -> Result<(), Box<dyn Error>> {
+        write_durably(path, &self.versioned(table)?)?;
+        Ok(())
+    }
+}
+
+/// Collects file writes and does them in one go when flushed, so that a burst
+/// of changes to a file only writes it once. Only the latest contents of
+/// each file are kept.
+pub struct Writer {
+    batched: bool,
+    pending: Mutex<HashMap<PathBuf, Contents>>,
+    // woken when a write is queued, see `queued`
+    queued: tokio::sync::Notify,
+}
+
+/// Makes the contents of a file when it's written, see `Writer::write_with`.
+pub type Encode = Box<dyn FnOnce() -> Result<Vec<u8>, Box<dyn Error>> + Send>;
+
+enum Contents {
+    Ready(Vec<u8>),
+    Deferred(Encode),
+    Removed,
+}
+
+impl Writer {
+    /// A writer that waits for `flush`.
+    pub fn batched() -> Writer {
+        Writer { batched: true, pending: Mutex::new(HashMap::new()), queued: tokio::sync::Notify::new() }
+    }
+
+    /// A writer that writes straight away.
+    pub fn immediate() -> Writer {
+        Writer { batched: false, pending: Mutex::new(HashMap::new()), queued: tokio::sync::Notify::new() }
+    }
+
+    pub fn write(&self, path: &Path, contents: Vec<u8>) {
+        self.queue(path, Contents::Ready(contents));
+    }
+
+    /// Like `write`, but only makes the contents when they're written, so a
+    /// large file that changes often is encoded once per flush rather than
+    /// on every change.
+    pub fn write_with(&self, path: &Path, encode: Encode) {
+        self.queue(path, Contents::Deferred(encode));
+    }
+
+    /// Removes the file, unless it's written again before the flush.
+    pub fn remove(&self, path: &Path) {
+        self.queue(path, Contents::Removed);
+    }
+
+    fn queue(&self, path: &Path, contents: Contents) {
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.insert(path.to_owned(), contents);
+            pending.len() >= MAX_PENDING
+        };
+        self.queued.notify_one();
+        // a full queue of files that can't be written waits for the flush
+        if !self.batched || (full && degraded().is_none()) {
+            self.flush();
+        }
+    }
+
+    /// Writes everything pending. Since there's no one waiting for them,
+    /// failures degrade the store, and what couldn't be written is kept to
+    /// try again on the next flush.
+    pub fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return;
+        }
+        debug!("flushing {} files", pending.len());
+        let mut failed = HashMap::new();
+        for (path, contents) in pending {
+            let contents = match contents {
+                Contents::Removed => {
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        if e.kind() != std::io::ErrorKind::NotFound {
+                            degrade(format!("unable to remove {}: {}", path.display(), e));
+                            failed.insert(path, Contents::Removed);
+                        }
+                    }
+                    continue;
+                }
+                Contents::Ready(contents) => contents,
+                Contents::Deferred(encode) => match encode() {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        error!("unable to encode {}: {}", path.display(), e);
+                        continue;
+                    }
+                },
+            };
+            if let Err(e) = write_durably(&path, &contents) {
+                let problem = match e.kind() {
+                    std::io::ErrorKind::StorageFull => "the disk is full".to_owned(),
+                    _ => e.to_string(),
+                };
+                degrade(format!("unable to write {}: {}", path.display(), problem));
+                failed.insert(path, Contents::Ready(contents));
+            }
+        }
+        if failed.is_empty() {
+            recover();
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        for (path, contents) in failed {
+            // unless it changed again in the meantime
+            pending.entry(path).or_insert(contents);
+        }
+    }
+
+    /// Waits until a write is queued, or returns straight away if one was
+    /// since the last wait, to flush a batched writer off the async threads
+    /// as soon as there's something to write.
+    pub async fn queued(&self) {
+        self.queued.notified().await;
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+/// Creates the directory and any missing parents so that only the user can
+/// get into them. Directories that are there already are left as they are.
+pub fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+/// Creates the data directory if it's missing, and warns if others can get
+/// into it.
+pub fn prepare_data_dir(dir: &Path) {
+    if let Err(e) = create_private_dir(dir) {
+        degrade(format!("unable to create {}: {}", dir.display(), e));
+        return;
+    }
+    match std::fs::metadata(dir) {
+        Ok(metadata) if metadata.permissions().mode() & 0o077 != 0 => warn!(
+            "{} can be read by other users, and clips are saved in it (chmod 700 {} fixes that)",
+            dir.display(),
+            dir.display()
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("unable to check the permissions of {}: {}", dir.display(), e),
+    }
+}
+
+// Writes to a temporary file that replaces the old one once it's synced, so
+// a crash leaves either the old or the new contents. Only the user can read
+// them, since they can hold anything that was copied.
+fn write_durably(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// A large clip that arrives in chunks, kept on disk rather than in memory
+/// until all of it is there. Dropping it before `finish` removes the partial
+/// file.
+pub struct Spool {
+    path: PathBuf,
+    file: Option<File>,
+    len: usize,
+}
+
+impl Spool {
+    pub fn create(dir: &Path) -> std::io::Result<Spool> {
+        create_private_dir(dir)?;
+        let name = format!(
+            "{}-{}.{}",
+            std::process::id(),
+            SPOOL_COUNT.fetch_add(1, Ordering::Relaxed),
+            SPOOL_EXTENSION
+        );
+        let path = dir.join(name);
+        // clips can be secrets
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        Ok(Spool { path, file: Some(file), len: 0 })
+    }
+
+    pub fn append(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => {
+                file.write_all(chunk)?;
+                self.len += chunk.len();
+                Ok(())
+            }
+            None => Err(std::io::Error::other("spool already finished")),
+        }
+    }
+
+    /// Bytes received so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Everything received, removing the file.
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        let mut file = File::open(&self.path)?;
+        let mut data = Vec::with_capacity(self.len);
+        file.read_to_end(&mut data)?;
+        Ok(data)
+        // dropping removes the file
+    }
+
+    /// Gives up on the transfer, removing what was received.
+    pub fn abort(self) {}
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        self.file = None;
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("unable to remove spooled transfer {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Removes partial transfers left behind by a crash, returning how many.
+pub fn clean_spool(dir: &Path) -> usize {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == SPOOL_EXTENSION) && std::fs::remove_file(&path).is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+fn backup_path(path: &Path, version: usize) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".v{}.bak", version));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::store::{clean_spool, create_private_dir, Health, Schema, Spool, Writer};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Notes {
+        notes: Vec<Note>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Note {
+        text: String,
+        pinned: bool,
+    }
+
+    // version 1 added `pinned` to every note
+    const NOTES: Schema = Schema {
+        name: "notes",
+        migrations: &[|table| {
+            let notes = table.get_mut("notes").and_then(|n| n.as_array_mut()).ok_or("no notes")?;
+            for note in notes {
+                note.as_table_mut().ok_or("not a note")?.insert("pinned".to_owned(), false.into());
+            }
+            Ok(())
+        }],
+    };
+
+    #[test]
+    fn migrates_with_backup() {
+        let dir = std::env::temp_dir().join(format!("repeat-store-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.toml");
+        std::fs::write(&path, "[[notes]]\ntext = \"old\"\n").unwrap();
+        assert_eq!(NOTES.check::<Notes>(&path), Health::Outdated(0));
+
+        let notes: Notes = NOTES.load(&path).unwrap().unwrap();
+        assert_eq!(notes.notes, vec![Note { text: "old".to_owned(), pinned: false }]);
+        assert!(dir.join("notes.toml.v0.bak").exists());
+        assert_eq!(NOTES.check::<Notes>(&path), Health::Current);
+
+        std::fs::write(&path, "schema_version = 2\n").unwrap();
+        assert!(NOTES.load::<Notes>(&path).is_err());
+        assert_eq!(std::fs::read_to_string(dir.join("notes.toml.broken")).unwrap(), "schema_version = 2\n");
+        assert!(matches!(NOTES.check::<Notes>(&path), Health::Broken(_)));
+        assert_eq!(NOTES.check::<Notes>(&dir.join("missing")), Health::Missing);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn private_dirs() {
+        let dir = std::env::temp_dir().join(format!("repeat-private-test-{}", std::process::id()));
+        let nested = dir.join("data").join("repeat");
+        create_private_dir(&nested).unwrap();
+        for dir in [&dir, &nested] {
+            assert_eq!(std::fs::metadata(dir).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        // there already
+        create_private_dir(&nested).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spool() {
+        let dir = std::env::temp_dir().join(format!("repeat-spool-test-{}", std::process::id()));
+        let mut spool = Spool::create(&dir).unwrap();
+        spool.append(b"hello ").unwrap();
+        spool.append(b"world").unwrap();
+        assert_eq!(spool.len(), 11);
+        assert_eq!(spool.finish().unwrap(), b"hello world");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        let mut aborted = Spool::create(&dir).unwrap();
+        aborted.append(b"partial").unwrap();
+        aborted.abort();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // left behind by a crash
+        std::fs::write(dir.join("1-0.part"), b"partial").unwrap();
+        std::fs::write(dir.join("unrelated"), b"").unwrap();
+        assert_eq!(clean_spool(&dir), 1);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batched_writes() {
+        let dir = std::env::temp_dir().join(format!("repeat-writer-test-{}", std::process::id()));
+        let path = dir.join("file");
+        let writer = Writer::batched();
+        writer.write(&path, b"first".to_vec());
+        writer.write(&path, b"second".to_vec());
+        assert_eq!(writer.pending(), 1);
+        assert!(!path.exists());
+
+        writer.flush();
+        assert_eq!(writer.pending(), 0);
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+
+        // a full queue doesn't wait for the flush
+        for i in 0..16 {
+            writer.write(&dir.join(i.to_string()), Vec::new());
+        }
+        assert_eq!(writer.pending(), 0);
+
+        Writer::immediate().write(&path, b"third".to_vec());
+        assert_eq!(std::fs::read(&path).unwrap(), b"third");
+
+        // deferred contents are only made once, when they're written
+        let encoded = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            let encoded = encoded.clone();
+            writer.write_with(&path, Box::new(move || {
+                encoded.fetch_add(1, Ordering::Relaxed);
+                Ok(b"fourth".to_vec())
+            }));
+        }
+        writer.flush();
+        assert_eq!(encoded.load(Ordering::Relaxed), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), b"fourth");
+
+        // removed on the flush, unless written again before it
+        writer.remove(&path);
+        assert!(path.exists());
+        writer.flush();
+        assert!(!path.exists());
+        writer.remove(&path);
+        writer.write(&path, b"fifth".to_vec());
+        writer.flush();
+        assert_eq!(std::fs::read(&path).unwrap(), b"fifth");
+        writer.remove(&dir.join("never written"));
+        writer.flush();
+        assert_eq!(writer.pending(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_writes() {
+        let writer = Writer::batched();
+        // a write queued before anyone waits isn't missed
+        writer.write(std::path::Path::new("/nonexistent/file"), Vec::new());
+        tokio::time::timeout(std::time::Duration::from_secs(1), writer.queued()).await.unwrap();
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(10), writer.queued()).await.is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use regex::{Regex, RegexBuilder};
+use std::error::Error;
+
+/// A parsed `:s/pattern/replacement/flags` command, vim style.
+#[derive(Debug)]
+pub struct Substitution {
+    pattern: Regex,
+    replacement: String,
+    global: bool,
+}
+
+impl Substitution {
+    /// Parses a command such as `:s/foo/bar/g` or `:%s/foo/bar/`. The leading
+    /// `:` and `%` are optional, and `\/` can be used for a literal slash.
+    pub fn parse(command: &str) -> Result<Substitution, Box<dyn Error>> {
+        let command = command.strip_prefix(':').unwrap_or(command);
+        let command = command.strip_prefix('%').unwrap_or(command);
+        let rest = command
+            .strip_prefix("s/")
+            .ok_or("substitution has to start with s/")?;
+
+        let mut parts = split_unescaped(rest);
+        if parts.len() < 2 {
+            return Err("substitution needs a pattern and a replacement".into());
+        }
+        let flags = if parts.len() > 2 { parts.remove(2) } else { String::new() };
+        let replacement = parts.remove(1);
+        let pattern = parts.remove(0);
+
+        let mut global = false;
+        let mut case_insensitive = false;
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'i' => case_insensitive = true,
+                _ => return Err(format!("unknown substitution flag {}", flag).into()),
+            }
+        }
+
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(case_insensitive)
+            .build()?;
+        Ok(Substitution {
+            pattern,
+            replacement: vim_replacement(&replacement),
+            global,
+        })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        if self.global {
+            self.pattern.replace_all(text, self.replacement.as_str()).into_owned()
+        } else {
+            self.pattern.replace(text, self.replacement.as_str()).into_owned()
+        }
+    }
+}
+
+pub fn is_command(input: &str) -> bool {
+    input.starts_with(':')
+}
+
+// Splits on `/` while keeping `\/` as a literal slash
+fn split_unescaped(input: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'/') => {
+                chars.next();
+                parts.last_mut().unwrap().push('/');
+            }
+            '/' => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+// vim uses & and \1 for groups while regex uses $0 and $1
+fn vim_replacement(replacement: &str) -> String {
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => out.push_str("$$"),
+            '&' => out.push_str("${0}"),
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => out.push_str(&format!("${{{}}}", d)),
+                Some('&') => out.push('&'),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::substitute::Substitution;
+
+    #[test]
+    fn first_and_global() {
+        let first = Substitution::parse(":s/foo/bar/").unwrap();
+        assert_eq!(first.apply("foo foo"), "bar foo");
+
+        let global = Substitution::parse(":%s/foo/bar/g").unwrap();
+        assert_eq!(global.apply("foo foo"), "bar bar");
+    }
+
+    #[test]
+    fn groups_and_escapes() {
+        let s = Substitution::parse(r":s/(\w+)@(\w+)/\2 at \1 \/ &/").unwrap();
+        assert_eq!(s.apply("me@host"), "host at me / me@host");
+
+        let dollars = Substitution::parse(":s/x/$1/").unwrap();
+        assert_eq!(dollars.apply("x"), "$1");
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Substitution::parse(":foo").is_err());
+        assert!(Substitution::parse(":s/foo").is_err());
+        assert!(Substitution::parse(":s/foo/bar/q").is_err());
+        assert!(Substitution::parse(":s/(/bar/").is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::store::{self, Schema};
+
+pub const FILE: &str = "trust.toml";
+
+pub const SCHEMA: Schema = Schema {
+    name: "trusted applications",
+    migrations: &[],
+};
+
+/// A remembered decision about an application, by its WM_CLASS.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trust {
+    Allow,
+    Deny,
+}
+
+/// The answer to a confirmation prompt.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Decision {
+    /// Allow until the daemon restarts
+    Once,
+    Always,
+    /// Deny until the daemon restarts
+    Deny,
+    Never,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct TrustFile {
+    #[serde(default)]
+    applications: BTreeMap<String, Trust>,
+}
+
+/// Which applications may have our selection served to them. Permanent
+/// decisions are saved as TOML, the ones for this session are only kept in
+/// memory.
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    decisions: Mutex<BTreeMap<String, Trust>>,
+    session: Mutex<HashMap<String, Trust>>,
+    // why the file isn't saved over, when it couldn't be read
+    read_only: Option<String>,
+}
+
+impl TrustStore {
+    pub fn load(path: PathBuf) -> TrustStore {
+        let (decisions, read_only) = match SCHEMA.load::<TrustFile>(&path) {
+            Ok(file) => (file.unwrap_or_default().applications, None),
+            Err(e) => {
+                store::degrade(format!("unable to read trusted applications {}: {}", path.display(), e));
+                let why = format!("{} couldn't be read, so it isn't changed: {}", path.display(), e);
+                (BTreeMap::new(), Some(why))
+            }
+        };
+        TrustStore {
+            path: Some(path),
+            decisions: Mutex::new(decisions),
+            session: Mutex::new(HashMap::new()),
+            read_only,
+        }
+    }
+
+    pub fn in_memory() -> TrustStore {
+        TrustStore {
+            path: None,
+            decisions: Mutex::new(BTreeMap::new()),
+            session: Mutex::new(HashMap::new()),
+            read_only: None,
+        }
+    }
+
+    /// Whether the application may be served, or `None` if it has to be asked.
+    pub fn allowed(&self, class: &str) -> Option<bool> {
+        let session = || self.session.lock().unwrap().get(class).copied();
+        match self.decisions.lock().unwrap().get(class).copied().or_else(session) {
+            Some(Trust::Allow) => Some(true),
+            Some(Trust::Deny) => Some(false),
+            None => None,
+        }
+    }
+
+    pub fn decide(&self, class: &str, decision: Decision) -> Result<(), Box<dyn Error>> {
+        match decision {
+            Decision::Once => {
+                self.session.lock().unwrap().insert(class.to_owned(), Trust::Allow);
+                Ok(())
+            }
+            Decision::Deny => {
+                self.session.lock().unwrap().insert(class.to_owned(), Trust::Deny);
+                Ok(())
+            }
+            Decision::Always => self.set(class, Some(Trust::Allow)),
+            Decision::Never => self.set(class, Some(Trust::Deny)),
+        }
+    }
+
+    /// Remembers the decision for the application, or forgets it with `None`.
+    pub fn set(&self, class: &str, trust: Option<Trust>) -> Result<(), Box<dyn Error>> {
+        if let Some(why) = &self.read_only {
+            return Err(why.clone().into());
+        }
+        self.session.lock().unwrap().remove(class);
+        let mut decisions = self.decisions.lock().unwrap();
+        match trust {
+            Some(trust) => decisions.insert(class.to_owned(), trust),
+            None => decisions.remove(class),
+        };
+
+        if let Some(path) = &self.path {
+            SCHEMA.save(path, &TrustFile { applications: decisions.clone() })?;
+        }
+        Ok(())
+    }
+
+    /// The permanent decisions, by application.
+    pub fn all(&self) -> Vec<(String, Trust)> {
+        self.decisions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(class, trust)| (class.clone(), *trust))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trust::{Decision, Trust, TrustStore};
+
+    #[test]
+    fn decisions() {
+        let store = TrustStore::in_memory();
+        assert_eq!(store.allowed("firefox"), None);
+
+        store.decide("firefox", Decision::Once).unwrap();
+        store.decide("xterm", Decision::Always).unwrap();
+        store.decide("sketchy", Decision::Never).unwrap();
+        store.decide("unsure", Decision::Deny).unwrap();
+        assert_eq!(store.allowed("firefox"), Some(true));
+        assert_eq!(store.allowed("xterm"), Some(true));
+        assert_eq!(store.allowed("sketchy"), Some(false));
+        assert_eq!(store.allowed("unsure"), Some(false));
+        // allowing or denying once only lasts for the session
+        assert_eq!(
+            store.all(),
+            vec![("sketchy".to_owned(), Trust::Deny), ("xterm".to_owned(), Trust::Allow)]
+        );
+
+        store.set("xterm", None).unwrap();
+        assert_eq!(store.allowed("xterm"), None);
+        // and can be changed from `rpt trust` too
+        store.set("unsure", Some(Trust::Allow)).unwrap();
+        assert_eq!(store.allowed("unsure"), Some(true));
+        store.set("unsure", None).unwrap();
+        assert_eq!(store.allowed("unsure"), None);
+    }
+
+    #[test]
+    fn persisted() {
+        let path = std::env::temp_dir()
+            .join(format!("repeat-trust-test-{}", std::process::id()))
+            .join("trust.toml");
+        let store = TrustStore::load(path.clone());
+        store.decide("Firefox", Decision::Always).unwrap();
+        store.decide("Once", Decision::Once).unwrap();
+        store.decide("Denied", Decision::Deny).unwrap();
+
+        let loaded = TrustStore::load(path.clone());
+        assert_eq!(loaded.allowed("Firefox"), Some(true));
+        assert_eq!(loaded.allowed("Once"), None);
+        assert_eq!(loaded.allowed("Denied"), None);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn unreadable_files_are_kept() {
+        let path = std::env::temp_dir()
+            .join(format!("repeat-trust-unreadable-{}", std::process::id()))
+            .join("trust.toml");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "[applications\nFirefox = \"allow\"\n").unwrap();
+        let store = TrustStore::load(path.clone());
+        assert!(store.decide("xterm", Decision::Always).is_err());
+        assert_eq!(store.allowed("xterm"), None);
+        // allowing for the session isn't saved, so it still works
+        store.decide("xterm", Decision::Once).unwrap();
+        assert_eq!(store.allowed("xterm"), Some(true));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "[applications\nFirefox = \"allow\"\n");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
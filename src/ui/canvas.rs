@@ -5,6 +5,7 @@ use rusttype::{point, Font, Scale, VMetrics};
 
 use crate::options::{Color, Options};
 use crate::ui;
+use crate::ui::text::Whitespace;
 
 pub struct Canvas {
     image: Image<Vec<u8>>,
@@ -14,7 +15,10 @@ pub struct Canvas {
     font: Font<'static>,
     scale: Scale,
     v_metrics: VMetrics,
+    // advance of the narrowest common glyph, for estimating what fits in a row
+    min_advance: f32,
     gc: xproto::Gcontext,
+    whitespace: Whitespace,
 }
 
 impl Canvas {
@@ -26,14 +30,7 @@ impl Canvas {
         options: &Options,
     ) -> Result<Canvas, Box<dyn std::error::Error>> {
         let depth = display.get_geometry_immediate(window).await?.depth;
-        let format = xproto::ImageFormat::Z_PIXMAP;
-        let len = breadx_image::storage_bytes(width, height, depth, None, format, 1);
-        let storage = vec![0u8; len];
-        let image = Image::with_display(storage, width, height, format, depth, display.setup())?;
-
-        let font = ui::text::font(options.font_name.as_deref())?;
-        let scale = Scale::uniform(options.font_size as f32);
-        let v_metrics = font.v_metrics(scale);
+        let mut canvas = Canvas::offscreen(display.setup(), depth, width, height, options)?;
 
         let pixmap = display.generate_xid().await?;
         let pixmap_gc = display.generate_xid().await?;
@@ -46,17 +43,59 @@ impl Canvas {
                 .foreground(display.default_screen().white_pixel)
                 .graphics_exposures(0),
         ).await?;
-        display.put_ximage_checked(&image, pixmap, pixmap_gc, 0, 0).await?;
+        display.put_ximage_checked(&canvas.image, pixmap, pixmap_gc, 0, 0).await?;
+
+        canvas.window = window;
+        canvas.gc = pixmap_gc;
+        Ok(canvas)
+    }
+
+    /// A canvas that is only drawn to memory, using the image format described
+    /// by `setup`. It can't be drawn to a display until `window` and `gc` are set.
+    pub fn offscreen(
+        setup: &xproto::Setup,
+        depth: u8,
+        width: u16,
+        height: u16,
+        options: &Options,
+    ) -> Result<Canvas, Box<dyn std::error::Error>> {
+        let font = ui::text::cached_font(options.font_name.as_deref())?;
+        Canvas::offscreen_with_font(setup, depth, width, height, font, options)
+    }
+
+    /// Like `offscreen`, but drawing with `font` rather than the one the
+    /// options name.
+    pub fn offscreen_with_font(
+        setup: &xproto::Setup,
+        depth: u8,
+        width: u16,
+        height: u16,
+        font: Font<'static>,
+        options: &Options,
+    ) -> Result<Canvas, Box<dyn std::error::Error>> {
+        let format = xproto::ImageFormat::Z_PIXMAP;
+        let len = breadx_image::storage_bytes(width, height, depth, None, format, 1);
+        let storage = vec![0u8; len];
+        let image = Image::with_display(storage, width, height, format, depth, setup)?;
+
+        let scale = Scale::uniform(options.font_size);
+        let (v_metrics, min_advance) = metrics(&font, scale);
 
         Ok(Canvas {
             image,
-            window,
+            window: 0,
             width,
             height,
             font,
             scale,
             v_metrics,
-            gc: pixmap_gc,
+            min_advance,
+            gc: 0,
+            whitespace: Whitespace {
+                tab_width: options.tab_width,
+                visible: options.show_whitespace,
+                line_ends: options.show_line_ends,
+            },
         })
     }
 
@@ -74,13 +113,63 @@ impl Canvas {
     }
 
     pub fn draw_text(&mut self, input: &str, color: &Color, row: u16, offset: u16) {
-        self.render_glyphs(offset, input, color, row);
+        let input = self.visible_prefix(input, offset);
+        let (text, _) = ui::text::visual_order(input);
+        self.render_glyphs(offset, &text, color, row);
+    }
+
+    /// Draws a line of a clip, with tabs expanded and control characters made
+    /// visible. `ends_line` tells if the line was followed by a line break.
+    /// Right-to-left lines are aligned to the right edge.
+    pub fn draw_line(&mut self, line: &str, ends_line: bool, color: &Color, row: u16, offset: u16) {
+        let line = self.visible_prefix(line, offset);
+        let text = ui::text::visualize(line, ends_line, &self.whitespace);
+        let (text, rtl) = ui::text::visual_order(&text);
+        let offset = if rtl {
+            let right_aligned = self.width as f32 - self.text_width(&text);
+            offset.max(right_aligned.max(0.0) as u16)
+        } else {
+            offset
+        };
+        self.render_glyphs(offset, &text, color, row);
+    }
+
+    // Cuts the text down to what can possibly fit in a row, so that huge lines
+    // don't have to be laid out just to be thrown away
+    fn visible_prefix<'a>(&self, text: &'a str, offset: u16) -> &'a str {
+        let max_chars = (self.width.saturating_sub(offset) as f32 / self.min_advance) as usize + 1;
+        match text.char_indices().nth(max_chars) {
+            Some((end, _)) => &text[..end],
+            None => text,
+        }
+    }
+
+    pub fn font_size(&self) -> f32 {
+        self.scale.y
+    }
+
+    /// Changes the size text is drawn in from now on, and so how many rows fit.
+    pub fn set_font_size(&mut self, size: f32) {
+        self.scale = Scale::uniform(size);
+        (self.v_metrics, self.min_advance) = metrics(&self.font, self.scale);
     }
 
     pub fn text_height(&self) -> f32 {
         self.v_metrics.ascent - self.v_metrics.descent + self.v_metrics.line_gap
     }
 
+    pub fn text_width(&self, text: &str) -> f32 {
+        self.font
+            .layout(text, self.scale, point(0.0, 0.0))
+            .last()
+            .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+            .unwrap_or(0.0)
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
     pub fn text_rows(&self) -> usize {
         self.height as usize / self.text_height() as usize
     }
@@ -92,8 +181,7 @@ impl Canvas {
                 text,
                 self.scale,
                 point(0.0, self.text_height() * row as f32 + self.v_metrics.ascent),
-            )
-            .into_iter();
+            );
 
         for glyph in glyphs {
             if let Some(bounding_box) = glyph.pixel_bounding_box() {
@@ -107,10 +195,8 @@ impl Canvas {
                     let x = dst_x + p_x as u16;
                     let y = dst_y + p_y as u16;
                     if x < max_x && y < max_y {
-                        let pixel = (((color.red * v) as u32) << 16u32)
-                            | (((color.green * v) as u32) << 8u32)
-                            | ((color.blue * v) as u32);
-                        self.image.set_pixel(x as usize, y as usize, pixel);
+                        let below = self.image.pixel(x as usize, y as usize);
+                        self.image.set_pixel(x as usize, y as usize, blend(below, color, v));
                     } else {
                         outside = true;
                     }
@@ -122,3 +208,184 @@ impl Canvas {
         }
     }
 }
+
+// The vertical metrics of the font at the scale, and the advance of the
+// narrowest common glyph
+fn metrics(font: &Font<'static>, scale: Scale) -> (VMetrics, f32) {
+    let min_advance = [' ', 'i', 'l', '.', '|']
+        .iter()
+        .map(|c| font.glyph(*c).scaled(scale).h_metrics().advance_width)
+        .fold(f32::MAX, f32::min)
+        .max(1.0);
+    (font.v_metrics(scale), min_advance)
+}
+
+// Mixes a glyph's coverage of a pixel between what is already there and the
+// text color, so edges fade into the background rather than to black
+fn blend(below: u32, color: &Color, coverage: f32) -> u32 {
+    let channel = |shift: u32, value: f32| {
+        let below = ((below >> shift) & 0xff) as f32;
+        ((below + (value - below) * coverage).round() as u32) << shift
+    };
+    channel(16, color.red) | channel(8, color.green) | channel(0, color.blue)
+}
+
+#[cfg(test)]
+mod tests {
+    use breadx::protocol::xproto;
+    use rusttype::Font;
+    use std::path::Path;
+
+    use crate::options::{Color, Options};
+    use crate::ui::canvas::{blend, Canvas};
+
+    const WIDTH: u16 = 200;
+    const HEIGHT: u16 = 100;
+    // bundled so that what's drawn doesn't depend on the fonts installed
+    const FONT: &[u8] = include_bytes!("testdata/DejaVuSansMono.ttf");
+
+    fn canvas() -> Canvas {
+        canvas_sized(WIDTH, HEIGHT)
+    }
+
+    fn canvas_sized(width: u16, height: u16) -> Canvas {
+        // a typical 24 bit TrueColor setup
+        let setup = xproto::Setup {
+            image_byte_order: xproto::ImageOrder::LSB_FIRST,
+            bitmap_format_scanline_unit: 32,
+            bitmap_format_scanline_pad: 32,
+            pixmap_formats: vec![xproto::Format {
+                depth: 24,
+                bits_per_pixel: 32,
+                scanline_pad: 32,
+            }],
+            ..Default::default()
+        };
+        let options = Options::default();
+        let font = Font::try_from_bytes(FONT).unwrap();
+        Canvas::offscreen_with_font(&setup, 24, width, height, font, &options).unwrap()
+    }
+
+    fn pixels(canvas: &Canvas) -> Vec<(usize, usize, u32)> {
+        let mut pixels = Vec::new();
+        for y in 0..HEIGHT as usize {
+            for x in 0..WIDTH as usize {
+                let pixel = canvas.image.pixel(x, y);
+                if pixel != 0 {
+                    pixels.push((x, y, pixel));
+                }
+            }
+        }
+        pixels
+    }
+
+    // The canvas as a binary PPM image
+    fn ppm(canvas: &Canvas) -> Vec<u8> {
+        let mut image = format!("P6\n{} {}\n255\n", canvas.width, canvas.height).into_bytes();
+        for y in 0..canvas.height as usize {
+            for x in 0..canvas.width as usize {
+                let pixel = canvas.image.pixel(x, y);
+                image.extend([(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+            }
+        }
+        image
+    }
+
+    // Compares the canvas with `testdata/<name>.ppm`, keeping what was drawn
+    // in the temporary directory if it's different. After a change to the
+    // drawing that was meant, UPDATE_GOLDEN=1 writes the new images instead.
+    fn assert_golden(canvas: &Canvas, name: &str) {
+        let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/ui/testdata").join(format!("{}.ppm", name));
+        let drawn = ppm(canvas);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&golden, &drawn).unwrap();
+            return;
+        }
+        let expected = std::fs::read(&golden)
+            .unwrap_or_else(|e| panic!("unable to read {}: {} (UPDATE_GOLDEN=1 writes it)", golden.display(), e));
+        if drawn != expected {
+            let actual = std::env::temp_dir().join(format!("repeat-{}.ppm", name));
+            std::fs::write(&actual, &drawn).unwrap();
+            panic!("{} was drawn differently than {}, see {}", name, golden.display(), actual.display());
+        }
+    }
+
+    #[test]
+    fn clear() {
+        let mut canvas = canvas();
+        assert!(pixels(&canvas).is_empty());
+        canvas.draw_text("text", &Color::white(), 0, 0);
+        assert!(!pixels(&canvas).is_empty());
+        canvas.clear();
+        assert!(pixels(&canvas).is_empty());
+    }
+
+    #[test]
+    fn text_stays_in_its_row() {
+        let mut canvas = canvas_sized(120, 80);
+        canvas.draw_text("Hello", &Color::white(), 2, 20);
+        assert_golden(&canvas, "text");
+    }
+
+    #[test]
+    fn highlight_color() {
+        let mut canvas = canvas_sized(120, 56);
+        canvas.draw_text("Chosen", &Color::green(), 0, 0);
+        canvas.draw_text("Next", &Color::white(), 1, 0);
+        assert_golden(&canvas, "highlight");
+    }
+
+    #[test]
+    fn truncation() {
+        let mut narrow = canvas_sized(120, 32);
+        let long = "a line that is much too long to fit into the canvas";
+        narrow.draw_text(long, &Color::white(), 0, 0);
+        assert_golden(&narrow, "truncation");
+
+        let mut canvas = canvas();
+        // huge lines are cut down before layout
+        let huge = "x".repeat(2_000_000);
+        assert!(canvas.visible_prefix(&huge, 0).len() < WIDTH as usize);
+        assert_eq!(canvas.visible_prefix("short", 0), "short");
+
+        // rows below the bottom are dropped rather than wrapping around
+        canvas.clear();
+        let rows = canvas.text_rows() as u16;
+        canvas.draw_text("below", &Color::white(), rows + 1, 0);
+        assert!(pixels(&canvas).is_empty());
+    }
+
+    #[test]
+    fn blending() {
+        let white = Color::white();
+        assert_eq!(blend(0x000000, &white, 1.0), 0xffffff);
+        assert_eq!(blend(0x000000, &white, 0.0), 0x000000);
+        assert_eq!(blend(0x202020, &white, 0.5), 0x909090);
+        assert_eq!(blend(0xffffff, &white, 0.5), 0xffffff);
+
+        // text on a light background has no dark fringes
+        let mut canvas = canvas();
+        for y in 0..HEIGHT as usize {
+            for x in 0..WIDTH as usize {
+                canvas.image.set_pixel(x, y, 0xc0c0c0);
+            }
+        }
+        canvas.draw_text("Fringes", &white, 0, 0);
+        let pixels = pixels(&canvas);
+        assert!(pixels.iter().all(|(_, _, pixel)| pixel & 0xff >= 0xc0));
+        assert!(pixels.iter().any(|(_, _, pixel)| *pixel == 0xffffff));
+    }
+
+    #[test]
+    fn font_size() {
+        let mut canvas = canvas();
+        let rows = canvas.text_rows();
+        let size = canvas.font_size();
+        canvas.set_font_size(size * 2.0);
+        assert_eq!(canvas.font_size(), size * 2.0);
+        assert!(canvas.text_rows() < rows);
+        assert!(canvas.text_width("wider") > 0.0);
+        canvas.set_font_size(size);
+        assert_eq!(canvas.text_rows(), rows);
+    }
+}
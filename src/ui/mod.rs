@@ -1,7 +1,11 @@
 use breadx::display::Display;
 
 mod canvas;
+mod prompt;
+mod speech;
+mod surfaces;
 mod text;
+mod watch;
 mod window;
 
 pub trait Widget {
@@ -11,6 +15,11 @@ pub trait Widget {
     fn draw<D: Display>(display: &mut D);
 }
 
+pub use prompt::Prompt;
+pub use surfaces::{Closed, Surface, Surfaces};
+pub use text::{cached_font, font_or_fallback};
+pub use watch::Watcher;
+pub use window::paste_last;
+pub use window::View;
 pub use window::Window;
-pub use window::WindowAction;
-pub use canvas::Canvas;
\ No newline at end of file
+pub use window::WindowAction;
\ No newline at end of file
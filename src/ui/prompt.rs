@@ -0,0 +1,120 @@
+// breadx_keysyms is deprecated in favour of xkeysym, but still does what we need
+#![allow(deprecated)]
+
+use std::error::Error;
+
+use crate::keys;
+use crate::options::Options;
+use crate::trust::Decision;
+use crate::ui;
+use crate::ui::window::{focus_window, get_active_screen_geom, get_focused_window, grab_keyboard};
+use breadx::protocol::{xproto::EventMask, Event};
+use breadx::{prelude::*, protocol::xproto};
+use breadx_keysyms::{keysyms, KeyboardState};
+use log::{debug, error};
+
+/// A small window asking whether an application may be served our selection.
+pub struct Prompt {
+    keyboard_state: KeyboardState,
+    window: xproto::Window,
+    focused_window: xproto::Window,
+    canvas: ui::canvas::Canvas,
+    class: String,
+}
+
+impl Prompt {
+    pub async fn create<D: AsyncDisplay>(
+        display: &mut D,
+        class: &str,
+        options: &Options,
+    ) -> Result<Prompt, Box<dyn Error>> {
+        let focused_window = get_focused_window(display).await?;
+        let geom = get_active_screen_geom(display).await?;
+        let options = &options.for_output(geom.output.as_deref());
+
+        let wid = display.generate_xid().await?;
+        let root = display.default_screen().root;
+        let width = 600u16;
+        let height = (options.font_size * 3.0) as u16;
+        display.create_window_checked(
+            0,
+            wid,
+            root,
+            geom.x + geom.width as i16 / 2i16 - width as i16 / 2i16,
+            geom.y + geom.height as i16 / 2i16 - height as i16 / 2i16,
+            width,
+            height,
+            2,
+            xproto::WindowClass::COPY_FROM_PARENT,
+            0,
+            xproto::CreateWindowAux::new()
+                .background_pixel(display.default_screen().white_pixel)
+                .override_redirect(1)
+                .event_mask(EventMask::EXPOSURE | EventMask::KEY_PRESS),
+        ).await?;
+
+        let canvas = ui::canvas::Canvas::new(display, wid, width, height, options).await?;
+        let keyboard_state = KeyboardState::new_async(display).await?;
+        let mut prompt = Prompt {
+            keyboard_state,
+            window: wid,
+            focused_window,
+            canvas,
+            class: class.to_owned(),
+        };
+
+        prompt.canvas.clear();
+        prompt.canvas.draw_text(&format!("Let {} paste from repeat?", class), &options.theme.input, 0, 0);
+        prompt.canvas.draw_text("[a]llow once  al[w]ays  [d]eny once  ne[v]er", &options.theme.text, 1, 0);
+        if !grab_keyboard(display, root).await? {
+            error!("unable to grab the keyboard for the prompt");
+        }
+        display.map_window_checked(wid).await?;
+        prompt.canvas.draw(display).await?;
+        focus_window(display, wid).await?;
+
+        Ok(prompt)
+    }
+
+    /// The X window it's drawn in.
+    pub fn id(&self) -> xproto::Window {
+        self.window
+    }
+
+    /// The application being asked about.
+    pub fn class(&self) -> &str {
+        &self.class
+    }
+
+    /// Returns the decision once a key for one has been pressed. Escape denies
+    /// once. The keyboard is left grabbed, see `Surfaces::handle_event`.
+    pub async fn handle_event<D: AsyncDisplay>(
+        &mut self,
+        display: &mut D,
+        event: &Event,
+    ) -> Result<Option<Decision>, Box<dyn Error>> {
+        let decision = match event {
+            Event::KeyPress(kp) => {
+                let sym = keys::symbol(&mut self.keyboard_state, display, kp.detail, kp.state).await?;
+                match sym {
+                    keysyms::KEY_a | keysyms::KEY_Return => Some(Decision::Once),
+                    keysyms::KEY_w => Some(Decision::Always),
+                    keysyms::KEY_d | keysyms::KEY_Escape => Some(Decision::Deny),
+                    keysyms::KEY_v => Some(Decision::Never),
+                    _ => None,
+                }
+            }
+            Event::Expose(ee) if ee.window == self.window => {
+                self.canvas.draw(display).await?;
+                None
+            }
+            _ => None,
+        };
+        if let Some(decision) = decision {
+            debug!("decided {:?} for {}", decision, self.class);
+            display.destroy_window_checked(self.window).await?;
+            focus_window(display, self.focused_window).await?;
+        }
+        Ok(decision)
+    }
+}
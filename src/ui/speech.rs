@@ -0,0 +1,78 @@
+use log::warn;
+use tokio::process::Command;
+
+use crate::db::Clip;
+
+// Spoken clips are cut off after this many characters
+const MAX_SPOKEN_CHARS: usize = 200;
+
+/// Reads out what's going on in the picker through speech-dispatcher.
+pub struct Speaker {
+    last: Option<String>,
+}
+
+impl Speaker {
+    pub fn new() -> Speaker {
+        Speaker { last: None }
+    }
+
+    /// Speaks the text unless it was the last thing said. A newer message
+    /// cuts off the one before it, so moving quickly doesn't queue up speech.
+    pub fn say(&mut self, text: String) {
+        if self.last.as_ref() == Some(&text) {
+            return;
+        }
+        let spawned = Command::new("spd-say")
+            .arg("--priority")
+            .arg("text")
+            .arg("--")
+            .arg(&text)
+            .spawn();
+        if let Err(e) = spawned {
+            warn!("unable to run spd-say: {}", e);
+        }
+        self.last = Some(text);
+    }
+}
+
+/// What to say about the highlighted clip, e.g. "2 of 10: hello world".
+pub fn announcement(position: usize, count: usize, clip: Option<&Clip>) -> String {
+    let clip = match clip {
+        None if count == 0 => return "no matches".to_owned(),
+        None => return format!("{} results", count),
+        Some(clip) => clip,
+    };
+    let text = if clip.contents.looks_binary() {
+        "binary data".to_owned()
+    } else {
+        let text = clip.contents.text();
+        let text = text.trim();
+        let mut spoken: String = text.chars().take(MAX_SPOKEN_CHARS).collect();
+        if spoken.len() < text.len() {
+            spoken.push_str(", and more");
+        }
+        spoken
+    };
+    let lines = clip.counts.lines;
+    if lines > 1 {
+        format!("{} of {}, {} lines: {}", position + 1, count, lines, text)
+    } else {
+        format!("{} of {}: {}", position + 1, count, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{Clip, ClipContents, Source};
+    use crate::ui::speech::announcement;
+
+    #[test]
+    fn announcements() {
+        let clip = |s: &str| Clip::new(Source::Primary, ClipContents::Text(s.to_owned()));
+        assert_eq!(announcement(0, 0, None), "no matches");
+        assert_eq!(announcement(1, 3, Some(&clip(" hello "))), "2 of 3: hello");
+        assert_eq!(announcement(0, 1, Some(&clip("a\nb"))), "1 of 1, 2 lines: a\nb");
+        assert_eq!(announcement(0, 1, Some(&clip("\0\u{1}"))), "1 of 1: binary data");
+        assert!(announcement(0, 1, Some(&clip(&"x".repeat(500)))).ends_with(", and more"));
+    }
+}
@@ -0,0 +1,201 @@
+use std::error::Error;
+
+use breadx::prelude::*;
+use breadx::protocol::{xproto, Event};
+
+use crate::clipboard::Clipboard;
+use crate::trust::Decision;
+use crate::ui::{Prompt, View, Watcher, Window, WindowAction};
+
+/// A window rpt has open.
+pub enum Surface {
+    Picker(Box<Window>),
+    Prompt(Prompt),
+    Watcher(Watcher),
+}
+
+/// A surface that closed while handling an event, and what came of it.
+pub enum Closed {
+    /// The picker, in the view it was left in
+    Picker(View),
+    Prompt { class: String, decision: Decision },
+    Watcher,
+}
+
+impl Surface {
+    fn id(&self) -> xproto::Window {
+        match self {
+            Surface::Picker(w) => w.id(),
+            Surface::Prompt(p) => p.id(),
+            Surface::Watcher(w) => w.id(),
+        }
+    }
+
+    fn takes_keys(&self) -> bool {
+        !matches!(self, Surface::Watcher(_))
+    }
+
+    async fn handle_event<D: AsyncDisplay>(
+        &mut self,
+        display: &mut D,
+        event: &Event,
+        clipboard: &mut Clipboard,
+    ) -> Result<Option<Closed>, Box<dyn Error>> {
+        Ok(match self {
+            Surface::Picker(w) => match w.handle_event(display, event, clipboard).await? {
+                WindowAction::CloseWindow => Some(Closed::Picker(w.view())),
+                WindowAction::StayOpen => None,
+            },
+            Surface::Prompt(p) => p
+                .handle_event(display, event)
+                .await?
+                .map(|decision| Closed::Prompt { class: p.class().to_owned(), decision }),
+            Surface::Watcher(w) => match w.handle_event(display, event).await? {
+                true => None,
+                false => Some(Closed::Watcher),
+            },
+        })
+    }
+}
+
+/// The windows that are open, oldest first. X events are routed to them:
+/// keys to a prompt if there is one, or else the newest one that takes the
+/// keyboard, events about a window to the one it belongs to, and the rest to
+/// all of them.
+pub struct Surfaces {
+    open: Vec<Surface>,
+    // how the picker was last left
+    view: View,
+    // the key that answered a prompt, kept from the windows under it until
+    // it's released
+    swallowed: Option<xproto::Keycode>,
+}
+
+impl Surfaces {
+    pub fn new(view: View) -> Surfaces {
+        Surfaces { open: Vec::new(), view, swallowed: None }
+    }
+
+    /// The view the picker is in, or was left in when it's closed.
+    pub fn view(&self) -> View {
+        self.open
+            .iter()
+            .find_map(|surface| match surface {
+                Surface::Picker(w) => Some(w.view()),
+                _ => None,
+            })
+            .unwrap_or(self.view)
+    }
+
+    pub fn open(&mut self, surface: Surface) {
+        self.open.push(surface);
+    }
+
+    pub fn picker(&mut self) -> Option<&mut Window> {
+        self.open.iter_mut().find_map(|surface| match surface {
+            Surface::Picker(w) => Some(w.as_mut()),
+            _ => None,
+        })
+    }
+
+    /// Removes the picker, leaving closing it to the caller.
+    pub fn take_picker(&mut self) -> Option<Window> {
+        let i = self.open.iter().position(|surface| matches!(surface, Surface::Picker(_)))?;
+        match self.open.remove(i) {
+            Surface::Picker(w) => {
+                self.view = w.view();
+                Some(*w)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Removes the watch window, leaving closing it to the caller.
+    pub fn take_watcher(&mut self) -> Option<Watcher> {
+        let i = self.open.iter().position(|surface| matches!(surface, Surface::Watcher(_)))?;
+        match self.open.remove(i) {
+            Surface::Watcher(w) => Some(w),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn has_prompt(&self) -> bool {
+        self.open.iter().any(|surface| matches!(surface, Surface::Prompt(_)))
+    }
+
+    /// Passes the event on to the surfaces it's for, and tells which of them
+    /// closed because of it.
+    pub async fn handle_event<D: AsyncDisplay>(
+        &mut self,
+        display: &mut D,
+        event: &Event,
+        clipboard: &mut Clipboard,
+    ) -> Result<Vec<Closed>, Box<dyn Error>> {
+        match event {
+            // including its repeats
+            Event::KeyPress(kp) if self.swallowed == Some(kp.detail) => return Ok(Vec::new()),
+            Event::KeyRelease(kr) if self.swallowed == Some(kr.detail) => {
+                self.swallowed = None;
+                return Ok(Vec::new());
+            }
+            _ => {}
+        }
+        let routed: Vec<usize> = match (event, event_window(event)) {
+            (Event::KeyPress(_) | Event::KeyRelease(_), _) => self
+                .open
+                .iter()
+                .rposition(|surface| matches!(surface, Surface::Prompt(_)))
+                .or_else(|| self.open.iter().rposition(Surface::takes_keys))
+                .into_iter()
+                .collect(),
+            (_, Some(window)) => self.open.iter().position(|surface| surface.id() == window).into_iter().collect(),
+            (_, None) => (0..self.open.len()).collect(),
+        };
+        let mut closed = Vec::new();
+        let mut gone = Vec::new();
+        for i in routed {
+            if let Some(outcome) = self.open[i].handle_event(display, event, clipboard).await? {
+                if let Closed::Picker(view) = outcome {
+                    self.view = view;
+                }
+                closed.push(outcome);
+                gone.push(i);
+            }
+        }
+        for i in gone.into_iter().rev() {
+            self.open.remove(i);
+        }
+        if closed.iter().any(|outcome| matches!(outcome, Closed::Prompt { .. })) {
+            if let Event::KeyPress(kp) = event {
+                self.swallowed = Some(kp.detail);
+            }
+            // the picker keeps the keyboard while it's open
+            if !self.open.iter().any(Surface::takes_keys) {
+                display.ungrab_keyboard_checked(xproto::Time::CURRENT_TIME).await?;
+            }
+        }
+        Ok(closed)
+    }
+
+    /// Lets the watch windows catch up with new clips.
+    pub async fn refresh<D: AsyncDisplay>(&mut self, display: &mut D) -> Result<(), Box<dyn Error>> {
+        for surface in &mut self.open {
+            if let Surface::Watcher(w) = surface {
+                w.refresh(display).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The window an event is about, for those that are about one of ours
+fn event_window(event: &Event) -> Option<xproto::Window> {
+    match event {
+        Event::Expose(e) => Some(e.window),
+        Event::FocusIn(e) => Some(e.event),
+        Event::FocusOut(e) => Some(e.event),
+        Event::VisibilityNotify(e) => Some(e.window),
+        Event::ClientMessage(e) => Some(e.window),
+        _ => None,
+    }
+}
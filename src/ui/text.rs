@@ -1,7 +1,18 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
 use std::{boxed::Box, error::Error};
 
 use font_loader::system_fonts;
+use log::{debug, warn};
 use rusttype::Font;
+use unicode_bidi::BidiInfo;
+
+// Fonts already loaded, by configured family. Finding one through fontconfig
+// is slow enough to notice every time the picker opens.
+static FONTS: LazyLock<Mutex<HashMap<Option<String>, Font<'static>>>> = LazyLock::new(Default::default);
+
+const GENERIC_FAMILIES: &[&str] = &["monospace", "mono", "sans-serif", "sans", "serif"];
 
 pub fn font(family: Option<&str>) -> Result<Font<'static>, Box<dyn Error>> {
     let name = match family {
@@ -16,6 +27,138 @@ pub fn font(family: Option<&str>) -> Result<Font<'static>, Box<dyn Error>> {
     let (font_data, _) =
         system_fonts::get(&property).ok_or("Could not get system fonts property")?;
 
-    let font: Font<'static> = Font::try_from_vec(font_data).expect("Error constructing Font");
+    let font = Font::try_from_vec(font_data).ok_or_else(|| format!("{} is not a font that can be drawn", name))?;
+    Ok(font)
+}
+
+/// The configured font, or else the first monospace font that works. Tells
+/// what was wrong with the configured one, if anything.
+pub fn font_or_fallback(family: Option<&str>) -> Result<(Font<'static>, Option<String>), Box<dyn Error>> {
+    // fontconfig quietly substitutes fonts that aren't installed, except for
+    // its generic names, which always mean some installed font
+    let missing = family.filter(|name| {
+        !GENERIC_FAMILIES.iter().any(|generic| generic.eq_ignore_ascii_case(name))
+            && !system_fonts::query_all().iter().any(|installed| installed.eq_ignore_ascii_case(name))
+    });
+    let problem = match (missing, font(family)) {
+        (None, Ok(font)) => return Ok((font, None)),
+        (Some(name), _) => format!("font {} is not installed", name),
+        (None, Err(e)) => e.to_string(),
+    };
+
+    let mut monospace = system_fonts::FontPropertyBuilder::new().monospace().build();
+    let candidates = system_fonts::query_specific(&mut monospace);
+    for name in candidates.iter().map(|n| n.as_str()).chain(["monospace"]) {
+        if let Ok(font) = font(Some(name)) {
+            return Ok((font, Some(format!("{}, using {} instead", problem, name))));
+        }
+    }
+    Err(format!("{}, and no other monospace font works either", problem).into())
+}
+
+/// Like `font_or_fallback`, but only looks the font up the first time and
+/// logs what was wrong with it.
+pub fn cached_font(family: Option<&str>) -> Result<Font<'static>, Box<dyn Error>> {
+    let key = family.map(|f| f.to_owned());
+    if let Some(font) = FONTS.lock().unwrap().get(&key) {
+        return Ok(font.clone());
+    }
+    let started = Instant::now();
+    let (font, problem) = font_or_fallback(family)?;
+    if let Some(problem) = problem {
+        warn!("{}", problem);
+    }
+    debug!("loaded font {:?} in {:?}", family, started.elapsed());
+    FONTS.lock().unwrap().insert(key, font.clone());
     Ok(font)
 }
+
+/// How whitespace and control characters in clips are shown.
+#[derive(Clone)]
+pub struct Whitespace {
+    pub tab_width: usize,
+    pub visible: bool,
+    pub line_ends: bool,
+}
+
+/// Expands tabs and replaces control characters with their visible symbols.
+/// `ends_line` tells if the line was followed by a line break.
+pub fn visualize(line: &str, ends_line: bool, whitespace: &Whitespace) -> String {
+    let tab_width = whitespace.tab_width.max(1);
+    let trailing_start = line.trim_end_matches(' ').len();
+    let mut out = String::with_capacity(line.len());
+    let mut column = 0;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\t' => {
+                let width = tab_width - column % tab_width;
+                if whitespace.visible {
+                    out.push('⇥');
+                    out.extend(std::iter::repeat_n(' ', width - 1));
+                } else {
+                    out.extend(std::iter::repeat_n(' ', width));
+                }
+                column += width;
+                continue;
+            }
+            ' ' if whitespace.visible && i >= trailing_start => out.push('␣'),
+            // Unicode has a "control picture" for each C0 control character
+            c if (c as u32) < 0x20 => out.push(char::from_u32(0x2400 + c as u32).unwrap()),
+            '\u{7f}' => out.push('␡'),
+            c => out.push(c),
+        }
+        column += 1;
+    }
+    if ends_line && whitespace.line_ends {
+        out.push('¶');
+    }
+    out
+}
+
+/// Reorders a line from logical to display order, so that right-to-left
+/// scripts like Arabic and Hebrew can be drawn left to right. Also tells
+/// whether the line as a whole is right-to-left and should be aligned right.
+pub fn visual_order(line: &str) -> (String, bool) {
+    // nothing to do for the common case
+    if line.chars().all(|c| (c as u32) < 0x590) {
+        return (line.to_owned(), false);
+    }
+    let bidi = BidiInfo::new(line, None);
+    match bidi.paragraphs.first() {
+        Some(paragraph) => (
+            bidi.reorder_line(paragraph, paragraph.range.clone()).into_owned(),
+            paragraph.level.is_rtl(),
+        ),
+        None => (line.to_owned(), false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ui::text::{visual_order, visualize, Whitespace};
+
+    #[test]
+    fn right_to_left() {
+        assert_eq!(visual_order("plain text"), ("plain text".to_owned(), false));
+        assert_eq!(visual_order("שלום"), ("םולש".to_owned(), true));
+        assert_eq!(visual_order("مرحبا 123"), ("123 ابحرم".to_owned(), true));
+        // the first strong character decides the direction of the line
+        assert_eq!(visual_order("say שלום"), ("say םולש".to_owned(), false));
+    }
+
+    #[test]
+    fn tabs_and_control_characters() {
+        let mut whitespace = Whitespace {
+            tab_width: 4,
+            visible: false,
+            line_ends: false,
+        };
+        assert_eq!(visualize("a\tb\tc", true, &whitespace), "a   b   c");
+        assert_eq!(visualize("\tx\r", false, &whitespace), "    x␍");
+
+        whitespace.visible = true;
+        whitespace.line_ends = true;
+        assert_eq!(visualize("ab\tc  ", true, &whitespace), "ab⇥ c␣␣¶");
+        assert_eq!(visualize("a b", false, &whitespace), "a b");
+    }
+}
@@ -0,0 +1,178 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use breadx::protocol::xproto::{AtomEnum, EventMask};
+use breadx::protocol::Event;
+use breadx::{prelude::*, protocol::xproto};
+use log::debug;
+
+use crate::db::{Database, SortMode};
+use crate::incognito;
+use crate::options::{Options, Theme};
+use crate::ui;
+
+const WIDTH: u16 = 600;
+const HEIGHT: u16 = 400;
+const TITLE: &str = "repeat watch";
+// The InputHint flag of WM_HINTS
+const INPUT_HINT: u32 = 1;
+
+/// A window that follows the history as clips are captured, newest at the
+/// top. It never takes the focus, so it can stay open on another monitor
+/// while copying from other windows.
+pub struct Watcher {
+    window: xproto::Window,
+    canvas: ui::canvas::Canvas,
+    database: Arc<Database>,
+    theme: Theme,
+    delete_window: xproto::Atom,
+    // id of the newest clip drawn, to only redraw once something new arrives
+    newest: Option<usize>,
+    // whether it was drawn in incognito mode, without the clips
+    hidden: bool,
+}
+
+impl Watcher {
+    pub async fn create<D: AsyncDisplay>(
+        display: &mut D,
+        database: Arc<Database>,
+        options: &Options,
+    ) -> Result<Watcher, Box<dyn Error>> {
+        let wid = display.generate_xid().await?;
+        let root = display.default_screen().root;
+        display.create_window_checked(
+            0,
+            wid,
+            root,
+            0,
+            0,
+            WIDTH,
+            HEIGHT,
+            0,
+            xproto::WindowClass::COPY_FROM_PARENT,
+            0,
+            xproto::CreateWindowAux::new()
+                .background_pixel(display.default_screen().white_pixel)
+                .event_mask(EventMask::EXPOSURE),
+        ).await?;
+
+        display.change_property_checked(
+            xproto::PropMode::REPLACE,
+            wid,
+            xproto::Atom::from(AtomEnum::WM_NAME),
+            xproto::Atom::from(AtomEnum::STRING),
+            8,
+            TITLE.len() as u32,
+            TITLE.as_bytes(),
+        ).await?;
+        // input = False, so the window manager never focuses us
+        let hints = [INPUT_HINT, 0, 0, 0, 0, 0, 0, 0, 0];
+        display.change_property_checked(
+            xproto::PropMode::REPLACE,
+            wid,
+            xproto::Atom::from(AtomEnum::WM_HINTS),
+            xproto::Atom::from(AtomEnum::WM_HINTS),
+            32,
+            hints.len() as u32,
+            &hints[..],
+        ).await?;
+        // without WM_DELETE_WINDOW, closing the window would make the window
+        // manager kill our whole connection
+        let protocols = display.intern_atom_immediate(false, "WM_PROTOCOLS").await?.atom;
+        let delete_window = display.intern_atom_immediate(false, "WM_DELETE_WINDOW").await?.atom;
+        display.change_property_checked(
+            xproto::PropMode::REPLACE,
+            wid,
+            protocols,
+            xproto::Atom::from(AtomEnum::ATOM),
+            32,
+            1,
+            &[delete_window][..],
+        ).await?;
+
+        let canvas = ui::canvas::Canvas::new(display, wid, WIDTH, HEIGHT, options).await?;
+        display.map_window_checked(wid).await?;
+
+        let mut watcher = Watcher {
+            window: wid,
+            canvas,
+            database,
+            theme: options.theme.clone(),
+            delete_window,
+            newest: None,
+            hidden: false,
+        };
+        watcher.redraw();
+        watcher.canvas.draw(display).await?;
+        Ok(watcher)
+    }
+
+    /// The X window it's drawn in.
+    pub fn id(&self) -> xproto::Window {
+        self.window
+    }
+
+    /// Redraws if a clip was captured since the last time.
+    pub async fn refresh<D: AsyncDisplay>(&mut self, display: &mut D) -> Result<(), Box<dyn Error>> {
+        let newest = self.database.sorted(SortMode::Recency, 1).first().and_then(|c| self.database.id_of(c));
+        if newest != self.newest || incognito::enabled() != self.hidden {
+            self.redraw();
+            self.canvas.draw(display).await?;
+        }
+        Ok(())
+    }
+
+    fn redraw(&mut self) {
+        self.canvas.clear();
+        self.hidden = incognito::enabled();
+        if self.hidden {
+            self.newest = None;
+            self.canvas.draw_line("incognito: the history is hidden", false, &self.theme.input, 0, 0);
+            return;
+        }
+        let clips = self.database.sorted(SortMode::Recency, self.canvas.text_rows());
+        self.newest = clips.first().and_then(|c| self.database.id_of(c));
+        for (row, clip) in clips.iter().enumerate() {
+            let text = match clip.contents.as_ref() {
+                contents if contents.looks_binary() => {
+                    format!("<binary data, {} KB>", contents.size().div_ceil(1024))
+                }
+                contents => {
+                    let preview = contents.preview(self.database.preview_bytes());
+                    let first = preview.lines().next().unwrap_or("");
+                    match clip.counts.lines {
+                        lines if lines > 1 => format!("{} (+ {} rows)", first, lines - 1),
+                        _ => first.to_owned(),
+                    }
+                }
+            };
+            let id = self.database.id_of(clip).map(|id| id.to_string()).unwrap_or_default();
+            let color = if row == 0 { &self.theme.highlight } else { &self.theme.text };
+            self.canvas.draw_line(&format!("{:>3} {}", id, text), false, color, row as u16, 0);
+        }
+    }
+
+    /// Returns whether the window is still open.
+    pub async fn handle_event<D: AsyncDisplay>(
+        &mut self,
+        display: &mut D,
+        event: &Event,
+    ) -> Result<bool, Box<dyn Error>> {
+        match event {
+            Event::Expose(ee) if ee.window == self.window => {
+                self.canvas.draw(display).await?;
+            }
+            Event::ClientMessage(cm) if cm.window == self.window && cm.data.as_data32()[0] == self.delete_window => {
+                debug!("watch window closed by the window manager");
+                self.close(display).await?;
+                return Ok(false);
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    pub async fn close<D: AsyncDisplay>(&self, display: &mut D) -> breadx::Result<()> {
+        display.destroy_window_checked(self.window).await
+    }
+}
@@ -1,16 +1,27 @@
-use std::borrow::Cow;
+// breadx_keysyms is deprecated in favour of xkeysym, but still does what we need
+#![allow(deprecated)]
+
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use crate::db::{Clip, ClipContents, Database};
-use crate::options::{Color, Options};
+use crate::clock::{self, LocalTime};
+use crate::db::{self, Clip, ClipContents, Database, SortMode};
+use crate::db::Source;
+use crate::history::SearchHistory;
+use crate::incognito;
+use crate::keys::{self, Action, KeyCombo, Keymap};
+use crate::snippets::{Snippet, Snippets};
+use crate::options::{Color, Options, PasteGuard, Theme};
 use crate::ui;
-use breadx::protocol::xproto::{ModMask, SendEventRequest};
+use crate::wm;
+use crate::ui::speech::{self, Speaker};
 use breadx::protocol::{self, xproto::EventMask, Event};
 use breadx::{prelude::*, protocol::xproto};
 use breadx_keysyms::{keysyms, KeyboardState};
 use log::{debug, error};
 use crate::clipboard::Clipboard;
+use crate::substitute::{self, Substitution};
 use crate::ui::window::WindowAction::{CloseWindow, StayOpen};
 
 pub struct Window {
@@ -23,14 +34,58 @@ pub struct Window {
     input: String,
     modes: Modes,
     searches: Vec<Clip>,
+    // how many near duplicates each of the searches stands for
+    collapsed: Vec<usize>,
+    // whether near duplicates are collapsed right now
+    collapse: bool,
+    // whether the archived clips are listed instead of the rest
+    archived: bool,
+    // whether the clips are laid out on a timeline instead of a list
+    timeline: bool,
+    // whether showing the history again was asked for once in incognito
+    // mode, and waits for the confirmation
+    revealing: bool,
+    // the warning shown when pasting was asked for once but crosses a paste
+    // guard, and waits for the confirmation
+    guarded: Option<String>,
+    paste_guards: Vec<PasteGuard>,
     current_choice: usize,
+    sort: SortMode,
+    pinned_first: bool,
+    // how much the font was made larger or smaller than configured
+    zoom: f32,
+    history: Arc<SearchHistory>,
+    // how far back in the search history the input was recalled from
+    history_pos: Option<usize>,
+    snippets: Arc<Snippets>,
+    // the clip being saved as a snippet, and the label typed so far
+    label_prompt: Option<(Clip, String)>,
+    // the clip whose earlier versions are listed, and the search at the time
+    chain_of: Option<(Clip, String)>,
+    theme: Theme,
+    keymap: Arc<Mutex<Keymap>>,
+    speaker: Option<Speaker>,
+    // a translucent window dimming the monitor behind the picker
+    backdrop: Option<xproto::Window>,
 }
 
 struct Modes {
-    shift: bool,
     ctrl: bool,
 }
 
+/// How the picker was left, for opening it the same way next time.
+#[derive(Clone, Copy, Debug)]
+pub struct View {
+    pub sort: SortMode,
+    /// Points added to the configured font size
+    pub zoom: f32,
+}
+
+// Bounds and step of changing the font size from the picker
+const MIN_FONT_SIZE: f32 = 6.0;
+const MAX_FONT_SIZE: f32 = 72.0;
+const FONT_STEP: f32 = 1.0;
+
 pub enum WindowAction {
     CloseWindow,
     StayOpen,
@@ -40,11 +95,21 @@ impl Window {
     pub async fn create<D: AsyncDisplay>(
         display: &mut D,
         database: Arc<Database>,
+        history: Arc<SearchHistory>,
+        snippets: Arc<Snippets>,
+        keymap: Arc<Mutex<Keymap>>,
+        view: View,
         options: &Options,
     ) -> Result<Window, Box<dyn Error>> {
         let focused_window = get_focused_window(display).await?;
         let geom = get_active_screen_geom(display).await?;
         debug!("active screen geom {:?}", geom);
+        let options = &options.for_output(geom.output.as_deref());
+
+        let backdrop = match options.theme.backdrop {
+            opacity if opacity > 0.0 => create_backdrop(display, &geom, opacity).await?,
+            _ => None,
+        };
 
         let wid = display.generate_xid().await?;
         let def_screen = display.default_screen();
@@ -55,8 +120,8 @@ impl Window {
             0,
             wid,
             root,
-            (geom.x + geom.width as i16 / 2i16 - width as i16 / 2i16).into(),
-            (geom.y + geom.height as i16 / 2i16 - height as i16 / 2i16).into(),
+            geom.x + geom.width as i16 / 2i16 - width as i16 / 2i16,
+            geom.y + geom.height as i16 / 2i16 - height as i16 / 2i16,
             width,
             height,
             2,
@@ -74,7 +139,10 @@ impl Window {
                 ),
         ).await?;
 
-        let canvas = ui::canvas::Canvas::new(display, wid, width, height, &options).await?;
+        let mut canvas = ui::canvas::Canvas::new(display, wid, width, height, options).await?;
+        if view.zoom != 0.0 {
+            canvas.set_font_size((options.font_size + view.zoom).clamp(MIN_FONT_SIZE, MAX_FONT_SIZE));
+        }
         let keyboard_state = KeyboardState::new_async(display).await?;
 
         let mut w = Window {
@@ -86,11 +154,29 @@ impl Window {
             canvas,
             input: String::new(),
             modes: Modes {
-                shift: false,
                 ctrl: false,
             },
             searches: Vec::new(),
+            collapsed: Vec::new(),
+            collapse: options.collapse_similar,
+            archived: false,
+            timeline: false,
+            revealing: false,
+            guarded: None,
+            paste_guards: options.paste_guards.clone(),
             current_choice: 0,
+            sort: view.sort,
+            pinned_first: options.pinned_first,
+            zoom: view.zoom,
+            history,
+            history_pos: None,
+            snippets,
+            label_prompt: None,
+            chain_of: None,
+            theme: options.theme.clone(),
+            keymap,
+            speaker: options.speech.then(Speaker::new),
+            backdrop,
         };
 
         w.redraw();
@@ -101,18 +187,55 @@ impl Window {
     }
 
     pub async fn hide<D: AsyncDisplay>(&self, display: &mut D) -> breadx::Result<()> {
+        display.ungrab_keyboard_checked(xproto::Time::CURRENT_TIME).await?;
+        if let Some(backdrop) = self.backdrop {
+            display.unmap_window_checked(backdrop).await?;
+        }
         display.unmap_window_checked(self.window).await
     }
 
+    /// The X window it's drawn in.
+    pub fn id(&self) -> xproto::Window {
+        self.window
+    }
+
+    /// The sort order and font size the picker is in now.
+    pub fn view(&self) -> View {
+        View {
+            sort: self.sort,
+            zoom: self.zoom,
+        }
+    }
+
+    /// Hides the window and gives the focus back to where it was.
+    pub async fn close<D: AsyncDisplay>(&self, display: &mut D) -> breadx::Result<()> {
+        self.hide(display).await?;
+        focus_window(display, self.focused_window).await
+    }
+
     pub async fn destroy<D: AsyncDisplay>(&self, display: &mut D) -> breadx::Result<()> {
+        if let Some(backdrop) = self.backdrop {
+            display.destroy_window_checked(backdrop).await?;
+        }
         display.destroy_window_checked(self.window).await
     }
 
     pub async fn show<D: AsyncDisplay>(&mut self, display: &mut D) -> breadx::Result<()> {
         let focused_window = get_focused_window(display).await?;
         self.focused_window = focused_window;
+        self.revealing = false;
+        self.guarded = None;
         self.research();
 
+        // grab before mapping, so keys typed while the window is opening can't
+        // end up in the previously focused window
+        if !grab_keyboard(display, self.root).await? {
+            error!("unable to grab the keyboard, typing may go to the wrong window");
+        }
+        // mapped first, so it ends up below the picker
+        if let Some(backdrop) = self.backdrop {
+            display.map_window_checked(backdrop).await?;
+        }
         display.map_window_checked(self.window).await?;
         let cookie = display.send_void_request(
             xproto::SetInputFocusRequest {
@@ -127,48 +250,313 @@ impl Window {
     }
 
     fn research(&mut self) {
+        if substitute::is_command(&self.input) {
+            // commands work on the highlighted clip, so keep the list as it is
+            return;
+        }
         self.current_choice = 0;
-        if self.input.is_empty() {
-            self.searches = self.database.clips().iter().rev().take(100).map(|c| c.clone()).collect();
+        self.guarded = None;
+        if incognito::enabled() {
+            self.searches.clear();
+            self.collapsed.clear();
+            return;
+        }
+        match &self.chain_of {
+            Some((clip, input)) if *input == self.input => {
+                self.searches = self.database.chain(clip);
+                self.collapsed = vec![0; self.searches.len()];
+                return;
+            }
+            // changing the search leaves the chain
+            _ => self.chain_of = None,
+        }
+        if let Some(pattern) = self.input.strip_prefix('@') {
+            self.searches = self.snippets.search(pattern, self.database.matcher())
+                .into_iter()
+                .map(|s| Clip::new(Source::Clipboard, ClipContents::Text(s.text)))
+                .collect();
+        } else if self.archived {
+            self.searches = self.database.search(&format!("archived: {}", self.input), 100);
+        } else if self.input.is_empty() {
+            let sort = if self.timeline { SortMode::Recency } else { self.sort };
+            self.searches = self.database.sorted(sort, 100);
+            if self.pinned_first && !self.timeline {
+                // however far down the order they'd be
+                let mut pinned = self.database.pinned_clips();
+                self.searches.retain(|clip| !pinned.contains(clip));
+                pinned.append(&mut self.searches);
+                pinned.truncate(100);
+                self.searches = pinned;
+            }
         } else {
             self.searches = self.database.search(&self.input, 100);
         }
+        if self.timeline {
+            self.searches.sort_by_key(|clip| std::cmp::Reverse(clip.captured));
+        }
+        self.collapsed = vec![0; self.searches.len()];
+        // every copy has its place on the timeline
+        if self.collapse && !self.timeline && !self.input.starts_with('@') {
+            self.collapse_similar();
+        }
+    }
+
+    // Folds each clip into the first one before it that is nearly the same
+    fn collapse_similar(&mut self) {
+        let previews: Vec<_> = self.searches.iter().map(|clip| clip.contents.preview(self.database.preview_bytes())).collect();
+        let texts: Vec<&str> = previews.iter().map(|preview| preview.as_ref()).collect();
+        let firsts = db::first_similar(&texts);
+        let mut kept: Vec<Clip> = Vec::new();
+        let mut collapsed: Vec<usize> = Vec::new();
+        // where each clip kept as its own ended up
+        let mut positions = vec![0; firsts.len()];
+        for (i, clip) in self.searches.drain(..).enumerate() {
+            if firsts[i] == i {
+                positions[i] = kept.len();
+                kept.push(clip);
+                collapsed.push(0);
+            } else {
+                collapsed[positions[firsts[i]]] += 1;
+            }
+        }
+        self.searches = kept;
+        self.collapsed = collapsed;
+    }
+
+    fn color_of(&self, i: usize, clip: &Clip) -> Color {
+        if self.current_choice == i {
+            self.theme.highlight.clone()
+        } else if self.database.is_queued(clip) {
+            self.theme.queued.clone()
+        } else {
+            self.theme.text.clone()
+        }
+    }
+
+    // Lays the clips out newest first along a line of the times they were
+    // copied, with the application each came from, and the hour above the
+    // first clip of each
+    fn draw_timeline(&mut self, status_row: u16) {
+        let indent = self.canvas.text_width("00:00:00 │ ") as u16;
+        let mut row = 1;
+        let mut previous: Option<LocalTime> = None;
+        for (i, clip) in self.searches.iter().enumerate() {
+            let at = LocalTime::of(clip.captured);
+            if !previous.is_some_and(|previous| previous.same_hour(&at)) {
+                if row + 1 >= status_row {
+                    break;
+                }
+                let hour = format!("{} {:02}:00", at.date(), at.hour);
+                self.canvas.draw_text(&hour, &self.theme.input, row, 0);
+                row += 1;
+            }
+            if row >= status_row {
+                break;
+            }
+            previous = Some(at);
+            let color = self.color_of(i, clip);
+            self.canvas.draw_text(&format!("{} │", at.time()), &color, row, 0);
+            if clip.contents.looks_binary() {
+                self.canvas.draw_text(&placeholder(&clip.contents), &color, row, indent);
+            } else {
+                let text = clip.contents.preview(self.database.preview_bytes());
+                let first = text.lines().next().unwrap_or_default();
+                self.canvas.draw_line(first, clip.counts.lines > 1, &color, row, indent);
+            }
+            if let Some(app) = clip.app_name() {
+                draw_labels(&mut self.canvas, &[format!("[{}]", app)], &color, row);
+            }
+            row += 1;
+        }
     }
 
     fn redraw(&mut self) {
         self.canvas.clear();
-        self.canvas.draw_text(&self.input, &Color::red(), 0, 0);
+        match &self.label_prompt {
+            Some((_, label)) => self.canvas.draw_text(&format!("snippet label: {}", label), &self.theme.input, 0, 0),
+            None => self.canvas.draw_text(&self.input, &self.theme.input, 0, 0),
+        }
+        if self.input.is_empty() && self.label_prompt.is_none() {
+            let sort = format!("[{}]", if self.timeline { "timeline" } else { self.sort.name() });
+            let offset = self.canvas.width() as f32 - self.canvas.text_width(&sort);
+            self.canvas.draw_text(&sort, &self.theme.input, 0, offset as u16);
+        }
         let max_rows = self.canvas.text_rows();
+        // the last row is the status bar
+        let status_row = max_rows.saturating_sub(1) as u16;
         let mut row_offset = 1;
+        let preview = if substitute::is_command(&self.input) {
+            self.searches
+                .get(self.current_choice)
+                .and_then(|clip| self.substituted(clip).ok())
+        } else {
+            None
+        };
         for (i, clip) in self.searches.iter().enumerate() {
-            if i > max_rows {
+            if row_offset >= status_row || self.timeline {
                 break;
             }
+            let clip = match &preview {
+                Some(substituted) if self.current_choice == i => substituted,
+                _ => clip,
+            };
+            let color = self.color_of(i, clip);
             match &clip.contents.as_ref() {
-                &ClipContents::Text(text) => {
-                    let color = if self.current_choice == i { Color::green() } else { Color::white() };
-                    let mut r = 0;
-                    for row in text.lines() {
+                contents if contents.looks_binary() => {
+                    self.canvas.draw_text(&format!("{}", i), &color, row_offset, 0);
+                    self.canvas.draw_text(&placeholder(contents), &color, row_offset, 20);
+                    row_offset += 1;
+                }
+                contents => {
+                    let line_count = clip.counts.lines;
+                    let text = contents.preview(self.database.preview_bytes());
+                    for (r, row) in text.lines().enumerate() {
+                        if row_offset >= status_row {
+                            break;
+                        }
                         if r == 5 {
                             // TODO: Configurable size
-                            let extra_rows = text.lines().count() - 5;
+                            let extra_rows = line_count - 5;
                             self.canvas.draw_text(&format!(" ... + {} rows", extra_rows), &color, row_offset, 0);
                             row_offset += 1;
                             break;
                         } else {
                             if r == 0 {
-                                self.canvas.draw_text(&format!("{}", i), &color, row_offset as u16, 0);
+                                self.canvas.draw_text(&format!("{}", i), &color, row_offset, 0);
+                                let mut labels: Vec<String> = self.database.tags(clip).iter().map(|tag| format!("#{}", tag)).collect();
+                                if let ClipContents::Files(paths) = clip.contents.as_ref() {
+                                    labels.push(match paths.len() {
+                                        1 => "file".to_owned(),
+                                        n => format!("{} files", n),
+                                    });
+                                }
+                                match self.collapsed.get(i).copied().unwrap_or(0) {
+                                    0 => {}
+                                    similar => labels.push(format!("+{} similar", similar)),
+                                }
+                                draw_labels(&mut self.canvas, &labels, &color, row_offset);
                             }
                             // TODO: Calculate the size of three numbers and use as offset
+                            let ends_line = r + 1 < line_count || text.ends_with('\n');
                             self.canvas
-                                .draw_text(row, &color, row_offset as u16, 20);
+                                .draw_line(row, ends_line, &color, row_offset, 20);
                             row_offset += 1;
                         }
-                        r += 1;
                     }
                 }
             }
         }
+        if self.timeline {
+            self.draw_timeline(status_row);
+        }
+        if incognito::enabled() {
+            let key = self.keymap.lock().unwrap().key_for(Action::Incognito).unwrap_or_else(|| "incognito".to_owned());
+            let lock = if self.revealing {
+                format!("show the history again? Press {} again to confirm", key)
+            } else {
+                format!("incognito: the history is hidden, press {} twice to show it", key)
+            };
+            self.canvas.draw_text(&lock, &self.theme.input, 1, 0);
+        }
+        if let Some(clip) = preview.as_ref().or(self.searches.get(self.current_choice)) {
+            let counts = clip.counts;
+            let mut status = format!(
+                "{} lines, {} words, {} bytes",
+                counts.lines, counts.words, counts.bytes
+            );
+            status.push_str(&format!(", copied {}", clock::ago(clip.age(SystemTime::now()))));
+            if let Some(app) = clip.app_name() {
+                status.push_str(&format!(", from {}", app));
+                if let Some(title) = clip.origin.as_ref().and_then(|origin| origin.title.as_deref()) {
+                    status.push_str(&format!(" ({})", title));
+                }
+            }
+            if self.archived {
+                status.push_str(", archived");
+            }
+            if self.chain_of.is_some() {
+                status.push_str(", versions newest first");
+            } else {
+                match self.database.chain(clip).len() {
+                    1 => {}
+                    2 => status.push_str(", edited from 1 earlier version"),
+                    n => status.push_str(&format!(", edited from {} earlier versions", n - 1)),
+                }
+            }
+            let status = self.guarded.as_deref().unwrap_or(&status);
+            self.canvas.draw_text(status, &self.theme.input, status_row, 0);
+        }
+
+        if let Some(speaker) = self.speaker.as_mut() {
+            let text = match &self.label_prompt {
+                Some((_, label)) => format!("snippet label: {}", label),
+                None => speech::announcement(
+                    self.current_choice,
+                    self.searches.len(),
+                    self.searches.get(self.current_choice),
+                ),
+            };
+            speaker.say(text);
+        }
+    }
+
+    fn substituted(&self, clip: &Clip) -> Result<Clip, Box<dyn Error>> {
+        let substitution = Substitution::parse(&self.input)?;
+        let contents = match clip.contents.as_ref() {
+            ClipContents::Text(text) => ClipContents::Text(substitution.apply(text)),
+            ClipContents::Files(_) => return Err("files can't be edited".into()),
+            ClipContents::Binary { .. } => return Err("binary clips can't be edited".into()),
+        };
+        Ok(Clip::new(clip.source, contents))
+    }
+
+    /// Replaces the input with an older or newer query from the search history.
+    fn recall(&mut self, older: bool) -> bool {
+        if self.history.len() == 0 {
+            return false;
+        }
+        let pos = match (self.history_pos, older) {
+            (None, true) => Some(0),
+            (Some(p), true) => Some((p + 1).min(self.history.len() - 1)),
+            (None, false) | (Some(0), false) => None,
+            (Some(p), false) => Some(p - 1),
+        };
+        self.input = pos.and_then(|p| self.history.get(p)).unwrap_or_default();
+        self.history_pos = pos;
+        self.research();
+        true
+    }
+
+    // Keys typed while asking for the label of a new snippet
+    fn label_key(&mut self, sym: u32) -> bool {
+        let (clip, mut label) = match self.label_prompt.take() {
+            None => return false,
+            Some(prompt) => prompt,
+        };
+        match sym {
+            keysyms::KEY_Escape => return true,
+            keysyms::KEY_BackSpace => {
+                label.pop();
+            }
+            keysyms::KEY_Return if !label.is_empty() => {
+                let snippet = Snippet {
+                    label,
+                    text: clip.contents.text().into_owned(),
+                };
+                if let Err(e) = self.snippets.add(snippet) {
+                    error!("unable to save snippet: {}", e);
+                }
+                return true;
+            }
+            _ if self.modes.ctrl => {}
+            key => {
+                if let Some(char) = keys::typed_char(key) {
+                    label.push(char);
+                }
+            }
+        }
+        self.label_prompt = Some((clip, label));
+        true
     }
 
     fn selection_down(&mut self) -> bool {
@@ -187,6 +575,188 @@ impl Window {
         true
     }
 
+    // Returns what should happen to the window if the action closes it, or
+    // `None` if it stays open and should be redrawn
+    async fn perform<D: AsyncDisplay>(
+        &mut self,
+        display: &mut D,
+        action: Action,
+        clipboard: &mut Clipboard,
+    ) -> Result<Option<WindowAction>, Box<dyn Error>> {
+        if action != Action::Incognito {
+            self.revealing = false;
+        }
+        if action != Action::Paste {
+            self.guarded = None;
+        } else if self.guarded.take().is_none() {
+            if let Some(warning) = self.paste_warning(display, clipboard).await? {
+                self.guarded = Some(warning);
+                return Ok(None);
+            }
+        }
+        match action {
+            Action::Close => {
+                self.hide(display).await?;
+                focus_window(display, self.focused_window).await?;
+                return Ok(Some(CloseWindow));
+            }
+            Action::Up if self.searches.is_empty() => {
+                self.recall(true);
+            }
+            Action::Up => {
+                self.selection_up();
+            }
+            Action::Down if self.searches.is_empty() => {
+                self.recall(false);
+            }
+            Action::Down => {
+                self.selection_down();
+            }
+            Action::HistoryBack => {
+                self.recall(true);
+            }
+            Action::HistoryForward => {
+                self.recall(false);
+            }
+            Action::CycleSort => {
+                self.sort = self.sort.next();
+                self.research();
+            }
+            Action::FontLarger | Action::FontSmaller => {
+                let step = if action == Action::FontLarger { FONT_STEP } else { -FONT_STEP };
+                let size = (self.canvas.font_size() + step).clamp(MIN_FONT_SIZE, MAX_FONT_SIZE);
+                self.zoom += size - self.canvas.font_size();
+                self.canvas.set_font_size(size);
+            }
+            Action::Expand => {
+                self.collapse = !self.collapse;
+                self.research();
+            }
+            Action::Archived => {
+                self.archived = !self.archived;
+                self.research();
+            }
+            Action::Timeline => {
+                self.timeline = !self.timeline;
+                self.research();
+            }
+            Action::Incognito if !incognito::enabled() => {
+                incognito::set(true);
+                self.research();
+            }
+            Action::Incognito if self.revealing => {
+                self.revealing = false;
+                incognito::set(false);
+                self.research();
+            }
+            Action::Incognito => {
+                self.revealing = true;
+            }
+            Action::Chain => {
+                self.chain_of = match (self.chain_of.take(), self.searches.get(self.current_choice)) {
+                    (None, Some(clip)) => Some((clip.clone(), self.input.clone())),
+                    _ => None,
+                };
+                self.research();
+            }
+            Action::Clear => {
+                self.input.clear();
+                self.history_pos = None;
+                self.research();
+            }
+            Action::Delete => {
+                self.input.pop();
+                self.history_pos = None;
+                self.research();
+            }
+            Action::SaveSnippet => {
+                if let Some(clip) = self.searches.get(self.current_choice) {
+                    self.label_prompt = Some((clip.clone(), String::new()));
+                }
+            }
+            Action::ToggleQueue => {
+                if let Some(clip) = self.searches.get(self.current_choice) {
+                    self.database.toggle_queued(clip.clone());
+                }
+            }
+            Action::Promote => {
+                let clip = match self.searches.get(self.current_choice) {
+                    Some(clip) => clip.clone(),
+                    None => return Ok(None),
+                };
+                self.hide(display).await?;
+                focus_window(display, self.focused_window).await?;
+                let to = clip.source.other();
+                clipboard.promote(display, clip, to).await?;
+                return Ok(Some(CloseWindow));
+            }
+            Action::Paste | Action::Copy => {
+                let chosen = match self.searches.get(self.current_choice) {
+                    // a non-empty paste queue starts with its first clip
+                    _ if self.database.queue_len() > 0 => self.database.advance_queue(),
+                    None => None,
+                    Some(clip) if substitute::is_command(&self.input) => {
+                        match self.substituted(clip) {
+                            Ok(substituted) => {
+                                self.database.add_clip(substituted.clone());
+                                Some(substituted)
+                            }
+                            Err(e) => {
+                                error!("invalid command {:?}: {}", self.input, e);
+                                return Ok(None);
+                            }
+                        }
+                    }
+                    Some(clip) => Some(clip.clone()),
+                };
+                self.history.add(&self.input);
+                self.hide(display).await?;
+                focus_window(display, self.focused_window).await?;
+                if let Some(clip) = chosen {
+                    self.database.select_clip(clip);
+                    clipboard.take_ownership(display).await?;
+                    if action == Action::Paste {
+                        clipboard.paste(display, self.focused_window).await?;
+                    }
+                }
+                return Ok(Some(CloseWindow));
+            }
+        }
+        Ok(None)
+    }
+
+    // What to warn about if pasting the clip that would be chosen into the
+    // focused window crosses a paste guard
+    async fn paste_warning<D: AsyncDisplay>(
+        &mut self,
+        display: &mut D,
+        clipboard: &mut Clipboard,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        if self.paste_guards.is_empty() {
+            return Ok(None);
+        }
+        let clip = match self.database.next_queued() {
+            Some(clip) => clip,
+            None => match self.searches.get(self.current_choice) {
+                Some(clip) => clip.clone(),
+                None => return Ok(None),
+            },
+        };
+        let from = match clip.app_name() {
+            Some(app) => app.to_owned(),
+            None => return Ok(None),
+        };
+        let to = match clipboard.window_class(display, self.focused_window).await? {
+            Some(class) => class,
+            None => return Ok(None),
+        };
+        if !self.paste_guards.iter().any(|guard| guard.guards(&from, &to)) {
+            return Ok(None);
+        }
+        let key = self.keymap.lock().unwrap().key_for(Action::Paste).unwrap_or_else(|| "paste".to_owned());
+        Ok(Some(format!("copied from {}, pasting into {}: press {} again to confirm", from, to, key)))
+    }
+
     pub async fn handle_event<D: AsyncDisplay>(
         &mut self,
         display: &mut D,
@@ -199,80 +769,35 @@ impl Window {
                 match sym {
                     keysyms::KEY_Control_L | keysyms::KEY_Control_R =>
                         self.modes.ctrl = false,
-                    keysyms::KEY_Shift_L | keysyms::KEY_Shift_R =>
-                        self.modes.shift = false,
                     _ => {}
                 }
             }
             Event::KeyPress(kp) => {
-                let column = if self.modes.shift { 1 } else if self.modes.ctrl { 2 } else { 0 };
-                let sym = self.keyboard_state.symbol_async(display, kp.detail, column).await?;
+                let sym = keys::symbol(&mut self.keyboard_state, display, kp.detail, kp.state).await?;
                 let redraw = match sym {
                     keysyms::KEY_Control_L | keysyms::KEY_Control_R => {
                         self.modes.ctrl = true;
                         false
                     }
-                    keysyms::KEY_Shift_L | keysyms::KEY_Shift_R => {
-                        self.modes.shift = true;
-                        false
-                    }
-
-                    keysyms::KEY_Escape => {
-                        self.hide(display).await?;
-                        focus_window(display, self.focused_window).await?;
-                        return Ok(CloseWindow);
-                    }
-
-                    keysyms::KEY_K | keysyms::KEY_k if self.modes.ctrl =>
-                        self.selection_up(),
-                    keysyms::KEY_Up =>
-                        self.selection_up(),
-                    keysyms::KEY_J | keysyms::KEY_j if self.modes.ctrl =>
-                        self.selection_down(),
-                    keysyms::KEY_Down =>
-                        self.selection_down(),
-
-                    keysyms::KEY_u | keysyms::KEY_U if self.modes.ctrl => {
-                        self.input.clear();
-                        self.research();
-                        true
-                    }
-                    keysyms::KEY_BackSpace => {
-                        self.input.pop();
-                        self.research();
-                        true
-                    }
-
-                    keysyms::KEY_Return => {
-                        self.hide(display).await?;
-                        focus_window(display, self.focused_window).await?;
-                        return if !self.searches.is_empty() {
-                            // Send Shift + Insert
-                            let choice = match self.searches.get(self.current_choice) {
-                                None => CloseWindow,
-                                Some(clip) => {
-                                    self.database.select_clip(clip.clone());
-                                    clipboard.take_ownership(display).await?;
-                                    if !self.modes.ctrl {
-                                        send_key(display, self.focused_window, self.root, 118, ModMask::SHIFT).await?;
-                                    }
-                                    CloseWindow
+                    sym if self.label_prompt.is_some() =>
+                        self.label_key(sym),
+                    sym => {
+                        let action = self.keymap.lock().unwrap().action(KeyCombo::new(self.modes.ctrl, sym));
+                        match action {
+                            Some(action) => match self.perform(display, action, clipboard).await? {
+                                Some(window_action) => return Ok(window_action),
+                                None => true,
+                            },
+                            None if self.modes.ctrl => true,
+                            None => {
+                                if let Some(char) = keys::typed_char(sym) {
+                                    self.input.push(char);
+                                    self.history_pos = None;
+                                    self.research();
                                 }
-                            };
-                            Ok(choice)
-                        } else {
-                            Ok(CloseWindow)
-                        };
-                    }
-                    _ if self.modes.ctrl => {
-                        true
-                    }
-                    key => {
-                        if let Some(char) = char::from_u32(key) {
-                            self.input.push(char);
-                            self.research();
+                                true
+                            }
                         }
-                        true
                     }
                 };
                 if redraw {
@@ -292,58 +817,132 @@ impl Window {
     }
 }
 
+// A black window over the monitor, made translucent (and blurred, on KDE) by
+// the compositor. Without a compositor it would black out the screen, so then
+// there is none.
+async fn create_backdrop<D: AsyncDisplay>(
+    display: &mut D,
+    geom: &Geometry,
+    opacity: f32,
+) -> Result<Option<xproto::Window>, Box<dyn Error>> {
+    let manager = format!("_NET_WM_CM_S{}", display.default_screen_index());
+    let manager = display.intern_atom_immediate(false, &manager).await?.atom;
+    if display.get_selection_owner_immediate(manager).await?.owner == 0 {
+        debug!("no compositor, so no backdrop");
+        return Ok(None);
+    }
+
+    let wid = display.generate_xid().await?;
+    let root = display.default_screen().root;
+    display.create_window_checked(
+        0,
+        wid,
+        root,
+        geom.x,
+        geom.y,
+        geom.width,
+        geom.height,
+        0,
+        xproto::WindowClass::COPY_FROM_PARENT,
+        0,
+        xproto::CreateWindowAux::new()
+            .background_pixel(display.default_screen().black_pixel)
+            .override_redirect(1),
+    ).await?;
+    let window_opacity = display.intern_atom_immediate(false, "_NET_WM_WINDOW_OPACITY").await?.atom;
+    let opacity = (opacity as f64 * u32::MAX as f64) as u32;
+    display.change_property_checked(
+        xproto::PropMode::REPLACE,
+        wid,
+        window_opacity,
+        xproto::Atom::from(xproto::AtomEnum::CARDINAL),
+        32,
+        1,
+        &[opacity][..],
+    ).await?;
+    // an empty region blurs the whole window
+    let blur = display.intern_atom_immediate(false, "_KDE_NET_WM_BLUR_BEHIND_REGION").await?.atom;
+    display.change_property_checked(
+        xproto::PropMode::REPLACE,
+        wid,
+        blur,
+        xproto::Atom::from(xproto::AtomEnum::CARDINAL),
+        32,
+        0,
+        &[0u32; 0][..],
+    ).await?;
+    Ok(Some(wid))
+}
+
 #[derive(Debug)]
-struct Geometry {
-    x: i16,
-    y: i16,
-    width: u16,
-    height: u16,
+pub(super) struct Geometry {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    /// The RandR name of the output, like `DP-1`
+    pub output: Option<String>,
 }
 
-// TODO: Take a keysym instead and look up the keycode
-async fn send_key<D: AsyncDisplay>(
-    dpy: &mut D,
-    window: xproto::Window,
-    root: xproto::Window,
-    key: xproto::Keycode,
-    modmask: ModMask,
-) -> breadx::Result<()> {
-    let mut event = xproto::KeyPressEvent {
-        response_type: xproto::KEY_PRESS_EVENT,
-        detail: key,
-        sequence: 0,
-        time: 0, // TODO: Need to set this?
-        root,
-        event: window,
-        child: 0,
-        root_x: 1,
-        root_y: 1,
-        event_x: 1,
-        event_y: 1,
-        state: modmask.into(),
-        same_screen: true,
-    };
-    let press_request = SendEventRequest {
-        propagate: true,
-        destination: window,
-        event_mask: EventMask::KEY_PRESS.into(),
-        event: Cow::Owned(event.into()),
-    };
-    let press_cookie = dpy.send_void_request(press_request, false).await?;
-    dpy.wait_for_reply(press_cookie).await?;
-
-    event.response_type = xproto::KEY_RELEASE_EVENT;
-    let release_request = SendEventRequest {
-        propagate: true,
-        destination: window,
-        event_mask: EventMask::KEY_RELEASE.into(),
-        event: Cow::Owned(event.into()),
+/// Chooses the newest clip and pastes it into the focused window, without
+/// showing the picker. Returns whether there was a clip to paste.
+pub async fn paste_last<D: AsyncDisplay>(
+    display: &mut D,
+    database: &Database,
+    clipboard: &mut Clipboard,
+) -> Result<bool, Box<dyn Error>> {
+    let clip = match database.sorted(SortMode::Recency, 1).pop() {
+        None => return Ok(false),
+        Some(clip) => clip,
     };
-    let release_cookie = dpy.send_void_request(release_request, false).await?;
-    dpy.wait_for_reply(release_cookie).await
+    let focused_window = get_focused_window(display).await?;
+    database.select_clip(clip);
+    clipboard.take_ownership(display).await?;
+    clipboard.paste(display, focused_window).await?;
+    Ok(true)
+}
+
+// The clip's tags and other notes about it, like how many near duplicates it
+// stands for, at the right end of the row
+// What is shown for a clip that isn't text
+fn placeholder(contents: &ClipContents) -> String {
+    match contents {
+        // which says what type it is
+        ClipContents::Binary { .. } => contents.text().into_owned(),
+        _ => format!("<binary data, {} KB>", contents.size().div_ceil(1024)),
+    }
+}
+
+fn draw_labels(canvas: &mut ui::canvas::Canvas, labels: &[String], color: &Color, row: u16) {
+    if labels.is_empty() {
+        return;
+    }
+    let labels = labels.join(" ");
+    let offset = canvas.width() as f32 - canvas.text_width(&labels);
+    canvas.draw_text(&labels, color, row, offset.max(0.0) as u16);
 }
 
-async fn focus_window<D: AsyncDisplay>(dpy: &mut D, window: xproto::Window) -> breadx::Result<()> {
+// The keyboard may still be grabbed by whatever ran `rpt show` on a hotkey, so
+// retry for a little while
+pub(super) async fn grab_keyboard<D: AsyncDisplay>(dpy: &mut D, window: xproto::Window) -> breadx::Result<bool> {
+    for _ in 0..50 {
+        let reply = dpy.grab_keyboard_immediate(
+            true,
+            window,
+            xproto::Time::CURRENT_TIME,
+            xproto::GrabMode::ASYNC,
+            xproto::GrabMode::ASYNC,
+        ).await?;
+        if reply.status == xproto::GrabStatus::SUCCESS {
+            return Ok(true);
+        }
+        debug!("keyboard grab failed ({:?}), retrying", reply.status);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    Ok(false)
+}
+
+pub(super) async fn focus_window<D: AsyncDisplay>(dpy: &mut D, window: xproto::Window) -> breadx::Result<()> {
     let cookie = dpy.send_void_request(
         xproto::SetInputFocusRequest {
             focus: window,
@@ -355,7 +954,10 @@ async fn focus_window<D: AsyncDisplay>(dpy: &mut D, window: xproto::Window) -> b
     dpy.wait_for_reply(cookie).await
 }
 
-async fn get_focused_window<D: AsyncDisplay>(connection: &mut D) -> breadx::Result<xproto::Window> {
+pub(super) async fn get_focused_window<D: AsyncDisplay>(connection: &mut D) -> breadx::Result<xproto::Window> {
+    if let Some(window) = wm::focused().await.and_then(|f| f.window) {
+        return Ok(window);
+    }
     // TODO: grab and ungrab with drop
     //connection.grab_server_checked()?;
     let focus = connection.get_input_focus().await?;
@@ -363,7 +965,7 @@ async fn get_focused_window<D: AsyncDisplay>(connection: &mut D) -> breadx::Resu
     //connection.ungrab_server_checked()?
 }
 
-async fn get_active_screen_geom<D: AsyncDisplay>(connection: &mut D) -> breadx::Result<Geometry> {
+pub(super) async fn get_active_screen_geom<D: AsyncDisplay>(connection: &mut D) -> breadx::Result<Geometry> {
     let focus = get_focused_window(connection).await?;
     let resources = {
         let request = protocol::randr::GetScreenResourcesRequest { window: focus };
@@ -378,7 +980,7 @@ async fn get_active_screen_geom<D: AsyncDisplay>(connection: &mut D) -> breadx::
     let mut crtcs = Vec::new();
     for crtc in resources.crtcs.iter() {
         let request = protocol::randr::GetCrtcInfoRequest {
-            crtc: crtc.clone(),
+            crtc: *crtc,
             config_timestamp: 0,
         };
         let cookie = connection.send_reply_request(request).await?;
@@ -402,10 +1004,24 @@ async fn get_active_screen_geom<D: AsyncDisplay>(connection: &mut D) -> breadx::
             crtcs.first().unwrap()
         });
 
+    let output = match active_crtc.outputs.first() {
+        Some(output) => {
+            let request = protocol::randr::GetOutputInfoRequest {
+                output: *output,
+                config_timestamp: 0,
+            };
+            let cookie = connection.send_reply_request(request).await?;
+            let reply = connection.wait_for_reply(cookie).await?;
+            Some(String::from_utf8_lossy(&reply.name).into_owned())
+        }
+        None => None,
+    };
+
     Ok(Geometry {
         x: active_crtc.x,
         y: active_crtc.y,
         width: active_crtc.width,
         height: active_crtc.height,
+        output,
     })
 }
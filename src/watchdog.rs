@@ -0,0 +1,165 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use breadx::prelude::*;
+use log::{error, warn};
+
+/// How often the X server is pinged.
+pub const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// How long a ping may go unanswered before it counts as missed.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Missed pings in a row after which the X server is given up on.
+pub const MAX_MISSED: usize = 3;
+/// How long the main loop may spend on one thing before it's reported.
+const SLOW: Duration = Duration::from_secs(10);
+/// How long it may spend on one thing before it's taken to be hung.
+const HUNG: Duration = Duration::from_secs(60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Keeps track of what the main loop is doing, so a hang can be told from
+/// a quiet spell: waiting for events counts as idle, anything else is
+/// expected to finish quickly. The main loop is the only one that locks the
+/// X connection, so what it's doing is what holds the lock.
+#[derive(Clone)]
+pub struct Watchdog {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    activity: Option<(&'static str, Instant)>,
+    answered: Instant,
+    missed: usize,
+}
+
+/// Marks the main loop busy until dropped.
+pub struct Busy {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().activity = None;
+    }
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog {
+            state: Arc::new(Mutex::new(State { activity: None, answered: Instant::now(), missed: 0 })),
+        }
+    }
+
+    /// Records that the main loop is doing `activity`, like "handling an X
+    /// event", until the returned guard is dropped.
+    pub fn busy(&self, activity: &'static str) -> Busy {
+        self.state.lock().unwrap().activity = Some((activity, Instant::now()));
+        Busy { state: self.state.clone() }
+    }
+
+    /// Records that the X server answered a ping.
+    pub fn answered(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.answered = Instant::now();
+        state.missed = 0;
+    }
+
+    /// Records that a ping went unanswered, and returns how many have in a row.
+    pub fn missed(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.missed += 1;
+        state.missed
+    }
+
+    /// What the main loop has been busy with for longer than `limit` by `now`.
+    fn stuck(&self, now: Instant, limit: Duration) -> Option<(&'static str, Duration)> {
+        let (activity, since) = self.state.lock().unwrap().activity?;
+        let busy = now.saturating_duration_since(since);
+        (busy > limit).then_some((activity, busy))
+    }
+
+    fn diagnostics(&self, now: Instant, connection_locked: bool) -> String {
+        let state = self.state.lock().unwrap();
+        format!(
+            "the X connection is {}, the X server last answered a ping {}s ago and has missed {} since",
+            if connection_locked { "locked" } else { "free" },
+            now.saturating_duration_since(state.answered).as_secs(),
+            state.missed
+        )
+    }
+
+    /// Checks on the main loop from a thread of its own, which keeps going
+    /// even if the async runtime is wedged. Slow activities are logged along
+    /// with the state of the X connection, and `hung` is called once one
+    /// has gone on for too long.
+    pub fn watch(
+        &self,
+        connection_locked: impl Fn() -> bool + Send + 'static,
+        hung: impl Fn() + Send + 'static,
+    ) -> std::io::Result<()> {
+        let watchdog = self.clone();
+        std::thread::Builder::new().name("watchdog".to_owned()).spawn(move || {
+            // the activity and when it started, once reported
+            let mut reported: Option<(&'static str, Instant)> = None;
+            loop {
+                std::thread::sleep(CHECK_INTERVAL);
+                let now = Instant::now();
+                if let Some((activity, busy)) = watchdog.stuck(now, HUNG) {
+                    error!(
+                        "rpt has been {} for {}s and looks hung; {}",
+                        activity,
+                        busy.as_secs(),
+                        watchdog.diagnostics(now, connection_locked())
+                    );
+                    hung();
+                    return;
+                }
+                let current = watchdog.state.lock().unwrap().activity;
+                if let Some((activity, busy)) = watchdog.stuck(now, SLOW) {
+                    if reported != current {
+                        warn!(
+                            "rpt has been {} for {}s; {}",
+                            activity,
+                            busy.as_secs(),
+                            watchdog.diagnostics(now, connection_locked())
+                        );
+                        reported = current;
+                    }
+                }
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Asks the X server for something trivial, to tell it's still answering.
+pub async fn ping<D: AsyncDisplay>(dpy: &mut D) -> breadx::Result<()> {
+    let cookie = dpy.get_input_focus().await?;
+    dpy.wait_for_reply(cookie).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::watchdog::Watchdog;
+
+    #[test]
+    fn stuck_only_while_busy() {
+        let watchdog = Watchdog::new();
+        let later = Instant::now() + Duration::from_secs(30);
+        assert_eq!(watchdog.stuck(later, Duration::from_secs(10)), None);
+
+        let busy = watchdog.busy("handling an X event");
+        assert_eq!(watchdog.stuck(Instant::now(), Duration::from_secs(10)), None);
+        let (activity, _) = watchdog.stuck(later, Duration::from_secs(10)).unwrap();
+        assert_eq!(activity, "handling an X event");
+        drop(busy);
+        assert_eq!(watchdog.stuck(later, Duration::from_secs(10)), None);
+
+        assert_eq!(watchdog.missed(), 1);
+        assert_eq!(watchdog.missed(), 2);
+        watchdog.answered();
+        assert_eq!(watchdog.missed(), 1);
+    }
+}
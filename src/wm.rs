@@ -0,0 +1,119 @@
+use std::env;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+// i3's IPC header: the magic string, then the payload length and message type
+// as native 32 bit integers. sway speaks the same protocol.
+const MAGIC: &[u8] = b"i3-ipc";
+const GET_TREE: u32 = 4;
+// How long the window manager has to answer. It's asked while the X
+// connection is locked, so one that hangs mustn't hold everything else up.
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Asks i3 or sway for the focused window from now on, rather than X. Some
+/// toolkits give the input focus to a proxy window, which isn't where a paste
+/// should go.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// The window i3 or sway says is focused.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Focused {
+    /// The X window, if it's an X11 client
+    pub window: Option<u32>,
+    /// The app_id of a Wayland client or the WM_CLASS class of an X11 one
+    pub app: Option<String>,
+}
+
+/// The focused window, if enabled and the window manager can be asked.
+pub async fn focused() -> Option<Focused> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    match tokio::time::timeout(TIMEOUT, get_tree()).await {
+        Ok(Ok(tree)) => find_focused(&tree),
+        Ok(Err(e)) => {
+            log::debug!("unable to ask the window manager for the focus: {}", e);
+            None
+        }
+        Err(_) => {
+            log::warn!("the window manager didn't say what's focused within {:?}", TIMEOUT);
+            None
+        }
+    }
+}
+
+async fn get_tree() -> Result<Value, Box<dyn Error>> {
+    let path = env::var_os("SWAYSOCK")
+        .or_else(|| env::var_os("I3SOCK"))
+        .ok_or("neither SWAYSOCK nor I3SOCK is set")?;
+    let mut stream = UnixStream::connect(path).await?;
+
+    let mut request = MAGIC.to_vec();
+    request.extend(0u32.to_ne_bytes());
+    request.extend(GET_TREE.to_ne_bytes());
+    stream.write_all(&request).await?;
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await?;
+    if &header[..6] != MAGIC {
+        return Err("not an i3 IPC reply".into());
+    }
+    let len = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+// Looks through the layout tree for the focused container
+fn find_focused(node: &Value) -> Option<Focused> {
+    if node["focused"].as_bool() == Some(true) {
+        let app = node["app_id"]
+            .as_str()
+            .or_else(|| node["window_properties"]["class"].as_str())
+            .map(|app| app.to_owned());
+        let window = node["window"].as_u64().and_then(|w| u32::try_from(w).ok());
+        return Some(Focused { window, app });
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(find_focused)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wm::{find_focused, Focused};
+
+    #[test]
+    fn focused_container() {
+        let tree = serde_json::json!({
+            "focused": false,
+            "nodes": [
+                {"focused": false, "nodes": [
+                    {"focused": false, "window": 1, "window_properties": {"class": "Firefox"}},
+                ]},
+                {"focused": false, "nodes": [], "floating_nodes": [
+                    {"focused": true, "window": 4194307, "window_properties": {"class": "XTerm"}},
+                ]},
+            ],
+        });
+        let expected = Focused { window: Some(4194307), app: Some("XTerm".to_owned()) };
+        assert_eq!(find_focused(&tree), Some(expected));
+
+        // Wayland clients in sway have an app_id and no X window
+        let sway = serde_json::json!({"nodes": [{"focused": true, "app_id": "foot", "window": null}]});
+        assert_eq!(find_focused(&sway), Some(Focused { window: None, app: Some("foot".to_owned()) }));
+
+        assert_eq!(find_focused(&serde_json::json!({"nodes": []})), None);
+    }
+}